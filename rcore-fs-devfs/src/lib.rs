@@ -66,6 +66,13 @@ impl DevFS {
         static ID: AtomicUsize = AtomicUsize::new(1);
         ID.fetch_add(1, Ordering::SeqCst)
     }
+
+    /// Generate a new cookie for directory entries, 0 and 1 are reserved for `.` and `..`
+    fn new_cookie() -> usize {
+        use core::sync::atomic::*;
+        static COOKIE: AtomicUsize = AtomicUsize::new(2);
+        COOKIE.fetch_add(1, Ordering::SeqCst)
+    }
 }
 
 pub struct DevINode {
@@ -73,6 +80,9 @@ pub struct DevINode {
     parent: Weak<DevINode>,
     fs: RwLock<Weak<DevFS>>,
     children: RwLock<BTreeMap<String, Arc<dyn INode>>>,
+    /// Names of children ordered by their cookies for `read_dir`,
+    /// always locked after `children`
+    cookies: RwLock<BTreeMap<usize, String>>,
     inode_id: usize,
 }
 
@@ -83,6 +93,7 @@ impl DevINode {
             parent,
             fs: RwLock::new(Weak::default()),
             children: RwLock::new(BTreeMap::new()),
+            cookies: RwLock::new(BTreeMap::new()),
             inode_id: DevFS::new_inode_id(),
         }
         .wrap()
@@ -114,6 +125,9 @@ impl DevINode {
         let dir = Self::new_with_parent(self.this.clone());
         *dir.fs.write() = self.fs.read().clone();
         children.insert(String::from(name), dir.clone());
+        self.cookies
+            .write()
+            .insert(DevFS::new_cookie(), String::from(name));
         Ok(dir)
     }
 
@@ -123,12 +137,16 @@ impl DevINode {
             return Err(FsError::EntryExist);
         }
         children.insert(String::from(name), dev);
+        self.cookies
+            .write()
+            .insert(DevFS::new_cookie(), String::from(name));
         Ok(())
     }
 
     pub fn remove(&self, name: &str) -> Result<()> {
         let mut children = self.children.write();
         children.remove(name).ok_or(FsError::EntryNotFound)?;
+        self.cookies.write().retain(|_, child| child != name);
         Ok(())
     }
 }
@@ -224,6 +242,32 @@ impl INode for DevINode {
         }
     }
 
+    /// Cookie 0 and 1 are `.` and `..`, children have increasing cookies from `new_cookie`
+    fn read_dir(&self, cookie: usize) -> Result<Option<DirEntry>> {
+        let (name, inode, next_cookie) = match cookie {
+            0 => (String::from("."), self.find(".")?, 1),
+            // `..` of root is itself
+            1 => match self.parent.upgrade() {
+                Some(parent) => (String::from(".."), parent as Arc<dyn INode>, 2),
+                None => (String::from(".."), self.find(".")?, 2),
+            },
+            _ => {
+                let children = self.children.read();
+                match self.cookies.read().range(cookie..).next() {
+                    Some((&cookie, name)) => (name.clone(), children[name].clone(), cookie + 1),
+                    None => return Ok(None),
+                }
+            }
+        };
+        let info = inode.metadata()?;
+        Ok(Some(DirEntry {
+            name,
+            inode: info.inode,
+            type_: info.type_,
+            next_cookie,
+        }))
+    }
+
     fn io_control(&self, _cmd: u32, _data: usize) -> Result<usize> {
         Err(FsError::NotSupported)
     }
@@ -1,10 +1,13 @@
 use fuse::{
     FileAttr, FileType, Filesystem, ReplyAttr, ReplyData, ReplyDirectory, ReplyEmpty, ReplyEntry,
-    ReplyStatfs, ReplyWrite, Request,
+    ReplyLock, ReplyStatfs, ReplyWrite, ReplyXattr, Request,
 };
+use rcore_fs::lock::{LockKey, LockManager, LockType, PosixLock};
+use rcore_fs::util::block_on;
 use rcore_fs::vfs;
 use std::collections::btree_map::BTreeMap;
 use std::ffi::OsStr;
+use std::path::Path;
 use std::sync::Arc;
 use time::Timespec;
 
@@ -13,13 +16,18 @@ const TTL: Timespec = Timespec { sec: 1, nsec: 0 }; // 1 second
 pub struct VfsFuse {
     fs: Arc<dyn vfs::FileSystem>,
     inodes: BTreeMap<usize, Arc<dyn vfs::INode>>,
+    locks: Arc<LockManager>,
 }
 
 impl VfsFuse {
     pub fn new(fs: Arc<dyn vfs::FileSystem>) -> Self {
         let mut inodes = BTreeMap::new();
         inodes.insert(1, fs.root_inode());
-        VfsFuse { fs, inodes }
+        VfsFuse {
+            fs,
+            inodes,
+            locks: Arc::new(LockManager::new()),
+        }
     }
     fn trans_time(time: vfs::Timespec) -> Timespec {
         Timespec {
@@ -63,23 +71,39 @@ impl VfsFuse {
         }
     }
     fn trans_error(err: vfs::FsError) -> i32 {
-        use libc::*;
-        match err {
-            vfs::FsError::NotSupported => ENOSYS,
-            vfs::FsError::EntryNotFound => ENOENT,
-            vfs::FsError::EntryExist => EEXIST,
-            vfs::FsError::IsDir => EISDIR,
-            vfs::FsError::NotFile => EISDIR,
-            vfs::FsError::NotDir => ENOTDIR,
-            vfs::FsError::NotSameFs => EXDEV,
-            vfs::FsError::InvalidParam => EINVAL,
-            vfs::FsError::NoDeviceSpace => ENOSPC,
-            vfs::FsError::DirRemoved => ENOENT,
-            vfs::FsError::DirNotEmpty => ENOTEMPTY,
-            vfs::FsError::WrongFs => EINVAL,
-            _ => EINVAL,
+        err.to_errno()
+    }
+    /// Reply the size of `data` if `size` is 0, otherwise the data itself
+    fn reply_xattr(reply: ReplyXattr, data: &[u8], size: u32) {
+        if size == 0 {
+            reply.size(data.len() as u32);
+        } else if data.len() > size as usize {
+            reply.error(libc::ERANGE);
+        } else {
+            reply.data(data);
         }
     }
+    /// Translate a lock request, whose `end` is inclusive
+    fn trans_lock(start: u64, end: u64, typ: u32, owner: u64, pid: u32) -> vfs::Result<PosixLock> {
+        let type_ = match typ as i32 {
+            libc::F_RDLCK => LockType::Read,
+            libc::F_WRLCK => LockType::Write,
+            libc::F_UNLCK => LockType::Unlock,
+            _ => return Err(vfs::FsError::InvalidParam),
+        };
+        let end = if end >= i64::MAX as u64 {
+            usize::MAX
+        } else {
+            end as usize + 1
+        };
+        Ok(PosixLock {
+            type_,
+            start: start as usize,
+            end,
+            owner,
+            pid,
+        })
+    }
     fn get_inode(&self, ino: u64) -> vfs::Result<&Arc<dyn vfs::INode>> {
         self.inodes
             .get(&(ino as usize))
@@ -164,6 +188,12 @@ impl Filesystem for VfsFuse {
         reply.attr(&TTL, &attr);
     }
 
+    fn readlink(&mut self, _req: &Request, ino: u64, reply: ReplyData) {
+        let inode = try_vfs!(reply, self.get_inode(ino));
+        let target = try_vfs!(reply, inode.read_link());
+        reply.data(target.as_bytes());
+    }
+
     fn mknod(
         &mut self,
         _req: &Request,
@@ -203,6 +233,24 @@ impl Filesystem for VfsFuse {
         self.unlink(req, parent, name, reply);
     }
 
+    fn symlink(
+        &mut self,
+        _req: &Request,
+        parent: u64,
+        name: &OsStr,
+        link: &Path,
+        reply: ReplyEntry,
+    ) {
+        let name = name.to_str().unwrap();
+        let link = link.to_str().unwrap();
+        let inode = try_vfs!(reply, self.get_inode(parent));
+        let target = try_vfs!(reply, inode.symlink(name, link));
+        let info = try_vfs!(reply, target.metadata());
+        self.inodes.insert(info.inode, target);
+        let attr = Self::trans_attr(info);
+        reply.entry(&TTL, &attr, 0);
+    }
+
     fn rename(
         &mut self,
         _req: &Request,
@@ -220,6 +268,30 @@ impl Filesystem for VfsFuse {
         reply.ok();
     }
 
+    /// `RENAME_EXCHANGE` on macOS. The kernel ABI of `fuse` doesn't pass rename flags on Linux.
+    #[cfg(target_os = "macos")]
+    fn exchange(
+        &mut self,
+        _req: &Request,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        _options: u64,
+        reply: ReplyEmpty,
+    ) {
+        let name = name.to_str().unwrap();
+        let newname = newname.to_str().unwrap();
+        let parent = try_vfs!(reply, self.get_inode(parent));
+        let newparent = try_vfs!(reply, self.get_inode(newparent));
+        let flags = vfs::RenameFlags {
+            exchange: true,
+            ..Default::default()
+        };
+        try_vfs!(reply, parent.rename(name, newparent, newname, flags));
+        reply.ok();
+    }
+
     fn link(
         &mut self,
         _req: &Request,
@@ -268,9 +340,12 @@ impl Filesystem for VfsFuse {
         reply.written(len as u32);
     }
 
-    fn flush(&mut self, _req: &Request, ino: u64, _fh: u64, _lock_owner: u64, reply: ReplyEmpty) {
+    fn flush(&mut self, _req: &Request, ino: u64, _fh: u64, lock_owner: u64, reply: ReplyEmpty) {
         let inode = try_vfs!(reply, self.get_inode(ino));
         try_vfs!(reply, inode.sync_data());
+        // closing any descriptor of the file releases the POSIX locks of its owner
+        let key = try_vfs!(reply, LockKey::new(inode.as_ref()));
+        self.locks.release_posix(key, lock_owner);
         reply.ok();
     }
 
@@ -293,16 +368,15 @@ impl Filesystem for VfsFuse {
         mut reply: ReplyDirectory,
     ) {
         let inode = try_vfs!(reply, self.get_inode(ino));
-        for i in offset as usize.. {
-            let name = match inode.get_entry(i) {
-                Ok(name) => name,
-                Err(vfs::FsError::EntryNotFound) => break,
-                e @ _ => try_vfs!(reply, e),
-            };
-            let inode = try_vfs!(reply, inode.find(name.as_str()));
-            let info = try_vfs!(reply, inode.metadata());
-            let kind = Self::trans_type(info.type_);
-            let full = reply.add(info.inode as u64, i as i64 + 1, kind, name);
+        for entry in inode.entries(offset as usize) {
+            let entry = try_vfs!(reply, entry);
+            let kind = Self::trans_type(entry.type_);
+            let full = reply.add(
+                entry.inode as u64,
+                entry.next_cookie as i64,
+                kind,
+                entry.name,
+            );
             if full {
                 break;
             }
@@ -323,4 +397,107 @@ impl Filesystem for VfsFuse {
             info.frsize as u32,
         );
     }
+
+    fn setxattr(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        name: &OsStr,
+        value: &[u8],
+        flags: u32,
+        _position: u32,
+        reply: ReplyEmpty,
+    ) {
+        let inode = try_vfs!(reply, self.get_inode(ino));
+        let flags = vfs::XattrFlags {
+            create: flags & libc::XATTR_CREATE as u32 != 0,
+            replace: flags & libc::XATTR_REPLACE as u32 != 0,
+        };
+        try_vfs!(reply, inode.set_xattr(name.to_str().unwrap(), value, flags));
+        reply.ok();
+    }
+
+    fn getxattr(&mut self, _req: &Request, ino: u64, name: &OsStr, size: u32, reply: ReplyXattr) {
+        let inode = try_vfs!(reply, self.get_inode(ino));
+        let value = try_vfs!(reply, inode.get_xattr(name.to_str().unwrap()));
+        Self::reply_xattr(reply, &value, size);
+    }
+
+    fn listxattr(&mut self, _req: &Request, ino: u64, size: u32, reply: ReplyXattr) {
+        let inode = try_vfs!(reply, self.get_inode(ino));
+        let names = try_vfs!(reply, inode.list_xattr());
+        let mut list = Vec::new();
+        for name in names {
+            list.extend_from_slice(name.as_bytes());
+            list.push(0);
+        }
+        Self::reply_xattr(reply, &list, size);
+    }
+
+    fn removexattr(&mut self, _req: &Request, ino: u64, name: &OsStr, reply: ReplyEmpty) {
+        let inode = try_vfs!(reply, self.get_inode(ino));
+        try_vfs!(reply, inode.remove_xattr(name.to_str().unwrap()));
+        reply.ok();
+    }
+
+    fn getlk(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        _fh: u64,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        typ: u32,
+        pid: u32,
+        reply: ReplyLock,
+    ) {
+        let inode = try_vfs!(reply, self.get_inode(ino));
+        let key = try_vfs!(reply, LockKey::new(inode.as_ref()));
+        let lock = try_vfs!(reply, Self::trans_lock(start, end, typ, lock_owner, pid));
+        match self.locks.test_posix(key, &lock) {
+            Some(holder) => {
+                let typ = match holder.type_ {
+                    LockType::Read => libc::F_RDLCK,
+                    _ => libc::F_WRLCK,
+                };
+                let end = if holder.end == usize::MAX {
+                    i64::MAX as u64
+                } else {
+                    holder.end as u64 - 1
+                };
+                reply.locked(holder.start as u64, end, typ as u32, holder.pid);
+            }
+            None => reply.locked(start, end, libc::F_UNLCK as u32, pid),
+        }
+    }
+
+    fn setlk(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        _fh: u64,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        typ: u32,
+        pid: u32,
+        sleep: bool,
+        reply: ReplyEmpty,
+    ) {
+        let inode = try_vfs!(reply, self.get_inode(ino));
+        let key = try_vfs!(reply, LockKey::new(inode.as_ref()));
+        let lock = try_vfs!(reply, Self::trans_lock(start, end, typ, lock_owner, pid));
+        if !sleep {
+            try_vfs!(reply, self.locks.try_set_posix(key, lock));
+            reply.ok();
+            return;
+        }
+        // wait in another thread, so that the holder can still reach us to unlock
+        let locks = self.locks.clone();
+        std::thread::spawn(move || match block_on(locks.set_posix(key, lock)) {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(Self::trans_error(err)),
+        });
+    }
 }
@@ -1,3 +1,5 @@
+#[cfg(feature = "use_fuse")]
+use std::ffi::OsStr;
 use std::fs::OpenOptions;
 use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
@@ -5,6 +7,8 @@ use std::sync::{Arc, Mutex};
 use structopt::StructOpt;
 
 use rcore_fs::dev::std_impl::StdTimeProvider;
+#[cfg(feature = "use_fuse")]
+use rcore_fs::read_only::ReadOnlyFS;
 use rcore_fs::vfs::FileSystem;
 #[cfg(feature = "use_fuse")]
 use rcore_fs_fuse::fuse::VfsFuse;
@@ -25,13 +29,34 @@ struct Opt {
     #[structopt(parse(from_os_str))]
     image: PathBuf,
 
-    /// Target directory
+    /// Target directory, not used by check
     #[structopt(parse(from_os_str))]
-    dir: PathBuf,
+    dir: Option<PathBuf>,
 
     /// File system: [sfs | sefs | ramfs]
     #[structopt(short = "f", long = "fs", default_value = "sfs")]
     fs: String,
+
+    /// Blocks of the metadata journal of a new SFS image, 0 for none.
+    /// It must hold the largest transaction of an operation.
+    #[structopt(long = "journal", default_value = "0")]
+    journal: usize,
+
+    /// Index large directories of a new SFS image by names
+    #[structopt(long = "dir-index")]
+    dir_index: bool,
+
+    /// Store directories of a new SFS image in compact entries of variable length
+    #[structopt(long = "compact-dirents")]
+    compact_dirents: bool,
+
+    /// Map blocks of a new SFS image by extent trees
+    #[structopt(long = "extents")]
+    extents: bool,
+
+    /// Count shared blocks of a new SFS image in a refmap, so that files can be cloned
+    #[structopt(long = "refmap")]
+    refmap: bool,
 }
 
 #[derive(Debug, StructOpt)]
@@ -47,7 +72,20 @@ enum Cmd {
     /// Mount <image> to <dir>
     #[cfg(feature = "use_fuse")]
     #[structopt(name = "mount")]
-    Mount,
+    Mount {
+        /// Mount read-only, never write to <image>
+        #[structopt(long = "read-only")]
+        read_only: bool,
+    },
+
+    /// Check the SFS <image>, exit with 0 if clean, 1 if repaired, 4 if not repaired,
+    /// and 8 if it can not be checked
+    #[structopt(name = "check")]
+    Check {
+        /// Repair the problems found
+        #[structopt(long = "repair")]
+        repair: bool,
+    },
 
     #[structopt(name = "git-version")]
     GitVersion,
@@ -58,42 +96,111 @@ fn main() {
     let opt = Opt::from_args();
 
     // open or create
-    let create = match opt.cmd {
+    let (create, read_only) = match opt.cmd {
         #[cfg(feature = "use_fuse")]
-        Cmd::Mount => !opt.image.is_dir() && !opt.image.is_file(),
-        Cmd::Zip => true,
-        Cmd::Unzip => false,
+        Cmd::Mount { read_only } => (!opt.image.is_dir() && !opt.image.is_file(), read_only),
+        Cmd::Zip => (true, false),
+        Cmd::Unzip => (false, true),
         Cmd::GitVersion => {
             println!("{}", git_version!());
             return;
         }
+        Cmd::Check { repair } => {
+            if opt.fs != "sfs" {
+                panic!("only sfs can be checked");
+            }
+            // an operational error, as of e2fsck
+            const CHECK_FAILED: i32 = 8;
+            let file = match OpenOptions::new().read(true).write(repair).open(&opt.image) {
+                Ok(file) => file,
+                Err(e) => {
+                    eprintln!("failed to open image: {}", e);
+                    std::process::exit(CHECK_FAILED);
+                }
+            };
+            let problems = match sfs::fsck::check(Arc::new(Mutex::new(file)), repair) {
+                Ok(problems) => problems,
+                Err(e) => {
+                    eprintln!("failed to check sfs: {:?}", e);
+                    std::process::exit(CHECK_FAILED);
+                }
+            };
+            for problem in problems.iter() {
+                println!("{}", problem);
+            }
+            std::process::exit(match (problems.is_empty(), repair) {
+                (true, _) => 0,
+                (false, true) => 1,
+                (false, false) => 4,
+            });
+        }
     };
+    if create && read_only {
+        eprintln!(
+            "{} does not exist, and is not created in read-only mode",
+            opt.image.display()
+        );
+        std::process::exit(2);
+    }
+    let dir = opt.dir.expect("<dir> is required");
 
     let fs: Arc<dyn FileSystem> = match opt.fs.as_str() {
         "sfs" => {
             let file = OpenOptions::new()
                 .read(true)
-                .write(create)
+                .write(!read_only)
                 .create(create)
                 .truncate(create)
                 .open(&opt.image)
                 .expect("failed to open image");
             let device = Mutex::new(file);
             const MAX_SPACE: usize = 0x1000 * 0x1000 * 1024; // 1G
-            match create {
-                true => sfs::SimpleFileSystem::create(Arc::new(device), MAX_SPACE)
-                    .expect("failed to create sfs"),
-                false => sfs::SimpleFileSystem::open(Arc::new(device)).expect("failed to open sfs"),
+            match (create, read_only) {
+                (true, _) => {
+                    let options = sfs::CreateOptions {
+                        journal_blocks: opt.journal,
+                        dir_index: opt.dir_index,
+                        compact_dirents: opt.compact_dirents,
+                        extents: opt.extents,
+                        refmap: opt.refmap,
+                    };
+                    let min = sfs::SimpleFileSystem::min_journal_blocks(MAX_SPACE, &options);
+                    if opt.journal != 0 && opt.journal < min {
+                        eprintln!("the journal needs {} blocks at least", min);
+                        std::process::exit(2);
+                    }
+                    sfs::SimpleFileSystem::create_with_options(
+                        Arc::new(device),
+                        MAX_SPACE,
+                        &options,
+                    )
+                    .expect("failed to create sfs")
+                }
+                (false, true) => sfs::SimpleFileSystem::open_read_only(Arc::new(device))
+                    .expect("failed to open sfs"),
+                (false, false) => {
+                    sfs::SimpleFileSystem::open(Arc::new(device)).expect("failed to open sfs")
+                }
             }
         }
         "sefs" => {
             std::fs::create_dir_all(&opt.image).unwrap();
-            let device = sefs::dev::StdStorage::new(&opt.image);
-            match create {
-                true => sefs::SEFS::create(Box::new(device), &StdTimeProvider)
-                    .expect("failed to create sefs"),
-                false => sefs::SEFS::open(Box::new(device), &StdTimeProvider)
-                    .expect("failed to open sefs"),
+            match (create, read_only) {
+                (true, _) => {
+                    let device = sefs::dev::StdStorage::new(&opt.image);
+                    sefs::SEFS::create(Box::new(device), &StdTimeProvider)
+                        .expect("failed to create sefs")
+                }
+                (false, true) => {
+                    let device = sefs::dev::StdStorage::new_read_only(&opt.image);
+                    sefs::SEFS::open_read_only(Box::new(device), &StdTimeProvider)
+                        .expect("failed to open sefs")
+                }
+                (false, false) => {
+                    let device = sefs::dev::StdStorage::new(&opt.image);
+                    sefs::SEFS::open(Box::new(device), &StdTimeProvider)
+                        .expect("failed to open sefs")
+                }
             }
         }
         "ramfs" => ramfs::RamFS::new(),
@@ -101,16 +208,23 @@ fn main() {
     };
     match opt.cmd {
         #[cfg(feature = "use_fuse")]
-        Cmd::Mount => {
-            fuse::mount(VfsFuse::new(fs), &opt.dir, &[]).expect("failed to mount fs");
+        Cmd::Mount { read_only } => {
+            if read_only {
+                // ramfs has no read-only mode, and the others reject the changes anyway
+                let fs = ReadOnlyFS::new(fs);
+                let options = [OsStr::new("-o"), OsStr::new("ro")];
+                fuse::mount(VfsFuse::new(fs), &dir, &options).expect("failed to mount fs");
+            } else {
+                fuse::mount(VfsFuse::new(fs), &dir, &[]).expect("failed to mount fs");
+            }
         }
         Cmd::Zip => {
-            zip_dir(&opt.dir, fs.root_inode()).expect("failed to zip fs");
+            zip_dir(&dir, fs.root_inode()).expect("failed to zip fs");
         }
         Cmd::Unzip => {
-            std::fs::create_dir(&opt.dir).expect("failed to create dir");
-            unzip_dir(&opt.dir, fs.root_inode()).expect("failed to unzip fs");
+            std::fs::create_dir(&dir).expect("failed to create dir");
+            unzip_dir(&dir, fs.root_inode()).expect("failed to unzip fs");
         }
-        Cmd::GitVersion => unreachable!(),
+        Cmd::GitVersion | Cmd::Check { .. } => unreachable!(),
     }
 }
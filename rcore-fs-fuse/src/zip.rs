@@ -1,21 +1,35 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
 use std::error::Error;
 use std::fs;
-use std::io::{Read, Write};
-#[cfg(unix)]
-use std::os::unix::ffi::OsStrExt;
+use std::hash::Hasher;
+use std::io::{Read, Seek, SeekFrom, Write};
 use std::path::Path;
-use std::str;
 use std::sync::Arc;
 
 use rcore_fs::{
     util::uninit_memory,
-    vfs::{FileType, INode},
+    vfs::{FileType, FsError, INode},
 };
 
 const DEFAULT_MODE: u32 = 0o664;
 const BUF_SIZE: usize = 0x1000;
 
+/// Files packed, by their size and content hash
+type Packed = HashMap<(usize, u64), Vec<Arc<dyn INode>>>;
+
+/// Pack the directory at `path` into `inode`.
+///
+/// Files with the same content share their blocks if the file system supports `clone_range`.
 pub fn zip_dir(path: &Path, inode: Arc<dyn INode>) -> Result<(), Box<dyn Error>> {
+    zip_dir_dedup(path, inode, &mut Packed::new())
+}
+
+fn zip_dir_dedup(
+    path: &Path,
+    inode: Arc<dyn INode>,
+    packed: &mut Packed,
+) -> Result<(), Box<dyn Error>> {
     let dir = fs::read_dir(path)?;
     for entry in dir {
         let entry = entry?;
@@ -25,35 +39,96 @@ pub fn zip_dir(path: &Path, inode: Arc<dyn INode>) -> Result<(), Box<dyn Error>>
         if type_.is_file() {
             let inode = inode.create(name, FileType::File, DEFAULT_MODE)?;
             let mut file = fs::File::open(entry.path())?;
-            inode.resize(file.metadata()?.len() as usize)?;
+            let size = file.metadata()?.len() as usize;
+            let key = (size, hash_file(&mut file)?);
+            if size > 0 && clone_same(&inode, &mut file, packed.entry(key).or_default())? {
+                continue;
+            }
+            file.seek(SeekFrom::Start(0))?;
+            inode.resize(size)?;
             let mut buf: [u8; BUF_SIZE] = unsafe { uninit_memory() };
             let mut offset = 0usize;
             let mut len = BUF_SIZE;
             while len == BUF_SIZE {
                 len = file.read(&mut buf)?;
-                inode.write_at(offset, &buf[..len])?;
+                // zeros are left as holes
+                if buf[..len].iter().any(|&b| b != 0) {
+                    inode.write_at(offset, &buf[..len])?;
+                }
                 offset += len;
             }
+            if size > 0 {
+                packed.entry(key).or_default().push(inode);
+            }
         } else if type_.is_dir() {
             let inode = inode.create(name, FileType::Dir, DEFAULT_MODE)?;
-            zip_dir(entry.path().as_path(), inode)?;
+            zip_dir_dedup(entry.path().as_path(), inode, packed)?;
         } else if type_.is_symlink() {
             let target = fs::read_link(entry.path())?;
-            let inode = inode.create(name, FileType::SymLink, DEFAULT_MODE)?;
-            #[cfg(unix)]
-            let data = target.as_os_str().as_bytes();
-            #[cfg(windows)]
-            let data = target.to_str().unwrap().as_bytes();
-            inode.resize(data.len())?;
-            inode.write_at(0, data)?;
+            let target = target
+                .to_str()
+                .ok_or_else(|| format!("{:?}: the target is not UTF-8", entry.path()))?;
+            inode.symlink(name, target)?;
         }
     }
     Ok(())
 }
 
+fn hash_file(file: &mut fs::File) -> Result<u64, Box<dyn Error>> {
+    let mut hasher = DefaultHasher::new();
+    let mut buf: [u8; BUF_SIZE] = unsafe { uninit_memory() };
+    loop {
+        let len = file.read(&mut buf)?;
+        if len == 0 {
+            return Ok(hasher.finish());
+        }
+        hasher.write(&buf[..len]);
+    }
+}
+
+/// Clone the content of `file` into `inode` from one of `candidates` with the same content.
+/// Return false if there is none, or the file system can not clone.
+fn clone_same(
+    inode: &Arc<dyn INode>,
+    file: &mut fs::File,
+    candidates: &[Arc<dyn INode>],
+) -> Result<bool, Box<dyn Error>> {
+    for candidate in candidates {
+        if !same_content(candidate, file)? {
+            continue;
+        }
+        return match inode.clone_range(candidate.as_ref(), 0, 0, 0) {
+            Ok(()) => Ok(true),
+            Err(FsError::NotSupported) => Ok(false),
+            Err(e) => Err(e.into()),
+        };
+    }
+    Ok(false)
+}
+
+fn same_content(inode: &Arc<dyn INode>, file: &mut fs::File) -> Result<bool, Box<dyn Error>> {
+    file.seek(SeekFrom::Start(0))?;
+    let mut buf: [u8; BUF_SIZE] = unsafe { uninit_memory() };
+    let mut inode_buf: [u8; BUF_SIZE] = unsafe { uninit_memory() };
+    let mut offset = 0usize;
+    loop {
+        let len = file.read(&mut buf)?;
+        if len == 0 {
+            return Ok(true);
+        }
+        if inode.read_at(offset, &mut inode_buf[..len])? != len || buf[..len] != inode_buf[..len] {
+            return Ok(false);
+        }
+        offset += len;
+    }
+}
+
 pub fn unzip_dir(path: &Path, inode: Arc<dyn INode>) -> Result<(), Box<dyn Error>> {
-    let files = inode.list()?;
-    for name in files.iter().skip(2) {
+    for entry in inode.entries(0) {
+        let name = entry?.name;
+        if name == "." || name == ".." {
+            continue;
+        }
         let inode = inode.lookup(name.as_str())?;
         let mut path = path.to_path_buf();
         path.push(name);
@@ -61,13 +136,24 @@ pub fn unzip_dir(path: &Path, inode: Arc<dyn INode>) -> Result<(), Box<dyn Error
         match info.type_ {
             FileType::File => {
                 let mut file = fs::File::create(&path)?;
+                // only copy the data, holes are left by `set_len`
+                file.set_len(info.size as u64)?;
                 let mut buf: [u8; BUF_SIZE] = unsafe { uninit_memory() };
                 let mut offset = 0usize;
-                let mut len = BUF_SIZE;
-                while len == BUF_SIZE {
-                    len = inode.read_at(offset, buf.as_mut())?;
-                    file.write(&buf[..len])?;
-                    offset += len;
+                while let Some(begin) = inode.seek_data(offset)? {
+                    let end = inode.seek_hole(begin)?.unwrap_or(info.size);
+                    file.seek(SeekFrom::Start(begin as u64))?;
+                    offset = begin;
+                    while offset < end {
+                        let len = BUF_SIZE.min(end - offset);
+                        let len = inode.read_at(offset, &mut buf[..len])?;
+                        if len == 0 {
+                            break;
+                        }
+                        file.write_all(&buf[..len])?;
+                        offset += len;
+                    }
+                    offset = end;
                 }
             }
             FileType::Dir => {
@@ -75,12 +161,11 @@ pub fn unzip_dir(path: &Path, inode: Arc<dyn INode>) -> Result<(), Box<dyn Error
                 unzip_dir(path.as_path(), inode)?;
             }
             FileType::SymLink => {
-                let mut buf: [u8; BUF_SIZE] = unsafe { uninit_memory() };
-                let len = inode.read_at(0, buf.as_mut())?;
+                let target = inode.read_link()?;
                 #[cfg(unix)]
-                std::os::unix::fs::symlink(str::from_utf8(&buf[..len]).unwrap(), path)?;
+                std::os::unix::fs::symlink(target, path)?;
                 #[cfg(windows)]
-                std::os::windows::fs::symlink_file(str::from_utf8(&buf[..len]).unwrap(), path)?;
+                std::os::windows::fs::symlink_file(target, path)?;
             }
             _ => panic!("unsupported file type"),
         }
@@ -2,7 +2,9 @@
 
 use core::any::Any;
 use rcore_fs::vfs::*;
+use std::ffi::CString;
 use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::ffi::OsStrExt;
 use std::path::{Path, PathBuf};
 use std::string::String;
 use std::sync::{Arc, Weak};
@@ -98,7 +100,7 @@ impl INode for HNode {
     }
 
     fn metadata(&self) -> Result<Metadata> {
-        let metadata = self.path.metadata()?;
+        let metadata = self.path.symlink_metadata()?;
         Ok(metadata.into())
     }
 
@@ -127,6 +129,87 @@ impl INode for HNode {
         .map_err(|_| FsError::InvalidParam)
     }
 
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    fn get_xattr(&self, name: &str) -> Result<Vec<u8>> {
+        let path = self.c_path()?;
+        let name = CString::new(name).map_err(|_| FsError::InvalidParam)?;
+        loop {
+            let size = xattr::check(unsafe {
+                xattr::get(path.as_ptr(), name.as_ptr(), std::ptr::null_mut(), 0)
+            })?;
+            let mut value = vec![0u8; size];
+            let ret =
+                unsafe { xattr::get(path.as_ptr(), name.as_ptr(), value.as_mut_ptr() as _, size) };
+            match xattr::check(ret) {
+                Ok(len) => {
+                    value.truncate(len);
+                    return Ok(value);
+                }
+                // the value grew between the two calls, try again
+                Err(xattr::Error::Range) => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    fn set_xattr(&self, name: &str, value: &[u8], flags: XattrFlags) -> Result<()> {
+        let path = self.c_path()?;
+        let name = CString::new(name).map_err(|_| FsError::InvalidParam)?;
+        let mut raw_flags = 0;
+        if flags.create {
+            raw_flags |= nix::libc::XATTR_CREATE;
+        }
+        if flags.replace {
+            raw_flags |= nix::libc::XATTR_REPLACE;
+        }
+        let ret = unsafe {
+            xattr::set(
+                path.as_ptr(),
+                name.as_ptr(),
+                value.as_ptr() as _,
+                value.len(),
+                raw_flags,
+            )
+        };
+        xattr::check(ret as _)?;
+        Ok(())
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    fn list_xattr(&self) -> Result<Vec<String>> {
+        let path = self.c_path()?;
+        let list = loop {
+            let size =
+                xattr::check(unsafe { xattr::list(path.as_ptr(), std::ptr::null_mut(), 0) })?;
+            let mut list = vec![0u8; size];
+            match xattr::check(unsafe { xattr::list(path.as_ptr(), list.as_mut_ptr() as _, size) })
+            {
+                Ok(len) => {
+                    list.truncate(len);
+                    break list;
+                }
+                // the list grew between the two calls, try again
+                Err(xattr::Error::Range) => continue,
+                Err(e) => return Err(e.into()),
+            }
+        };
+        // names are separated by '\0'
+        list.split(|&b| b == 0)
+            .filter(|name| !name.is_empty())
+            .map(|name| String::from_utf8(name.to_vec()).map_err(|_| FsError::InvalidParam))
+            .collect()
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    fn remove_xattr(&self, name: &str) -> Result<()> {
+        let path = self.c_path()?;
+        let name = CString::new(name).map_err(|_| FsError::InvalidParam)?;
+        let ret = unsafe { xattr::remove(path.as_ptr(), name.as_ptr()) };
+        xattr::check(ret as _)?;
+        Ok(())
+    }
+
     fn sync_all(&self) -> Result<()> {
         self.open_file()?.as_mut().unwrap().sync_all()?;
         Ok(())
@@ -142,9 +225,34 @@ impl INode for HNode {
         Ok(())
     }
 
+    #[cfg(target_os = "linux")]
+    fn fallocate(&self, flags: FallocateFlags, offset: usize, len: usize) -> Result<()> {
+        use nix::fcntl::{fallocate, FallocateFlags as HostFlags};
+        use std::os::unix::io::AsRawFd;
+        flags.check()?;
+        let mut host_flags = HostFlags::empty();
+        host_flags.set(HostFlags::FALLOC_FL_KEEP_SIZE, flags.keep_size);
+        host_flags.set(HostFlags::FALLOC_FL_PUNCH_HOLE, flags.punch_hole);
+        host_flags.set(HostFlags::FALLOC_FL_ZERO_RANGE, flags.zero_range);
+        let guard = self.open_file()?;
+        let fd = guard.as_ref().unwrap().as_raw_fd();
+        fallocate(fd, host_flags, offset as _, len as _).map_err(std::io::Error::from)?;
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    fn seek_data(&self, offset: usize) -> Result<Option<usize>> {
+        self.lseek(offset, nix::unistd::Whence::SeekData)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn seek_hole(&self, offset: usize) -> Result<Option<usize>> {
+        self.lseek(offset, nix::unistd::Whence::SeekHole)
+    }
+
     fn create(&self, name: &str, type_: FileType, _mode: u32) -> Result<Arc<dyn INode>> {
         let new_path = self.path.join(name);
-        if new_path.exists() {
+        if new_path.symlink_metadata().is_ok() {
             return Err(FsError::EntryExist);
         }
         match type_ {
@@ -163,6 +271,23 @@ impl INode for HNode {
         }))
     }
 
+    fn symlink(&self, name: &str, target: &str) -> Result<Arc<dyn INode>> {
+        let new_path = self.path.join(name);
+        std::os::unix::fs::symlink(target, &new_path)?;
+        Ok(Arc::new(HNode {
+            path: new_path,
+            file: Mutex::new(None),
+            fs: self.fs.clone(),
+        }))
+    }
+
+    fn read_link(&self) -> Result<String> {
+        std::fs::read_link(&self.path)?
+            .into_os_string()
+            .into_string()
+            .map_err(|_| FsError::InvalidParam)
+    }
+
     fn link(&self, name: &str, other: &Arc<dyn INode>) -> Result<()> {
         let other = other.downcast_ref::<Self>().ok_or(FsError::NotSameFs)?;
         std::fs::hard_link(&other.path, &self.path.join(name))?;
@@ -171,27 +296,39 @@ impl INode for HNode {
 
     fn unlink(&self, name: &str) -> Result<()> {
         let new_path = self.path.join(name);
-        if new_path.is_file() {
-            std::fs::remove_file(new_path)?;
-        } else if new_path.is_dir() {
+        // do not follow symlinks
+        if new_path.symlink_metadata()?.is_dir() {
             std::fs::remove_dir(new_path)?;
         } else {
-            return Err(FsError::EntryNotFound);
+            std::fs::remove_file(new_path)?;
         }
         Ok(())
     }
 
-    fn move_(&self, old_name: &str, target: &Arc<dyn INode>, new_name: &str) -> Result<()> {
+    fn rename(
+        &self,
+        old_name: &str,
+        target: &Arc<dyn INode>,
+        new_name: &str,
+        flags: RenameFlags,
+    ) -> Result<()> {
         let target = target.downcast_ref::<Self>().ok_or(FsError::NotSameFs)?;
         let old_path = self.path.join(old_name);
         let new_path = target.path.join(new_name);
-        std::fs::rename(old_path, new_path)?;
-        Ok(())
+        if flags == RenameFlags::default() {
+            std::fs::rename(old_path, new_path)?;
+            return Ok(());
+        }
+        if flags.noreplace && flags.exchange {
+            return Err(FsError::InvalidParam);
+        }
+        rename_with_flags(&old_path, &new_path, flags)
     }
 
     fn find(&self, name: &str) -> Result<Arc<dyn INode>> {
         let new_path = self.path.join(name);
-        if new_path.exists() {
+        // dangling symlinks also exist
+        if new_path.symlink_metadata().is_ok() {
             Ok(Arc::new(HNode {
                 path: new_path,
                 file: Mutex::new(None),
@@ -216,6 +353,61 @@ impl INode for HNode {
         }
     }
 
+    /// The cookie is the position from `telldir`, which is stable across opens on Linux
+    #[cfg(target_os = "linux")]
+    fn read_dir(&self, cookie: usize) -> Result<Option<DirEntry>> {
+        use nix::{errno::Errno, libc};
+        let path = self.c_path()?;
+        let dir = unsafe { libc::opendir(path.as_ptr()) };
+        if dir.is_null() {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        let result = unsafe {
+            libc::seekdir(dir, cookie as _);
+            // readdir returns null at the end of directory or on error
+            Errno::clear();
+            let entry = libc::readdir(dir);
+            let result = if entry.is_null() {
+                match Errno::last() {
+                    Errno::UnknownErrno => Ok(None),
+                    e => Err(std::io::Error::from_raw_os_error(e as i32)),
+                }
+            } else {
+                let name = std::ffi::CStr::from_ptr((*entry).d_name.as_ptr());
+                let name = String::from_utf8(name.to_bytes().to_vec());
+                Ok(Some((
+                    name,
+                    (*entry).d_ino,
+                    (*entry).d_type,
+                    libc::telldir(dir),
+                )))
+            };
+            libc::closedir(dir);
+            result
+        };
+        let (name, inode, d_type, next_cookie) = match result? {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+        let name = name.map_err(|_| FsError::InvalidParam)?;
+        let type_ = match d_type {
+            libc::DT_REG => FileType::File,
+            libc::DT_DIR => FileType::Dir,
+            libc::DT_LNK => FileType::SymLink,
+            libc::DT_CHR => FileType::CharDevice,
+            libc::DT_BLK => FileType::BlockDevice,
+            libc::DT_FIFO => FileType::NamedPipe,
+            libc::DT_SOCK => FileType::Socket,
+            _ => Metadata::from(self.path.join(&name).symlink_metadata()?).type_,
+        };
+        Ok(Some(DirEntry {
+            name,
+            inode: inode as usize,
+            type_,
+            next_cookie: next_cookie as usize,
+        }))
+    }
+
     fn io_control(&self, _cmd: u32, _data: usize) -> Result<usize> {
         Err(FsError::NotSupported)
     }
@@ -255,4 +447,168 @@ impl HNode {
         }
         Ok(maybe_file)
     }
+
+    /// `lseek` the host file with `SEEK_DATA` or `SEEK_HOLE`, return `None` on `ENXIO`
+    #[cfg(target_os = "linux")]
+    fn lseek(&self, offset: usize, whence: nix::unistd::Whence) -> Result<Option<usize>> {
+        use nix::errno::Errno;
+        use std::os::unix::io::AsRawFd;
+        let guard = self.open_file()?;
+        let fd = guard.as_ref().unwrap().as_raw_fd();
+        match nix::unistd::lseek(fd, offset as _, whence) {
+            Ok(offset) => Ok(Some(offset as usize)),
+            Err(Errno::ENXIO) => Ok(None),
+            Err(e) => Err(std::io::Error::from(e).into()),
+        }
+    }
+
+    /// Get `self.path` as a C string
+    fn c_path(&self) -> Result<CString> {
+        CString::new(self.path.as_os_str().as_bytes()).map_err(|_| FsError::InvalidParam)
+    }
+}
+
+/// Rename with `RENAME_NOREPLACE` or `RENAME_EXCHANGE` of the host
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+fn rename_with_flags(old_path: &Path, new_path: &Path, flags: RenameFlags) -> Result<()> {
+    use nix::fcntl::{renameat2, RenameFlags as HostFlags};
+    let mut host_flags = HostFlags::empty();
+    host_flags.set(HostFlags::RENAME_NOREPLACE, flags.noreplace);
+    host_flags.set(HostFlags::RENAME_EXCHANGE, flags.exchange);
+    renameat2(None, old_path, None, new_path, host_flags)
+        .map_err(|e| std::io::Error::from(e).into())
+}
+
+/// Rename with `RENAME_EXCL` or `RENAME_SWAP` of the host
+#[cfg(target_os = "macos")]
+fn rename_with_flags(old_path: &Path, new_path: &Path, flags: RenameFlags) -> Result<()> {
+    use nix::libc;
+    let old_path =
+        CString::new(old_path.as_os_str().as_bytes()).map_err(|_| FsError::InvalidParam)?;
+    let new_path =
+        CString::new(new_path.as_os_str().as_bytes()).map_err(|_| FsError::InvalidParam)?;
+    let mut host_flags = 0;
+    if flags.noreplace {
+        host_flags |= libc::RENAME_EXCL;
+    }
+    if flags.exchange {
+        host_flags |= libc::RENAME_SWAP;
+    }
+    let ret = unsafe { libc::renamex_np(old_path.as_ptr(), new_path.as_ptr(), host_flags) };
+    if ret < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+#[cfg(not(any(all(target_os = "linux", target_env = "gnu"), target_os = "macos")))]
+fn rename_with_flags(_old_path: &Path, _new_path: &Path, _flags: RenameFlags) -> Result<()> {
+    Err(FsError::NotSupported)
+}
+
+/// Extended attribute syscalls of the host, which never follow symlinks.
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+mod xattr {
+    use nix::libc::{self, c_char, c_int, c_void, size_t, ssize_t};
+    use rcore_fs::vfs::FsError;
+
+    #[cfg(target_os = "linux")]
+    const ENOATTR: c_int = libc::ENODATA;
+    #[cfg(target_os = "macos")]
+    const ENOATTR: c_int = libc::ENOATTR;
+
+    pub enum Error {
+        /// The buffer is too small
+        Range,
+        Other(std::io::Error),
+    }
+
+    impl From<Error> for FsError {
+        fn from(e: Error) -> Self {
+            match e {
+                Error::Range => FsError::AttrTooBig,
+                Error::Other(e) => match e.raw_os_error() {
+                    Some(ENOATTR) => FsError::NoAttr,
+                    Some(libc::E2BIG) => FsError::AttrTooBig,
+                    Some(libc::ENOTSUP) => FsError::NotSupported,
+                    Some(libc::EEXIST) => FsError::EntryExist,
+                    _ => e.into(),
+                },
+            }
+        }
+    }
+
+    /// Convert the return value of syscalls to `Result`
+    pub fn check(ret: ssize_t) -> Result<usize, Error> {
+        if ret >= 0 {
+            return Ok(ret as usize);
+        }
+        let e = std::io::Error::last_os_error();
+        match e.raw_os_error() {
+            Some(libc::ERANGE) => Err(Error::Range),
+            _ => Err(Error::Other(e)),
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    pub unsafe fn get(
+        path: *const c_char,
+        name: *const c_char,
+        value: *mut c_void,
+        size: size_t,
+    ) -> ssize_t {
+        libc::lgetxattr(path, name, value, size)
+    }
+
+    #[cfg(target_os = "linux")]
+    pub unsafe fn set(
+        path: *const c_char,
+        name: *const c_char,
+        value: *const c_void,
+        size: size_t,
+        flags: c_int,
+    ) -> c_int {
+        libc::lsetxattr(path, name, value, size, flags)
+    }
+
+    #[cfg(target_os = "linux")]
+    pub unsafe fn list(path: *const c_char, list: *mut c_char, size: size_t) -> ssize_t {
+        libc::llistxattr(path, list, size)
+    }
+
+    #[cfg(target_os = "linux")]
+    pub unsafe fn remove(path: *const c_char, name: *const c_char) -> c_int {
+        libc::lremovexattr(path, name)
+    }
+
+    #[cfg(target_os = "macos")]
+    pub unsafe fn get(
+        path: *const c_char,
+        name: *const c_char,
+        value: *mut c_void,
+        size: size_t,
+    ) -> ssize_t {
+        libc::getxattr(path, name, value, size, 0, libc::XATTR_NOFOLLOW)
+    }
+
+    #[cfg(target_os = "macos")]
+    pub unsafe fn set(
+        path: *const c_char,
+        name: *const c_char,
+        value: *const c_void,
+        size: size_t,
+        flags: c_int,
+    ) -> c_int {
+        libc::setxattr(path, name, value, size, 0, flags | libc::XATTR_NOFOLLOW)
+    }
+
+    #[cfg(target_os = "macos")]
+    pub unsafe fn list(path: *const c_char, list: *mut c_char, size: size_t) -> ssize_t {
+        libc::listxattr(path, list, size, libc::XATTR_NOFOLLOW)
+    }
+
+    #[cfg(target_os = "macos")]
+    pub unsafe fn remove(path: *const c_char, name: *const c_char) -> c_int {
+        libc::removexattr(path, name, libc::XATTR_NOFOLLOW)
+    }
 }
@@ -9,8 +9,13 @@ use alloc::{
     collections::BTreeMap,
     string::String,
     sync::{Arc, Weak},
+    vec::Vec,
 };
 use core::{any::Any, future::Future, pin::Pin};
+use rcore_fs::cache::{CacheStats, Dentry, DentryCache, DEFAULT_DENTRY_CAPACITY};
+use rcore_fs::notify::Notifier;
+use rcore_fs::page_cache::PinnedPage;
+use rcore_fs::util::BoxFuture;
 use rcore_fs::vfs::*;
 use spin::RwLock;
 
@@ -18,6 +23,8 @@ use spin::RwLock;
 mod tests;
 
 /// The filesystem on which all the other filesystems are mounted
+///
+/// Lookups are cached, so the inner file system should be changed only through its `MountFS`.
 pub struct MountFS {
     /// The inner file system
     inner: Arc<dyn FileSystem>,
@@ -27,10 +34,23 @@ pub struct MountFS {
     self_mountpoint: Option<Arc<MNode>>,
     /// Weak reference to self
     self_ref: Weak<MountFS>,
+    /// Change events of INodes, used only if the inner file system has no notifier
+    notifier: Notifier,
+    /// Entries found in the inner file system, by the id of the inner directory
+    dentries: DentryCache<Child>,
 }
 
 type INodeId = usize;
 
+/// A cached entry of `MountFS`
+#[derive(Clone)]
+struct Child {
+    /// The inner INode
+    inode: Arc<dyn INode>,
+    /// The `MNode` of it in use, reused by later lookups
+    node: Weak<MNode>,
+}
+
 /// INode for `MountFS`
 pub struct MNode {
     /// The inner INode
@@ -49,6 +69,8 @@ impl MountFS {
             mountpoints: RwLock::new(BTreeMap::new()),
             self_mountpoint: None,
             self_ref: Weak::default(),
+            notifier: Notifier::new(),
+            dentries: DentryCache::new(DEFAULT_DENTRY_CAPACITY),
         }
         .wrap()
     }
@@ -67,6 +89,11 @@ impl MountFS {
         }
     }
 
+    /// Hit and miss counters of the lookups of entries
+    pub fn dentry_stats(&self) -> &CacheStats {
+        self.dentries.stats()
+    }
+
     /// Strong type version of `root_inode`
     pub fn mountpoint_root_inode(&self) -> Arc<MNode> {
         MNode {
@@ -104,6 +131,8 @@ impl MNode {
             mountpoints: RwLock::new(BTreeMap::new()),
             self_mountpoint: Some(self.self_ref.upgrade().unwrap()),
             self_ref: Weak::default(),
+            notifier: Notifier::new(),
+            dentries: DentryCache::new(DEFAULT_DENTRY_CAPACITY),
         }
         .wrap();
         self.vfs
@@ -124,20 +153,123 @@ impl MNode {
         }
     }
 
+    /// Wrap an inner INode of the same file system
+    fn wrap_inner(&self, inode: Arc<dyn INode>) -> Arc<MNode> {
+        MNode {
+            inode,
+            vfs: self.vfs.clone(),
+            self_ref: Weak::default(),
+        }
+        .wrap()
+    }
+
+    /// Find `name` in this directory without crossing mount points, through the dentry cache
+    fn find_child(&self, name: &str) -> Result<Arc<MNode>> {
+        let dir = self.inode.metadata()?.inode;
+        let dentries = &self.vfs.dentries;
+        // not to insert what is changed while looking up
+        let generation = dentries.generation(dir);
+        let inode = match dentries.lookup(dir, name) {
+            Some(Dentry::Positive(child)) => match child.node.upgrade() {
+                Some(node) => return Ok(node),
+                None => child.inode,
+            },
+            Some(Dentry::Negative) => return Err(FsError::EntryNotFound),
+            None => match self.inode.find(name) {
+                Ok(inode) => inode,
+                Err(FsError::EntryNotFound) => {
+                    dentries.insert_negative(dir, name, generation);
+                    return Err(FsError::EntryNotFound);
+                }
+                Err(e) => return Err(e),
+            },
+        };
+        let node = self.wrap_inner(inode.clone());
+        let child = Child {
+            inode,
+            node: Arc::downgrade(&node),
+        };
+        dentries.insert(dir, name, child, generation);
+        Ok(node)
+    }
+
+    /// Forget the cached entry `name` of directory `dir` after it is changed
+    fn invalidate(&self, dir: &dyn INode, name: &str) -> Result<()> {
+        let dir = dir.metadata()?.inode;
+        self.vfs.dentries.invalidate(dir, name);
+        Ok(())
+    }
+
     /// Is the root INode of its FS?
     fn is_mountpoint_root(&self) -> bool {
         self.inode.fs().root_inode().metadata().unwrap().inode
             == self.inode.metadata().unwrap().inode
     }
 
+    /// The notifier to emit events into, if the inner file system does not emit them
+    fn emitter(&self) -> Option<&Notifier> {
+        match self.vfs.inner.notifier() {
+            Some(_) => None,
+            None => Some(&self.vfs.notifier),
+        }
+    }
+
+    /// Emit an event of this INode if the inner file system does not
+    fn emit_self(&self, emit: fn(&Notifier, usize)) -> Result<()> {
+        if let Some(notifier) = self.emitter() {
+            emit(notifier, self.metadata()?.inode);
+        }
+        Ok(())
+    }
+
+    /// Do the rename by `f`, and emit its events if the inner file system does not
+    fn rename_with(
+        &self,
+        old_name: &str,
+        target: &Arc<dyn INode>,
+        new_name: &str,
+        flags: RenameFlags,
+        f: impl FnOnce() -> Result<()>,
+    ) -> Result<()> {
+        let notifier = match self.emitter() {
+            Some(notifier) => notifier,
+            None => return f(),
+        };
+        let inode = self.inode.find(old_name)?;
+        let other = target.find(new_name).ok();
+        f()?;
+        let dir = self.metadata()?.inode;
+        let dest = target.metadata()?.inode;
+        let info = inode.metadata()?;
+        let is_dir = info.type_ == FileType::Dir;
+        notifier.rename(dir, old_name, dest, new_name, info.inode, is_dir);
+        if let Some(other) = other {
+            let other = other.metadata()?;
+            if other.inode == info.inode {
+                // both names are links to the same INode
+            } else if flags.exchange {
+                let other_is_dir = other.type_ == FileType::Dir;
+                notifier.rename(dest, new_name, dir, old_name, other.inode, other_is_dir);
+            } else {
+                notifier.unlinked(other.inode, other.nlinks);
+            }
+        }
+        Ok(())
+    }
+
     /// Strong type version of `create()`
     pub fn create(&self, name: &str, type_: FileType, mode: u32) -> Result<Arc<Self>> {
-        Ok(MNode {
-            inode: self.inode.create(name, type_, mode)?,
-            vfs: self.vfs.clone(),
-            self_ref: Weak::default(),
+        let inode = self.inode.create(name, type_, mode)?;
+        self.invalidate(self.inode.as_ref(), name)?;
+        if type_ == FileType::Dir {
+            // the id may be reused from a removed directory
+            self.vfs.dentries.invalidate_dir(inode.metadata()?.inode);
+        }
+        if let Some(notifier) = self.emitter() {
+            let dir = self.inode.metadata()?.inode;
+            notifier.create(dir, name, type_ == FileType::Dir);
         }
-        .wrap())
+        Ok(self.wrap_inner(inode))
     }
 
     /// Strong type version of `find()`
@@ -162,24 +294,14 @@ impl MNode {
                     }
                 } else {
                     // Not trespassing filesystem border. Parent and myself in the same filesystem.
-                    Ok(MNode {
-                        inode: self.inode.find(name)?, // Going up is handled by the filesystem. A better API?
-                        vfs: self.vfs.clone(),
-                        self_ref: Weak::default(),
-                    }
-                    .wrap())
+                    // Going up is handled by the filesystem. A better API?
+                    Ok(self.wrap_inner(self.inode.find(name)?))
                 }
             }
             _ => {
                 // Going down may trespass the filesystem border.
                 // An INode replacement is required here.
-                Ok(MNode {
-                    inode: self.overlaid_inode().inode.find(name)?,
-                    vfs: self.vfs.clone(),
-                    self_ref: Weak::default(),
-                }
-                .wrap()
-                .overlaid_inode())
+                Ok(self.overlaid_inode().find_child(name)?.overlaid_inode())
             }
         }
     }
@@ -225,6 +347,14 @@ impl FileSystem for MountFS {
     fn info(&self) -> FsInfo {
         self.inner.info()
     }
+
+    fn notifier(&self) -> Option<&Notifier> {
+        self.inner.notifier().or(Some(&self.notifier))
+    }
+
+    fn identity(&self) -> usize {
+        self.inner.identity()
+    }
 }
 
 // unwrap `MNode` and forward methods to inner except `find()`
@@ -234,7 +364,41 @@ impl INode for MNode {
     }
 
     fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize> {
-        self.inode.write_at(offset, buf)
+        let len = self.inode.write_at(offset, buf)?;
+        if let Some(notifier) = self.emitter() {
+            notifier.modify(self.metadata()?.inode);
+        }
+        Ok(len)
+    }
+
+    fn read_vectored_at(&self, offset: usize, bufs: &mut [&mut [u8]]) -> Result<usize> {
+        self.inode.read_vectored_at(offset, bufs)
+    }
+
+    fn write_vectored_at(&self, offset: usize, bufs: &[&[u8]]) -> Result<usize> {
+        let len = self.inode.write_vectored_at(offset, bufs)?;
+        if let Some(notifier) = self.emitter() {
+            notifier.modify(self.metadata()?.inode);
+        }
+        Ok(len)
+    }
+
+    fn async_read_at<'a>(
+        &'a self,
+        offset: usize,
+        buf: &'a mut [u8],
+    ) -> BoxFuture<'a, Result<usize>> {
+        self.inode.async_read_at(offset, buf)
+    }
+
+    fn async_write_at<'a>(&'a self, offset: usize, buf: &'a [u8]) -> BoxFuture<'a, Result<usize>> {
+        Box::pin(async move {
+            let len = self.inode.async_write_at(offset, buf).await?;
+            if let Some(notifier) = self.emitter() {
+                notifier.modify(self.metadata()?.inode);
+            }
+            Ok(len)
+        })
     }
 
     fn poll(&self) -> Result<PollStatus> {
@@ -253,7 +417,26 @@ impl INode for MNode {
     }
 
     fn set_metadata(&self, metadata: &Metadata) -> Result<()> {
-        self.inode.set_metadata(metadata)
+        self.inode.set_metadata(metadata)?;
+        self.emit_self(Notifier::attrib)
+    }
+
+    fn get_xattr(&self, name: &str) -> Result<Vec<u8>> {
+        self.inode.get_xattr(name)
+    }
+
+    fn set_xattr(&self, name: &str, value: &[u8], flags: XattrFlags) -> Result<()> {
+        self.inode.set_xattr(name, value, flags)?;
+        self.emit_self(Notifier::attrib)
+    }
+
+    fn list_xattr(&self) -> Result<Vec<String>> {
+        self.inode.list_xattr()
+    }
+
+    fn remove_xattr(&self, name: &str) -> Result<()> {
+        self.inode.remove_xattr(name)?;
+        self.emit_self(Notifier::attrib)
     }
 
     fn sync_all(&self) -> Result<()> {
@@ -265,28 +448,119 @@ impl INode for MNode {
     }
 
     fn resize(&self, len: usize) -> Result<()> {
-        self.inode.resize(len)
+        self.inode.resize(len)?;
+        self.emit_self(Notifier::modify)
+    }
+
+    fn fallocate(&self, flags: FallocateFlags, offset: usize, len: usize) -> Result<()> {
+        self.inode.fallocate(flags, offset, len)?;
+        self.emit_self(Notifier::modify)
+    }
+
+    fn seek_data(&self, offset: usize) -> Result<Option<usize>> {
+        self.inode.seek_data(offset)
+    }
+
+    fn seek_hole(&self, offset: usize) -> Result<Option<usize>> {
+        self.inode.seek_hole(offset)
+    }
+
+    fn copy_range(
+        &self,
+        src: &dyn INode,
+        src_offset: usize,
+        dst_offset: usize,
+        len: usize,
+    ) -> Result<usize> {
+        let len = self.inode.copy_range(src, src_offset, dst_offset, len)?;
+        if let Some(notifier) = self.emitter() {
+            notifier.modify(self.metadata()?.inode);
+        }
+        Ok(len)
+    }
+
+    fn clone_range(
+        &self,
+        src: &dyn INode,
+        src_offset: usize,
+        dst_offset: usize,
+        len: usize,
+    ) -> Result<()> {
+        self.inode.clone_range(src, src_offset, dst_offset, len)?;
+        if let Some(notifier) = self.emitter() {
+            notifier.modify(self.metadata()?.inode);
+        }
+        Ok(())
     }
 
     fn create(&self, name: &str, type_: FileType, mode: u32) -> Result<Arc<dyn INode>> {
         Ok(self.create(name, type_, mode)?)
     }
 
+    fn symlink(&self, name: &str, target: &str) -> Result<Arc<dyn INode>> {
+        let inode = self.inode.symlink(name, target)?;
+        self.invalidate(self.inode.as_ref(), name)?;
+        if let Some(notifier) = self.emitter() {
+            notifier.create(self.metadata()?.inode, name, false);
+        }
+        Ok(self.wrap_inner(inode))
+    }
+
+    fn read_link(&self) -> Result<String> {
+        self.inode.read_link()
+    }
+
     fn link(&self, name: &str, other: &Arc<dyn INode>) -> Result<()> {
-        self.inode.link(name, other)
+        self.inode.link(name, other)?;
+        self.invalidate(self.inode.as_ref(), name)?;
+        if let Some(notifier) = self.emitter() {
+            notifier.create(self.metadata()?.inode, name, false);
+            notifier.attrib(other.metadata()?.inode);
+        }
+        Ok(())
     }
 
     fn unlink(&self, name: &str) -> Result<()> {
-        let inode_id = self.inode.find(name)?.metadata()?.inode;
+        let inode = self.inode.find(name)?;
+        let inode_id = inode.metadata()?.inode;
         // target INode is being mounted
         if self.vfs.mountpoints.read().contains_key(&inode_id) {
             return Err(FsError::Busy);
         }
-        self.inode.unlink(name)
+        self.inode.unlink(name)?;
+        self.invalidate(self.inode.as_ref(), name)?;
+        let info = inode.metadata()?;
+        if info.type_ == FileType::Dir {
+            self.vfs.dentries.invalidate_dir(inode_id);
+        }
+        if let Some(notifier) = self.emitter() {
+            let is_dir = info.type_ == FileType::Dir;
+            let dir = self.metadata()?.inode;
+            notifier.delete(dir, name, inode_id, is_dir, info.nlinks);
+        }
+        Ok(())
     }
 
     fn move_(&self, old_name: &str, target: &Arc<dyn INode>, new_name: &str) -> Result<()> {
-        self.inode.move_(old_name, target, new_name)
+        self.rename_with(old_name, target, new_name, RenameFlags::default(), || {
+            self.inode.move_(old_name, target, new_name)
+        })?;
+        self.invalidate(self.inode.as_ref(), old_name)?;
+        self.invalidate(target.as_ref(), new_name)
+    }
+
+    fn rename(
+        &self,
+        old_name: &str,
+        target: &Arc<dyn INode>,
+        new_name: &str,
+        flags: RenameFlags,
+    ) -> Result<()> {
+        self.rename_with(old_name, target, new_name, flags, || {
+            self.inode.rename(old_name, target, new_name, flags)
+        })?;
+        self.invalidate(self.inode.as_ref(), old_name)?;
+        self.invalidate(target.as_ref(), new_name)
     }
 
     fn find(&self, name: &str) -> Result<Arc<dyn INode>> {
@@ -301,6 +575,10 @@ impl INode for MNode {
         self.inode.get_entry_with_metadata(id)
     }
 
+    fn read_dir(&self, cookie: usize) -> Result<Option<DirEntry>> {
+        self.inode.read_dir(cookie)
+    }
+
     fn io_control(&self, cmd: u32, data: usize) -> Result<usize> {
         self.inode.io_control(cmd, data)
     }
@@ -309,6 +587,10 @@ impl INode for MNode {
         self.inode.mmap(area)
     }
 
+    fn map_page(&self, index: usize) -> Result<PinnedPage> {
+        self.inode.map_page(index)
+    }
+
     fn fs(&self) -> Arc<dyn FileSystem> {
         self.vfs.clone()
     }
@@ -1,4 +1,5 @@
 use crate::*;
+use rcore_fs::notify::{Event, EventKind, EventMask};
 use rcore_fs_ramfs::RamFS;
 
 #[test]
@@ -16,6 +17,30 @@ fn mount() {
     assert!((root as Arc<dyn INode>).lookup("mnt/file").is_ok());
 }
 
+#[test]
+fn lock_through_two_mounts() {
+    use rcore_fs::lock::{LockKey, LockManager, LockType};
+    let rootfs = MountFS::new(RamFS::new());
+    let root = rootfs.mountpoint_root_inode();
+    let ramfs = RamFS::new();
+    ramfs
+        .root_inode()
+        .create("file", FileType::File, 0o777)
+        .unwrap();
+    for name in ["a", "b"] {
+        let mnt = root.create(name, FileType::Dir, 0o777).unwrap();
+        mnt.mount(ramfs.clone()).unwrap();
+    }
+
+    let root = root as Arc<dyn INode>;
+    let a = LockKey::new(root.lookup("a/file").unwrap().as_ref()).unwrap();
+    let b = LockKey::new(root.lookup("b/file").unwrap().as_ref()).unwrap();
+    assert_eq!(a, b);
+    let locks = LockManager::new();
+    locks.try_flock(a, 1, LockType::Write).unwrap();
+    assert_eq!(locks.try_flock(b, 2, LockType::Read), Err(FsError::Again));
+}
+
 #[test]
 fn remove_busy() {
     let rootfs = MountFS::new(RamFS::new());
@@ -25,3 +50,328 @@ fn remove_busy() {
     mnt.mount(ramfs).unwrap();
     assert_eq!(root.unlink("mnt"), Err(FsError::Busy));
 }
+
+#[test]
+fn xattr() {
+    let rootfs = MountFS::new(RamFS::new());
+    let root = rootfs.mountpoint_root_inode();
+    let file = root.create("file", FileType::File, 0o777).unwrap();
+    let create = XattrFlags {
+        create: true,
+        ..XattrFlags::default()
+    };
+    let replace = XattrFlags {
+        replace: true,
+        ..XattrFlags::default()
+    };
+    assert_eq!(file.get_xattr("user.a"), Err(FsError::NoAttr));
+    assert_eq!(
+        file.set_xattr("user.a", b"1", replace),
+        Err(FsError::NoAttr)
+    );
+    file.set_xattr("user.a", b"1", create).unwrap();
+    assert_eq!(
+        file.set_xattr("user.a", b"2", create),
+        Err(FsError::EntryExist)
+    );
+    file.set_xattr("user.a", b"2", replace).unwrap();
+    file.set_xattr("user.b", b"", XattrFlags::default())
+        .unwrap();
+    assert_eq!(file.get_xattr("user.a").unwrap(), b"2");
+    assert_eq!(file.list_xattr().unwrap(), vec!["user.a", "user.b"]);
+    file.remove_xattr("user.a").unwrap();
+    assert_eq!(file.remove_xattr("user.a"), Err(FsError::NoAttr));
+    assert_eq!(file.list_xattr().unwrap(), vec!["user.b"]);
+}
+
+#[test]
+fn permission() {
+    let rootfs = MountFS::new(RamFS::new());
+    let root = rootfs.mountpoint_root_inode() as Arc<dyn INode>;
+    let alice = Credentials {
+        uid: 1000,
+        gid: 1000,
+        groups: vec![100],
+    };
+    let bob = Credentials {
+        uid: 1001,
+        gid: 1001,
+        groups: vec![],
+    };
+
+    let home = root
+        .create_checked(&Credentials::root(), "home", FileType::Dir, 0o755)
+        .unwrap();
+    assert_eq!(
+        home.create_checked(&alice, "file", FileType::File, 0o644)
+            .err(),
+        Some(FsError::PermissionDenied)
+    );
+    let mut info = home.metadata().unwrap();
+    info.mode = 0o777 | S_ISVTX;
+    home.set_metadata(&info).unwrap();
+
+    let file = home
+        .create_checked(&alice, "file", FileType::File, 0o640)
+        .unwrap();
+    let info = file.metadata().unwrap();
+    assert_eq!((info.uid, info.gid), (1000, 1000));
+    assert!(root
+        .open_checked(&alice, "home/file", 0, AccessMode::READ_WRITE)
+        .is_ok());
+    assert_eq!(
+        root.open_checked(&bob, "home/file", 0, AccessMode::READ)
+            .err(),
+        Some(FsError::PermissionDenied)
+    );
+
+    // group and supplementary group
+    let mut info = file.metadata().unwrap();
+    info.gid = 100;
+    file.set_metadata(&info).unwrap();
+    let carol = Credentials {
+        uid: 1002,
+        gid: 1002,
+        groups: vec![100],
+    };
+    assert!(root
+        .open_checked(&carol, "home/file", 0, AccessMode::READ)
+        .is_ok());
+    assert_eq!(
+        root.open_checked(&carol, "home/file", 0, AccessMode::WRITE)
+            .err(),
+        Some(FsError::PermissionDenied)
+    );
+
+    // sticky directory
+    assert_eq!(
+        home.unlink_checked(&bob, "file"),
+        Err(FsError::PermissionDenied)
+    );
+    home.unlink_checked(&alice, "file").unwrap();
+
+    // search permission
+    let mut info = home.metadata().unwrap();
+    info.mode = 0o700;
+    home.set_metadata(&info).unwrap();
+    home.create("file", FileType::File, 0o644).unwrap();
+    assert_eq!(
+        root.lookup_checked(&bob, "home/file").err(),
+        Some(FsError::PermissionDenied)
+    );
+    assert!(root
+        .lookup_checked(&Credentials::root(), "home/file")
+        .is_ok());
+}
+
+#[test]
+fn symlink() {
+    let rootfs = MountFS::new(RamFS::new());
+    let root = rootfs.mountpoint_root_inode();
+    let link = root.symlink("link", "/some/target").unwrap();
+    assert_eq!(link.read_link().unwrap(), "/some/target");
+    let link = (root.clone() as Arc<dyn INode>).lookup("link").unwrap();
+    assert_eq!(link.metadata().unwrap().type_, FileType::SymLink);
+    assert_eq!(link.read_link().unwrap(), "/some/target");
+    assert_eq!(root.read_link(), Err(FsError::InvalidParam));
+}
+
+#[test]
+fn read_dir() {
+    let rootfs = MountFS::new(RamFS::new());
+    let root = rootfs.mountpoint_root_inode();
+    root.create("file", FileType::File, 0o777).unwrap();
+    let dir = root.create("dir", FileType::Dir, 0o777).unwrap();
+    let entries = (root.clone() as Arc<dyn INode>)
+        .entries(0)
+        .collect::<Result<Vec<_>>>()
+        .unwrap();
+    let names: Vec<_> = entries.iter().map(|entry| entry.name.as_str()).collect();
+    assert_eq!(names, [".", "..", "file", "dir"]);
+    assert_eq!(entries[3].type_, FileType::Dir);
+    assert_eq!(entries[3].inode, dir.metadata().unwrap().inode);
+
+    // resume after "file" while it is being removed
+    root.unlink("file").unwrap();
+    let next = (root.clone() as Arc<dyn INode>)
+        .read_dir(entries[2].next_cookie)
+        .unwrap()
+        .unwrap();
+    assert_eq!(next.name, "dir");
+    assert_eq!(
+        (root as Arc<dyn INode>)
+            .read_dir(entries[3].next_cookie)
+            .unwrap(),
+        None
+    );
+}
+
+#[test]
+fn rename() {
+    let rootfs = MountFS::new(RamFS::new());
+    let root = rootfs.mountpoint_root_inode() as Arc<dyn INode>;
+    let exchange = RenameFlags {
+        exchange: true,
+        ..Default::default()
+    };
+    let file = root.create("file", FileType::File, 0o777).unwrap();
+    let dir1 = root.create("dir1", FileType::Dir, 0o777).unwrap();
+    let dir2 = dir1.create("dir2", FileType::Dir, 0o777).unwrap();
+
+    // directories can be moved
+    dir1.move_("dir2", &root, "dir3").unwrap();
+    assert_eq!(
+        root.lookup("dir3/..").unwrap().metadata().unwrap().inode,
+        root.metadata().unwrap().inode
+    );
+    root.move_("dir3", &dir1, "dir2").unwrap();
+    assert_eq!(root.move_("dir1", &dir2, "x"), Err(FsError::InvalidParam));
+
+    root.rename("file", &dir1, "dir2", exchange).unwrap();
+    assert_eq!(
+        root.lookup("dir1/dir2").unwrap().metadata().unwrap().inode,
+        file.metadata().unwrap().inode
+    );
+    assert_eq!(
+        root.rename(
+            "file",
+            &dir1,
+            "dir2",
+            RenameFlags {
+                noreplace: true,
+                exchange: true,
+            }
+        ),
+        Err(FsError::InvalidParam)
+    );
+
+    // replace a file
+    let file2 = root.create("file2", FileType::File, 0o777).unwrap();
+    let noreplace = RenameFlags {
+        noreplace: true,
+        ..Default::default()
+    };
+    assert_eq!(
+        dir1.rename("dir2", &root, "file2", noreplace),
+        Err(FsError::EntryExist)
+    );
+    dir1.move_("dir2", &root, "file2").unwrap();
+    assert_eq!(file2.metadata().unwrap().nlinks, 0);
+    assert_eq!(root.get_entry(2).unwrap(), "dir1");
+    assert_eq!(root.get_entry(3).unwrap(), "file");
+    assert_eq!(root.get_entry(4).unwrap(), "file2");
+}
+
+/// A file system without change events
+struct Silent(Arc<RamFS>);
+
+impl FileSystem for Silent {
+    fn sync(&self) -> Result<()> {
+        self.0.sync()
+    }
+
+    fn root_inode(&self) -> Arc<dyn INode> {
+        self.0.root_inode()
+    }
+
+    fn info(&self) -> FsInfo {
+        self.0.info()
+    }
+}
+
+#[test]
+fn notify() {
+    let rootfs = MountFS::new(RamFS::new());
+    let root = rootfs.mountpoint_root_inode();
+    let mnt = root.create("mnt", FileType::Dir, 0o777).unwrap();
+    let mnt_watch = (mnt.clone() as Arc<dyn INode>)
+        .watch(EventMask::ALL)
+        .unwrap();
+    let kinds = |events: Vec<Event>| -> Vec<EventKind> {
+        events.into_iter().map(|event| event.kind).collect()
+    };
+
+    // watches on a mount point see the mounted file system
+    mnt.mount(RamFS::new()).unwrap();
+    let mnt = (root.clone() as Arc<dyn INode>).lookup("mnt").unwrap();
+    let inner_watch = mnt.watch(EventMask::ALL).unwrap();
+    mnt.create("file", FileType::File, 0o777).unwrap();
+    assert!(mnt_watch.read_events().is_empty());
+    let events = inner_watch.read_events();
+    assert_eq!(kinds(events.clone()), vec![EventKind::Create]);
+    assert_eq!(events[0].name.as_deref(), Some("file"));
+
+    // MountFS emits events for the file systems without them
+    let silent = root.create("silent", FileType::Dir, 0o777).unwrap();
+    silent.mount(Arc::new(Silent(RamFS::new()))).unwrap();
+    let silent = (root as Arc<dyn INode>).lookup("silent").unwrap();
+    let dir_watch = silent.watch(EventMask::ALL).unwrap();
+    let file = silent.create("file", FileType::File, 0o777).unwrap();
+    let file_watch = file.watch(EventMask::ALL).unwrap();
+    file.write_at(0, b"data").unwrap();
+    silent.move_("file", &silent, "moved").unwrap();
+    silent.unlink("moved").unwrap();
+    assert_eq!(
+        kinds(dir_watch.read_events()),
+        vec![
+            EventKind::Create,
+            EventKind::MovedFrom,
+            EventKind::MovedTo,
+            EventKind::Delete
+        ]
+    );
+    assert_eq!(
+        kinds(file_watch.read_events()),
+        vec![
+            EventKind::Modify,
+            EventKind::MoveSelf,
+            EventKind::DeleteSelf,
+            EventKind::Ignored
+        ]
+    );
+}
+
+#[test]
+fn dentry_cache() {
+    let rootfs = MountFS::new(RamFS::new());
+    let root = rootfs.mountpoint_root_inode();
+    let dir = root.create("dir", FileType::Dir, 0o777).unwrap();
+    let root = root as Arc<dyn INode>;
+    let stats = || {
+        let stats = rootfs.dentry_stats().snapshot();
+        (stats.hits, stats.misses)
+    };
+    assert_eq!(stats(), (0, 0));
+
+    // negative entries become positive after creation
+    assert_eq!(root.lookup("dir/file").err(), Some(FsError::EntryNotFound));
+    assert_eq!(root.lookup("dir/file").err(), Some(FsError::EntryNotFound));
+    assert_eq!(stats(), (2, 2));
+    let file = dir.create("file", FileType::File, 0o777).unwrap();
+    let found = root.lookup("dir/file").unwrap();
+    assert!(Arc::ptr_eq(&found, &root.lookup("dir/file").unwrap()));
+    assert_eq!(
+        found.metadata().unwrap().inode,
+        file.metadata().unwrap().inode
+    );
+
+    // moved and removed names are invalidated
+    dir.move_("file", &root, "moved").unwrap();
+    assert_eq!(root.lookup("dir/file").err(), Some(FsError::EntryNotFound));
+    assert!(root.lookup("moved").is_ok());
+    root.unlink("moved").unwrap();
+    assert_eq!(root.lookup("moved").err(), Some(FsError::EntryNotFound));
+    dir.symlink("moved", "..").unwrap();
+    assert_eq!(root.lookup("dir/moved").unwrap().read_link().unwrap(), "..");
+
+    // mount points are still crossed after the lookup is cached
+    let mnt = dir.create("mnt", FileType::Dir, 0o777).unwrap();
+    assert!(root.lookup("dir/mnt/inner").is_err());
+    let ramfs = RamFS::new();
+    ramfs
+        .root_inode()
+        .create("inner", FileType::File, 0o777)
+        .unwrap();
+    mnt.mount(ramfs).unwrap();
+    assert!(root.lookup("dir/mnt/inner").is_ok());
+}
@@ -7,14 +7,22 @@ use alloc::{
     collections::BTreeMap,
     string::{String, ToString},
     sync::{Arc, Weak},
+    vec,
     vec::Vec,
 };
 use core::any::Any;
+use rcore_fs::notify::Notifier;
+use rcore_fs::page_cache::{PageCache, PinnedPage, PAGE_SIZE};
 use rcore_fs::vfs::*;
-use spin::{RwLock, RwLockWriteGuard};
+use spin::{Mutex, RwLock, RwLockWriteGuard};
 
 pub struct RamFS {
     root: Arc<LockedINode>,
+    notifier: Notifier,
+    /// Pages of files mapped into memory
+    pages: PageCache,
+    /// Held by renames across directories, which are the only changes of parents
+    rename_lock: Mutex<()>,
 }
 
 impl FileSystem for RamFS {
@@ -38,6 +46,10 @@ impl FileSystem for RamFS {
             namemax: 0,
         }
     }
+
+    fn notifier(&self) -> Option<&Notifier> {
+        Some(&self.notifier)
+    }
 }
 
 impl RamFS {
@@ -46,7 +58,9 @@ impl RamFS {
             this: Weak::default(),
             parent: Weak::default(),
             children: BTreeMap::new(),
+            cookies: BTreeMap::new(),
             content: Vec::new(),
+            xattrs: BTreeMap::new(),
             extra: Metadata {
                 dev: 0,
                 inode: new_inode_id(),
@@ -65,7 +79,12 @@ impl RamFS {
             },
             fs: Weak::default(),
         })));
-        let fs = Arc::new(RamFS { root });
+        let fs = Arc::new(RamFS {
+            root,
+            notifier: Notifier::new(),
+            pages: PageCache::default(),
+            rename_lock: Mutex::new(()),
+        });
         let mut root = fs.root.0.write();
         root.parent = Arc::downgrade(&fs.root);
         root.this = Arc::downgrade(&fs.root);
@@ -82,8 +101,12 @@ struct RamFSINode {
     this: Weak<LockedINode>,
     /// Reference to children INodes
     children: BTreeMap<String, Arc<LockedINode>>,
+    /// Names of children ordered by their cookies for `read_dir`
+    cookies: BTreeMap<usize, String>,
     /// Content of the file
     content: Vec<u8>,
+    /// Extended attributes
+    xattrs: BTreeMap<String, Vec<u8>>,
     /// INode metadata
     extra: Metadata,
     /// Reference to FS
@@ -102,7 +125,11 @@ impl INode for LockedINode {
         let end = file.content.len().min(offset + buf.len());
         let src = &file.content[start..end];
         buf[0..src.len()].copy_from_slice(src);
-        Ok(src.len())
+        let len = src.len();
+        file.fs()
+            .pages
+            .overlay(file.extra.inode, offset, &mut buf[..len]);
+        Ok(len)
     }
 
     fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize> {
@@ -116,9 +143,75 @@ impl INode for LockedINode {
         }
         let target = &mut content[offset..offset + buf.len()];
         target.copy_from_slice(buf);
+        let fs = file.fs();
+        fs.pages.update(file.extra.inode, offset, buf);
+        fs.notifier.modify(file.extra.inode);
         Ok(buf.len())
     }
 
+    fn copy_range(
+        &self,
+        src: &dyn INode,
+        src_offset: usize,
+        dst_offset: usize,
+        len: usize,
+    ) -> Result<usize> {
+        let src = match src.downcast_ref::<LockedINode>() {
+            Some(src) => src,
+            None => return generic_copy_range(self, src, src_offset, dst_offset, len),
+        };
+        if core::ptr::eq(self, src) {
+            let mut file = self.0.write();
+            if file.extra.type_ == FileType::Dir {
+                return Err(FsError::IsDir);
+            }
+            let len = len.min(file.content.len().saturating_sub(src_offset));
+            if src_offset < dst_offset + len && dst_offset < src_offset + len {
+                return Err(FsError::InvalidParam);
+            }
+            if len == 0 {
+                return Ok(0);
+            }
+            if dst_offset + len > file.content.len() {
+                file.content.resize(dst_offset + len, 0);
+            }
+            file.writeback_pages();
+            let range = src_offset..src_offset + len;
+            file.content.copy_within(range, dst_offset);
+            let fs = file.fs();
+            let copied = &file.content[dst_offset..dst_offset + len];
+            fs.pages.update(file.extra.inode, dst_offset, copied);
+            fs.notifier.modify(file.extra.inode);
+            return Ok(len);
+        }
+        // lock in the order of address to avoid deadlock with a copy in the other direction
+        let (src_file, mut file) = if (src as *const LockedINode) < (self as *const LockedINode) {
+            let src_file = src.0.read();
+            (src_file, self.0.write())
+        } else {
+            let file = self.0.write();
+            (src.0.read(), file)
+        };
+        if src_file.extra.type_ == FileType::Dir || file.extra.type_ == FileType::Dir {
+            return Err(FsError::IsDir);
+        }
+        let len = len.min(src_file.content.len().saturating_sub(src_offset));
+        if len == 0 {
+            return Ok(0);
+        }
+        if dst_offset + len > file.content.len() {
+            file.content.resize(dst_offset + len, 0);
+        }
+        let (fs, id) = (file.fs(), file.extra.inode);
+        let copied = &mut file.content[dst_offset..dst_offset + len];
+        copied.copy_from_slice(&src_file.content[src_offset..src_offset + len]);
+        // the source may be changed through memory mappings
+        fs.pages.overlay(src_file.extra.inode, src_offset, copied);
+        fs.pages.update(id, dst_offset, copied);
+        fs.notifier.modify(id);
+        Ok(len)
+    }
+
     fn poll(&self) -> Result<PollStatus> {
         let file = self.0.read();
         if file.extra.type_ == FileType::Dir {
@@ -146,27 +239,97 @@ impl INode for LockedINode {
         file.extra.mode = metadata.mode;
         file.extra.uid = metadata.uid;
         file.extra.gid = metadata.gid;
+        file.fs().notifier.attrib(file.extra.inode);
+        Ok(())
+    }
+
+    fn get_xattr(&self, name: &str) -> Result<Vec<u8>> {
+        let file = self.0.read();
+        file.xattrs.get(name).cloned().ok_or(FsError::NoAttr)
+    }
+
+    fn set_xattr(&self, name: &str, value: &[u8], flags: XattrFlags) -> Result<()> {
+        if name.is_empty() || name.len() > XATTR_NAME_MAX {
+            return Err(FsError::InvalidParam);
+        }
+        if value.len() > XATTR_SIZE_MAX {
+            return Err(FsError::AttrTooBig);
+        }
+        let mut file = self.0.write();
+        let exist = file.xattrs.contains_key(name);
+        if flags.create && exist {
+            return Err(FsError::EntryExist);
+        }
+        if flags.replace && !exist {
+            return Err(FsError::NoAttr);
+        }
+        file.xattrs.insert(String::from(name), value.to_vec());
+        file.fs().notifier.attrib(file.extra.inode);
+        Ok(())
+    }
+
+    fn list_xattr(&self) -> Result<Vec<String>> {
+        let file = self.0.read();
+        Ok(file.xattrs.keys().cloned().collect())
+    }
+
+    fn remove_xattr(&self, name: &str) -> Result<()> {
+        let mut file = self.0.write();
+        file.xattrs.remove(name).ok_or(FsError::NoAttr)?;
+        file.fs().notifier.attrib(file.extra.inode);
         Ok(())
     }
 
     fn sync_all(&self) -> Result<()> {
+        self.0.write().writeback_pages();
         Ok(())
     }
 
     fn sync_data(&self) -> Result<()> {
+        self.0.write().writeback_pages();
         Ok(())
     }
 
     fn resize(&self, len: usize) -> Result<()> {
         let mut file = self.0.write();
         if file.extra.type_ == FileType::File {
+            let fs = file.fs();
+            fs.pages
+                .truncate(file.extra.inode, len.min(file.content.len()));
             file.content.resize(len, 0);
+            fs.notifier.modify(file.extra.inode);
             Ok(())
         } else {
             Err(FsError::NotFile)
         }
     }
 
+    /// The content is always allocated, holes are filled with zeros
+    fn fallocate(&self, flags: FallocateFlags, offset: usize, len: usize) -> Result<()> {
+        flags.check()?;
+        let mut file = self.0.write();
+        if file.extra.type_ != FileType::File {
+            return Err(FsError::NotFile);
+        }
+        let end = offset.checked_add(len).ok_or(FsError::InvalidParam)?;
+        if len == 0 {
+            return Err(FsError::InvalidParam);
+        }
+        if flags.punch_hole || flags.zero_range {
+            let zero_end = end.min(file.content.len());
+            if offset < zero_end {
+                file.writeback_pages();
+                file.content[offset..zero_end].fill(0);
+                file.reload_pages(offset, zero_end);
+            }
+        }
+        if !flags.keep_size && end > file.content.len() {
+            file.content.resize(end, 0);
+        }
+        file.fs().notifier.modify(file.extra.inode);
+        Ok(())
+    }
+
     fn create2(
         &self,
         name: &str,
@@ -186,7 +349,9 @@ impl INode for LockedINode {
                 parent: Weak::clone(&file.this),
                 this: Weak::default(),
                 children: BTreeMap::new(),
+                cookies: BTreeMap::new(),
                 content: Vec::new(),
+                xattrs: BTreeMap::new(),
                 extra: Metadata {
                     dev: 0,
                     inode: new_inode_id(),
@@ -206,14 +371,31 @@ impl INode for LockedINode {
                 fs: Weak::clone(&file.fs),
             })));
             temp_file.0.write().this = Arc::downgrade(&temp_file);
-            file.children
-                .insert(String::from(name), Arc::clone(&temp_file));
+            file.insert_child(name, Arc::clone(&temp_file));
+            file.fs()
+                .notifier
+                .create(file.extra.inode, name, type_ == FileType::Dir);
             Ok(temp_file)
         } else {
             Err(FsError::NotDir)
         }
     }
 
+    fn symlink(&self, name: &str, target: &str) -> Result<Arc<dyn INode>> {
+        let inode = self.create2(name, FileType::SymLink, 0o777, 0)?;
+        let link = inode.downcast_ref::<LockedINode>().unwrap();
+        link.0.write().content = Vec::from(target.as_bytes());
+        Ok(inode)
+    }
+
+    fn read_link(&self) -> Result<String> {
+        let file = self.0.read();
+        if file.extra.type_ != FileType::SymLink {
+            return Err(FsError::InvalidParam);
+        }
+        String::from_utf8(file.content.clone()).map_err(|_| FsError::InvalidParam)
+    }
+
     fn link(&self, name: &str, other: &Arc<dyn INode>) -> Result<()> {
         let other = other
             .downcast_ref::<LockedINode>()
@@ -234,9 +416,11 @@ impl INode for LockedINode {
             return Err(FsError::EntryExist);
         }
 
-        file.children
-            .insert(String::from(name), other_l.this.upgrade().unwrap());
+        file.insert_child(name, other_l.this.upgrade().unwrap());
         other_l.extra.nlinks += 1;
+        let notifier = &file.fs().notifier;
+        notifier.create(file.extra.inode, name, false);
+        notifier.attrib(other_l.extra.inode);
         Ok(())
     }
 
@@ -252,18 +436,156 @@ impl INode for LockedINode {
         if !other.0.read().children.is_empty() {
             return Err(FsError::DirNotEmpty);
         }
-        other.0.write().extra.nlinks -= 1;
-        file.children.remove(name);
+        let mut other = other.0.write();
+        other.extra.nlinks -= 1;
+        let (inode, is_dir) = (other.extra.inode, other.extra.type_ == FileType::Dir);
+        let nlinks = other.extra.nlinks;
+        drop(other);
+        file.remove_child(name);
+        let notifier = &file.fs().notifier;
+        notifier.delete(file.extra.inode, name, inode, is_dir, nlinks);
         Ok(())
     }
 
-    fn move_(&self, old_name: &str, target: &Arc<dyn INode>, new_name: &str) -> Result<()> {
-        let elem = self.find(old_name)?;
-        target.link(new_name, &elem)?;
-        if let Err(err) = self.unlink(old_name) {
-            // recover
-            target.unlink(new_name)?;
-            return Err(err);
+    fn rename(
+        &self,
+        old_name: &str,
+        target: &Arc<dyn INode>,
+        new_name: &str,
+        flags: RenameFlags,
+    ) -> Result<()> {
+        let dest = target
+            .downcast_ref::<LockedINode>()
+            .ok_or(FsError::NotSameFs)?;
+        if old_name == "." || old_name == ".." || new_name == "." || new_name == ".." {
+            return Err(FsError::IsDir);
+        }
+        if flags.noreplace && flags.exchange {
+            return Err(FsError::InvalidParam);
+        }
+        let same_dir = core::ptr::eq(self, dest);
+        let fs = self.0.read().fs();
+        let _rename_guard = match same_dir {
+            true => None,
+            false => Some(fs.rename_lock.lock()),
+        };
+        let mut guards = loop {
+            // check before locking, since it locks the ancestors of `dest`
+            let checked = match same_dir {
+                true => None,
+                false => {
+                    let elem = self.find(old_name)?;
+                    dest.check_not_beneath(&elem)?;
+                    let other = match flags.exchange {
+                        true => dest.find(new_name).ok(),
+                        false => None,
+                    };
+                    if let Some(other) = &other {
+                        self.check_not_beneath(other)?;
+                    }
+                    Some((elem, other))
+                }
+            };
+
+            // to make sure locking order.
+            let guards = if same_dir {
+                vec![self.0.write()]
+            } else {
+                lock_multiple(&[&self.0, &dest.0])
+            };
+            let unchanged = match &checked {
+                None => true,
+                Some((elem, other)) => {
+                    let same = |child: Option<&Arc<LockedINode>>,
+                                checked: Option<&Arc<dyn INode>>| {
+                        match (child, checked) {
+                            (Some(child), Some(checked)) => {
+                                Arc::as_ptr(child) as *const u8 == Arc::as_ptr(checked) as *const u8
+                            }
+                            (None, None) => true,
+                            _ => false,
+                        }
+                    };
+                    same(guards[0].children.get(old_name), Some(elem))
+                        && (!flags.exchange
+                            || same(guards[1].children.get(new_name), other.as_ref()))
+                }
+            };
+            if unchanged {
+                break guards;
+            }
+            // the entries are replaced before locking, check them again
+        };
+        let (file, rest) = guards.split_first_mut().unwrap();
+        let dest_file: &RamFSINode = rest.first().map_or(&**file, |dest_file| &**dest_file);
+        if file.extra.type_ != FileType::Dir || dest_file.extra.type_ != FileType::Dir {
+            return Err(FsError::NotDir);
+        }
+        let elem = file
+            .children
+            .get(old_name)
+            .cloned()
+            .ok_or(FsError::EntryNotFound)?;
+        let other = dest_file.children.get(new_name).cloned();
+        let this = file.this.clone();
+        let dest_this = dest_file.this.clone();
+        let dir_id = file.extra.inode;
+        let dest_id = dest_file.extra.inode;
+        let (elem_id, is_dir) = {
+            let elem = elem.0.read();
+            (elem.extra.inode, elem.extra.type_ == FileType::Dir)
+        };
+        match &other {
+            None if flags.exchange => return Err(FsError::EntryNotFound),
+            None => {}
+            Some(_) if flags.noreplace => return Err(FsError::EntryExist),
+            Some(_) if flags.exchange => {}
+            Some(other) if Arc::ptr_eq(other, &elem) => return Ok(()),
+            Some(other) => {
+                // `self` is not empty as it contains `elem`
+                if core::ptr::eq(other.as_ref(), self) {
+                    return Err(FsError::DirNotEmpty);
+                }
+                let mut other = other.0.write();
+                let other_is_dir = other.extra.type_ == FileType::Dir;
+                if is_dir && !other_is_dir {
+                    return Err(FsError::NotDir);
+                }
+                if !is_dir && other_is_dir {
+                    return Err(FsError::IsDir);
+                }
+                if !other.children.is_empty() {
+                    return Err(FsError::DirNotEmpty);
+                }
+                other.extra.nlinks -= 1;
+                fs.notifier.unlinked(other.extra.inode, other.extra.nlinks);
+            }
+        }
+
+        file.remove_child(old_name);
+        if flags.exchange {
+            file.insert_child(old_name, other.clone().unwrap());
+        }
+        let dest_file = match rest.first_mut() {
+            Some(dest_file) => dest_file,
+            None => file,
+        };
+        dest_file.remove_child(new_name);
+        dest_file.insert_child(new_name, elem.clone());
+        if !same_dir {
+            elem.0.write().parent = dest_this;
+            if let (true, Some(other)) = (flags.exchange, &other) {
+                other.0.write().parent = this;
+            }
+        }
+        fs.notifier
+            .rename(dir_id, old_name, dest_id, new_name, elem_id, is_dir);
+        if let (true, Some(other)) = (flags.exchange, other) {
+            let other = other.0.read();
+            let other_is_dir = other.extra.type_ == FileType::Dir;
+            let other_id = other.extra.inode;
+            fs.notifier
+                .rename(dest_id, new_name, dir_id, old_name, other_id, other_is_dir);
         }
         Ok(())
     }
@@ -303,12 +625,60 @@ impl INode for LockedINode {
         }
     }
 
+    /// Cookie 0 and 1 are `.` and `..`, children have increasing cookies from `new_cookie`
+    fn read_dir(&self, cookie: usize) -> Result<Option<DirEntry>> {
+        let file = self.0.read();
+        if file.extra.type_ != FileType::Dir {
+            return Err(FsError::NotDir);
+        }
+        let (name, inode, next_cookie) = match cookie {
+            0 => (String::from("."), file.this.upgrade(), 1),
+            1 => (String::from(".."), file.parent.upgrade(), 2),
+            _ => match file.cookies.range(cookie..).next() {
+                Some((&cookie, name)) => {
+                    (name.clone(), file.children.get(name).cloned(), cookie + 1)
+                }
+                None => return Ok(None),
+            },
+        };
+        let inode = inode.ok_or(FsError::EntryNotFound)?;
+        // unlock before locking `.` or `..`
+        drop(file);
+        let info = inode.metadata()?;
+        Ok(Some(DirEntry {
+            name,
+            inode: info.inode,
+            type_: info.type_,
+            next_cookie,
+        }))
+    }
+
     fn io_control(&self, _cmd: u32, _data: usize) -> Result<usize> {
         Err(FsError::NotSupported)
     }
 
-    fn mmap(&self, _area: MMapArea) -> Result<()> {
-        Err(FsError::NotSupported)
+    fn mmap(&self, area: MMapArea) -> Result<()> {
+        if self.0.read().extra.type_ != FileType::File {
+            return Err(FsError::NotFile);
+        }
+        if !area.offset.is_multiple_of(PAGE_SIZE) {
+            return Err(FsError::InvalidParam);
+        }
+        Ok(())
+    }
+
+    fn map_page(&self, index: usize) -> Result<PinnedPage> {
+        let file = self.0.read();
+        if file.extra.type_ != FileType::File {
+            return Err(FsError::NotFile);
+        }
+        let offset = index.checked_mul(PAGE_SIZE).ok_or(FsError::InvalidParam)?;
+        file.fs()
+            .pages
+            .get_or_load(file.extra.inode, index, |data| {
+                file.load_page(offset, data);
+                Ok(())
+            })
     }
 
     fn fs(&self) -> Arc<dyn FileSystem> {
@@ -320,6 +690,96 @@ impl INode for LockedINode {
     }
 }
 
+impl LockedINode {
+    /// Return `InvalidParam` if `self` is `ancestor` or beneath it,
+    /// so that moving `ancestor` into `self` would make a cycle.
+    /// Return `DirRemoved` if `self` is removed.
+    /// The parents must not change, see `RamFS::rename_lock`.
+    fn check_not_beneath(&self, ancestor: &Arc<dyn INode>) -> Result<()> {
+        let ancestor = Arc::as_ptr(ancestor) as *const u8;
+        let file = self.0.read();
+        if file.extra.nlinks == 0 {
+            return Err(FsError::DirRemoved);
+        }
+        let mut dir = file.this.upgrade().ok_or(FsError::DirRemoved)?;
+        drop(file);
+        loop {
+            if Arc::as_ptr(&dir) as *const u8 == ancestor {
+                return Err(FsError::InvalidParam);
+            }
+            // the ancestors of a directory in use are not removed
+            let parent = dir.0.read().parent.upgrade().ok_or(FsError::DirRemoved)?;
+            if Arc::ptr_eq(&parent, &dir) {
+                // reach the root
+                return Ok(());
+            }
+            dir = parent;
+        }
+    }
+}
+
+impl Drop for LockedINode {
+    fn drop(&mut self) {
+        let file = self.0.read();
+        if let Some(fs) = file.fs.upgrade() {
+            fs.pages.remove_inode(file.extra.inode);
+        }
+    }
+}
+
+impl RamFSINode {
+    fn fs(&self) -> Arc<RamFS> {
+        self.fs.upgrade().unwrap()
+    }
+
+    /// Fill a page at `offset` from the content
+    fn load_page(&self, offset: usize, data: &mut [u8]) {
+        let start = self.content.len().min(offset);
+        let end = self.content.len().min(offset + data.len());
+        let len = end - start;
+        data[..len].copy_from_slice(&self.content[start..end]);
+        data[len..].fill(0);
+    }
+
+    /// Write back the cached pages written through memory mappings
+    fn writeback_pages(&mut self) {
+        let fs = self.fs();
+        let content = &mut self.content;
+        fs.pages
+            .writeback(self.extra.inode, |offset, data| {
+                let end = content.len().min(offset + data.len());
+                if offset < end {
+                    content[offset..end].copy_from_slice(&data[..end - offset]);
+                }
+                Ok(())
+            })
+            .unwrap();
+    }
+
+    /// Reload the cached pages in `begin..end` after the content is changed without writes
+    fn reload_pages(&self, begin: usize, end: usize) {
+        self.fs()
+            .pages
+            .reload(self.extra.inode, begin, end, |offset, data| {
+                self.load_page(offset, data);
+                Ok(())
+            })
+            .unwrap();
+    }
+
+    /// Insert a child with a new cookie
+    fn insert_child(&mut self, name: &str, child: Arc<LockedINode>) {
+        self.children.insert(String::from(name), child);
+        self.cookies.insert(new_cookie(), String::from(name));
+    }
+
+    /// Remove a child and its cookie
+    fn remove_child(&mut self, name: &str) {
+        self.children.remove(name);
+        self.cookies.retain(|_, child| child != name);
+    }
+}
+
 /// Lock INodes order by their inode id
 fn lock_multiple<'a>(locks: &[&'a RwLock<RamFSINode>]) -> Vec<RwLockWriteGuard<'a, RamFSINode>> {
     let mut order: Vec<usize> = (0..locks.len()).collect();
@@ -341,3 +801,10 @@ fn new_inode_id() -> usize {
     static ID: AtomicUsize = AtomicUsize::new(1);
     ID.fetch_add(1, Ordering::SeqCst)
 }
+
+/// Generate a new cookie for directory entries, 0 and 1 are reserved for `.` and `..`
+fn new_cookie() -> usize {
+    use core::sync::atomic::*;
+    static COOKIE: AtomicUsize = AtomicUsize::new(2);
+    COOKIE.fetch_add(1, Ordering::SeqCst)
+}
@@ -0,0 +1,364 @@
+//! A `Storage` misbehaving on purpose, the counterpart of `rcore_fs::dev::fault` for SEFS
+//!
+//! `FaultyStorage` can fail the operations on some files or at random, keep the writes to
+//! a file in a volatile cache until it is flushed, which are lost, or partly applied in
+//! any order, on a simulated power loss, and record the operations into a `StorageLog`,
+//! whose prefixes can be replayed to get the storages after a crash at any point.
+
+use super::{DevResult, DeviceError, File, Storage};
+use alloc::{boxed::Box, collections::BTreeSet, sync::Arc, vec::Vec};
+use rcore_fs::dev::fault::XorShift;
+use spin::Mutex;
+
+/// An operation on the storage
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum StorageOp {
+    Create(usize),
+    Remove(usize),
+    Write {
+        file: usize,
+        offset: usize,
+        data: Vec<u8>,
+    },
+    SetLen {
+        file: usize,
+        len: usize,
+    },
+    /// The file is flushed, the operations on it before are durable
+    Flush(usize),
+}
+
+impl StorageOp {
+    /// The file operated on
+    pub fn file(&self) -> usize {
+        match *self {
+            StorageOp::Create(file) | StorageOp::Remove(file) | StorageOp::Flush(file) => file,
+            StorageOp::Write { file, .. } | StorageOp::SetLen { file, .. } => file,
+        }
+    }
+
+    /// Apply a `Write` or `SetLen` to `file`
+    fn apply(&self, file: &dyn File) -> DevResult<()> {
+        match *self {
+            StorageOp::Write {
+                offset, ref data, ..
+            } => file.write_all_at(data, offset),
+            StorageOp::SetLen { len, .. } => file.set_len(len),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Operations recorded by a `FaultyStorage`, in the order they are issued
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct StorageLog {
+    pub ops: Vec<StorageOp>,
+}
+
+impl StorageLog {
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    /// Apply the first `len` operations to `storage`, which then holds the files
+    /// after a crash at that point
+    pub fn replay(&self, len: usize, storage: &dyn Storage) -> DevResult<()> {
+        for op in self.ops[..len].iter() {
+            match *op {
+                StorageOp::Create(file) => {
+                    storage.create(file)?;
+                }
+                StorageOp::Remove(file) => storage.remove(file)?,
+                StorageOp::Write {
+                    file,
+                    offset,
+                    ref data,
+                } => storage.open(file)?.write_all_at(data, offset)?,
+                StorageOp::SetLen { file, len } => storage.open(file)?.set_len(len)?,
+                StorageOp::Flush(file) => storage.open(file)?.flush()?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct State {
+    failing_files: BTreeSet<usize>,
+    probability: f64,
+    rng: XorShift,
+    /// Keep the writes and length changes in `unflushed` until their files are flushed
+    caching: bool,
+    unflushed: Vec<StorageOp>,
+    log: Option<StorageLog>,
+}
+
+impl State {
+    fn check(&mut self, file: usize) -> DevResult<()> {
+        let probability = self.probability;
+        if self.failing_files.contains(&file) || self.rng.chance(probability) {
+            return Err(DeviceError);
+        }
+        Ok(())
+    }
+
+    fn record(&mut self, op: StorageOp) {
+        if let Some(log) = self.log.as_mut() {
+            log.ops.push(op);
+        }
+    }
+
+    /// Record a write or length change, return true if it is cached instead of going to the file
+    fn change(&mut self, op: StorageOp) -> bool {
+        self.record(op.clone());
+        if !self.caching {
+            return false;
+        }
+        self.unflushed.push(op);
+        true
+    }
+
+    /// Apply the cached changes of `file` to `buf` read at `offset`, of which `len` bytes
+    /// are read from the file. Return the length changed by them.
+    fn overlay(&self, file: usize, offset: usize, buf: &mut [u8], len: usize) -> usize {
+        let mut end = len;
+        for op in self.unflushed.iter().filter(|op| op.file() == file) {
+            match *op {
+                StorageOp::Write {
+                    offset: write_offset,
+                    ref data,
+                    ..
+                } => {
+                    // the file is extended with zeros up to the write
+                    let write_end = write_offset + data.len();
+                    let extended = write_end.saturating_sub(offset).min(buf.len());
+                    if end < extended {
+                        buf[end..extended].fill(0);
+                        end = extended;
+                    }
+                    let begin = write_offset.max(offset);
+                    if begin - offset < extended {
+                        buf[begin - offset..extended].copy_from_slice(
+                            &data[begin - write_offset..offset + extended - write_offset],
+                        );
+                    }
+                }
+                StorageOp::SetLen { len, .. } => {
+                    let cut = len.saturating_sub(offset).min(buf.len());
+                    if end < cut {
+                        buf[end..cut].fill(0);
+                    }
+                    end = cut;
+                }
+                _ => {}
+            }
+        }
+        end
+    }
+
+    /// Drop the cached changes of `file`, e.g. when it is removed
+    fn forget(&mut self, file: usize) {
+        self.unflushed.retain(|op| op.file() != file);
+    }
+
+    /// A random subset of the cached changes in random order
+    fn random_survivors(&mut self) -> Vec<usize> {
+        let n = self.unflushed.len();
+        let mut order: Vec<usize> = (0..n).collect();
+        for i in (1..n).rev() {
+            let j = self.rng.below(i + 1);
+            order.swap(i, j);
+        }
+        order.truncate(self.rng.below(n + 1));
+        order
+    }
+}
+
+/// A wrapper of `Storage` with faults, the clones share the faults and the log
+#[derive(Clone)]
+pub struct FaultyStorage<S> {
+    inner: S,
+    state: Arc<Mutex<State>>,
+}
+
+impl<S> FaultyStorage<S> {
+    pub fn new(inner: S) -> Self {
+        FaultyStorage {
+            inner,
+            state: Arc::default(),
+        }
+    }
+
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    /// Fail all operations on `file`
+    pub fn fail_file(&self, file: usize) {
+        self.state.lock().failing_files.insert(file);
+    }
+
+    /// Fail each operation with `probability`
+    pub fn fail_randomly(&self, probability: f64) {
+        self.state.lock().probability = probability;
+    }
+
+    /// Reset the random generator of faults to `seed`
+    pub fn seed(&self, seed: u64) {
+        self.state.lock().rng = XorShift::new(seed);
+    }
+
+    /// Stop failing operations
+    pub fn clear(&self) {
+        let mut state = self.state.lock();
+        state.failing_files.clear();
+        state.probability = 0.0;
+    }
+
+    /// Whether to keep the writes and length changes of a file in a volatile cache
+    /// until it is flushed
+    ///
+    /// Changes cached before are kept until flushed or a power loss.
+    pub fn cache_writes(&self, caching: bool) {
+        self.state.lock().caching = caching;
+    }
+
+    /// Number of the cached changes, which are lost on a power loss
+    pub fn unflushed(&self) -> usize {
+        self.state.lock().unflushed.len()
+    }
+
+    /// Start recording the operations, dropping the log recorded before
+    pub fn record(&self) {
+        self.state.lock().log = Some(StorageLog::default());
+    }
+
+    /// The number of operations recorded so far
+    pub fn log_len(&self) -> usize {
+        self.state.lock().log.as_ref().map_or(0, StorageLog::len)
+    }
+
+    /// Stop recording and return the log
+    pub fn take_log(&self) -> StorageLog {
+        self.state.lock().log.take().unwrap_or_default()
+    }
+
+    fn wrap(&self, file: usize, inner: Box<dyn File>) -> Box<dyn File> {
+        Box::new(FaultyFile {
+            inner,
+            id: file,
+            state: self.state.clone(),
+        })
+    }
+}
+
+impl<S: Storage> FaultyStorage<S> {
+    /// Simulate a power loss: the cached changes at `keep` reach the files in that order,
+    /// while the others are lost
+    pub fn power_loss(&self, keep: &[usize]) -> DevResult<()> {
+        let unflushed = core::mem::take(&mut self.state.lock().unflushed);
+        for op in keep.iter().map(|&i| &unflushed[i]) {
+            let file = self.inner.open(op.file())?;
+            op.apply(&*file)?;
+            file.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Simulate a power loss, where a random subset of the cached changes reach the files
+    /// in random order
+    pub fn random_power_loss(&self) -> DevResult<()> {
+        let keep = self.state.lock().random_survivors();
+        self.power_loss(&keep)
+    }
+}
+
+impl<S: Storage> Storage for FaultyStorage<S> {
+    fn open(&self, file_id: usize) -> DevResult<Box<dyn File>> {
+        self.state.lock().check(file_id)?;
+        Ok(self.wrap(file_id, self.inner.open(file_id)?))
+    }
+
+    fn create(&self, file_id: usize) -> DevResult<Box<dyn File>> {
+        let mut state = self.state.lock();
+        state.check(file_id)?;
+        state.record(StorageOp::Create(file_id));
+        state.forget(file_id);
+        drop(state);
+        Ok(self.wrap(file_id, self.inner.create(file_id)?))
+    }
+
+    fn remove(&self, file_id: usize) -> DevResult<()> {
+        let mut state = self.state.lock();
+        state.check(file_id)?;
+        state.record(StorageOp::Remove(file_id));
+        state.forget(file_id);
+        drop(state);
+        self.inner.remove(file_id)
+    }
+}
+
+struct FaultyFile {
+    inner: Box<dyn File>,
+    id: usize,
+    state: Arc<Mutex<State>>,
+}
+
+impl File for FaultyFile {
+    fn read_at(&self, buf: &mut [u8], offset: usize) -> DevResult<usize> {
+        self.state.lock().check(self.id)?;
+        let len = self.inner.read_at(buf, offset)?;
+        Ok(self.state.lock().overlay(self.id, offset, buf, len))
+    }
+
+    fn write_at(&self, buf: &[u8], offset: usize) -> DevResult<usize> {
+        let mut state = self.state.lock();
+        state.check(self.id)?;
+        let write = StorageOp::Write {
+            file: self.id,
+            offset,
+            data: buf.to_vec(),
+        };
+        if state.change(write) {
+            return Ok(buf.len());
+        }
+        drop(state);
+        self.inner.write_at(buf, offset)
+    }
+
+    fn set_len(&self, len: usize) -> DevResult<()> {
+        let mut state = self.state.lock();
+        state.check(self.id)?;
+        if state.change(StorageOp::SetLen { file: self.id, len }) {
+            return Ok(());
+        }
+        drop(state);
+        self.inner.set_len(len)
+    }
+
+    fn flush(&self) -> DevResult<()> {
+        let mut state = self.state.lock();
+        state.check(self.id)?;
+        let (changes, rest): (Vec<_>, _) = core::mem::take(&mut state.unflushed)
+            .into_iter()
+            .partition(|op| op.file() == self.id);
+        state.unflushed = rest;
+        drop(state);
+        for (i, op) in changes.iter().enumerate() {
+            if let Err(e) = op.apply(&*self.inner) {
+                // keep the rest for the next flush
+                let mut state = self.state.lock();
+                let mut rest = changes[i..].to_vec();
+                rest.append(&mut state.unflushed);
+                state.unflushed = rest;
+                return Err(e);
+            }
+        }
+        self.state.lock().record(StorageOp::Flush(self.id));
+        self.inner.flush()
+    }
+}
@@ -5,6 +5,7 @@ use rcore_fs::vfs::FsError;
 #[cfg(any(test, feature = "std"))]
 pub use self::std_impl::*;
 
+pub mod fault;
 pub mod std_impl;
 
 /// A file stores a normal file or directory.
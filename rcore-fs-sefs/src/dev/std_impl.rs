@@ -8,6 +8,7 @@ use std::path::{Path, PathBuf};
 
 pub struct StdStorage {
     path: PathBuf,
+    read_only: bool,
 }
 
 impl StdStorage {
@@ -15,6 +16,15 @@ impl StdStorage {
         assert!(path.as_ref().is_dir());
         StdStorage {
             path: path.as_ref().to_path_buf(),
+            read_only: false,
+        }
+    }
+
+    /// Open the files for reading only, no file can be created or removed
+    pub fn new_read_only(path: impl AsRef<Path>) -> Self {
+        StdStorage {
+            read_only: true,
+            ..Self::new(path)
         }
     }
 }
@@ -23,11 +33,17 @@ impl super::Storage for StdStorage {
     fn open(&self, file_id: usize) -> DevResult<Box<dyn super::File>> {
         let mut path = self.path.to_path_buf();
         path.push(format!("{}", file_id));
-        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        let file = OpenOptions::new()
+            .read(true)
+            .write(!self.read_only)
+            .open(path)?;
         Ok(Box::new(Mutex::new(file)))
     }
 
     fn create(&self, file_id: usize) -> DevResult<Box<dyn super::File>> {
+        if self.read_only {
+            return Err(DeviceError);
+        }
         let mut path = self.path.to_path_buf();
         path.push(format!("{}", file_id));
         let file = OpenOptions::new()
@@ -39,6 +55,9 @@ impl super::Storage for StdStorage {
     }
 
     fn remove(&self, file_id: usize) -> DevResult<()> {
+        if self.read_only {
+            return Err(DeviceError);
+        }
         let mut path = self.path.to_path_buf();
         path.push(format!("{}", file_id));
         remove_file(path)?;
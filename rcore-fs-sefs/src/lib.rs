@@ -1,19 +1,21 @@
 #![cfg_attr(not(any(test, feature = "std")), no_std)]
 
 extern crate alloc;
+#[macro_use]
+extern crate log;
 
 use alloc::{
     boxed::Box,
-    collections::BTreeMap,
     string::String,
     sync::{Arc, Weak},
-    vec::Vec,
+    vec,
 };
 use core::any::Any;
 use core::fmt::{Debug, Error, Formatter};
 
 use bitvec::prelude::*;
 use rcore_fs::{
+    cache::INodeCache,
     dev::TimeProvider,
     dirty::Dirty,
     util::uninit_memory,
@@ -26,6 +28,8 @@ use structs::*;
 
 pub mod dev;
 mod structs;
+#[cfg(test)]
+mod tests;
 
 /// Helper methods for `File`
 impl dyn File {
@@ -53,6 +57,14 @@ impl dyn File {
     }
 }
 
+/// Check the length of the name of a new entry
+fn check_name(name: &str) -> vfs::Result<()> {
+    if name.len() > MAX_FNAME_LEN {
+        return Err(FsError::NameTooLong);
+    }
+    Ok(())
+}
+
 /// inode for SEFS
 pub struct INodeImpl {
     /// inode number
@@ -77,18 +89,20 @@ impl Debug for INodeImpl {
 
 impl INodeImpl {
     /// Only for Dir
-    fn get_file_inode_and_entry_id(&self, name: &str) -> Option<(INodeId, usize)> {
-        (0..self.disk_inode.read().blocks as usize)
-            .map(|i| {
-                let entry = self.file.read_direntry(i).unwrap();
-                (entry, i)
-            })
-            .find(|(entry, _)| entry.name.as_ref() == name)
-            .map(|(entry, id)| (entry.id as INodeId, id))
-    }
-    fn get_file_inode_id(&self, name: &str) -> Option<INodeId> {
-        self.get_file_inode_and_entry_id(name)
-            .map(|(inode_id, _)| inode_id)
+    fn get_file_inode_and_entry_id(&self, name: &str) -> vfs::Result<Option<(INodeId, usize)>> {
+        let blocks = self.disk_inode.read().blocks as usize;
+        for i in 0..blocks {
+            let entry = self.file.read_direntry(i)?;
+            if !entry.is_free() && entry.name.as_ref() == name {
+                return Ok(Some((entry.id as INodeId, i)));
+            }
+        }
+        Ok(None)
+    }
+    fn get_file_inode_id(&self, name: &str) -> vfs::Result<Option<INodeId>> {
+        Ok(self
+            .get_file_inode_and_entry_id(name)?
+            .map(|(inode_id, _)| inode_id))
     }
     /// Init dir content. Insert 2 init entries.
     /// This do not init nlinks, please modify the nlinks in the invoker.
@@ -111,33 +125,88 @@ impl INodeImpl {
         )?;
         Ok(())
     }
-    fn dirent_append(&self, entry: &DiskEntry) -> vfs::Result<()> {
+    /// Read the `n`th used dirent, skipping free ones
+    fn dirent_nth(&self, n: usize) -> vfs::Result<DiskEntry> {
+        let total = self.disk_inode.read().blocks as usize;
+        let mut n = n;
+        for id in 0..total {
+            let entry = self.file.read_direntry(id)?;
+            if entry.is_free() {
+                continue;
+            }
+            if n == 0 {
+                return Ok(entry);
+            }
+            n -= 1;
+        }
+        Err(FsError::EntryNotFound)
+    }
+    /// Insert a dirent into the first free slot, or append it if there is none
+    fn dirent_insert(&self, entry: &DiskEntry) -> vfs::Result<()> {
         let mut inode = self.disk_inode.write();
         let total = &mut inode.blocks;
+        for id in 0..*total as usize {
+            if self.file.read_direntry(id)?.is_free() {
+                self.file.write_direntry(id, entry)?;
+                return Ok(());
+            }
+        }
         self.file.write_direntry(*total as usize, entry)?;
         *total += 1;
         Ok(())
     }
-    /// remove a page in middle of file and insert the last page here, useful for dirent remove
-    /// should be only used in unlink
+    /// Free a dirent in place, so that other dirents keep their positions for readdir.
+    /// Free dirents at the end are dropped, thus the last dirent is always in use.
+    /// The file is not truncated, since the INode counting them may be written after it.
     fn dirent_remove(&self, id: usize) -> vfs::Result<()> {
-        let total = self.disk_inode.read().blocks as usize;
+        let mut inode = self.disk_inode.write();
+        let mut total = inode.blocks as usize;
         debug_assert!(id < total);
-        let last_direntry = self.file.read_direntry(total - 1)?;
-        if id != total - 1 {
-            self.file.write_direntry(id, &last_direntry)?;
+        self.file.write_direntry(id, &DiskEntry::free())?;
+        while total > 0 && self.file.read_direntry(total - 1)?.is_free() {
+            total -= 1;
         }
-        self.file.set_len((total - 1) * DIRENT_SIZE)?;
-        self.disk_inode.write().blocks -= 1;
+        inode.blocks = total as u32;
+        Ok(())
+    }
+    /// Only for Dir. Point '..' to `parent`.
+    /// This do not modify the nlinks, please modify the nlinks in the invoker.
+    fn set_parent(&self, parent: INodeId) -> vfs::Result<()> {
+        self.file.write_direntry(
+            1,
+            &DiskEntry {
+                id: parent as u32,
+                name: Str256::from(".."),
+            },
+        )?;
         Ok(())
     }
+    /// Only for Dir. Return `InvalidParam` if `self` is the dir `ancestor` or beneath it,
+    /// so that moving `ancestor` into `self` would make a cycle.
+    fn check_not_beneath(&self, ancestor: INodeId) -> vfs::Result<()> {
+        let mut id = self.id;
+        loop {
+            if id == ancestor {
+                return Err(FsError::InvalidParam);
+            }
+            if id == BLKN_ROOT {
+                return Ok(());
+            }
+            id = self.fs.get_inode(id)?.file.read_direntry(1)?.id as INodeId;
+        }
+    }
     fn nlinks_inc(&self) {
         self.disk_inode.write().nlinks += 1;
     }
-    fn nlinks_dec(&self) {
+    /// Fail with `Corrupted` if the INode has no link left
+    fn nlinks_dec(&self) -> vfs::Result<()> {
         let mut disk_inode = self.disk_inode.write();
-        assert!(disk_inode.nlinks > 0);
+        if disk_inode.nlinks == 0 {
+            error!("INode {} has no link to remove", self.id);
+            return Err(FsError::Corrupted);
+        }
         disk_inode.nlinks -= 1;
+        Ok(())
     }
 }
 
@@ -151,6 +220,7 @@ impl vfs::INode for INodeImpl {
         Ok(len)
     }
     fn write_at(&self, offset: usize, buf: &[u8]) -> vfs::Result<usize> {
+        self.fs.check_writable()?;
         let DiskINode { type_, size, .. } = **self.disk_inode.read();
         if type_ != FileType::File && type_ != FileType::SymLink {
             return Err(FsError::NotFile);
@@ -203,6 +273,7 @@ impl vfs::INode for INodeImpl {
         })
     }
     fn set_metadata(&self, metadata: &vfs::Metadata) -> vfs::Result<()> {
+        self.fs.check_writable()?;
         let mut disk_inode = self.disk_inode.write();
         disk_inode.mode = metadata.mode;
         disk_inode.uid = metadata.uid as u16;
@@ -213,6 +284,9 @@ impl vfs::INode for INodeImpl {
         Ok(())
     }
     fn sync_all(&self) -> vfs::Result<()> {
+        if self.fs.read_only {
+            return Ok(());
+        }
         let mut disk_inode = self.disk_inode.write();
         if disk_inode.dirty() {
             self.fs
@@ -224,10 +298,14 @@ impl vfs::INode for INodeImpl {
         Ok(())
     }
     fn sync_data(&self) -> vfs::Result<()> {
+        if self.fs.read_only {
+            return Ok(());
+        }
         self.file.flush()?;
         Ok(())
     }
     fn resize(&self, len: usize) -> vfs::Result<()> {
+        self.fs.check_writable()?;
         let type_ = self.disk_inode.read().type_;
         if type_ != FileType::File && type_ != FileType::SymLink {
             return Err(FsError::NotFile);
@@ -242,6 +320,7 @@ impl vfs::INode for INodeImpl {
         type_: vfs::FileType,
         mode: u32,
     ) -> vfs::Result<Arc<dyn vfs::INode>> {
+        self.fs.check_writable()?;
         let type_ = match type_ {
             vfs::FileType::File => FileType::File,
             vfs::FileType::Dir => FileType::Dir,
@@ -255,9 +334,10 @@ impl vfs::INode for INodeImpl {
         if info.nlinks == 0 {
             return Err(FsError::DirRemoved);
         }
+        check_name(name)?;
 
         // Ensure the name is not exist
-        if self.get_file_inode_id(name).is_some() {
+        if self.get_file_inode_id(name)?.is_some() {
             return Err(FsError::EntryExist);
         }
 
@@ -272,7 +352,7 @@ impl vfs::INode for INodeImpl {
             id: inode.id as u32,
             name: Str256::from(name),
         };
-        self.dirent_append(&entry)?;
+        self.dirent_insert(&entry)?;
         inode.nlinks_inc();
         if type_ == FileType::Dir {
             inode.nlinks_inc(); //for .
@@ -281,7 +361,17 @@ impl vfs::INode for INodeImpl {
 
         Ok(inode)
     }
+    fn read_link(&self) -> vfs::Result<String> {
+        let DiskINode { type_, size, .. } = **self.disk_inode.read();
+        if type_ != FileType::SymLink {
+            return Err(FsError::InvalidParam);
+        }
+        let mut buf = vec![0u8; size as usize];
+        self.file.read_exact_at(&mut buf, 0)?;
+        String::from_utf8(buf).map_err(|_| FsError::InvalidParam)
+    }
     fn unlink(&self, name: &str) -> vfs::Result<()> {
+        self.fs.check_writable()?;
         let info = self.metadata()?;
         if info.type_ != vfs::FileType::Dir {
             return Err(FsError::NotDir);
@@ -297,9 +387,9 @@ impl vfs::INode for INodeImpl {
         }
 
         let (inode_id, entry_id) = self
-            .get_file_inode_and_entry_id(name)
+            .get_file_inode_and_entry_id(name)?
             .ok_or(FsError::EntryNotFound)?;
-        let inode = self.fs.get_inode(inode_id);
+        let inode = self.fs.get_inode(inode_id)?;
 
         let type_ = inode.disk_inode.read().type_;
         if type_ == FileType::Dir {
@@ -309,16 +399,19 @@ impl vfs::INode for INodeImpl {
                 return Err(FsError::DirNotEmpty);
             }
         }
-        inode.nlinks_dec();
+        inode.nlinks_dec()?;
         if type_ == FileType::Dir {
-            inode.nlinks_dec(); //for .
-            self.nlinks_dec(); //for ..
+            inode.nlinks_dec()?; //for .
+            self.nlinks_dec()?; //for ..
         }
         self.dirent_remove(entry_id)?;
+        // the file of the INode is removed with its last link, after the entry is gone
+        self.sync_data()?;
 
         Ok(())
     }
     fn link(&self, name: &str, other: &Arc<dyn INode>) -> vfs::Result<()> {
+        self.fs.check_writable()?;
         let info = self.metadata()?;
         if info.type_ != vfs::FileType::Dir {
             return Err(FsError::NotDir);
@@ -326,7 +419,8 @@ impl vfs::INode for INodeImpl {
         if info.nlinks == 0 {
             return Err(FsError::DirRemoved);
         }
-        if self.get_file_inode_id(name).is_some() {
+        check_name(name)?;
+        if self.get_file_inode_id(name)?.is_some() {
             return Err(FsError::EntryExist);
         }
         let child = other
@@ -342,11 +436,18 @@ impl vfs::INode for INodeImpl {
             id: child.id as u32,
             name: Str256::from(name),
         };
-        self.dirent_append(&entry)?;
+        self.dirent_insert(&entry)?;
         child.nlinks_inc();
         Ok(())
     }
-    fn move_(&self, old_name: &str, target: &Arc<dyn INode>, new_name: &str) -> vfs::Result<()> {
+    fn rename(
+        &self,
+        old_name: &str,
+        target: &Arc<dyn INode>,
+        new_name: &str,
+        flags: vfs::RenameFlags,
+    ) -> vfs::Result<()> {
+        self.fs.check_writable()?;
         let info = self.metadata()?;
         if info.type_ != vfs::FileType::Dir {
             return Err(FsError::NotDir);
@@ -354,12 +455,16 @@ impl vfs::INode for INodeImpl {
         if info.nlinks == 0 {
             return Err(FsError::DirRemoved);
         }
-        if old_name == "." {
+        if old_name == "." || new_name == "." {
             return Err(FsError::IsDir);
         }
-        if old_name == ".." {
+        if old_name == ".." || new_name == ".." {
             return Err(FsError::IsDir);
         }
+        check_name(new_name)?;
+        if flags.noreplace && flags.exchange {
+            return Err(FsError::InvalidParam);
+        }
 
         let dest = target
             .downcast_ref::<INodeImpl>()
@@ -374,37 +479,91 @@ impl vfs::INode for INodeImpl {
         if dest_info.nlinks == 0 {
             return Err(FsError::DirRemoved);
         }
-        if dest.get_file_inode_id(new_name).is_some() {
-            return Err(FsError::EntryExist);
-        }
 
         let (inode_id, entry_id) = self
-            .get_file_inode_and_entry_id(old_name)
+            .get_file_inode_and_entry_id(old_name)?
             .ok_or(FsError::EntryNotFound)?;
-        if info.inode == dest_info.inode {
-            // rename: in place modify name
-            let entry = DiskEntry {
-                id: inode_id as u32,
-                name: Str256::from(new_name),
-            };
-            self.file.write_direntry(entry_id, &entry)?;
-        } else {
-            // move
-            let inode = self.fs.get_inode(inode_id);
-
-            let entry = DiskEntry {
-                id: inode_id as u32,
-                name: Str256::from(new_name),
-            };
-            dest.dirent_append(&entry)?;
-            self.dirent_remove(entry_id)?;
-
-            if inode.metadata()?.type_ == vfs::FileType::Dir {
-                self.nlinks_dec();
-                dest.nlinks_inc();
+        let inode = self.fs.get_inode(inode_id)?;
+        let is_dir = inode.disk_inode.read().type_ == FileType::Dir;
+        let replaced = dest.get_file_inode_and_entry_id(new_name)?;
+        if flags.noreplace && replaced.is_some() {
+            return Err(FsError::EntryExist);
+        }
+        if flags.exchange && replaced.is_none() {
+            return Err(FsError::EntryNotFound);
+        }
+        // cross directory, the parent of moved directories changes
+        let moved = info.inode != dest_info.inode;
+        if moved && is_dir {
+            dest.check_not_beneath(inode_id)?;
+        }
+
+        let entry = DiskEntry {
+            id: inode_id as u32,
+            name: Str256::from(new_name),
+        };
+        match replaced {
+            None if !moved => {
+                // rename: in place modify name
+                self.file.write_direntry(entry_id, &entry)?;
+            }
+            None => {
+                dest.dirent_insert(&entry)?;
+                self.dirent_remove(entry_id)?;
+            }
+            Some((other_id, other_entry_id)) if flags.exchange => {
+                let other = self.fs.get_inode(other_id)?;
+                let other_is_dir = other.disk_inode.read().type_ == FileType::Dir;
+                if moved && other_is_dir {
+                    self.check_not_beneath(other_id)?;
+                }
+                let other_entry = DiskEntry {
+                    id: other_id as u32,
+                    name: Str256::from(old_name),
+                };
+                dest.file.write_direntry(other_entry_id, &entry)?;
+                self.file.write_direntry(entry_id, &other_entry)?;
+                if moved && other_is_dir {
+                    other.set_parent(self.id)?;
+                    dest.nlinks_dec()?;
+                    self.nlinks_inc();
+                }
+            }
+            Some((other_id, _)) if other_id == inode_id => {
+                // both names are links to the same inode, do nothing
+                return Ok(());
+            }
+            Some((other_id, other_entry_id)) => {
+                let other = self.fs.get_inode(other_id)?;
+                let DiskINode { type_, blocks, .. } = **other.disk_inode.read();
+                let other_is_dir = type_ == FileType::Dir;
+                if is_dir && !other_is_dir {
+                    return Err(FsError::NotDir);
+                }
+                if !is_dir && other_is_dir {
+                    return Err(FsError::IsDir);
+                }
+                // only . and ..
+                if other_is_dir && blocks > 2 {
+                    return Err(FsError::DirNotEmpty);
+                }
+                dest.file.write_direntry(other_entry_id, &entry)?;
+                self.dirent_remove(entry_id)?;
+                // the replaced inode is freed with its last link, after the entry is gone
+                dest.sync_data()?;
+                other.nlinks_dec()?;
+                if other_is_dir {
+                    other.nlinks_dec()?; //for .
+                    dest.nlinks_dec()?; //for ..
+                }
             }
         }
 
+        if moved && is_dir {
+            inode.set_parent(dest.id)?;
+            self.nlinks_dec()?;
+            dest.nlinks_inc();
+        }
         Ok(())
     }
     fn find(&self, name: &str) -> vfs::Result<Arc<dyn vfs::INode>> {
@@ -412,19 +571,40 @@ impl vfs::INode for INodeImpl {
         if info.type_ != vfs::FileType::Dir {
             return Err(FsError::NotDir);
         }
-        let inode_id = self.get_file_inode_id(name).ok_or(FsError::EntryNotFound)?;
-        Ok(self.fs.get_inode(inode_id))
+        let inode_id = self
+            .get_file_inode_id(name)?
+            .ok_or(FsError::EntryNotFound)?;
+        Ok(self.fs.get_inode(inode_id)?)
     }
     fn get_entry(&self, id: usize) -> vfs::Result<String> {
         if self.disk_inode.read().type_ != FileType::Dir {
             return Err(FsError::NotDir);
         }
-        if id >= self.disk_inode.read().blocks as usize {
-            return Err(FsError::EntryNotFound);
-        };
-        let entry = self.file.read_direntry(id)?;
+        let entry = self.dirent_nth(id)?;
         Ok(String::from(entry.name.as_ref()))
     }
+    /// The cookie is the position of dirent, which is stable since unlink leaves a free slot.
+    fn read_dir(&self, cookie: usize) -> vfs::Result<Option<vfs::DirEntry>> {
+        let DiskINode { type_, blocks, .. } = **self.disk_inode.read();
+        if type_ != FileType::Dir {
+            return Err(FsError::NotDir);
+        }
+        for id in cookie..blocks as usize {
+            let entry = self.file.read_direntry(id)?;
+            if entry.is_free() {
+                continue;
+            }
+            let inode = self.fs.get_inode(entry.id as INodeId)?;
+            let type_ = inode.disk_inode.read().type_;
+            return Ok(Some(vfs::DirEntry {
+                name: String::from(entry.name.as_ref()),
+                inode: entry.id as INodeId,
+                type_: vfs::FileType::from(type_),
+                next_cookie: id + 1,
+            }));
+        }
+        Ok(None)
+    }
     fn io_control(&self, _cmd: u32, _data: usize) -> vfs::Result<usize> {
         Err(FsError::NotSupported)
     }
@@ -442,12 +622,19 @@ impl vfs::INode for INodeImpl {
 impl Drop for INodeImpl {
     /// Auto sync when drop
     fn drop(&mut self) {
-        self.sync_all()
-            .expect("Failed to sync when dropping the SEFS Inode");
-        if self.disk_inode.read().nlinks == 0 {
+        if let Err(e) = self.sync_all() {
+            error!("failed to sync INode {} when dropping: {:?}", self.id, e);
+            // the changes are lost
+            self.disk_inode.write().sync();
+        }
+        if self.disk_inode.read().nlinks == 0 && !self.fs.read_only {
+            // keep the INode allocated if its file can not be removed, it is leaked but consistent
+            if let Err(e) = self.fs.device.remove(self.id) {
+                error!("failed to remove the file of INode {}: {:?}", self.id, e);
+                return;
+            }
             self.disk_inode.write().sync();
             self.fs.free_block(self.id);
-            self.fs.device.remove(self.id).unwrap();
         }
     }
 }
@@ -459,7 +646,7 @@ pub struct SEFS {
     /// blocks in use are marked 0
     free_map: RwLock<Dirty<BitVec<Lsb0, u8>>>,
     /// inode list
-    inodes: RwLock<BTreeMap<INodeId, Weak<INodeImpl>>>,
+    inodes: INodeCache<INodeImpl>,
     /// device
     device: Box<dyn Storage>,
     /// metadata file
@@ -468,6 +655,8 @@ pub struct SEFS {
     time_provider: &'static dyn TimeProvider,
     /// Pointer to self, used by INodes
     self_ptr: Weak<SEFS>,
+    /// Never write to the device
+    read_only: bool,
 }
 
 impl SEFS {
@@ -475,6 +664,20 @@ impl SEFS {
     pub fn open(
         device: Box<dyn Storage>,
         time_provider: &'static dyn TimeProvider,
+    ) -> vfs::Result<Arc<Self>> {
+        Self::load(device, time_provider, false)
+    }
+    /// Load SEFS without writing to the device, all changes fail with `ReadOnly`
+    pub fn open_read_only(
+        device: Box<dyn Storage>,
+        time_provider: &'static dyn TimeProvider,
+    ) -> vfs::Result<Arc<Self>> {
+        Self::load(device, time_provider, true)
+    }
+    fn load(
+        device: Box<dyn Storage>,
+        time_provider: &'static dyn TimeProvider,
+        read_only: bool,
     ) -> vfs::Result<Arc<Self>> {
         let meta_file = device.open(0)?;
         let super_block = meta_file.load_struct::<SuperBlock>(BLKN_SUPER)?;
@@ -495,16 +698,19 @@ impl SEFS {
             )?;
         }
 
-        Ok(SEFS {
+        let sefs = SEFS {
             super_block: RwLock::new(Dirty::new(super_block)),
             free_map: RwLock::new(Dirty::new(free_map)),
-            inodes: RwLock::new(BTreeMap::new()),
+            inodes: INodeCache::new(),
             device,
             meta_file,
             time_provider,
             self_ptr: Weak::default(),
+            read_only,
         }
-        .wrap())
+        .wrap();
+        sefs.get_inode(BLKN_ROOT)?;
+        Ok(sefs)
     }
     /// Create a new SEFS
     pub fn create(
@@ -533,11 +739,12 @@ impl SEFS {
         let sefs = SEFS {
             super_block: RwLock::new(Dirty::new_dirty(super_block)),
             free_map: RwLock::new(Dirty::new_dirty(free_map)),
-            inodes: RwLock::new(BTreeMap::new()),
+            inodes: INodeCache::new(),
             device,
             meta_file,
             time_provider,
             self_ptr: Weak::default(),
+            read_only: false,
         }
         .wrap();
 
@@ -565,27 +772,37 @@ impl SEFS {
         unsafe { Arc::from_raw(ptr) }
     }
 
-    /// Allocate a block, return block id
-    fn alloc_block(&self) -> Option<usize> {
+    /// Fail with `ReadOnly` if opened read-only
+    fn check_writable(&self) -> vfs::Result<()> {
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        Ok(())
+    }
+
+    /// Allocate a block, return block id.
+    /// A new group is added if all are full, fail if the meta file can not be extended for it.
+    fn alloc_block(&self) -> vfs::Result<usize> {
         let mut free_map = self.free_map.write();
         let mut super_block = self.super_block.write();
-        let id = free_map.alloc().or_else(|| {
-            // allocate a new group
-            let new_group_id = super_block.groups as usize;
-            super_block.groups += 1;
-            super_block.blocks += BLKBITS as u32;
-            super_block.unused_blocks += BLKBITS as u32 - 1;
-            self.meta_file
-                .set_len(super_block.groups as usize * BLKBITS * BLKSIZE)
-                .expect("failed to extend meta file");
-            free_map.extend(core::iter::repeat(true).take(BLKBITS));
-            free_map.set(Self::get_freemap_block_id_of_group(new_group_id), false);
-            // allocate block again
-            free_map.alloc()
-        });
-        assert!(id.is_some(), "allocate block should always success");
+        let id = match free_map.alloc() {
+            Some(id) => id,
+            None => {
+                // allocate a new group
+                let new_group_id = super_block.groups as usize;
+                self.meta_file
+                    .set_len((new_group_id + 1) * BLKBITS * BLKSIZE)?;
+                super_block.groups += 1;
+                super_block.blocks += BLKBITS as u32;
+                super_block.unused_blocks += BLKBITS as u32 - 1;
+                free_map.extend(core::iter::repeat_n(true, BLKBITS));
+                free_map.set(Self::get_freemap_block_id_of_group(new_group_id), false);
+                // allocate block again
+                free_map.alloc().ok_or(FsError::NoDeviceSpace)?
+            }
+        };
         super_block.unused_blocks -= 1;
-        id
+        Ok(id)
     }
     /// Free a block
     fn free_block(&self, block_id: usize) {
@@ -602,37 +819,39 @@ impl SEFS {
         id: INodeId,
         disk_inode: Dirty<DiskINode>,
         create: bool,
-    ) -> Arc<INodeImpl> {
+    ) -> vfs::Result<Arc<INodeImpl>> {
+        let file = match create {
+            true => self.device.create(id)?,
+            false => self.device.open(id)?,
+        };
         let inode = Arc::new(INodeImpl {
             id,
             disk_inode: RwLock::new(disk_inode),
-            file: match create {
-                true => self.device.create(id).unwrap(),
-                false => self.device.open(id).unwrap(),
-            },
+            file,
             fs: self.self_ptr.upgrade().unwrap(),
         });
-        self.inodes.write().insert(id, Arc::downgrade(&inode));
-        inode
+        self.inodes.insert(id, &inode);
+        Ok(inode)
     }
     /// Get inode by id. Load if not in memory.
-    /// ** Must ensure it's a valid INode **
-    fn get_inode(&self, id: INodeId) -> Arc<INodeImpl> {
-        assert!(!self.free_map.read()[id]);
-
-        // In the BTreeSet and not weak.
-        if let Some(inode) = self.inodes.read().get(&id) {
-            if let Some(inode) = inode.upgrade() {
-                return inode;
-            }
+    ///
+    /// Fail with `Corrupted` if the block is not allocated to an INode.
+    fn get_inode(&self, id: INodeId) -> vfs::Result<Arc<INodeImpl>> {
+        if self.free_map.read().get(id).as_deref() != Some(&false) {
+            error!("INode {} is not allocated", id);
+            return Err(FsError::Corrupted);
+        }
+
+        if let Some(inode) = self.inodes.get(id) {
+            return Ok(inode);
         }
-        // Load if not in set, or is weak ref.
-        let disk_inode = Dirty::new(self.meta_file.load_struct::<DiskINode>(id).unwrap());
+        // Load if not in memory
+        let disk_inode = Dirty::new(self.meta_file.load_struct::<DiskINode>(id)?);
         self._new_inode(id, disk_inode, false)
     }
     /// Create a new INode file
     fn new_inode(&self, type_: FileType, mode: u16) -> vfs::Result<Arc<INodeImpl>> {
-        let id = self.alloc_block().ok_or(FsError::NoDeviceSpace)?;
+        let id = self.alloc_block()?;
         let time = self.time_provider.current_time().sec as u32;
         let disk_inode = Dirty::new_dirty(DiskINode {
             size: 0,
@@ -646,18 +865,11 @@ impl SEFS {
             mtime: time,
             ctime: time,
         });
-        Ok(self._new_inode(id, disk_inode, true))
-    }
-    fn flush_weak_inodes(&self) {
-        let mut inodes = self.inodes.write();
-        let remove_ids: Vec<_> = inodes
-            .iter()
-            .filter(|(_, inode)| inode.upgrade().is_none())
-            .map(|(&id, _)| id)
-            .collect();
-        for id in remove_ids.iter() {
-            inodes.remove(id);
+        let inode = self._new_inode(id, disk_inode, true);
+        if inode.is_err() {
+            self.free_block(id);
         }
+        inode
     }
     fn get_freemap_block_id_of_group(group_id: usize) -> usize {
         BLKBITS * group_id + BLKN_FREEMAP
@@ -667,16 +879,18 @@ impl SEFS {
 impl vfs::FileSystem for SEFS {
     /// Write back super block if dirty
     fn sync(&self) -> vfs::Result<()> {
-        // sync super_block
-        let mut super_block = self.super_block.write();
-        if super_block.dirty() {
-            self.meta_file
-                .write_all_at(super_block.as_buf(), BLKSIZE * BLKN_SUPER)?;
-            super_block.sync();
+        if self.read_only {
+            return Ok(());
         }
-        // sync free_map
         let mut free_map = self.free_map.write();
+        let mut super_block = self.super_block.write();
+        // sync free_map, the unused blocks are not counted before they are marked free
         if free_map.dirty() {
+            let offset = BLKSIZE * BLKN_SUPER + core::mem::offset_of!(SuperBlock, unused_blocks);
+            let mut counted = 0u32;
+            self.meta_file.read_exact_at(counted.as_buf_mut(), offset)?;
+            let unused = super_block.unused_blocks.min(counted);
+            self.meta_file.write_all_at(unused.as_buf(), offset)?;
             for i in 0..super_block.groups as usize {
                 let slice = &free_map.as_raw_slice()[BLKSIZE * i..BLKSIZE * (i + 1)];
                 self.meta_file
@@ -684,19 +898,26 @@ impl vfs::FileSystem for SEFS {
             }
             free_map.sync();
         }
+        // sync super_block
+        if super_block.dirty() {
+            self.meta_file
+                .write_all_at(super_block.as_buf(), BLKSIZE * BLKN_SUPER)?;
+            super_block.sync();
+        }
+        drop((free_map, super_block));
         // sync all INodes
-        self.flush_weak_inodes();
-        for inode in self.inodes.read().values() {
-            if let Some(inode) = inode.upgrade() {
-                inode.sync_all()?;
-            }
+        self.inodes.flush();
+        for inode in self.inodes.alive() {
+            inode.sync_all()?;
         }
         self.meta_file.flush()?;
         Ok(())
     }
 
+    /// Opening checks the root INode, so this panics only if it can not be read again later
     fn root_inode(&self) -> Arc<dyn vfs::INode> {
         self.get_inode(BLKN_ROOT)
+            .expect("failed to load the root INode")
     }
 
     fn info(&self) -> vfs::FsInfo {
@@ -717,7 +938,9 @@ impl vfs::FileSystem for SEFS {
 impl Drop for SEFS {
     /// Auto sync when drop
     fn drop(&mut self) {
-        self.sync().expect("Failed to sync when dropping the SEFS");
+        if let Err(e) = self.sync() {
+            error!("failed to sync when dropping the SEFS: {:?}", e);
+        }
     }
 }
 
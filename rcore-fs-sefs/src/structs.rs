@@ -68,6 +68,21 @@ impl Debug for Str256 {
     }
 }
 
+impl DiskEntry {
+    /// An unused entry, left in the middle of a directory by unlink
+    pub fn free() -> Self {
+        DiskEntry {
+            id: 0,
+            name: Str256([0; 256]),
+        }
+    }
+    /// Whether this entry is unused.
+    /// Inode 0 is the superblock, which can not be a file.
+    pub fn is_free(&self) -> bool {
+        self.id == 0
+    }
+}
+
 impl<'a> From<&'a str> for Str256 {
     fn from(s: &'a str) -> Self {
         let mut ret = [0u8; 256];
@@ -0,0 +1,209 @@
+extern crate std;
+
+use crate::*;
+use alloc::{collections::BTreeMap, format, vec::Vec};
+use rcore_fs::{crash, dev::std_impl::StdTimeProvider};
+use std::sync::Mutex;
+
+/// A file in memory
+struct MemFile(Arc<Mutex<Vec<u8>>>);
+
+impl File for MemFile {
+    fn read_at(&self, buf: &mut [u8], offset: usize) -> DevResult<usize> {
+        let data = self.0.lock().unwrap();
+        let len = buf.len().min(data.len().saturating_sub(offset));
+        buf[..len].copy_from_slice(&data[offset..offset + len]);
+        Ok(len)
+    }
+    fn write_at(&self, buf: &[u8], offset: usize) -> DevResult<usize> {
+        let mut data = self.0.lock().unwrap();
+        if data.len() < offset + buf.len() {
+            data.resize(offset + buf.len(), 0);
+        }
+        data[offset..offset + buf.len()].copy_from_slice(buf);
+        Ok(buf.len())
+    }
+    fn set_len(&self, len: usize) -> DevResult<()> {
+        self.0.lock().unwrap().resize(len, 0);
+        Ok(())
+    }
+    fn flush(&self) -> DevResult<()> {
+        Ok(())
+    }
+}
+
+type Files = BTreeMap<usize, Arc<Mutex<Vec<u8>>>>;
+
+/// A storage in memory, the clones share the files
+#[derive(Default, Clone)]
+struct MemStorage(Arc<Mutex<Files>>);
+
+impl MemStorage {
+    /// Copy the files into a new storage
+    fn snapshot(&self) -> MemStorage {
+        let files = self.0.lock().unwrap();
+        let copy = files
+            .iter()
+            .map(|(&id, data)| (id, Arc::new(Mutex::new(data.lock().unwrap().clone()))))
+            .collect();
+        MemStorage(Arc::new(Mutex::new(copy)))
+    }
+}
+
+impl Storage for MemStorage {
+    fn open(&self, file_id: usize) -> DevResult<Box<dyn File>> {
+        match self.0.lock().unwrap().get(&file_id) {
+            Some(data) => Ok(Box::new(MemFile(data.clone()))),
+            None => Err(DeviceError),
+        }
+    }
+    fn create(&self, file_id: usize) -> DevResult<Box<dyn File>> {
+        let data = Arc::new(Mutex::new(Vec::new()));
+        self.0.lock().unwrap().insert(file_id, data.clone());
+        Ok(Box::new(MemFile(data)))
+    }
+    fn remove(&self, file_id: usize) -> DevResult<()> {
+        match self.0.lock().unwrap().remove(&file_id) {
+            Some(_) => Ok(()),
+            None => Err(DeviceError),
+        }
+    }
+}
+
+/// Check that the entries, the links, the free map and the files agree with each other.
+/// If `leaks`, the INodes and the links may be counted more than they are used.
+fn check_storage(storage: MemStorage, leaks: bool) -> Result<(), String> {
+    let sefs = SEFS::open_read_only(Box::new(storage.clone()), &StdTimeProvider)
+        .map_err(|e| format!("open: {:?}", e))?;
+    let inodes = crash::check_tree(&sefs.root_inode(), leaks)?;
+    let super_block = sefs.super_block.read();
+    let free_map = sefs.free_map.read();
+    let blocks = super_block.blocks as usize;
+    if free_map.len() != blocks {
+        return Err(format!(
+            "{} blocks, but {} in the free map",
+            blocks,
+            free_map.len()
+        ));
+    }
+    for block in 0..blocks {
+        let used =
+            block == BLKN_SUPER || block % BLKBITS == BLKN_FREEMAP || inodes.contains_key(&block);
+        match (free_map[block], used) {
+            (true, true) => return Err(format!("block {} is in use but free", block)),
+            (false, false) if !leaks => return Err(format!("block {} is leaked", block)),
+            _ => {}
+        }
+    }
+    let unused = free_map.count_ones();
+    if super_block.unused_blocks as usize > unused
+        || (super_block.unused_blocks as usize) < unused && !leaks
+    {
+        return Err(format!(
+            "{} unused blocks are counted, but {} are free",
+            super_block.unused_blocks, unused
+        ));
+    }
+    for &file in storage.0.lock().unwrap().keys() {
+        if file != 0 && !inodes.contains_key(&file) && !leaks {
+            return Err(format!("file {} is leaked", file));
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn power_loss() {
+    let storage = MemStorage::default();
+    let device = dev::fault::FaultyStorage::new(storage.clone());
+    device.cache_writes(true);
+    let file = device.create(1).unwrap();
+    file.write_all_at(&[1; 4], 0).unwrap();
+    file.write_all_at(&[2; 4], 6).unwrap();
+    file.set_len(8).unwrap();
+    // the changes are seen through the cache only
+    let mut buf = [0xffu8; 12];
+    assert_eq!(file.read_at(&mut buf, 0).unwrap(), 8);
+    assert_eq!(buf[..8], [1, 1, 1, 1, 0, 0, 2, 2]);
+    assert!(storage.0.lock().unwrap()[&1].lock().unwrap().is_empty());
+    assert_eq!(device.unflushed(), 3);
+    file.flush().unwrap();
+    assert_eq!(device.unflushed(), 0);
+    assert_eq!(
+        *storage.0.lock().unwrap()[&1].lock().unwrap(),
+        [1, 1, 1, 1, 0, 0, 2, 2]
+    );
+
+    // the truncation reaches the file after the write
+    file.write_all_at(&[3; 2], 8).unwrap();
+    file.set_len(2).unwrap();
+    device.power_loss(&[1, 0]).unwrap();
+    assert_eq!(
+        *storage.0.lock().unwrap()[&1].lock().unwrap(),
+        [1, 1, 0, 0, 0, 0, 0, 0, 3, 3]
+    );
+}
+
+/// The entries created are written before the INodes they refer to,
+/// so the storage may be inconsistent from then on until the next `Sync` ends
+fn unsafe_step(step: &crash::Step) -> bool {
+    matches!(step, crash::Step::Create(..))
+}
+
+#[test]
+fn crash_consistency() -> vfs::Result<()> {
+    let storage = MemStorage::default();
+    let device = dev::fault::FaultyStorage::new(storage.clone());
+    let sefs = SEFS::create(Box::new(device.clone()), &StdTimeProvider)?;
+    sefs.sync()?;
+    let base = storage.snapshot();
+    check_storage(base.snapshot(), false).unwrap();
+
+    // a crash may only leak INodes, and leaves none at a `Sync`
+    device.record();
+    let ends = crash::run_workload(&*sefs, crash::WORKLOAD, || device.log_len())?;
+    let log = device.take_log();
+    let checkpoints = crash::checkpoints(crash::WORKLOAD, &ends);
+    let windows = crash::unsafe_windows(crash::WORKLOAD, &ends, unsafe_step);
+    for len in 0..=log.len() {
+        if windows.iter().any(|window| window.contains(&len)) {
+            continue;
+        }
+        let image = base.snapshot();
+        log.replay(len, &image).unwrap();
+        if let Err(e) = check_storage(image, !checkpoints.contains(&len)) {
+            panic!("crash after {} of {} operations: {}", len, log.len(), e);
+        }
+    }
+
+    // a power loss after each step, where some of the changes not flushed reach the files
+    // in any order
+    let ends = (1..=crash::WORKLOAD.len()).collect::<Vec<_>>();
+    let checkpoints = crash::checkpoints(crash::WORKLOAD, &ends);
+    let windows = crash::unsafe_windows(crash::WORKLOAD, &ends, unsafe_step);
+    for steps in 0..=crash::WORKLOAD.len() {
+        if windows.iter().any(|window| window.contains(&steps)) {
+            continue;
+        }
+        for seed in 1..8 {
+            let storage = MemStorage::default();
+            let device = dev::fault::FaultyStorage::new(storage.clone());
+            let sefs = SEFS::create(Box::new(device.clone()), &StdTimeProvider)?;
+            sefs.sync()?;
+            device.cache_writes(true);
+            device.seed(seed);
+            let mut open = Vec::new();
+            for step in crash::WORKLOAD[..steps].iter() {
+                step.run(&*sefs, &mut open)?;
+            }
+            device.random_power_loss().unwrap();
+            let image = storage.snapshot();
+            drop(sefs);
+            let synced = steps == 0 || checkpoints.contains(&steps);
+            if let Err(e) = check_storage(image, !synced) {
+                panic!("power loss after {} steps: {}", steps, e);
+            }
+        }
+    }
+    Ok(())
+}
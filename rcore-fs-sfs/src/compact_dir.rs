@@ -0,0 +1,281 @@
+//! Compact directory entries in SFS
+//!
+//! With FEATURE_COMPACT_DIRENT, an entry is a `CompactEntry` followed by its name, and the
+//! entries in a block are chained by `rec_len` like ext2. The position of an entry is its
+//! offset in the directory. An entry is removed by merging it into the previous one, or by
+//! freeing it in place if it is the first in the block, so the entries in use never move.
+//! Empty blocks at the end are truncated, but the first block with "." and ".." is kept.
+
+use crate::*;
+use core::mem::size_of;
+
+const HEADER_SIZE: usize = size_of::<CompactEntry>();
+
+/// Parse the entry at `offset` of a block of entries, return the header and the name,
+/// or why it is broken
+pub(crate) fn parse_entry(
+    buf: &[u8],
+    offset: usize,
+) -> Result<(CompactEntry, &[u8]), &'static str> {
+    if offset + HEADER_SIZE > BLKSIZE {
+        return Err("is beyond the end of the block");
+    }
+    let mut header = CompactEntry::default();
+    header
+        .as_buf_mut()
+        .copy_from_slice(&buf[offset..offset + HEADER_SIZE]);
+    let rec_len = header.rec_len as usize;
+    if rec_len < HEADER_SIZE || !rec_len.is_multiple_of(4) || offset + rec_len > BLKSIZE {
+        return Err("has a wrong length");
+    }
+    let name_end = offset + HEADER_SIZE + header.name_len as usize;
+    if name_end > offset + rec_len {
+        return Err("has a name longer than the entry");
+    }
+    Ok((header, &buf[offset + HEADER_SIZE..name_end]))
+}
+
+impl INodeImpl {
+    /// Read the entry at `pos` of the block `buf`, failing with `Corrupted` if it is broken
+    fn parse_entry<'b>(&self, buf: &'b [u8], pos: usize) -> vfs::Result<(CompactEntry, &'b [u8])> {
+        parse_entry(buf, pos % BLKSIZE).map_err(|reason| {
+            error!("entry {} of directory {} {}", pos, self.id, reason);
+            FsError::Corrupted
+        })
+    }
+    /// Convert an entry in use to `DiskEntry`
+    fn to_disk_entry(
+        &self,
+        pos: usize,
+        header: &CompactEntry,
+        name: &[u8],
+    ) -> vfs::Result<DiskEntry> {
+        match core::str::from_utf8(name) {
+            Ok(name) if !name.is_empty() && !name.contains('\0') => Ok(DiskEntry {
+                id: header.id,
+                name: Str256::from(name),
+            }),
+            _ => {
+                error!("entry {} of directory {} has a bad name", pos, self.id);
+                Err(FsError::Corrupted)
+            }
+        }
+    }
+    /// Write an entry of `rec_len` at `pos`
+    fn write_record(
+        &self,
+        pos: usize,
+        rec_len: usize,
+        direntry: &DiskEntry,
+        type_: FileType,
+    ) -> vfs::Result<()> {
+        let name = direntry.name.as_ref().as_bytes();
+        let header = CompactEntry {
+            id: direntry.id,
+            rec_len: rec_len as u16,
+            name_len: name.len() as u8,
+            type_: type_ as u8,
+        };
+        let mut buf = [0u8; CompactEntry::len_of(MAX_FNAME_LEN)];
+        buf[..HEADER_SIZE].copy_from_slice(header.as_buf());
+        buf[HEADER_SIZE..HEADER_SIZE + name.len()].copy_from_slice(name);
+        self._write_at(pos, &buf[..HEADER_SIZE + name.len()])?;
+        Ok(())
+    }
+    fn read_header(&self, pos: usize) -> vfs::Result<CompactEntry> {
+        let mut header = CompactEntry::default();
+        self._read_at(pos, header.as_buf_mut())?;
+        Ok(header)
+    }
+    fn write_header(&self, pos: usize, header: &CompactEntry) -> vfs::Result<()> {
+        self._write_at(pos, header.as_buf())?;
+        Ok(())
+    }
+
+    /// Init the block with '.' and '..'
+    pub(crate) fn compact_init(&self, parent: INodeId) -> vfs::Result<()> {
+        self._resize(BLKSIZE)?;
+        let dot = DiskEntry {
+            id: self.id as u32,
+            name: Str256::from("."),
+        };
+        self.write_record(0, COMPACT_DOTDOT, &dot, FileType::Dir)?;
+        let dotdot = DiskEntry {
+            id: parent as u32,
+            name: Str256::from(".."),
+        };
+        self.write_record(
+            COMPACT_DOTDOT,
+            BLKSIZE - COMPACT_DOTDOT,
+            &dotdot,
+            FileType::Dir,
+        )
+    }
+    /// Read the entry at `pos`, which is free if its id is 0
+    pub(crate) fn compact_read(&self, pos: usize) -> vfs::Result<DiskEntry> {
+        let mut buf = [0u8; BLKSIZE];
+        let begin = pos - pos % BLKSIZE;
+        let end = (pos + CompactEntry::len_of(MAX_FNAME_LEN)).min(begin + BLKSIZE);
+        self._read_at(pos, &mut buf[pos % BLKSIZE..end - begin])?;
+        let (header, name) = self.parse_entry(&buf, pos)?;
+        match header.id {
+            0 => Ok(DiskEntry::free()),
+            _ => self.to_disk_entry(pos, &header, name),
+        }
+    }
+    /// Overwrite the entry at `pos` in place, where the name must fit
+    pub(crate) fn compact_write(
+        &self,
+        pos: usize,
+        direntry: &DiskEntry,
+        type_: FileType,
+    ) -> vfs::Result<()> {
+        let rec_len = self.read_header(pos)?.rec_len as usize;
+        if rec_len < CompactEntry::len_of(direntry.name.as_ref().len()) {
+            error!("entry {} of directory {} is too short", pos, self.id);
+            return Err(FsError::Corrupted);
+        }
+        self.write_record(pos, rec_len, direntry, type_)
+    }
+    /// Whether a name of `name_len` bytes fits in the entry at `pos`
+    pub(crate) fn compact_fits(&self, pos: usize, name_len: usize) -> vfs::Result<bool> {
+        Ok(self.read_header(pos)?.rec_len as usize >= CompactEntry::len_of(name_len))
+    }
+    /// Visit the entries in use from position `from` in order, until `f` returns something
+    pub(crate) fn compact_scan<R>(
+        &self,
+        from: usize,
+        mut f: impl FnMut(DirEntryAt) -> vfs::Result<Option<R>>,
+    ) -> vfs::Result<Option<R>> {
+        let size = self.disk_inode.read().size as usize;
+        let mut buf = [0u8; BLKSIZE];
+        for block in from / BLKSIZE..size / BLKSIZE {
+            self._read_at(block * BLKSIZE, &mut buf)?;
+            let mut pos = block * BLKSIZE;
+            while pos < (block + 1) * BLKSIZE {
+                let (header, name) = self.parse_entry(&buf, pos)?;
+                let next = pos + header.rec_len as usize;
+                if header.id != 0 && pos >= from {
+                    // never a name index
+                    let type_ = FileType::from_raw(header.type_ as u16)
+                        .filter(|&type_| type_ != FileType::DirIndex);
+                    let type_ = type_.ok_or_else(|| {
+                        error!("entry {} of directory {} has a bad type", pos, self.id);
+                        FsError::Corrupted
+                    })?;
+                    let entry = DirEntryAt {
+                        pos,
+                        next,
+                        entry: self.to_disk_entry(pos, &header, name)?,
+                        type_: Some(type_),
+                    };
+                    if let Some(result) = f(entry)? {
+                        return Ok(Some(result));
+                    }
+                }
+                pos = next;
+            }
+        }
+        Ok(None)
+    }
+    /// Insert an entry into the first space large enough from block `from`,
+    /// or append a block if there is none. Return the position of the entry.
+    ///
+    /// `from` is advanced to the first block which may still have space for any name,
+    /// skipping those found full, but not those only too full for this name.
+    pub(crate) fn compact_insert(
+        &self,
+        direntry: &DiskEntry,
+        type_: FileType,
+        from: &mut usize,
+    ) -> vfs::Result<usize> {
+        let needed = CompactEntry::len_of(direntry.name.as_ref().len());
+        let shortest = CompactEntry::len_of(1);
+        let size = self.disk_inode.read().size as usize;
+        let mut buf = [0u8; BLKSIZE];
+        // the first block skipped with space for a shorter name
+        let mut open = None;
+        for block in *from..size / BLKSIZE {
+            self._read_at(block * BLKSIZE, &mut buf)?;
+            let mut pos = block * BLKSIZE;
+            while pos < (block + 1) * BLKSIZE {
+                let (mut header, _) = self.parse_entry(&buf, pos)?;
+                let rec_len = header.rec_len as usize;
+                let used = header.used_len();
+                if rec_len - used >= needed {
+                    if used != 0 {
+                        // split the unused space after the entry
+                        header.rec_len = used as u16;
+                        self.write_header(pos, &header)?;
+                    }
+                    self.write_record(pos + used, rec_len - used, direntry, type_)?;
+                    *from = open.unwrap_or(block);
+                    return Ok(pos + used);
+                }
+                if rec_len - used >= shortest && open.is_none() {
+                    open = Some(block);
+                }
+                pos += rec_len;
+            }
+        }
+        self._resize(size + BLKSIZE)?;
+        self.write_record(size, BLKSIZE, direntry, type_)?;
+        *from = open.unwrap_or(size / BLKSIZE);
+        Ok(size)
+    }
+    /// Remove the entry at `pos`, and truncate the empty blocks at the end
+    pub(crate) fn compact_remove(&self, pos: usize) -> vfs::Result<()> {
+        let mut buf = [0u8; BLKSIZE];
+        let begin = pos - pos % BLKSIZE;
+        self._read_at(begin, &mut buf)?;
+        let mut prev = None;
+        let mut cur = begin;
+        while cur < pos {
+            prev = Some(cur);
+            cur += self.parse_entry(&buf, cur)?.0.rec_len as usize;
+        }
+        if cur != pos {
+            error!("no entry {} in directory {}", pos, self.id);
+            return Err(FsError::Corrupted);
+        }
+        let (mut header, _) = self.parse_entry(&buf, pos)?;
+        match prev {
+            Some(prev) => {
+                let (mut prev_header, _) = self.parse_entry(&buf, prev)?;
+                prev_header.rec_len += header.rec_len;
+                self.write_header(prev, &prev_header)?;
+            }
+            None => {
+                header.id = 0;
+                self.write_header(pos, &header)?;
+            }
+        }
+
+        let size = self.disk_inode.read().size as usize;
+        let mut blocks = size / BLKSIZE;
+        while blocks > 1 {
+            let header = self.read_header((blocks - 1) * BLKSIZE)?;
+            if header.id != 0 || header.rec_len as usize != BLKSIZE {
+                break;
+            }
+            blocks -= 1;
+        }
+        if blocks * BLKSIZE < size {
+            self._resize(blocks * BLKSIZE)?;
+        }
+        Ok(())
+    }
+    /// Whether there are only '.' and '..'
+    pub(crate) fn compact_is_empty(&self) -> vfs::Result<bool> {
+        if self.disk_inode.read().size as usize > BLKSIZE {
+            // the last block is in use
+            return Ok(false);
+        }
+        let mut count = 0;
+        let more = self.compact_scan(0, |_| {
+            count += 1;
+            Ok(if count > 2 { Some(()) } else { None })
+        })?;
+        Ok(more.is_none())
+    }
+}
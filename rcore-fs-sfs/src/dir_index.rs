@@ -0,0 +1,253 @@
+//! Name index of large directories in SFS
+//!
+//! The entries of an indexed directory stay in the linear format, so that they are read
+//! in the same way. The directory refers to a hidden INode of type `DirIndex`, holding a
+//! `DirIndexHeader` and a hash table of the entries by their names, probed linearly.
+//! Free entries are linked from the header and reused without scanning, and indexed
+//! directories are not truncated when their entries are removed. With compact entries,
+//! the header refers to the first block which may have space instead.
+
+use crate::*;
+use core::mem::size_of;
+
+const HEADER_SIZE: usize = size_of::<DirIndexHeader>();
+const SLOT_SIZE: usize = size_of::<DirIndexSlot>();
+
+/// FNV-1a hash of a name
+pub(crate) fn name_hash(name: &[u8]) -> u32 {
+    name.iter().fold(0x811c_9dc5u32, |hash, &b| {
+        (hash ^ b as u32).wrapping_mul(0x0100_0193)
+    })
+}
+
+/// Size of an index with `slots` slots
+pub(crate) fn index_size(slots: usize) -> usize {
+    HEADER_SIZE + slots * SLOT_SIZE
+}
+
+/// Operations on a `DirIndex` INode
+impl INodeImpl {
+    pub(crate) fn index_header(&self) -> vfs::Result<DirIndexHeader> {
+        let mut header = DirIndexHeader { count: 0, free: 0 };
+        self._read_at(0, header.as_buf_mut())?;
+        Ok(header)
+    }
+    fn set_index_header(&self, header: &DirIndexHeader) -> vfs::Result<()> {
+        self._write_at(0, header.as_buf())?;
+        Ok(())
+    }
+    fn index_slots(&self) -> usize {
+        (self.disk_inode.read().size as usize - HEADER_SIZE) / SLOT_SIZE
+    }
+    fn index_slot(&self, i: usize) -> vfs::Result<DirIndexSlot> {
+        let mut slot = DirIndexSlot::default();
+        self._read_at(index_size(i), slot.as_buf_mut())?;
+        Ok(slot)
+    }
+    fn set_index_slot(&self, i: usize, slot: &DirIndexSlot) -> vfs::Result<()> {
+        self._write_at(index_size(i), slot.as_buf())?;
+        Ok(())
+    }
+}
+
+/// Operations on an indexed directory
+impl INodeImpl {
+    /// The name index of this directory, if any
+    pub(crate) fn dir_index(&self) -> vfs::Result<Option<Arc<INodeImpl>>> {
+        match self.disk_inode.read().index {
+            0 => Ok(None),
+            id => Ok(Some(self.fs.get_inode(id as INodeId)?)),
+        }
+    }
+    /// Find the entry `name` by `index`, return the INode and the entry
+    pub(crate) fn index_find(
+        &self,
+        index: &INodeImpl,
+        name: &str,
+    ) -> vfs::Result<Option<(INodeId, usize)>> {
+        let hash = name_hash(name.as_bytes());
+        let slots = index.index_slots();
+        for i in 0..slots {
+            let slot = index.index_slot((hash as usize + i) % slots)?;
+            if slot.entry == 0 {
+                break;
+            }
+            if slot.hash == hash {
+                let id = slot.entry as usize - 1;
+                let entry = self.read_direntry(id)?;
+                if !entry.is_free() && entry.name.as_ref() == name {
+                    return Ok(Some((entry.id as INodeId, id)));
+                }
+            }
+        }
+        Ok(None)
+    }
+    /// Put the entry `id` named `name` into an empty slot of `index`
+    pub(crate) fn index_put(&self, index: &INodeImpl, name: &str, id: usize) -> vfs::Result<()> {
+        let hash = name_hash(name.as_bytes());
+        let slots = index.index_slots();
+        for i in 0..slots {
+            let pos = (hash as usize + i) % slots;
+            if index.index_slot(pos)?.entry == 0 {
+                let slot = DirIndexSlot {
+                    hash,
+                    entry: id as u32 + 1,
+                };
+                return index.set_index_slot(pos, &slot);
+            }
+        }
+        error!("the name index {} is full", index.id);
+        Err(FsError::Corrupted)
+    }
+    /// Remove the entry `id` named `name` from `index`,
+    /// moving back the following slots which can not be found after the removed one
+    pub(crate) fn index_remove(&self, index: &INodeImpl, name: &str, id: usize) -> vfs::Result<()> {
+        let hash = name_hash(name.as_bytes());
+        let slots = index.index_slots();
+        let mut hole = None;
+        for i in 0..slots {
+            let pos = (hash as usize + i) % slots;
+            let slot = index.index_slot(pos)?;
+            if slot.entry == 0 {
+                break;
+            }
+            if slot.entry as usize == id + 1 {
+                hole = Some(pos);
+                break;
+            }
+        }
+        let mut hole = match hole {
+            Some(hole) => hole,
+            None => {
+                error!("entry {} of directory {} is not indexed", id, self.id);
+                return Err(FsError::Corrupted);
+            }
+        };
+        let mut pos = hole;
+        loop {
+            pos = (pos + 1) % slots;
+            let slot = index.index_slot(pos)?;
+            if slot.entry == 0 {
+                break;
+            }
+            // the distances from where it is probed
+            let home = slot.hash as usize % slots;
+            if (hole + slots - home) % slots < (pos + slots - home) % slots {
+                index.set_index_slot(hole, &slot)?;
+                hole = pos;
+            }
+        }
+        index.set_index_slot(hole, &DirIndexSlot::default())
+    }
+    /// Fill `index` of `slots` slots with `header` and the entries in use
+    fn fill_index(
+        &self,
+        index: &INodeImpl,
+        header: &DirIndexHeader,
+        slots: usize,
+    ) -> vfs::Result<()> {
+        index._resize(0)?;
+        index._resize(index_size(slots))?;
+        index.set_index_header(header)?;
+        self.scan_direntries(0, |found| {
+            self.index_put(index, found.entry.name.as_ref(), found.pos)?;
+            Ok(None::<()>)
+        })?;
+        Ok(())
+    }
+    /// Index this linear directory, linking its free entries
+    pub(crate) fn build_index(&self) -> vfs::Result<()> {
+        let index = self.fs.new_inode_dir_index()?;
+        let mut header = DirIndexHeader { count: 0, free: 0 };
+        if self.compact_dirents() {
+            self.scan_direntries(0, |_| {
+                header.count += 1;
+                Ok(None::<()>)
+            })?;
+        } else {
+            let dirent_count = self.disk_inode.read().size as usize / DIRENT_SIZE;
+            for id in (0..dirent_count).rev() {
+                if self.read_direntry(id)?.is_free() {
+                    self.write_free_linked(id, header.free)?;
+                    header.free = id as u32 + 1;
+                } else {
+                    header.count += 1;
+                }
+            }
+        }
+        let slots = (header.count as usize * 2)
+            .next_power_of_two()
+            .max(DIR_INDEX_MIN_SLOTS);
+        // freed by dropping if failed
+        self.fill_index(&index, &header, slots)?;
+        index.nlinks_inc();
+        self.disk_inode.write().index = index.id as u32;
+        Ok(())
+    }
+    /// Free the entry `id` in the fixed format, linked to the free entry `next` + 1
+    fn write_free_linked(&self, id: usize, next: u32) -> vfs::Result<()> {
+        self._write_at(DIRENT_SIZE * id, DiskEntry::free_linked(next).as_buf())?;
+        Ok(())
+    }
+    /// Take the first free entry in the fixed format, or append one if there is none
+    fn pop_free_entry(&self, header: &mut DirIndexHeader) -> vfs::Result<usize> {
+        let dirent_count = self.disk_inode.read().size as usize / DIRENT_SIZE;
+        match header.free as usize {
+            0 => {
+                self._resize((dirent_count + 1) * DIRENT_SIZE)?;
+                Ok(dirent_count)
+            }
+            free => {
+                let entry = self.read_direntry(free - 1)?;
+                if free > dirent_count || !entry.is_free() {
+                    error!("entry {} of directory {} is not free", free - 1, self.id);
+                    return Err(FsError::Corrupted);
+                }
+                header.free = entry.next_free();
+                Ok(free - 1)
+            }
+        }
+    }
+    /// Insert a direntry to an INode of `type_` into the first free entry of `index`,
+    /// or append it if there is none. The index doubles if more than half of it is used.
+    pub(crate) fn index_insert_direntry(
+        &self,
+        index: &INodeImpl,
+        direntry: &DiskEntry,
+        type_: FileType,
+    ) -> vfs::Result<()> {
+        let mut header = index.index_header()?;
+        let id = if self.compact_dirents() {
+            let mut free = header.free as usize;
+            let id = self.compact_insert(direntry, type_, &mut free)?;
+            header.free = free as u32;
+            id
+        } else {
+            let id = self.pop_free_entry(&mut header)?;
+            self.write_direntry(id, direntry, type_)?;
+            id
+        };
+        header.count += 1;
+        let slots = index.index_slots();
+        if header.count as usize * 2 > slots {
+            return self.fill_index(index, &header, slots * 2);
+        }
+        index.set_index_header(&header)?;
+        self.index_put(index, direntry.name.as_ref(), id)
+    }
+    /// Free the direntry `id` in `index`, and link it as the first free entry
+    pub(crate) fn index_remove_direntry(&self, index: &INodeImpl, id: usize) -> vfs::Result<()> {
+        let entry = self.read_direntry(id)?;
+        self.index_remove(index, entry.name.as_ref(), id)?;
+        let mut header = index.index_header()?;
+        if self.compact_dirents() {
+            self.compact_remove(id)?;
+            header.free = header.free.min((id / BLKSIZE) as u32);
+        } else {
+            self.write_free_linked(id, header.free)?;
+            header.free = id as u32 + 1;
+        }
+        header.count -= 1;
+        index.set_index_header(&header)
+    }
+}
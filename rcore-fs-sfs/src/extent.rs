@@ -0,0 +1,348 @@
+//! Extent trees of SFS files
+//!
+//! With FEATURE_EXTENTS, the block pointers of an INode hold the root of a tree of extents,
+//! each mapping a run of file blocks to contiguous disk blocks. Other nodes of the tree take
+//! a block each. An entry of an index node refers to the child mapping the file blocks from
+//! its `start` up to the next entry, except that the first child also maps those before.
+//! Nodes are split when they are full and freed when they are empty, but never merged.
+
+use crate::*;
+use core::mem::size_of;
+
+const HEADER_SIZE: usize = size_of::<ExtentHeader>();
+const ENTRY_SIZE: usize = size_of::<Extent>();
+
+/// Where a node of the extent tree is
+#[derive(Debug, Copy, Clone)]
+pub(crate) enum NodeAt {
+    /// in the INode
+    Root,
+    Block(BlockId),
+}
+
+impl NodeAt {
+    /// Max number of entries in the node
+    pub(crate) fn capacity(self) -> usize {
+        match self {
+            NodeAt::Root => EXTENT_ROOT_NENTRY,
+            NodeAt::Block(_) => EXTENT_BLOCK_NENTRY,
+        }
+    }
+}
+
+/// A node of the extent tree in memory
+#[derive(Debug, Default)]
+pub(crate) struct ExtentNode {
+    pub depth: usize,
+    pub entries: Vec<Extent>,
+}
+
+impl ExtentNode {
+    /// Parse a node with at most `capacity` entries, or return why it is broken
+    pub(crate) fn parse(buf: &[u8], capacity: usize) -> Result<Self, &'static str> {
+        let mut header = ExtentHeader::default();
+        header.as_buf_mut().copy_from_slice(&buf[..HEADER_SIZE]);
+        if header.count as usize > capacity {
+            return Err("has too many entries");
+        }
+        if header.depth as usize > MAX_EXTENT_DEPTH {
+            return Err("is too deep");
+        }
+        let entries = (0..header.count as usize)
+            .map(|i| {
+                let mut entry = Extent::default();
+                let offset = HEADER_SIZE + ENTRY_SIZE * i;
+                entry
+                    .as_buf_mut()
+                    .copy_from_slice(&buf[offset..offset + ENTRY_SIZE]);
+                entry
+            })
+            .collect();
+        Ok(ExtentNode {
+            depth: header.depth as usize,
+            entries,
+        })
+    }
+    /// Serialize the node into `buf`, return the length written
+    pub(crate) fn write_to(&self, buf: &mut [u8]) -> usize {
+        let header = ExtentHeader {
+            count: self.entries.len() as u16,
+            depth: self.depth as u16,
+        };
+        buf[..HEADER_SIZE].copy_from_slice(header.as_buf());
+        for (i, entry) in self.entries.iter().enumerate() {
+            let offset = HEADER_SIZE + ENTRY_SIZE * i;
+            buf[offset..offset + ENTRY_SIZE].copy_from_slice(entry.as_buf());
+        }
+        HEADER_SIZE + ENTRY_SIZE * self.entries.len()
+    }
+    /// Index of the child mapping file block `id` in an index node
+    fn child_of(&self, id: BlockId) -> usize {
+        self.entries
+            .partition_point(|entry| entry.start as usize <= id)
+            .saturating_sub(1)
+    }
+}
+
+impl INodeImpl {
+    pub(crate) fn load_node(&self, at: NodeAt) -> vfs::Result<ExtentNode> {
+        let result = match at {
+            NodeAt::Root => ExtentNode::parse(self.disk_inode.read().extent_root(), at.capacity()),
+            NodeAt::Block(block) => {
+                let mut buf = [0u8; BLKSIZE];
+                self.fs.read_meta(block, 0, &mut buf)?;
+                ExtentNode::parse(&buf, at.capacity())
+            }
+        };
+        result.map_err(|reason| {
+            error!("extent node {:?} of INode {} {}", at, self.id, reason);
+            FsError::Corrupted
+        })
+    }
+    fn store_node(&self, at: NodeAt, node: &ExtentNode) -> vfs::Result<()> {
+        match at {
+            NodeAt::Root => {
+                let mut disk_inode = self.disk_inode.write();
+                let root = disk_inode.extent_root_mut();
+                root.fill(0);
+                node.write_to(root);
+            }
+            NodeAt::Block(block) => {
+                // the whole block, which may be newly allocated
+                let mut buf = [0u8; BLKSIZE];
+                node.write_to(&mut buf);
+                self.fs.write_meta(block, 0, &buf)?;
+            }
+        }
+        Ok(())
+    }
+    /// Load the child `entry` of an index node at `depth`
+    pub(crate) fn load_child(&self, entry: &Extent, depth: usize) -> vfs::Result<ExtentNode> {
+        let child = self.load_node(NodeAt::Block(entry.block as BlockId))?;
+        if child.depth + 1 != depth {
+            error!(
+                "extent node {} of INode {} has a wrong depth",
+                entry.block, self.id
+            );
+            return Err(FsError::Corrupted);
+        }
+        Ok(child)
+    }
+
+    /// Map file block `id` by the extent tree. Return the disk block, 0 for a hole,
+    /// and the number of file blocks from `id` mapped in the same way.
+    pub(crate) fn extent_lookup(&self, id: BlockId) -> vfs::Result<(BlockId, usize)> {
+        let mut node = self.load_node(NodeAt::Root)?;
+        // the end of the file blocks mapped by the node
+        let mut end = usize::MAX;
+        while node.depth > 0 && !node.entries.is_empty() {
+            let i = node.child_of(id);
+            if let Some(next) = node.entries.get(i + 1) {
+                end = next.start as usize;
+            }
+            node = self.load_child(&node.entries[i], node.depth)?;
+        }
+        let i = node
+            .entries
+            .partition_point(|entry| entry.start as usize <= id);
+        if let Some(extent) = i.checked_sub(1).map(|i| &node.entries[i]) {
+            let extent_end = extent.start as usize + extent.len as usize;
+            if id < extent_end {
+                let block = extent.block as usize + id - extent.start as usize;
+                return Ok((block, extent_end - id));
+            }
+        }
+        let hole_end = node.entries.get(i).map_or(end, |next| next.start as usize);
+        Ok((0, hole_end.min(end) - id))
+    }
+    /// Map the hole at file blocks of `extent`, merged with the adjacent extents if contiguous
+    pub(crate) fn extent_insert(&self, extent: Extent) -> vfs::Result<()> {
+        let root = self.load_node(NodeAt::Root)?;
+        self.insert_into(NodeAt::Root, root, extent)?;
+        Ok(())
+    }
+    /// Insert `extent` into the subtree of `node` at `at`.
+    /// Return the entry of the new sibling if split.
+    fn insert_into(
+        &self,
+        at: NodeAt,
+        mut node: ExtentNode,
+        extent: Extent,
+    ) -> vfs::Result<Option<Extent>> {
+        if node.depth > 0 && !node.entries.is_empty() {
+            let i = node.child_of(extent.start as usize);
+            let entry = node.entries[i];
+            let child = self.load_child(&entry, node.depth)?;
+            match self.insert_into(NodeAt::Block(entry.block as BlockId), child, extent)? {
+                Some(sibling) => node.entries.insert(i + 1, sibling),
+                None => return Ok(None),
+            }
+            return self.store_split(at, node);
+        }
+        node.depth = 0;
+        let i = node
+            .entries
+            .partition_point(|entry| entry.start <= extent.start);
+        let adjacent =
+            |a: &Extent, b: &Extent| a.start + a.len == b.start && a.block + a.len == b.block;
+        if i > 0 && adjacent(&node.entries[i - 1], &extent) {
+            node.entries[i - 1].len += extent.len;
+            if i < node.entries.len() && adjacent(&node.entries[i - 1], &node.entries[i]) {
+                let next = node.entries.remove(i);
+                node.entries[i - 1].len += next.len;
+            }
+        } else if i < node.entries.len() && adjacent(&extent, &node.entries[i]) {
+            let next = &mut node.entries[i];
+            next.start = extent.start;
+            next.block = extent.block;
+            next.len += extent.len;
+        } else {
+            node.entries.insert(i, extent);
+        }
+        self.store_split(at, node)
+    }
+    /// Store `node` at `at`, splitting it if it is too large.
+    /// Return the entry of the new sibling if split.
+    fn store_split(&self, at: NodeAt, mut node: ExtentNode) -> vfs::Result<Option<Extent>> {
+        if node.entries.len() <= at.capacity() {
+            self.store_node(at, &node)?;
+            return Ok(None);
+        }
+        let block = self.fs.alloc_block()?;
+        match at {
+            NodeAt::Root => {
+                // the tree grows by moving the root into a block
+                self.store_node(NodeAt::Block(block), &node)?;
+                let root = ExtentNode {
+                    depth: node.depth + 1,
+                    entries: vec![Extent {
+                        start: node.entries[0].start,
+                        block: block as u32,
+                        len: 0,
+                    }],
+                };
+                self.store_node(NodeAt::Root, &root)?;
+                Ok(None)
+            }
+            NodeAt::Block(_) => {
+                let right = ExtentNode {
+                    depth: node.depth,
+                    entries: node.entries.split_off(node.entries.len() / 2),
+                };
+                // the entries moved are lost until the parent refers to the new block
+                self.store_node(NodeAt::Block(block), &right)?;
+                self.store_node(at, &node)?;
+                Ok(Some(Extent {
+                    start: right.entries[0].start,
+                    block: block as u32,
+                    len: 0,
+                }))
+            }
+        }
+    }
+    /// Unmap file blocks `begin..end`, return the disk blocks unmapped as `(block, len)` runs.
+    /// The nodes emptied are freed.
+    ///
+    /// If an extent is cut in the middle and its end can not be inserted again,
+    /// e.g. no block for splitting the leaf, the extent is restored and nothing is unmapped.
+    pub(crate) fn extent_remove(
+        &self,
+        begin: BlockId,
+        end: BlockId,
+    ) -> vfs::Result<Vec<(BlockId, usize)>> {
+        let mut removed = Vec::new();
+        // the end of an extent cut in the middle, which is inserted again
+        let mut rest = None;
+        let root = self.load_node(NodeAt::Root)?;
+        self.remove_from(NodeAt::Root, root, begin, end, &mut removed, &mut rest)?;
+        if let Some(rest) = rest {
+            if let Err(e) = self.extent_insert(rest) {
+                // only the middle of the extent is removed, map it again with the end,
+                // which is merged into the start left in the leaf without another entry
+                debug_assert_eq!(removed.len(), 1);
+                let (block, len) = removed[0];
+                self.extent_insert(Extent {
+                    start: begin as u32,
+                    block: block as u32,
+                    len: len as u32 + rest.len,
+                })?;
+                return Err(e);
+            }
+        }
+        Ok(removed)
+    }
+    /// Unmap file blocks `begin..end` in the subtree of `node` at `at`,
+    /// return whether it is empty
+    fn remove_from(
+        &self,
+        at: NodeAt,
+        mut node: ExtentNode,
+        begin: BlockId,
+        end: BlockId,
+        removed: &mut Vec<(BlockId, usize)>,
+        rest: &mut Option<Extent>,
+    ) -> vfs::Result<bool> {
+        let mut changed = false;
+        if node.depth > 0 {
+            // from the child mapping `begin` to the last one starting before `end`
+            let mut i = node.child_of(begin);
+            let mut first = true;
+            while i < node.entries.len() && (first || (node.entries[i].start as usize) < end) {
+                first = false;
+                let entry = node.entries[i];
+                let child = self.load_child(&entry, node.depth)?;
+                let block = entry.block as BlockId;
+                if self.remove_from(NodeAt::Block(block), child, begin, end, removed, rest)? {
+                    self.fs.free_block(block);
+                    node.entries.remove(i);
+                    changed = true;
+                } else {
+                    i += 1;
+                }
+            }
+        } else {
+            let mut kept = Vec::with_capacity(node.entries.len() + 1);
+            for extent in node.entries.drain(..) {
+                let start = extent.start as usize;
+                let extent_end = start + extent.len as usize;
+                if extent_end <= begin || end <= start {
+                    kept.push(extent);
+                    continue;
+                }
+                changed = true;
+                let (b, e) = (start.max(begin), extent_end.min(end));
+                removed.push((extent.block as usize + b - start, e - b));
+                if start < begin {
+                    kept.push(Extent {
+                        len: (begin - start) as u32,
+                        ..extent
+                    });
+                }
+                if end < extent_end {
+                    let tail = Extent {
+                        start: end as u32,
+                        block: (extent.block as usize + end - start) as u32,
+                        len: (extent_end - end) as u32,
+                    };
+                    match start < begin {
+                        // the leaf may be full
+                        true => *rest = Some(tail),
+                        false => kept.push(tail),
+                    }
+                }
+            }
+            node.entries = kept;
+        }
+        let empty = node.entries.is_empty();
+        if empty {
+            node.depth = 0;
+        }
+        match at {
+            // an empty block is freed by the parent
+            NodeAt::Block(_) if empty => {}
+            _ if changed => self.store_node(at, &node)?,
+            _ => {}
+        }
+        Ok(empty)
+    }
+}
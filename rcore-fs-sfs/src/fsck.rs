@@ -0,0 +1,1179 @@
+//! Offline checker of SFS images
+//!
+//! The tree is walked from `BLKN_ROOT` on the on-disk structures instead of `INodeImpl`,
+//! so that a corrupted image is reported rather than failing the walk. In repair mode,
+//! bad entries are removed, bad block pointers become holes, and the links, the free map,
+//! the refmap and the count of unused blocks are set as found. The blocks of removed
+//! or unreachable INodes are freed, there is no lost+found. A bad name index is removed,
+//! and its directory is read in the linear format. A broken compact entry is removed
+//! with the entries after it in its block, and a broken extent or extent node is removed.
+
+use crate::compact_dir::parse_entry;
+use crate::dir_index::{index_size, name_hash};
+use crate::extent::{ExtentNode, NodeAt};
+use crate::structs::*;
+use crate::SimpleFileSystem;
+use alloc::{
+    collections::{BTreeMap, BTreeSet},
+    sync::Arc,
+    vec,
+    vec::Vec,
+};
+use core::{
+    fmt,
+    mem::{offset_of, size_of},
+    ops::Range,
+};
+use rcore_fs::{
+    dev::Device,
+    util::uninit_memory,
+    vfs::{self, FileSystem, FsError},
+};
+
+/// A problem found in an image
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Problem {
+    /// The entry `index` of directory `dir` is invalid, which is removed by repair
+    BadEntry {
+        dir: INodeId,
+        index: usize,
+        reason: &'static str,
+    },
+    /// The entry "." or ".." of directory `dir` refers to `found` instead of `expected`
+    WrongDotEntry {
+        dir: INodeId,
+        name: &'static str,
+        expected: INodeId,
+        found: INodeId,
+    },
+    /// The size of INode `id` does not fit in its blocks
+    WrongSize { id: INodeId, size: u32, blocks: u32 },
+    /// INode `id` refers to `block`, which can not be used by it, and becomes a hole by repair
+    BadPointer {
+        id: INodeId,
+        block: BlockId,
+        reason: &'static str,
+    },
+    /// The links of INode `id` do not match the entries referring to it
+    WrongLinks {
+        id: INodeId,
+        nlinks: u16,
+        entries: usize,
+    },
+    /// A block in use is free in the free map
+    UsedButFree(BlockId),
+    /// A block not in use is not free in the free map
+    Leaked(BlockId),
+    /// The count of unused blocks in the super block is wrong
+    WrongUnusedBlocks { counted: u32, free: usize },
+    /// The references to a shared block are counted wrong in the refmap
+    WrongShares {
+        block: BlockId,
+        shares: u8,
+        references: usize,
+    },
+    /// The name index of directory `dir` does not match its entries, which is removed by repair
+    BadIndex { dir: INodeId, reason: &'static str },
+    /// An extent or an extent node of INode `id` is invalid, which is removed by repair
+    BadExtent { id: INodeId, reason: &'static str },
+    /// The compact entry at `index` of directory `dir` records the type `found` of its INode
+    WrongEntryType {
+        dir: INodeId,
+        index: usize,
+        found: u8,
+        expected: FileType,
+    },
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Problem::BadEntry { dir, index, reason } => {
+                write!(f, "entry {} of directory {} {}", index, dir, reason)
+            }
+            Problem::WrongDotEntry {
+                dir,
+                name,
+                expected,
+                found,
+            } => write!(
+                f,
+                "entry {:?} of directory {} refers to {} instead of {}",
+                name, dir, found, expected
+            ),
+            Problem::WrongSize { id, size, blocks } => {
+                write!(f, "INode {} has size {} in {} blocks", id, size, blocks)
+            }
+            Problem::BadPointer { id, block, reason } => {
+                write!(f, "INode {} refers to block {} {}", id, block, reason)
+            }
+            Problem::WrongLinks {
+                id,
+                nlinks,
+                entries,
+            } => write!(
+                f,
+                "INode {} has {} links, but {} entries",
+                id, nlinks, entries
+            ),
+            Problem::UsedButFree(block) => write!(f, "block {} is in use but free", block),
+            Problem::Leaked(block) => write!(f, "block {} is leaked", block),
+            Problem::WrongUnusedBlocks { counted, free } => write!(
+                f,
+                "{} unused blocks are counted, but {} are free",
+                counted, free
+            ),
+            Problem::WrongShares {
+                block,
+                shares,
+                references,
+            } => write!(
+                f,
+                "block {} is shared {} times in the refmap, but has {} references",
+                block, shares, references
+            ),
+            Problem::BadIndex { dir, reason } => {
+                write!(f, "the name index of directory {} {}", dir, reason)
+            }
+            Problem::BadExtent { id, reason } => {
+                write!(f, "an extent of INode {} {}", id, reason)
+            }
+            Problem::WrongEntryType {
+                dir,
+                index,
+                found,
+                expected,
+            } => write!(
+                f,
+                "entry {} of directory {} has type {} instead of {:?}",
+                index, dir, found, expected
+            ),
+        }
+    }
+}
+
+/// Check the SFS image on `device`, return the problems found, which are repaired if `repair`.
+///
+/// The journal is replayed before checking, in memory only unless `repair`.
+/// Fail with `Corrupted` if the image can not be checked at all, e.g. the root is not a directory.
+pub fn check(device: Arc<dyn Device>, repair: bool) -> vfs::Result<Vec<Problem>> {
+    let sfs = match repair {
+        true => SimpleFileSystem::open(device)?,
+        false => SimpleFileSystem::open_read_only(device)?,
+    };
+    let mut checker = Checker::new(&sfs, repair)?;
+    checker.check()?;
+    if repair {
+        sfs.sync()?;
+    }
+    Ok(checker.problems)
+}
+
+/// An INode found valid
+struct Found {
+    disk: DiskINode,
+    /// disk blocks of the content, only for directories and name indexes
+    map: Vec<BlockId>,
+    /// indirect blocks or extent nodes in use, only for directories and name indexes
+    indirects: Vec<BlockId>,
+    /// whether `disk` is changed by repair
+    dirty: bool,
+}
+
+struct Checker<'a> {
+    sfs: &'a SimpleFileSystem,
+    repair: bool,
+    problems: Vec<Problem>,
+    /// blocks which may be used by INodes and their contents
+    data: Range<BlockId>,
+    /// blocks of the super block, the free map, the refmap and the journal
+    reserved: Vec<Range<BlockId>>,
+    has_refmap: bool,
+    has_dir_index: bool,
+    /// whether directories consist of compact entries
+    compact: bool,
+    /// whether blocks are mapped by extent trees
+    extents: bool,
+    /// references to every block
+    references: Vec<usize>,
+    /// whether a block is used as metadata, which can not be shared
+    exclusive: Vec<bool>,
+    /// INodes loaded, or why they are invalid
+    inodes: BTreeMap<INodeId, Result<Found, &'static str>>,
+    /// the parents of the directories found
+    parents: BTreeMap<INodeId, INodeId>,
+    /// entries referring to every INode, counting "." and ".."
+    links: BTreeMap<INodeId, usize>,
+}
+
+impl<'a> Checker<'a> {
+    fn new(sfs: &'a SimpleFileSystem, repair: bool) -> vfs::Result<Self> {
+        let super_block = sfs.super_block.read();
+        let blocks = super_block.blocks as usize;
+        let has_refmap = super_block.has_refmap();
+        let mut maps = super_block.freemap_blocks as usize;
+        if has_refmap {
+            maps += super_block.refmap_blocks as usize;
+        }
+        let mut reserved = vec![
+            BLKN_SUPER..BLKN_SUPER + 1,
+            BLKN_FREEMAP..BLKN_FREEMAP + maps,
+        ];
+        if super_block.has_journal() {
+            let start = super_block.journal_start as usize;
+            reserved.push(start..start + super_block.journal_blocks as usize);
+        }
+        let data_start = reserved.iter().map(|range| range.end).max().unwrap();
+        if data_start > blocks
+            || sfs.free_map.read().len() < blocks
+            || (has_refmap && sfs.ref_map.read().len() < blocks)
+        {
+            error!("the layout is corrupted: {:?}", *super_block);
+            return Err(FsError::Corrupted);
+        }
+        Ok(Checker {
+            sfs,
+            repair,
+            problems: Vec::new(),
+            data: data_start..blocks,
+            reserved,
+            has_refmap,
+            has_dir_index: super_block.has_dir_index(),
+            compact: super_block.compact_dirents(),
+            extents: super_block.extents(),
+            references: vec![0; blocks],
+            exclusive: vec![false; blocks],
+            inodes: BTreeMap::new(),
+            parents: BTreeMap::new(),
+            links: BTreeMap::new(),
+        })
+    }
+
+    fn check(&mut self) -> vfs::Result<()> {
+        if let Err(reason) = self.load_inode(BLKN_ROOT)? {
+            error!("the root {}", reason);
+            return Err(FsError::Corrupted);
+        }
+        if self.inodes[&BLKN_ROOT].as_ref().unwrap().disk.type_ != FileType::Dir {
+            error!("the root INode is not a directory");
+            return Err(FsError::Corrupted);
+        }
+        self.parents.insert(BLKN_ROOT, BLKN_ROOT);
+        let mut dirs = vec![BLKN_ROOT];
+        while let Some(dir) = dirs.pop() {
+            self.check_dir(dir, &mut dirs)?;
+        }
+        self.check_links()?;
+        self.check_blocks();
+        Ok(())
+    }
+
+    /// Load and check INode `id` if not yet, return why it is invalid
+    fn load_inode(&mut self, id: INodeId) -> vfs::Result<Result<FileType, &'static str>> {
+        if !self.inodes.contains_key(&id) {
+            let found = self.check_inode(id)?;
+            self.inodes.insert(id, found);
+        }
+        Ok(match &self.inodes[&id] {
+            Ok(found) => Ok(found.disk.type_),
+            Err(reason) => Err(reason),
+        })
+    }
+
+    fn check_inode(&mut self, id: INodeId) -> vfs::Result<Result<Found, &'static str>> {
+        if id != BLKN_ROOT && !self.data.contains(&id) {
+            return Ok(Err("refers to a block out of range"));
+        }
+        if self.references[id] != 0 {
+            return Ok(Err("refers to a block in use"));
+        }
+        let mut buf = [0u8; size_of::<DiskINode>()];
+        self.sfs.read_meta(id, 0, &mut buf)?;
+        let type_offset = offset_of!(DiskINode, type_);
+        let type_ = u16::from_ne_bytes([buf[type_offset], buf[type_offset + 1]]);
+        let last_type = match self.has_dir_index {
+            true => FileType::DirIndex,
+            false => FileType::BlockDevice,
+        };
+        if !(FileType::File as u16..=last_type as u16).contains(&type_) {
+            return Ok(Err("refers to an invalid INode"));
+        }
+        let mut disk: DiskINode = unsafe { uninit_memory() };
+        disk.as_buf_mut().copy_from_slice(&buf);
+        if !self.has_dir_index || disk.type_ != FileType::Dir {
+            disk.index = 0;
+        }
+        // size unit of directories, and the size of "." and ".."
+        let (dir_unit, dots_size) = match self.compact {
+            true => (BLKSIZE, BLKSIZE),
+            false => (DIRENT_SIZE, DIRENT_SIZE * 2),
+        };
+        // "." and ".." are in the first block, which must be valid to repair them
+        let no_dots = "refers to a directory without \".\" and \"..\"";
+        if disk.type_ == FileType::Dir {
+            // the first block of an extent tree is checked with the tree
+            let first = disk.direct[0] as usize;
+            if (disk.size as usize) < dots_size
+                || disk.blocks == 0
+                || (!self.extents && (!self.data.contains(&first) || self.references[first] != 0))
+            {
+                return Ok(Err(no_dots));
+            }
+        }
+        self.references[id] = 1;
+        self.exclusive[id] = true;
+
+        let mut dirty = false;
+        let (size, blocks) = (disk.size as usize, disk.blocks as usize);
+        let max_size = blocks.min(MAX_NBLOCK_DOUBLE_INDIRECT) * BLKSIZE;
+        if blocks > MAX_NBLOCK_DOUBLE_INDIRECT
+            || size > max_size
+            || (disk.type_ == FileType::Dir && !size.is_multiple_of(dir_unit))
+        {
+            self.problems.push(Problem::WrongSize {
+                id,
+                size: disk.size,
+                blocks: disk.blocks,
+            });
+            let mut size = size.min(max_size);
+            if disk.type_ == FileType::Dir {
+                size -= size % dir_unit;
+            }
+            disk.blocks = blocks.min(MAX_NBLOCK_DOUBLE_INDIRECT) as u32;
+            disk.size = size as u32;
+            dirty = true;
+        }
+        let (map, indirects) = match self.extents {
+            true => self.check_extents(id, &mut disk, &mut dirty)?,
+            false => self.check_pointers(id, &mut disk, &mut dirty)?,
+        };
+        if disk.type_ == FileType::Dir && map[0] == 0 {
+            // not in use any more
+            for &block in map.iter().chain(&indirects).chain(&[id]) {
+                if block != 0 {
+                    self.references[block] -= 1;
+                }
+            }
+            return Ok(Err(no_dots));
+        }
+        Ok(Ok(Found {
+            disk,
+            map,
+            indirects,
+            dirty,
+        }))
+    }
+
+    /// Why `block` can not be referenced, if it can not
+    fn unusable(&self, block: BlockId, shareable: bool) -> Option<&'static str> {
+        let references = self.references.get(block).copied().unwrap_or(0);
+        if !self.data.contains(&block) {
+            Some("out of range")
+        } else if references != 0
+            && (!shareable
+                || self.exclusive[block]
+                || !self.has_refmap
+                || references > MAX_BLOCK_SHARES as usize)
+        {
+            Some("already in use")
+        } else {
+            None
+        }
+    }
+
+    /// Take a reference of INode `id` to `block`, return whether it is valid
+    fn claim(&mut self, id: INodeId, block: BlockId, shareable: bool) -> bool {
+        self.claim_run(id, block, 1, shareable)
+    }
+
+    /// Take a reference of INode `id` to blocks `block..block + len` if all of them are valid,
+    /// return whether they are
+    fn claim_run(&mut self, id: INodeId, block: BlockId, len: usize, shareable: bool) -> bool {
+        let bad =
+            (block..block + len).find_map(|block| Some((block, self.unusable(block, shareable)?)));
+        if let Some((block, reason)) = bad {
+            self.problems
+                .push(Problem::BadPointer { id, block, reason });
+            return false;
+        }
+        for block in block..block + len {
+            self.references[block] += 1;
+            self.exclusive[block] = !shareable;
+        }
+        true
+    }
+
+    /// Check the block pointers of INode `id`.
+    /// Return the blocks of the content and the indirect blocks of a directory or a name index.
+    fn check_pointers(
+        &mut self,
+        id: INodeId,
+        disk: &mut DiskINode,
+        dirty: &mut bool,
+    ) -> vfs::Result<(Vec<BlockId>, Vec<BlockId>)> {
+        let is_meta = matches!(disk.type_, FileType::Dir | FileType::DirIndex);
+        // only the data of files can be shared
+        let shareable = !is_meta;
+        let blocks = disk.blocks as usize;
+        let mut map = match is_meta {
+            true => vec![0; blocks],
+            false => Vec::new(),
+        };
+        let mut indirects = Vec::new();
+        for (i, direct) in disk.direct.iter_mut().enumerate().take(blocks) {
+            if *direct != 0 && !self.claim(id, *direct as usize, shareable) {
+                *direct = 0;
+                *dirty = true;
+            }
+            if is_meta {
+                map[i] = *direct as usize;
+            }
+        }
+        let indirect = disk.indirect as usize;
+        if indirect != 0 {
+            if self.claim(id, indirect, false) {
+                indirects.push(indirect);
+                let range = NDIRECT..blocks.clamp(NDIRECT, MAX_NBLOCK_INDIRECT);
+                self.check_indirect(id, indirect, range, shareable, &mut map)?;
+            } else {
+                disk.indirect = 0;
+                *dirty = true;
+            }
+        }
+        let db_indirect = disk.db_indirect as usize;
+        if db_indirect != 0 {
+            if self.claim(id, db_indirect, false) {
+                indirects.push(db_indirect);
+                let mut entries = IndirectBlock {
+                    entries: [0; BLK_NENTRY],
+                };
+                self.sfs.read_meta(db_indirect, 0, entries.as_buf_mut())?;
+                for (i, &indirect) in entries.entries.iter().enumerate() {
+                    let indirect = indirect as usize;
+                    if indirect == 0 {
+                        continue;
+                    }
+                    if !self.claim(id, indirect, false) {
+                        self.clear_entry(db_indirect, i)?;
+                        continue;
+                    }
+                    indirects.push(indirect);
+                    let begin = MAX_NBLOCK_INDIRECT + i * BLK_NENTRY;
+                    let end = (begin + BLK_NENTRY).min(blocks).max(begin);
+                    self.check_indirect(id, indirect, begin..end, shareable, &mut map)?;
+                }
+            } else {
+                disk.db_indirect = 0;
+                *dirty = true;
+            }
+        }
+        if !is_meta {
+            indirects.clear();
+        }
+        Ok((map, indirects))
+    }
+
+    /// Check the extent tree of INode `id`.
+    /// Return the blocks of the content and the extent nodes of a directory or a name index.
+    fn check_extents(
+        &mut self,
+        id: INodeId,
+        disk: &mut DiskINode,
+        dirty: &mut bool,
+    ) -> vfs::Result<(Vec<BlockId>, Vec<BlockId>)> {
+        let is_meta = matches!(disk.type_, FileType::Dir | FileType::DirIndex);
+        let blocks = disk.blocks as usize;
+        let mut map = match is_meta {
+            true => vec![0; blocks],
+            false => Vec::new(),
+        };
+        let mut nodes = Vec::new();
+        let mut root = match ExtentNode::parse(disk.extent_root(), NodeAt::Root.capacity()) {
+            Ok(root) => root,
+            Err(reason) => {
+                self.problems.push(Problem::BadExtent { id, reason });
+                // all blocks are lost
+                disk.extent_root_mut().fill(0);
+                *dirty = true;
+                return Ok((map, nodes));
+            }
+        };
+        if self.check_extent_node(id, &mut root, 0..blocks, !is_meta, &mut map, &mut nodes)? {
+            let root_buf = disk.extent_root_mut();
+            root_buf.fill(0);
+            root.write_to(root_buf);
+            *dirty = true;
+        }
+        if !is_meta {
+            nodes.clear();
+        }
+        Ok((map, nodes))
+    }
+
+    /// Check the entries of an extent `node` of INode `id` mapping file blocks in `range`.
+    /// Return whether it is changed by removing bad entries.
+    fn check_extent_node(
+        &mut self,
+        id: INodeId,
+        node: &mut ExtentNode,
+        range: Range<BlockId>,
+        shareable: bool,
+        map: &mut [BlockId],
+        nodes: &mut Vec<BlockId>,
+    ) -> vfs::Result<bool> {
+        let mut changed = false;
+        let mut kept = Vec::with_capacity(node.entries.len());
+        // the end of the last extent, or the start of the last child
+        let mut last = range.start;
+        let entries = core::mem::take(&mut node.entries);
+        for (i, entry) in entries.iter().enumerate() {
+            let (start, block, len) = (
+                entry.start as usize,
+                entry.block as usize,
+                entry.len as usize,
+            );
+            if node.depth == 0 {
+                let reason = if len == 0 {
+                    Some("is empty")
+                } else if start < last {
+                    Some("overlaps the previous one")
+                } else if start + len > range.end {
+                    Some("is beyond the end")
+                } else {
+                    None
+                };
+                if let Some(reason) = reason {
+                    self.problems.push(Problem::BadExtent { id, reason });
+                } else if self.claim_run(id, block, len, shareable) {
+                    if let Some(map) = map.get_mut(start..start + len) {
+                        for (j, entry) in map.iter_mut().enumerate() {
+                            *entry = block + j;
+                        }
+                    }
+                    last = start + len;
+                    kept.push(*entry);
+                    continue;
+                }
+                changed = true;
+                continue;
+            }
+            // the first child also maps the blocks before its start
+            if i != 0 && (start <= last || start >= range.end) {
+                self.problems.push(Problem::BadExtent {
+                    id,
+                    reason: "refers to a node out of order",
+                });
+                changed = true;
+                continue;
+            }
+            if !self.claim(id, block, false) {
+                changed = true;
+                continue;
+            }
+            let mut buf = [0u8; BLKSIZE];
+            self.sfs.read_meta(block, 0, &mut buf)?;
+            let reason = match ExtentNode::parse(&buf, NodeAt::Block(block).capacity()) {
+                Err(reason) => Some(reason),
+                Ok(child) if child.depth + 1 != node.depth => {
+                    Some("refers to a node of a wrong depth")
+                }
+                Ok(child) if child.entries.is_empty() => Some("refers to an empty node"),
+                Ok(mut child) => {
+                    let begin = if i == 0 { range.start } else { start };
+                    let end = entries.get(i + 1).map_or(range.end, |next| {
+                        (next.start as usize).clamp(begin, range.end)
+                    });
+                    if self.check_extent_node(id, &mut child, begin..end, shareable, map, nodes)?
+                        && self.repair
+                        && !child.entries.is_empty()
+                    {
+                        let len = child.write_to(&mut buf);
+                        self.sfs.write_meta(block, 0, &buf[..len])?;
+                    }
+                    if !child.entries.is_empty() {
+                        nodes.push(block);
+                        last = start.max(last);
+                        kept.push(*entry);
+                        continue;
+                    }
+                    // all of its extents are removed
+                    None
+                }
+            };
+            if let Some(reason) = reason {
+                self.problems.push(Problem::BadExtent { id, reason });
+            }
+            // not in use any more
+            self.references[block] -= 1;
+            changed = true;
+        }
+        node.entries = kept;
+        if node.entries.is_empty() {
+            node.depth = 0;
+        }
+        Ok(changed)
+    }
+
+    /// Check the pointers to file blocks `range` in the indirect block `indirect`
+    fn check_indirect(
+        &mut self,
+        id: INodeId,
+        indirect: BlockId,
+        range: Range<BlockId>,
+        shareable: bool,
+        map: &mut [BlockId],
+    ) -> vfs::Result<()> {
+        let mut entries = IndirectBlock {
+            entries: [0; BLK_NENTRY],
+        };
+        self.sfs.read_meta(indirect, 0, entries.as_buf_mut())?;
+        for (i, file_block) in range.enumerate() {
+            let block = entries.entries[i] as usize;
+            if block == 0 {
+                continue;
+            }
+            if !self.claim(id, block, shareable) {
+                self.clear_entry(indirect, i)?;
+            } else if let Some(entry) = map.get_mut(file_block) {
+                *entry = block;
+            }
+        }
+        Ok(())
+    }
+
+    /// Clear entry `index` of the indirect block `block` by repair
+    fn clear_entry(&self, block: BlockId, index: usize) -> vfs::Result<()> {
+        if self.repair {
+            self.sfs
+                .write_meta(block, ENTRY_SIZE * index, 0u32.as_buf())?;
+        }
+        Ok(())
+    }
+
+    /// Read or write entry `index` of a directory with content blocks `map`
+    fn io_entry(
+        &self,
+        map: &[BlockId],
+        index: usize,
+        buf: &mut [u8],
+        write: bool,
+    ) -> vfs::Result<()> {
+        self.io_at(map, index * DIRENT_SIZE, &mut buf[..DIRENT_SIZE], write)
+    }
+
+    /// Read or write the content with blocks `map` at `offset`.
+    /// Holes are read as zeros, and only zeros are written to them, which are skipped.
+    fn io_at(
+        &self,
+        map: &[BlockId],
+        offset: usize,
+        buf: &mut [u8],
+        write: bool,
+    ) -> vfs::Result<()> {
+        let mut pos = 0;
+        while pos < buf.len() {
+            let offset = offset + pos;
+            let len = (BLKSIZE - offset % BLKSIZE).min(buf.len() - pos);
+            let part = &mut buf[pos..pos + len];
+            match map.get(offset / BLKSIZE).copied().unwrap_or(0) {
+                0 if write => {}
+                0 => part.fill(0),
+                block if write => self.sfs.write_meta(block, offset % BLKSIZE, part)?,
+                block => self.sfs.read_meta(block, offset % BLKSIZE, part)?,
+            }
+            pos += len;
+        }
+        Ok(())
+    }
+
+    /// Check the entries of directory `dir`, push the directories found into `dirs`
+    fn check_dir(&mut self, dir: INodeId, dirs: &mut Vec<INodeId>) -> vfs::Result<()> {
+        let index_id = match &self.inodes[&dir] {
+            Ok(found) => found.disk.index as INodeId,
+            Err(_) => unreachable!("only valid directories are walked"),
+        };
+        // the hashes of the names of valid entries by their positions
+        let mut used = BTreeMap::new();
+        let free = match self.compact {
+            true => {
+                self.check_compact_entries(dir, dirs, &mut used)?;
+                None
+            }
+            false => Some(self.check_fixed_entries(dir, dirs, &mut used)?),
+        };
+        if index_id != 0 {
+            if let Err(reason) = self.check_index(index_id, &used, free.as_ref())? {
+                self.problems.push(Problem::BadIndex { dir, reason });
+                if let Ok(found) = self.inodes.get_mut(&dir).unwrap() {
+                    found.disk.index = 0;
+                    found.dirty = true;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Check the entries of directory `dir` in the fixed format, return the links of free entries
+    fn check_fixed_entries(
+        &mut self,
+        dir: INodeId,
+        dirs: &mut Vec<INodeId>,
+        used: &mut BTreeMap<usize, u32>,
+    ) -> vfs::Result<BTreeMap<usize, usize>> {
+        let (map, size) = match &self.inodes[&dir] {
+            Ok(found) => (found.map.clone(), found.disk.size as usize),
+            Err(_) => unreachable!("only valid directories are walked"),
+        };
+        let mut names = BTreeSet::new();
+        let mut free = BTreeMap::new();
+        for index in 0..size / DIRENT_SIZE {
+            let mut buf = [0u8; DIRENT_SIZE];
+            self.io_entry(&map, index, &mut buf, false)?;
+            let id = u32::from_ne_bytes([buf[0], buf[1], buf[2], buf[3]]) as INodeId;
+            let name = &buf[ENTRY_SIZE..];
+            let name = &name[..name.iter().position(|&b| b == 0).unwrap_or(name.len())];
+
+            if index < 2 {
+                let (dot, expected) = match index {
+                    0 => (".", dir),
+                    _ => ("..", self.parents[&dir]),
+                };
+                if id != expected || name != dot.as_bytes() {
+                    self.problems.push(Problem::WrongDotEntry {
+                        dir,
+                        name: dot,
+                        expected,
+                        found: id,
+                    });
+                    if self.repair {
+                        let mut entry = DiskEntry {
+                            id: expected as u32,
+                            name: Str256::from(dot),
+                        };
+                        self.io_entry(&map, index, entry.as_buf_mut(), true)?;
+                    }
+                }
+                *self.links.entry(expected).or_insert(0) += 1;
+                used.insert(index, name_hash(dot.as_bytes()));
+                continue;
+            }
+            if id == 0 {
+                // a free entry
+                let next = &buf[ENTRY_SIZE..ENTRY_SIZE + 4];
+                let next = u32::from_ne_bytes([next[0], next[1], next[2], next[3]]);
+                free.insert(index, next as usize);
+                continue;
+            }
+            match self.check_entry(dir, id, name, &mut names, dirs)? {
+                Ok(_) => {
+                    used.insert(index, name_hash(name));
+                }
+                Err(reason) => {
+                    self.problems.push(Problem::BadEntry { dir, index, reason });
+                    if self.repair {
+                        self.io_entry(&map, index, &mut [0; DIRENT_SIZE], true)?;
+                    }
+                }
+            }
+        }
+        Ok(free)
+    }
+
+    /// Check the compact entries of directory `dir`.
+    /// A broken entry is removed with the entries after it in the block,
+    /// and the first block is rebuilt by repair if "." or ".." is broken.
+    fn check_compact_entries(
+        &mut self,
+        dir: INodeId,
+        dirs: &mut Vec<INodeId>,
+        used: &mut BTreeMap<usize, u32>,
+    ) -> vfs::Result<()> {
+        let (map, size) = match &self.inodes[&dir] {
+            Ok(found) => (found.map.clone(), found.disk.size as usize),
+            Err(_) => unreachable!("only valid directories are walked"),
+        };
+        let parent = self.parents[&dir];
+        let mut names = BTreeSet::new();
+        let mut buf = [0u8; BLKSIZE];
+        for block in 0..size / BLKSIZE {
+            let begin = block * BLKSIZE;
+            if map.get(block).copied().unwrap_or(0) == 0 {
+                // can not be written, the directory ends before it
+                self.problems.push(Problem::BadEntry {
+                    dir,
+                    index: begin,
+                    reason: "is in a hole",
+                });
+                if let Ok(found) = self.inodes.get_mut(&dir).unwrap() {
+                    found.disk.size = begin as u32;
+                    found.dirty = true;
+                }
+                break;
+            }
+            self.io_at(&map, begin, &mut buf, false)?;
+            let mut changed = false;
+            let mut prev = None;
+            let mut offset = 0;
+            if block == 0 {
+                changed |= self.check_compact_dots(dir, parent, &mut buf);
+                *self.links.entry(dir).or_insert(0) += 1;
+                *self.links.entry(parent).or_insert(0) += 1;
+                used.insert(0, name_hash(b"."));
+                used.insert(COMPACT_DOTDOT, name_hash(b".."));
+                prev = Some(COMPACT_DOTDOT);
+                offset =
+                    COMPACT_DOTDOT + parse_entry(&buf, COMPACT_DOTDOT).unwrap().0.rec_len as usize;
+            }
+            while offset < BLKSIZE {
+                let index = begin + offset;
+                let mut header = match parse_entry(&buf, offset) {
+                    Ok((header, _)) => header,
+                    Err(reason) => {
+                        self.problems.push(Problem::BadEntry { dir, index, reason });
+                        // the rest of the block becomes unused
+                        let header = match prev {
+                            Some(prev) => CompactEntry {
+                                rec_len: (BLKSIZE - prev) as u16,
+                                ..parse_entry(&buf, prev).unwrap().0
+                            },
+                            None => CompactEntry {
+                                rec_len: BLKSIZE as u16,
+                                ..CompactEntry::default()
+                            },
+                        };
+                        put_compact(&mut buf, prev.unwrap_or(offset), &header);
+                        changed = true;
+                        break;
+                    }
+                };
+                let rec_len = header.rec_len as usize;
+                if header.id != 0 {
+                    let name_end = offset + size_of::<CompactEntry>() + header.name_len as usize;
+                    let name = buf[offset + size_of::<CompactEntry>()..name_end].to_vec();
+                    let result = match name.contains(&0) {
+                        true => Err("has a name with '\\0'"),
+                        false => {
+                            self.check_entry(dir, header.id as INodeId, &name, &mut names, dirs)?
+                        }
+                    };
+                    match result {
+                        Ok(type_) => {
+                            used.insert(index, name_hash(&name));
+                            if header.type_ != type_ as u8 {
+                                self.problems.push(Problem::WrongEntryType {
+                                    dir,
+                                    index,
+                                    found: header.type_,
+                                    expected: type_,
+                                });
+                                header.type_ = type_ as u8;
+                                put_compact(&mut buf, offset, &header);
+                                changed = true;
+                            }
+                        }
+                        Err(reason) => {
+                            self.problems.push(Problem::BadEntry { dir, index, reason });
+                            // merged into the previous entry, or freed if it is the first
+                            match prev {
+                                Some(prev) => {
+                                    let mut prev_header = parse_entry(&buf, prev).unwrap().0;
+                                    prev_header.rec_len += header.rec_len;
+                                    put_compact(&mut buf, prev, &prev_header);
+                                    offset += rec_len;
+                                    changed = true;
+                                    continue;
+                                }
+                                None => {
+                                    header.id = 0;
+                                    put_compact(&mut buf, offset, &header);
+                                    changed = true;
+                                }
+                            }
+                        }
+                    }
+                }
+                prev = Some(offset);
+                offset += rec_len;
+            }
+            if changed && self.repair {
+                self.io_at(&map, begin, &mut buf, true)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Check "." and ".." in the first block `buf` of directory `dir` with compact entries,
+    /// return whether `buf` is changed to repair them
+    fn check_compact_dots(&mut self, dir: INodeId, parent: INodeId, buf: &mut [u8]) -> bool {
+        let dot = match parse_entry(buf, 0) {
+            Ok((header, name)) if header.rec_len as usize == COMPACT_DOTDOT && name == b"." => {
+                Some(header.id as INodeId)
+            }
+            _ => None,
+        };
+        let dotdot = match parse_entry(buf, COMPACT_DOTDOT) {
+            Ok((header, name)) if dot.is_some() && name == b".." => Some(header.id as INodeId),
+            _ => None,
+        };
+        let mut changed = false;
+        for (pos, name, expected, found) in
+            [(0, ".", dir, dot), (COMPACT_DOTDOT, "..", parent, dotdot)]
+        {
+            if found == Some(expected) {
+                continue;
+            }
+            self.problems.push(Problem::WrongDotEntry {
+                dir,
+                name,
+                expected,
+                found: found.unwrap_or(0),
+            });
+            if found.is_some() {
+                // only the id is wrong
+                buf[pos..pos + 4].copy_from_slice(&(expected as u32).to_ne_bytes());
+                changed = true;
+            }
+        }
+        if dotdot.is_none() {
+            // the other entries in the block are lost
+            let dot = CompactEntry {
+                id: dir as u32,
+                rec_len: COMPACT_DOTDOT as u16,
+                name_len: 1,
+                type_: FileType::Dir as u8,
+            };
+            let dotdot = CompactEntry {
+                id: parent as u32,
+                rec_len: (BLKSIZE - COMPACT_DOTDOT) as u16,
+                name_len: 2,
+                type_: FileType::Dir as u8,
+            };
+            buf.fill(0);
+            put_compact(buf, 0, &dot);
+            buf[size_of::<CompactEntry>()] = b'.';
+            put_compact(buf, COMPACT_DOTDOT, &dotdot);
+            let name = COMPACT_DOTDOT + size_of::<CompactEntry>();
+            buf[name..name + 2].copy_from_slice(b"..");
+            changed = true;
+        }
+        changed
+    }
+
+    /// Check the entry `name` of directory `dir` referring to INode `id`,
+    /// and count its link if it is valid. Return the type of the INode, or why it is invalid.
+    fn check_entry(
+        &mut self,
+        dir: INodeId,
+        id: INodeId,
+        name: &[u8],
+        names: &mut BTreeSet<Vec<u8>>,
+        dirs: &mut Vec<INodeId>,
+    ) -> vfs::Result<Result<FileType, &'static str>> {
+        let reason = if name.len() == MAX_FNAME_LEN + 1 {
+            "has no end of the name"
+        } else if name.is_empty() {
+            "has an empty name"
+        } else if core::str::from_utf8(name).is_err() {
+            "has a name not in UTF-8"
+        } else if name.contains(&b'/') {
+            "has a name with '/'"
+        } else if name == b"." || name == b".." {
+            "is \".\" or \"..\" again"
+        } else if !names.insert(name.to_vec()) {
+            "has a name used by another entry"
+        } else {
+            match self.load_inode(id)? {
+                Err(reason) => reason,
+                Ok(FileType::Dir) if self.parents.contains_key(&id) => {
+                    "is a hard link to a directory"
+                }
+                Ok(FileType::DirIndex) => "refers to a name index",
+                Ok(type_) => {
+                    if type_ == FileType::Dir {
+                        self.parents.insert(id, dir);
+                        dirs.push(id);
+                    }
+                    *self.links.entry(id).or_insert(0) += 1;
+                    return Ok(Ok(type_));
+                }
+            }
+        };
+        Ok(Err(reason))
+    }
+
+    /// Check the name index `id` with the `used` entries and the `free` entries
+    /// of its directory, which are not linked with compact entries.
+    /// Return why it is invalid, and release it if it is a name index.
+    fn check_index(
+        &mut self,
+        id: INodeId,
+        used: &BTreeMap<usize, u32>,
+        free: Option<&BTreeMap<usize, usize>>,
+    ) -> vfs::Result<Result<(), &'static str>> {
+        match self.load_inode(id)? {
+            Ok(FileType::DirIndex) => {}
+            Ok(_) => return Ok(Err("refers to an INode which is not a name index")),
+            Err(reason) => return Ok(Err(reason)),
+        }
+        if self.links.contains_key(&id) {
+            return Ok(Err("is shared with another directory"));
+        }
+        let result = self.check_index_content(id, used, free)?;
+        match result {
+            Ok(()) => {
+                self.links.insert(id, 1);
+            }
+            Err(_) => {
+                // not in use any more
+                let found = self.inodes.insert(id, Err("refers to a bad name index"));
+                let found = found.unwrap().unwrap();
+                for &block in found.map.iter().chain(&found.indirects).chain(&[id]) {
+                    if block != 0 {
+                        self.references[block] -= 1;
+                    }
+                }
+            }
+        }
+        Ok(result)
+    }
+
+    /// Check the header and the slots of the name index `id`
+    fn check_index_content(
+        &self,
+        id: INodeId,
+        used: &BTreeMap<usize, u32>,
+        free: Option<&BTreeMap<usize, usize>>,
+    ) -> vfs::Result<Result<(), &'static str>> {
+        let found = self.inodes[&id].as_ref().unwrap();
+        let size = found.disk.size as usize;
+        let slots = size.saturating_sub(index_size(0)) / size_of::<DirIndexSlot>();
+        if size != index_size(slots) || !slots.is_power_of_two() || used.len() * 2 > slots {
+            return Ok(Err("has a wrong size"));
+        }
+        let mut header = DirIndexHeader { count: 0, free: 0 };
+        self.io_at(&found.map, 0, header.as_buf_mut(), false)?;
+        if header.count as usize != used.len() {
+            return Ok(Err("has a wrong count of entries"));
+        }
+        let mut next = match free {
+            Some(_) => header.free as usize,
+            None => 0,
+        };
+        let mut visited = BTreeSet::new();
+        while next != 0 {
+            if !visited.insert(next - 1) {
+                return Ok(Err("has a cycle of free entries"));
+            }
+            next = match free.unwrap().get(&(next - 1)) {
+                Some(&next) => next,
+                None => return Ok(Err("links an entry in use as free")),
+            };
+        }
+        let mut table = vec![DirIndexSlot::default(); slots];
+        for (i, slot) in table.iter_mut().enumerate() {
+            self.io_at(&found.map, index_size(i), slot.as_buf_mut(), false)?;
+        }
+        let mut indexed = BTreeSet::new();
+        for (pos, slot) in table.iter().enumerate() {
+            if slot.entry == 0 {
+                continue;
+            }
+            let entry = slot.entry as usize - 1;
+            if used.get(&entry) != Some(&slot.hash) || !indexed.insert(entry) {
+                return Ok(Err("has a slot of a wrong entry"));
+            }
+            // probed from `home` to `pos` without an empty slot
+            let home = slot.hash as usize % slots;
+            let mut probe = home;
+            while probe != pos {
+                if table[probe].entry == 0 {
+                    return Ok(Err("has a slot which can not be found"));
+                }
+                probe = (probe + 1) % slots;
+            }
+        }
+        if indexed.len() != used.len() {
+            return Ok(Err("misses entries"));
+        }
+        Ok(Ok(()))
+    }
+
+    /// Check the links of the INodes found, and write the INodes changed by repair
+    fn check_links(&mut self) -> vfs::Result<()> {
+        for (&id, found) in self.inodes.iter_mut() {
+            let found = match found {
+                Ok(found) => found,
+                Err(_) => continue,
+            };
+            let entries = self.links.get(&id).copied().unwrap_or(0);
+            if found.disk.nlinks as usize != entries {
+                self.problems.push(Problem::WrongLinks {
+                    id,
+                    nlinks: found.disk.nlinks,
+                    entries,
+                });
+                found.disk.nlinks = entries.min(u16::MAX as usize) as u16;
+                found.dirty = true;
+            }
+            if self.repair && found.dirty {
+                self.sfs.write_meta(id, 0, found.disk.as_buf())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Check the free map, the refmap and the count of unused blocks with the references
+    fn check_blocks(&mut self) {
+        // the blocks of INodes found invalid are not in use
+        let blocks = self.data.end;
+        let mut free_map = self.sfs.free_map.write();
+        let mut free = 0;
+        for block in 0..blocks {
+            let used = self.references[block] != 0
+                || self.reserved.iter().any(|range| range.contains(&block));
+            match (used, free_map[block]) {
+                (true, true) => self.problems.push(Problem::UsedButFree(block)),
+                (false, false) => self.problems.push(Problem::Leaked(block)),
+                _ => {}
+            }
+            if !used {
+                free += 1;
+            }
+            if self.repair && used == free_map[block] {
+                free_map.set(block, !used);
+            }
+        }
+        let mut super_block = self.sfs.super_block.write();
+        if super_block.unused_blocks as usize != free {
+            self.problems.push(Problem::WrongUnusedBlocks {
+                counted: super_block.unused_blocks,
+                free,
+            });
+            if self.repair {
+                super_block.unused_blocks = free as u32;
+            }
+        }
+        if !self.has_refmap {
+            return;
+        }
+        let mut ref_map = self.sfs.ref_map.write();
+        for block in 0..blocks {
+            let references = self.references[block];
+            if ref_map[block] as usize != references.saturating_sub(1) {
+                self.problems.push(Problem::WrongShares {
+                    block,
+                    shares: ref_map[block],
+                    references,
+                });
+                if self.repair {
+                    ref_map[block] = references.saturating_sub(1) as u8;
+                }
+            }
+        }
+    }
+}
+
+/// Overwrite the header of the compact entry at `offset` of the block `buf`
+fn put_compact(buf: &mut [u8], offset: usize, header: &CompactEntry) {
+    buf[offset..offset + size_of::<CompactEntry>()].copy_from_slice(header.as_buf());
+}
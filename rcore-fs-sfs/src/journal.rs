@@ -0,0 +1,300 @@
+//! Write-ahead journal of the metadata in SFS
+//!
+//! Metadata blocks changed by the operations are kept in memory as the running transaction,
+//! instead of being written in place. A commit writes them after the journal header and the
+//! descriptor blocks naming their places beyond those in the header, then the header, and at
+//! last writes them in place and clears the header. Opening an image replays a committed
+//! header, so that a crash leaves either all or none of a transaction.
+//!
+//! A transaction larger than the journal is never split, the journal is aborted instead,
+//! leaving the image as of the last commit.
+
+use crate::structs::*;
+use crate::DeviceExt;
+use alloc::{
+    boxed::Box,
+    collections::{btree_map::Entry, BTreeMap},
+    vec::Vec,
+};
+use rcore_fs::{dev::Device, vfs};
+use spin::Mutex;
+
+type Block = Box<[u8; BLKSIZE]>;
+
+#[derive(Default)]
+struct State {
+    /// blocks changed by the running transaction
+    blocks: BTreeMap<BlockId, Block>,
+    /// blocks freed by the running transaction, which can not be reused before it commits
+    freed: Vec<BlockId>,
+    /// sequence number of the running transaction
+    sequence: u32,
+    /// a transaction did not fit, nothing is committed any more
+    aborted: bool,
+}
+
+pub struct Journal {
+    /// the header, followed by the blocks of a transaction
+    start: BlockId,
+    /// number of blocks including the header
+    blocks: usize,
+    /// blocks which an operation may change at most, kept free before it starts
+    reserve: usize,
+    state: Mutex<State>,
+}
+
+/// Number of descriptor blocks after the header of a transaction of `count` blocks
+fn descriptors(count: usize) -> usize {
+    count.saturating_sub(JOURNAL_NENTRY).div_ceil(BLK_NENTRY)
+}
+
+/// Max number of blocks in a transaction, in a journal of `blocks` blocks including the header
+pub fn capacity(blocks: usize) -> usize {
+    let free = blocks.saturating_sub(1);
+    free - free.saturating_sub(JOURNAL_NENTRY).div_ceil(BLK_NENTRY + 1)
+}
+
+/// Min number of blocks of a journal, for a transaction of `count` blocks
+pub fn min_blocks(count: usize) -> usize {
+    1 + descriptors(count) + count
+}
+
+/// Split `len` bytes at `offset` of block `id` into parts of each block,
+/// as `(block, offset in the block, range in the buffer)`
+fn parts(
+    id: BlockId,
+    offset: usize,
+    len: usize,
+) -> impl Iterator<Item = (BlockId, usize, core::ops::Range<usize>)> {
+    let mut pos = 0;
+    core::iter::from_fn(move || {
+        if pos == len {
+            return None;
+        }
+        let (block, begin) = (id + (offset + pos) / BLKSIZE, (offset + pos) % BLKSIZE);
+        let part = pos..len.min(pos + BLKSIZE - begin);
+        pos = part.end;
+        Some((block, begin, part))
+    })
+}
+
+/// FNV-1a of the transaction in `header` with the places `ids` of `blocks`
+fn checksum<'a>(
+    header: &JournalHeader,
+    ids: &[u32],
+    blocks: impl Iterator<Item = &'a Block>,
+) -> u32 {
+    let mut hash = 0x811c_9dc5u32;
+    let mut update = |data: &[u8]| {
+        for &b in data {
+            hash = (hash ^ b as u32).wrapping_mul(0x0100_0193);
+        }
+    };
+    update(&header.sequence.to_le_bytes());
+    update(&header.count.to_le_bytes());
+    for id in ids {
+        update(&id.to_le_bytes());
+    }
+    for block in blocks {
+        update(&block[..]);
+    }
+    hash
+}
+
+impl Journal {
+    pub fn new(start: BlockId, blocks: usize, reserve: usize) -> Self {
+        Journal {
+            start,
+            blocks,
+            reserve,
+            state: Mutex::default(),
+        }
+    }
+
+    /// Max number of blocks in a transaction
+    fn capacity(&self) -> usize {
+        capacity(self.blocks)
+    }
+
+    /// Whether the running transaction should be committed before an operation starts,
+    /// for which there may not be enough space left
+    pub fn nearly_full(&self) -> bool {
+        self.state.lock().blocks.len() + self.reserve > self.capacity()
+    }
+
+    pub fn is_aborted(&self) -> bool {
+        self.state.lock().aborted
+    }
+
+    /// Replay the transaction committed before a crash. If `read_only`, it is not written
+    /// in place, but kept as the running transaction to be read through the journal.
+    pub fn recover(&self, device: &dyn Device, read_only: bool) -> vfs::Result<()> {
+        let header = device.load_struct::<JournalHeader>(self.start)?;
+        let count = header.count as usize;
+        if header.magic != JOURNAL_MAGIC || count > self.capacity() {
+            return Ok(());
+        }
+        let mut ids = header.blocks[..count.min(JOURNAL_NENTRY)].to_vec();
+        for i in 0..descriptors(count) {
+            let descriptor = device.load_struct::<IndirectBlock>(self.start + 1 + i)?;
+            let len = (count - ids.len()).min(BLK_NENTRY);
+            ids.extend_from_slice(&descriptor.entries[..len]);
+        }
+        let first = self.start + 1 + descriptors(count);
+        let mut blocks = Vec::with_capacity(count);
+        for i in 0..count {
+            let mut data: Block = Box::new([0; BLKSIZE]);
+            device.read_block(first + i, 0, &mut data[..])?;
+            blocks.push(data);
+        }
+        if checksum(&header, &ids, blocks.iter()) != header.checksum {
+            // crashed while committing, the transaction is lost as a whole
+            warn!("ignore the uncommitted transaction {}", header.sequence);
+            return Ok(());
+        }
+        let mut state = self.state.lock();
+        state.sequence = header.sequence.wrapping_add(1);
+        state.blocks = ids
+            .iter()
+            .map(|&block| block as BlockId)
+            .zip(blocks)
+            .collect();
+        if !read_only {
+            info!(
+                "replay the transaction {} of {} blocks",
+                header.sequence, count
+            );
+            self.checkpoint(device, &state.blocks)?;
+            state.blocks.clear();
+        }
+        Ok(())
+    }
+
+    /// Read `buf` at `offset` of block `id` as changed by the running transaction
+    pub fn read(
+        &self,
+        device: &dyn Device,
+        id: BlockId,
+        offset: usize,
+        buf: &mut [u8],
+    ) -> vfs::Result<()> {
+        let state = self.state.lock();
+        for (block, begin, part) in parts(id, offset, buf.len()) {
+            let buf = &mut buf[part];
+            match state.blocks.get(&block) {
+                Some(data) => buf.copy_from_slice(&data[begin..begin + buf.len()]),
+                None => device.read_block(block, begin, buf)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Write `buf` at `offset` of block `id` in the running transaction
+    pub fn write(
+        &self,
+        device: &dyn Device,
+        id: BlockId,
+        offset: usize,
+        buf: &[u8],
+    ) -> vfs::Result<()> {
+        let mut state = self.state.lock();
+        if state.aborted {
+            return Err(vfs::FsError::ReadOnly);
+        }
+        for (block, begin, part) in parts(id, offset, buf.len()) {
+            let buf = &buf[part];
+            let data = match state.blocks.entry(block) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => {
+                    let mut data: Block = Box::new([0; BLKSIZE]);
+                    if buf.len() < BLKSIZE {
+                        // a growing image may end before the block, which reads as zeros
+                        device.read_at(block * BLKSIZE, &mut data[..])?;
+                    }
+                    entry.insert(data)
+                }
+            };
+            data[begin..begin + buf.len()].copy_from_slice(buf);
+        }
+        Ok(())
+    }
+
+    /// Free `block` when the running transaction commits
+    pub fn free(&self, block: BlockId) {
+        self.state.lock().freed.push(block);
+    }
+
+    /// Take the blocks to be freed by the running transaction
+    pub fn take_freed(&self) -> Vec<BlockId> {
+        core::mem::take(&mut self.state.lock().freed)
+    }
+
+    /// Commit the running transaction, which is kept to be committed again on failure.
+    ///
+    /// If it does not fit in the journal, the journal is aborted with `NoDeviceSpace`,
+    /// and the changes since the last commit are lost.
+    pub fn commit(&self, device: &dyn Device) -> vfs::Result<()> {
+        let mut state = self.state.lock();
+        if state.aborted {
+            return Err(vfs::FsError::ReadOnly);
+        }
+        let count = state.blocks.len();
+        if count == 0 {
+            return Ok(());
+        }
+        if count > self.capacity() {
+            error!(
+                "abort the journal, the transaction of {} blocks is larger than it",
+                count
+            );
+            state.aborted = true;
+            return Err(vfs::FsError::NoDeviceSpace);
+        }
+        let ids: Vec<u32> = state.blocks.keys().map(|&block| block as u32).collect();
+        let mut header = JournalHeader {
+            magic: JOURNAL_MAGIC,
+            sequence: state.sequence,
+            count: count as u32,
+            checksum: 0,
+            blocks: [0; JOURNAL_NENTRY],
+        };
+        let (named, rest) = ids.split_at(count.min(JOURNAL_NENTRY));
+        header.blocks[..named.len()].copy_from_slice(named);
+        for (i, entries) in rest.chunks(BLK_NENTRY).enumerate() {
+            let mut descriptor = IndirectBlock {
+                entries: [0; BLK_NENTRY],
+            };
+            descriptor.entries[..entries.len()].copy_from_slice(entries);
+            device.write_block(self.start + 1 + i, 0, descriptor.as_buf())?;
+        }
+        let first = self.start + 1 + descriptors(count);
+        for (i, data) in state.blocks.values().enumerate() {
+            device.write_block(first + i, 0, &data[..])?;
+        }
+        header.checksum = checksum(&header, &ids, state.blocks.values());
+        // the blocks must be durable before the header, and the header before the blocks in place
+        device.sync()?;
+        device.write_block(self.start, 0, header.as_buf())?;
+        device.sync()?;
+        self.checkpoint(device, &state.blocks)?;
+        state.blocks.clear();
+        state.sequence = state.sequence.wrapping_add(1);
+        Ok(())
+    }
+
+    /// Write `blocks` in place, then clear the header not to replay them again.
+    ///
+    /// Clearing needs no sync, the next commit syncs before writing its header,
+    /// and replaying the header again writes the same blocks.
+    fn checkpoint(
+        &self,
+        device: &dyn Device,
+        blocks: &BTreeMap<BlockId, Block>,
+    ) -> vfs::Result<()> {
+        for (&block, data) in blocks.iter() {
+            device.write_block(block, 0, &data[..])?;
+        }
+        device.sync()?;
+        device.write_block(self.start, 0, 0u32.as_buf())
+    }
+}
@@ -5,7 +5,8 @@ extern crate alloc;
 extern crate log;
 
 use alloc::{
-    collections::BTreeMap,
+    boxed::Box,
+    collections::{BTreeMap, BTreeSet},
     string::String,
     sync::{Arc, Weak},
     vec,
@@ -13,38 +14,54 @@ use alloc::{
 };
 use core::{
     any::Any,
+    convert::TryFrom,
     fmt::{Debug, Error, Formatter},
+    ops::{Deref, DerefMut, Range},
 };
 
 use bitvec::prelude::*;
-use spin::RwLock;
+use spin::{Mutex, RwLock};
 
 use rcore_fs::{
+    cache::INodeCache,
     dev::Device,
     dirty::Dirty,
+    notify::Notifier,
+    page_cache::{PageCache, PinnedPage, PAGE_SIZE},
     util::*,
     vfs::{self, FileSystem, FsError, INode, MMapArea, Metadata},
 };
 
+use journal::Journal;
 pub use structs::*;
 
+mod compact_dir;
+mod dir_index;
+mod extent;
+pub mod fsck;
+mod journal;
 mod structs;
 #[cfg(test)]
 mod tests;
 
+static ZEROS: [u8; BLKSIZE] = [0; BLKSIZE];
+
 trait DeviceExt: Device {
+    /// Read `buf` at `offset` of block `id`, which may run into the following blocks.
+    /// A short read, e.g. beyond the end of a truncated image, fails with `DeviceError`.
     fn read_block(&self, id: BlockId, offset: usize, buf: &mut [u8]) -> vfs::Result<()> {
-        debug_assert!(offset + buf.len() <= BLKSIZE);
         match self.read_at(id * BLKSIZE + offset, buf) {
             Ok(len) if len == buf.len() => Ok(()),
-            _ => panic!("cannot read block {} offset {} from device", id, offset),
+            Ok(_) => Err(FsError::DeviceError),
+            Err(e) => Err(e.into()),
         }
     }
+    /// Write `buf` at `offset` of block `id`, which may run into the following blocks
     fn write_block(&self, id: BlockId, offset: usize, buf: &[u8]) -> vfs::Result<()> {
-        debug_assert!(offset + buf.len() <= BLKSIZE);
         match self.write_at(id * BLKSIZE + offset, buf) {
             Ok(len) if len == buf.len() => Ok(()),
-            _ => panic!("cannot write block {} offset {} to device", id, offset),
+            Ok(_) => Err(FsError::DeviceError),
+            Err(e) => Err(e.into()),
         }
     }
     /// Load struct `T` from given block in device
@@ -55,7 +72,70 @@ trait DeviceExt: Device {
     }
 }
 
-impl DeviceExt for dyn Device {}
+impl DeviceExt for dyn Device + '_ {}
+
+/// Async version of `DeviceExt::read_block`
+async fn async_read_block(
+    device: &dyn Device,
+    id: BlockId,
+    offset: usize,
+    buf: &mut [u8],
+) -> vfs::Result<()> {
+    match device.async_read_at(id * BLKSIZE + offset, buf).await {
+        Ok(len) if len == buf.len() => Ok(()),
+        Ok(_) => Err(FsError::DeviceError),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Async version of `DeviceExt::write_block`
+async fn async_write_block(
+    device: &dyn Device,
+    id: BlockId,
+    offset: usize,
+    buf: &[u8],
+) -> vfs::Result<()> {
+    match device.async_write_at(id * BLKSIZE + offset, buf).await {
+        Ok(len) if len == buf.len() => Ok(()),
+        Ok(_) => Err(FsError::DeviceError),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Split `begin..end` of the concatenation of buffers of lengths `lens` into
+/// parts of each buffer, as `(buffer index, range in the buffer, position)`
+fn vectored_parts(
+    lens: &[usize],
+    begin: usize,
+    end: usize,
+) -> impl Iterator<Item = (usize, Range<usize>, usize)> + '_ {
+    let mut pos = 0;
+    lens.iter().enumerate().filter_map(move |(i, &len)| {
+        let start = pos;
+        pos += len;
+        let (b, e) = (begin.max(start), end.min(pos));
+        (b < e).then(|| (i, b - start..e - start, b))
+    })
+}
+
+/// Check the length of the name of a new entry
+fn check_name(name: &str) -> vfs::Result<()> {
+    if name.len() > MAX_FNAME_LEN {
+        return Err(FsError::NameTooLong);
+    }
+    Ok(())
+}
+
+/// A direntry in use, found by `INodeImpl::scan_direntries`
+struct DirEntryAt {
+    /// position of the direntry
+    pos: usize,
+    /// position after the direntry
+    next: usize,
+    entry: DiskEntry,
+    /// type of the INode, only recorded in compact entries
+    type_: Option<FileType>,
+}
 
 /// INode for SFS
 pub struct INodeImpl {
@@ -81,94 +161,262 @@ impl Debug for INodeImpl {
 }
 
 impl INodeImpl {
-    /// Map file block id to disk block id
+    /// Whether blocks are mapped by the extent tree instead of the block pointers
+    fn extents(&self) -> bool {
+        self.fs.super_block.read().extents()
+    }
+    fn sparse(&self) -> bool {
+        self.fs.super_block.read().sparse()
+    }
+    /// Map file block id to disk block id, 0 for a hole
     fn get_disk_block_id(&self, file_block_id: BlockId) -> vfs::Result<BlockId> {
         let disk_inode = self.disk_inode.read();
         match file_block_id {
             id if id >= disk_inode.blocks as BlockId => Err(FsError::InvalidParam),
+            id if self.extents() => {
+                drop(disk_inode);
+                Ok(self.extent_lookup(id)?.0)
+            }
             id if id < MAX_NBLOCK_DIRECT => Ok(disk_inode.direct[id] as BlockId),
             id if id < MAX_NBLOCK_INDIRECT => {
-                let mut disk_block_id: u32 = 0;
-                self.fs.device.read_block(
-                    disk_inode.indirect as usize,
-                    ENTRY_SIZE * (id - NDIRECT),
-                    disk_block_id.as_buf_mut(),
-                )?;
-                Ok(disk_block_id as BlockId)
+                drop(disk_inode);
+                let indirect = self.indirect_block(false, false)?;
+                if indirect == 0 {
+                    return Ok(0);
+                }
+                self.indirect_entry(indirect, id - NDIRECT, false)
             }
             id if id < MAX_NBLOCK_DOUBLE_INDIRECT => {
+                drop(disk_inode);
                 // double indirect
                 let indirect_id = id - MAX_NBLOCK_INDIRECT;
-                let mut indirect_block_id: u32 = 0;
-                self.fs.device.read_block(
-                    disk_inode.db_indirect as usize,
-                    ENTRY_SIZE * (indirect_id / BLK_NENTRY),
-                    indirect_block_id.as_buf_mut(),
-                )?;
-                assert!(indirect_block_id > 0);
-                let mut disk_block_id: u32 = 0;
-                self.fs.device.read_block(
-                    indirect_block_id as usize,
-                    ENTRY_SIZE * (indirect_id as usize % BLK_NENTRY),
-                    disk_block_id.as_buf_mut(),
-                )?;
-                assert!(disk_block_id > 0);
-                Ok(disk_block_id as BlockId)
+                let db_indirect = self.indirect_block(true, false)?;
+                if db_indirect == 0 {
+                    return Ok(0);
+                }
+                let indirect = self.indirect_entry(db_indirect, indirect_id / BLK_NENTRY, false)?;
+                if indirect == 0 {
+                    return Ok(0);
+                }
+                self.indirect_entry(indirect, indirect_id % BLK_NENTRY, false)
             }
             _ => unimplemented!("triple indirect blocks is not supported"),
         }
     }
+    /// Map file block id to disk block id like `get_disk_block_id`, with the number of
+    /// following file blocks mapped contiguously or as holes, which is at least 1
+    fn map_disk_blocks(&self, file_block_id: BlockId) -> vfs::Result<(BlockId, usize)> {
+        if !self.extents() {
+            return Ok((self.get_disk_block_id(file_block_id)?, 1));
+        }
+        let blocks = self.disk_inode.read().blocks as BlockId;
+        if file_block_id >= blocks {
+            return Err(FsError::InvalidParam);
+        }
+        let (disk_block_id, len) = self.extent_lookup(file_block_id)?;
+        Ok((disk_block_id, len.min(blocks - file_block_id)))
+    }
+    /// Disk block to allocate for file block id, after the disk block of the previous one
+    fn block_goal(&self, file_block_id: BlockId) -> vfs::Result<BlockId> {
+        match file_block_id.checked_sub(1) {
+            Some(prev) => match self.get_disk_block_id(prev)? {
+                0 => Ok(0),
+                block => Ok(block + 1),
+            },
+            None => Ok(0),
+        }
+    }
+    /// Map file block id to disk block id, 0 to make a hole.
+    /// Indirect blocks or extent nodes are allocated if needed.
     fn set_disk_block_id(&self, file_block_id: BlockId, disk_block_id: BlockId) -> vfs::Result<()> {
+        let alloc = disk_block_id != 0;
+        let disk_block_id = disk_block_id as u32;
         match file_block_id {
             id if id >= self.disk_inode.read().blocks as BlockId => Err(FsError::InvalidParam),
+            id if self.extents() => {
+                self.extent_remove(id, id + 1)?;
+                if alloc {
+                    self.extent_insert(Extent {
+                        start: id as u32,
+                        block: disk_block_id,
+                        len: 1,
+                    })?;
+                }
+                Ok(())
+            }
             id if id < MAX_NBLOCK_DIRECT => {
-                self.disk_inode.write().direct[id] = disk_block_id as u32;
+                self.disk_inode.write().direct[id] = disk_block_id;
                 Ok(())
             }
             id if id < MAX_NBLOCK_INDIRECT => {
-                let disk_block_id = disk_block_id as u32;
-                self.fs.device.write_block(
-                    self.disk_inode.read().indirect as usize,
+                let indirect = self.indirect_block(false, alloc)?;
+                if indirect == 0 {
+                    return Ok(());
+                }
+                self.fs.write_meta(
+                    indirect,
                     ENTRY_SIZE * (id - NDIRECT),
                     disk_block_id.as_buf(),
-                )?;
-                Ok(())
+                )
             }
             id if id < MAX_NBLOCK_DOUBLE_INDIRECT => {
                 // double indirect
                 let indirect_id = id - MAX_NBLOCK_INDIRECT;
-                let mut indirect_block_id: u32 = 0;
-                self.fs.device.read_block(
-                    self.disk_inode.read().db_indirect as usize,
-                    ENTRY_SIZE * (indirect_id / BLK_NENTRY),
-                    indirect_block_id.as_buf_mut(),
-                )?;
-                assert!(indirect_block_id > 0);
-                let disk_block_id = disk_block_id as u32;
-                self.fs.device.write_block(
-                    indirect_block_id as usize,
-                    ENTRY_SIZE * (indirect_id as usize % BLK_NENTRY),
+                let db_indirect = self.indirect_block(true, alloc)?;
+                if db_indirect == 0 {
+                    return Ok(());
+                }
+                let indirect = self.indirect_entry(db_indirect, indirect_id / BLK_NENTRY, alloc)?;
+                if indirect == 0 {
+                    return Ok(());
+                }
+                self.fs.write_meta(
+                    indirect,
+                    ENTRY_SIZE * (indirect_id % BLK_NENTRY),
                     disk_block_id.as_buf(),
-                )?;
-                Ok(())
+                )
             }
             _ => unimplemented!("triple indirect blocks is not supported"),
         }
     }
+    /// Get the indirect block, or the double indirect block if `double`.
+    /// Allocate it if `alloc`, otherwise return 0 if it does not exist.
+    fn indirect_block(&self, double: bool, alloc: bool) -> vfs::Result<BlockId> {
+        let block = {
+            let disk_inode = self.disk_inode.read();
+            if double {
+                disk_inode.db_indirect
+            } else {
+                disk_inode.indirect
+            }
+        };
+        if block != 0 || !alloc {
+            return Ok(block as BlockId);
+        }
+        // allocate without holding the inode, since `sync` locks the inode with the free map held
+        let new_block = self.fs.alloc_zeroed_block()?;
+        let mut disk_inode = self.disk_inode.write();
+        let block = if double {
+            &mut disk_inode.db_indirect
+        } else {
+            &mut disk_inode.indirect
+        };
+        if *block != 0 {
+            // allocated by others
+            let block = *block as BlockId;
+            drop(disk_inode);
+            self.fs.free_block(new_block);
+            return Ok(block);
+        }
+        *block = new_block as u32;
+        Ok(new_block)
+    }
+    /// Get the `index`th entry in the indirect block `block`.
+    /// Allocate a block for it if `alloc`, otherwise return 0 if it does not exist.
+    fn indirect_entry(&self, block: BlockId, index: usize, alloc: bool) -> vfs::Result<BlockId> {
+        let mut entry: u32 = 0;
+        self.fs
+            .read_meta(block, ENTRY_SIZE * index, entry.as_buf_mut())?;
+        if entry != 0 || !alloc {
+            return Ok(entry as BlockId);
+        }
+        let new_block = self.fs.alloc_zeroed_block()?;
+        self.fs
+            .write_meta(block, ENTRY_SIZE * index, (new_block as u32).as_buf())?;
+        Ok(new_block)
+    }
+    /// Make file blocks in `begin..end` holes by clearing their pointers, without freeing them.
+    ///
+    /// Pointers beyond `blocks` may be left by truncation, clear them before growing.
+    fn clear_disk_block_ids(&self, begin: BlockId, end: BlockId) -> vfs::Result<()> {
+        if self.extents() {
+            self.extent_remove(begin, end)?;
+            return Ok(());
+        }
+        if begin < MAX_NBLOCK_DIRECT {
+            let mut disk_inode = self.disk_inode.write();
+            for id in begin..end.min(MAX_NBLOCK_DIRECT) {
+                disk_inode.direct[id] = 0;
+            }
+        }
+        let DiskINode {
+            indirect,
+            db_indirect,
+            ..
+        } = **self.disk_inode.read();
+        // indirect
+        let (b, e) = (begin.max(NDIRECT), end.min(MAX_NBLOCK_INDIRECT));
+        if indirect != 0 && b < e {
+            self.fs.write_meta(
+                indirect as usize,
+                ENTRY_SIZE * (b - NDIRECT),
+                &ZEROS[..ENTRY_SIZE * (e - b)],
+            )?;
+        }
+        // double indirect
+        let b = begin.max(MAX_NBLOCK_INDIRECT) - MAX_NBLOCK_INDIRECT;
+        let e = end.max(MAX_NBLOCK_INDIRECT) - MAX_NBLOCK_INDIRECT;
+        if db_indirect != 0 && b < e {
+            let mut first = b / BLK_NENTRY;
+            if !b.is_multiple_of(BLK_NENTRY) {
+                // the indirect block is partially in use
+                let indirect = self.indirect_entry(db_indirect as usize, first, false)?;
+                if indirect != 0 {
+                    let e = e.min((first + 1) * BLK_NENTRY);
+                    self.fs.write_meta(
+                        indirect,
+                        ENTRY_SIZE * (b % BLK_NENTRY),
+                        &ZEROS[..ENTRY_SIZE * (e - b)],
+                    )?;
+                }
+                first += 1;
+            }
+            let last = e.div_ceil(BLK_NENTRY);
+            if first < last {
+                self.fs.write_meta(
+                    db_indirect as usize,
+                    ENTRY_SIZE * first,
+                    &ZEROS[..ENTRY_SIZE * (last - first)],
+                )?;
+            }
+        }
+        Ok(())
+    }
     /// Only for Dir
-    fn get_file_inode_and_entry_id(&self, name: &str) -> Option<(INodeId, usize)> {
-        (0..self.disk_inode.read().size as usize / DIRENT_SIZE)
-            .map(|i| (self.read_direntry(i as usize).unwrap(), i))
-            .find(|(entry, _)| entry.name.as_ref() == name)
-            .map(|(entry, id)| (entry.id as INodeId, id as usize))
+    fn get_file_inode_and_entry_id(&self, name: &str) -> vfs::Result<Option<(INodeId, usize)>> {
+        if let Some(index) = self.dir_index()? {
+            return self.index_find(&index, name);
+        }
+        self.scan_direntries(0, |found| {
+            Ok(match found.entry.name.as_ref() == name {
+                true => Some((found.entry.id as INodeId, found.pos)),
+                false => None,
+            })
+        })
+    }
+    fn get_file_inode_id(&self, name: &str) -> vfs::Result<Option<INodeId>> {
+        Ok(self
+            .get_file_inode_and_entry_id(name)?
+            .map(|(inode_id, _)| inode_id))
+    }
+    /// Whether the directories consist of compact entries, whose positions are offsets.
+    /// Otherwise the position of a direntry is its index.
+    fn compact_dirents(&self) -> bool {
+        self.fs.super_block.read().compact_dirents()
     }
-    fn get_file_inode_id(&self, name: &str) -> Option<INodeId> {
-        self.get_file_inode_and_entry_id(name)
-            .map(|(inode_id, _)| inode_id)
+    /// Position of '..'
+    fn dotdot(&self) -> usize {
+        match self.compact_dirents() {
+            true => COMPACT_DOTDOT,
+            false => 1,
+        }
     }
     /// Init dir content. Insert 2 init entries.
     /// This do not init nlinks, please modify the nlinks in the invoker.
     fn init_direntry(&self, parent: INodeId) -> vfs::Result<()> {
+        if self.compact_dirents() {
+            return self.compact_init(parent);
+        }
         // Insert entries: '.' '..'
         self._resize(DIRENT_SIZE * 2)?;
         self.write_direntry(
@@ -177,6 +425,7 @@ impl INodeImpl {
                 id: self.id as u32,
                 name: Str256::from("."),
             },
+            FileType::Dir,
         )?;
         self.write_direntry(
             1,
@@ -184,143 +433,324 @@ impl INodeImpl {
                 id: parent as u32,
                 name: Str256::from(".."),
             },
+            FileType::Dir,
         )?;
         Ok(())
     }
     fn read_direntry(&self, id: usize) -> vfs::Result<DiskEntry> {
+        if self.compact_dirents() {
+            return self.compact_read(id);
+        }
         let mut direntry: DiskEntry = unsafe { uninit_memory() };
         self._read_at(DIRENT_SIZE * id, direntry.as_buf_mut())?;
         Ok(direntry)
     }
-    fn write_direntry(&self, id: usize, direntry: &DiskEntry) -> vfs::Result<()> {
+    /// Write a direntry to an INode of `type_`, which is only recorded in compact entries.
+    /// A compact entry is overwritten in place, where the name must fit.
+    fn write_direntry(&self, id: usize, direntry: &DiskEntry, type_: FileType) -> vfs::Result<()> {
+        if self.compact_dirents() {
+            return self.compact_write(id, direntry, type_);
+        }
         self._write_at(DIRENT_SIZE * id, direntry.as_buf())?;
         Ok(())
     }
-    fn append_direntry(&self, direntry: &DiskEntry) -> vfs::Result<()> {
+    /// Visit the direntries in use from position `from` in order, until `f` returns something
+    fn scan_direntries<R>(
+        &self,
+        from: usize,
+        mut f: impl FnMut(DirEntryAt) -> vfs::Result<Option<R>>,
+    ) -> vfs::Result<Option<R>> {
+        if self.compact_dirents() {
+            return self.compact_scan(from, f);
+        }
+        let dirent_count = self.disk_inode.read().size as usize / DIRENT_SIZE;
+        for id in from..dirent_count {
+            let entry = self.read_direntry(id)?;
+            if entry.is_free() {
+                continue;
+            }
+            let found = DirEntryAt {
+                pos: id,
+                next: id + 1,
+                entry,
+                type_: None,
+            };
+            if let Some(result) = f(found)? {
+                return Ok(Some(result));
+            }
+        }
+        Ok(None)
+    }
+    /// Read the `n`th used direntry, skipping free ones
+    fn nth_direntry(&self, n: usize) -> vfs::Result<DiskEntry> {
+        let mut n = n;
+        let entry = self.scan_direntries(0, |found| {
+            if n == 0 {
+                return Ok(Some(found.entry));
+            }
+            n -= 1;
+            Ok(None)
+        })?;
+        entry.ok_or(FsError::EntryNotFound)
+    }
+    /// Insert a direntry to an INode of `type_` into the first free space,
+    /// or append it if there is none.
+    /// Large directories are indexed after it if FEATURE_DIR_INDEX.
+    fn insert_direntry(&self, direntry: &DiskEntry, type_: FileType) -> vfs::Result<()> {
+        if let Some(index) = self.dir_index()? {
+            return self.index_insert_direntry(&index, direntry, type_);
+        }
+        if self.compact_dirents() {
+            self.compact_insert(direntry, type_, &mut 0)?;
+        } else {
+            let size = self.disk_inode.read().size as usize;
+            let dirent_count = size / DIRENT_SIZE;
+            for id in 0..dirent_count {
+                if self.read_direntry(id)?.is_free() {
+                    return self.write_direntry(id, direntry, type_);
+                }
+            }
+            self._resize(size + DIRENT_SIZE)?;
+            self.write_direntry(dirent_count, direntry, type_)?;
+        }
         let size = self.disk_inode.read().size as usize;
-        let dirent_count = size / DIRENT_SIZE;
-        self._resize(size + DIRENT_SIZE)?;
-        self.write_direntry(dirent_count, direntry)?;
+        if size > DIR_INDEX_THRESHOLD && self.fs.super_block.read().indexes_dirs() {
+            self.build_index()?;
+        }
         Ok(())
     }
-    /// remove a direntry in middle of file and insert the last one here, useful for direntry remove
-    /// should be only used in unlink
+    /// Free a direntry in place, so that other entries keep their positions for readdir.
+    /// Free entries at the end are truncated, thus the last direntry is always in use,
+    /// unless the directory is indexed.
+    /// Images before REVISION_FREE_DIRENT are kept without free entries.
     fn remove_direntry(&self, id: usize) -> vfs::Result<()> {
+        if let Some(index) = self.dir_index()? {
+            return self.index_remove_direntry(&index, id);
+        }
+        if self.compact_dirents() {
+            return self.compact_remove(id);
+        }
         let size = self.disk_inode.read().size as usize;
-        let dirent_count = size / DIRENT_SIZE;
+        let mut dirent_count = size / DIRENT_SIZE;
         debug_assert!(id < dirent_count);
-        let last_dirent = self.read_direntry(dirent_count - 1)?;
-        self.write_direntry(id, &last_dirent)?;
-        self._resize(size - DIRENT_SIZE)?;
+        if !self.fs.super_block.read().has_free_dirents() {
+            // older images have no free entries, move the last one here instead,
+            // which changes its position for readdir
+            let last = self.read_direntry(dirent_count - 1)?;
+            self.write_direntry(id, &last, FileType::Invalid)?;
+            return self._resize(size - DIRENT_SIZE);
+        }
+        self.write_direntry(id, &DiskEntry::free(), FileType::Invalid)?;
+        while dirent_count > 0 && self.read_direntry(dirent_count - 1)?.is_free() {
+            dirent_count -= 1;
+        }
+        self._resize(dirent_count * DIRENT_SIZE)?;
         Ok(())
     }
+    /// Rename the direntry `id` named `old_name` to an INode of `type_`.
+    /// A compact entry is moved if the new name does not fit in place.
+    fn rename_direntry(
+        &self,
+        id: usize,
+        old_name: &str,
+        direntry: &DiskEntry,
+        type_: FileType,
+    ) -> vfs::Result<()> {
+        if self.compact_dirents() && !self.compact_fits(id, direntry.name.as_ref().len())? {
+            self.insert_direntry(direntry, type_)?;
+            return self.remove_direntry(id);
+        }
+        match self.dir_index()? {
+            Some(index) => {
+                self.index_remove(&index, old_name, id)?;
+                self.write_direntry(id, direntry, type_)?;
+                self.index_put(&index, direntry.name.as_ref(), id)
+            }
+            None => self.write_direntry(id, direntry, type_),
+        }
+    }
+    /// Only for Dir. Whether there are only '.' and '..'
+    fn is_empty_dir(&self) -> vfs::Result<bool> {
+        if let Some(index) = self.dir_index()? {
+            return Ok(index.index_header()?.count <= 2);
+        }
+        if self.compact_dirents() {
+            return self.compact_is_empty();
+        }
+        Ok(self.disk_inode.read().size as usize / DIRENT_SIZE <= 2)
+    }
+    /// Only for Dir. Point '..' to `parent`.
+    /// This do not modify the nlinks, please modify the nlinks in the invoker.
+    fn set_parent(&self, parent: INodeId) -> vfs::Result<()> {
+        self.write_direntry(
+            self.dotdot(),
+            &DiskEntry {
+                id: parent as u32,
+                name: Str256::from(".."),
+            },
+            FileType::Dir,
+        )
+    }
+    /// Only for Dir. Return `InvalidParam` if `self` is the dir `ancestor` or beneath it,
+    /// so that moving `ancestor` into `self` would make a cycle.
+    fn check_not_beneath(&self, ancestor: INodeId) -> vfs::Result<()> {
+        let mut id = self.id;
+        loop {
+            if id == ancestor {
+                return Err(FsError::InvalidParam);
+            }
+            if id == BLKN_ROOT {
+                return Ok(());
+            }
+            id = self.fs.get_inode(id)?.read_direntry(self.dotdot())?.id as INodeId;
+        }
+    }
     /// Resize content size, no matter what type it is.
+    /// Growing leaves holes, blocks are allocated on write.
     fn _resize(&self, len: usize) -> vfs::Result<()> {
         if len > MAX_FILE_SIZE {
-            return Err(FsError::InvalidParam);
+            return Err(FsError::FileTooBig);
+        }
+        let blocks = len.div_ceil(BLKSIZE);
+        if blocks > MAX_NBLOCK_DOUBLE_INDIRECT {
+            return Err(FsError::FileTooBig);
+        }
+        let DiskINode {
+            size: old_size,
+            blocks: old_blocks,
+            ..
+        } = **self.disk_inode.read();
+        let (old_size, old_blocks) = (old_size as usize, old_blocks as usize);
+        if len > old_size {
+            // blocks preallocated after the end are kept
+            self._grow_blocks(blocks)?;
+            self.disk_inode.write().size = len as u32;
+            // the rest of the old last block may be dirty after truncation
+            let old_end = old_size.div_ceil(BLKSIZE) * BLKSIZE;
+            self._clean_at(old_size, len.min(old_end))?;
+        } else if blocks < old_blocks {
+            self._free_blocks(blocks, old_blocks)?;
+            let mut disk_inode = self.disk_inode.write();
+            disk_inode.blocks = blocks as u32;
+            disk_inode.size = len as u32;
+        } else {
+            self.disk_inode.write().size = len as u32;
         }
-        let blocks = ((len + BLKSIZE - 1) / BLKSIZE) as u32;
-        if blocks > MAX_NBLOCK_DOUBLE_INDIRECT as u32 {
+        // the cached pages beyond the old or new end are zeros
+        self.fs.pages.truncate(self.id, len.min(old_size));
+        Ok(())
+    }
+    /// Extend the block map to `blocks` blocks with holes, not changing the size.
+    /// The blocks are allocated instead on images before REVISION_SPARSE.
+    fn _grow_blocks(&self, blocks: usize) -> vfs::Result<()> {
+        if blocks > MAX_NBLOCK_DOUBLE_INDIRECT {
             return Err(FsError::InvalidParam);
         }
-        use core::cmp::Ordering;
-        let old_blocks = self.disk_inode.read().blocks;
-        match blocks.cmp(&old_blocks) {
-            Ordering::Equal => {
-                self.disk_inode.write().size = len as u32;
-            }
-            Ordering::Greater => {
-                let mut disk_inode = self.disk_inode.write();
-                disk_inode.blocks = blocks;
-                // allocate indirect block if needed
-                if old_blocks < MAX_NBLOCK_DIRECT as u32 && blocks >= MAX_NBLOCK_DIRECT as u32 {
-                    disk_inode.indirect = self.fs.alloc_block().expect("no space") as u32;
-                }
-                // allocate double indirect block if needed
-                if blocks >= MAX_NBLOCK_INDIRECT as u32 {
-                    if disk_inode.db_indirect == 0 {
-                        disk_inode.db_indirect = self.fs.alloc_block().expect("no space") as u32;
-                    }
-                    let indirect_begin = {
-                        if (old_blocks as usize) < MAX_NBLOCK_INDIRECT {
-                            0
-                        } else {
-                            (old_blocks as usize - MAX_NBLOCK_INDIRECT) / BLK_NENTRY + 1
-                        }
-                    };
-                    let indirect_end = (blocks as usize - MAX_NBLOCK_INDIRECT) / BLK_NENTRY + 1;
-                    for i in indirect_begin..indirect_end {
-                        let indirect = self.fs.alloc_block().expect("no space") as u32;
-                        self.fs.device.write_block(
-                            disk_inode.db_indirect as usize,
-                            ENTRY_SIZE * i,
-                            indirect.as_buf(),
-                        )?;
-                    }
+        let old_blocks = self.disk_inode.read().blocks as usize;
+        if blocks > old_blocks {
+            self.clear_disk_block_ids(old_blocks, blocks)?;
+            self.disk_inode.write().blocks = blocks as u32;
+            if !self.sparse() {
+                // older images have no holes, allocate the new blocks
+                if let Err(e) = self._alloc_range(old_blocks * BLKSIZE, blocks * BLKSIZE) {
+                    self._free_blocks(old_blocks, blocks)?;
+                    self.disk_inode.write().blocks = old_blocks as u32;
+                    return Err(e);
                 }
-                drop(disk_inode);
-                // allocate extra blocks
-                for i in old_blocks..blocks {
-                    let disk_block_id = self.fs.alloc_block().expect("no space");
-                    self.set_disk_block_id(i as usize, disk_block_id)?;
-                }
-                // clean up
-                let mut disk_inode = self.disk_inode.write();
-                let old_size = disk_inode.size as usize;
-                disk_inode.size = len as u32;
-                drop(disk_inode);
-                self._clean_at(old_size, len)?;
             }
-            Ordering::Less => {
-                // free extra blocks
-                for i in blocks..old_blocks {
-                    let disk_block_id = self.get_disk_block_id(i as usize)?;
-                    self.fs.free_block(disk_block_id);
-                }
-                let mut disk_inode = self.disk_inode.write();
-                // free indirect block if needed
-                if blocks < MAX_NBLOCK_DIRECT as u32
-                    && disk_inode.blocks >= MAX_NBLOCK_DIRECT as u32
-                {
-                    self.fs.free_block(disk_inode.indirect as usize);
-                    disk_inode.indirect = 0;
+        }
+        Ok(())
+    }
+    /// Free file blocks in `begin..end`, and indirect blocks no longer needed by `begin` blocks.
+    /// This do not modify `blocks`, please modify it in the invoker.
+    fn _free_blocks(&self, begin: usize, end: usize) -> vfs::Result<()> {
+        if self.extents() {
+            for (block, len) in self.extent_remove(begin, end)? {
+                for block in block..block + len {
+                    self.fs.free_block(block);
                 }
-                // free double indirect block if needed
-                if disk_inode.blocks >= MAX_NBLOCK_INDIRECT as u32 {
-                    let indirect_begin = {
-                        if (blocks as usize) < MAX_NBLOCK_INDIRECT {
-                            0
-                        } else {
-                            (blocks as usize - MAX_NBLOCK_INDIRECT) / BLK_NENTRY + 1
-                        }
-                    };
-                    let indirect_end =
-                        (disk_inode.blocks as usize - MAX_NBLOCK_INDIRECT) / BLK_NENTRY + 1;
-                    for i in indirect_begin..indirect_end {
-                        let mut indirect: u32 = 0;
-                        self.fs.device.read_block(
-                            disk_inode.db_indirect as usize,
-                            ENTRY_SIZE * i,
-                            indirect.as_buf_mut(),
-                        )?;
-                        assert!(indirect > 0);
-                        self.fs.free_block(indirect as usize);
-                    }
-                    if blocks < MAX_NBLOCK_INDIRECT as u32 {
-                        assert!(disk_inode.db_indirect > 0);
-                        self.fs.free_block(disk_inode.db_indirect as usize);
-                        disk_inode.db_indirect = 0;
-                    }
+            }
+            return Ok(());
+        }
+        // free data blocks
+        for i in begin..end {
+            let disk_block_id = self.get_disk_block_id(i)?;
+            if disk_block_id != 0 {
+                self.fs.free_block(disk_block_id);
+            }
+        }
+        let DiskINode {
+            indirect,
+            db_indirect,
+            ..
+        } = **self.disk_inode.read();
+        // free indirect block if needed
+        if begin <= MAX_NBLOCK_DIRECT && indirect != 0 {
+            self.fs.free_block(indirect as usize);
+            self.disk_inode.write().indirect = 0;
+        }
+        // free double indirect block if needed
+        if end > MAX_NBLOCK_INDIRECT && db_indirect != 0 {
+            let indirect_begin =
+                (begin.max(MAX_NBLOCK_INDIRECT) - MAX_NBLOCK_INDIRECT).div_ceil(BLK_NENTRY);
+            let indirect_end = (end - MAX_NBLOCK_INDIRECT).div_ceil(BLK_NENTRY);
+            for i in indirect_begin..indirect_end {
+                let indirect = self.indirect_entry(db_indirect as usize, i, false)?;
+                if indirect != 0 {
+                    self.fs.free_block(indirect);
                 }
-                disk_inode.blocks = blocks;
-                disk_inode.size = len as u32;
+            }
+            if begin <= MAX_NBLOCK_INDIRECT {
+                self.fs.free_block(db_indirect as usize);
+                self.disk_inode.write().db_indirect = 0;
+            }
+        }
+        Ok(())
+    }
+    /// Deallocate blocks in `begin..end` to make holes, and clean the partial blocks at edges
+    fn _punch_hole(&self, begin: usize, end: usize) -> vfs::Result<()> {
+        let blocks = self.disk_inode.read().blocks as usize;
+        let iter = BlockIter {
+            begin,
+            end: end.min(blocks * BLKSIZE),
+            block_size_log2: BLKSIZE_LOG2,
+        };
+        for range in iter {
+            let disk_block_id = self.get_disk_block_id(range.block)?;
+            if disk_block_id == 0 {
+                continue;
+            }
+            if range.is_full() {
+                self.set_disk_block_id(range.block, 0)?;
+                self.fs.free_block(disk_block_id);
+            } else {
+                let disk_block_id = self.unshare_block(range.block, disk_block_id)?;
+                self.fs
+                    .device
+                    .write_block(disk_block_id, range.begin, &ZEROS[..range.len()])?;
+            }
+        }
+        Ok(())
+    }
+    /// Allocate zeroed blocks for holes in `begin..end`
+    fn _alloc_range(&self, begin: usize, end: usize) -> vfs::Result<()> {
+        for i in begin / BLKSIZE..end.div_ceil(BLKSIZE) {
+            if self.get_disk_block_id(i)? == 0 {
+                let disk_block_id = self.fs.alloc_zeroed_block_near(self.block_goal(i)?)?;
+                self.set_disk_block_id(i, disk_block_id)?;
             }
         }
         Ok(())
     }
     // Note: the _\w*_at method always return begin>size?0:begin<end?0:(min(size,end)-begin) when success
     /// Read/Write content, no matter what type it is
-    fn _io_at<F>(&self, begin: usize, end: usize, mut f: F) -> vfs::Result<usize>
+    ///
+    /// Holes are passed to `f` as block 0, or allocated first if `alloc`,
+    /// in which case shared blocks are also copied to be written.
+    /// Contiguous disk blocks are coalesced into one range, whose `end` may
+    /// exceed `BLKSIZE`, so that they are submitted to the device at once.
+    /// New blocks are allocated after the previous ones if possible.
+    fn _io_at<F>(&self, begin: usize, end: usize, alloc: bool, mut f: F) -> vfs::Result<usize>
     where
         F: FnMut(&Arc<dyn Device>, &BlockRange, usize) -> vfs::Result<()>,
     {
@@ -333,80 +763,399 @@ impl INodeImpl {
 
         // For each block
         let mut buf_offset = 0usize;
+        let mut pending: Option<BlockRange> = None;
+        // the last blocks mapped as (file block, disk block, number of blocks)
+        let mut mapped = (0, 0, 0);
         for mut range in iter {
-            range.block = self.get_disk_block_id(range.block)?;
-            f(&self.fs.device, &range, buf_offset)?;
-            buf_offset += range.len();
+            let (file_block, disk_block, len) = mapped;
+            let mut disk_block_id = if (file_block..file_block + len).contains(&range.block) {
+                match disk_block {
+                    0 => 0,
+                    block => block + range.block - file_block,
+                }
+            } else {
+                let (disk_block, len) = self.map_disk_blocks(range.block)?;
+                mapped = (range.block, disk_block, len);
+                disk_block
+            };
+            if disk_block_id == 0 && alloc {
+                let goal = match pending.as_ref() {
+                    Some(last) if last.block != 0 => last.block + last.end.div_ceil(BLKSIZE),
+                    _ => self.block_goal(range.block)?,
+                };
+                disk_block_id = if range.is_full() {
+                    self.fs.alloc_block_near(goal)?
+                } else {
+                    self.fs.alloc_zeroed_block_near(goal)?
+                };
+                self.set_disk_block_id(range.block, disk_block_id)?;
+                mapped = (0, 0, 0);
+            } else if alloc {
+                let new_block = self.unshare_block(range.block, disk_block_id)?;
+                if new_block != disk_block_id {
+                    mapped = (0, 0, 0);
+                }
+                disk_block_id = new_block;
+            }
+            range.block = disk_block_id;
+            if let Some(last) = pending.as_mut() {
+                // the next disk block, or still a hole
+                let next = match last.block {
+                    0 => 0,
+                    block => block + last.end / BLKSIZE,
+                };
+                if range.begin == 0 && last.end.is_multiple_of(BLKSIZE) && range.block == next {
+                    last.end += range.len();
+                    continue;
+                }
+                f(&self.fs.device, last, buf_offset)?;
+                buf_offset += last.len();
+            }
+            pending = Some(range);
+        }
+        if let Some(last) = pending {
+            f(&self.fs.device, &last, buf_offset)?;
+            buf_offset += last.len();
         }
         Ok(buf_offset)
     }
+    /// Whether the content is metadata, i.e. the entries or the name index of a directory
+    fn is_meta(&self) -> bool {
+        matches!(
+            self.disk_inode.read().type_,
+            FileType::Dir | FileType::DirIndex
+        )
+    }
     /// Read content, no matter what type it is
     fn _read_at(&self, offset: usize, buf: &mut [u8]) -> vfs::Result<usize> {
-        self._io_at(offset, offset + buf.len(), |device, range, offset| {
-            device.read_block(
-                range.block,
-                range.begin,
-                &mut buf[offset..offset + range.len()],
-            )
-        })
+        let meta = self.is_meta();
+        self._io_at(
+            offset,
+            offset + buf.len(),
+            false,
+            |device, range, offset| {
+                let buf = &mut buf[offset..offset + range.len()];
+                if range.block == 0 {
+                    // read a hole
+                    buf.fill(0);
+                    return Ok(());
+                }
+                match meta {
+                    true => self.fs.read_meta(range.block, range.begin, buf),
+                    false => device.read_block(range.block, range.begin, buf),
+                }
+            },
+        )
     }
     /// Write content, no matter what type it is
     fn _write_at(&self, offset: usize, buf: &[u8]) -> vfs::Result<usize> {
-        self._io_at(offset, offset + buf.len(), |device, range, offset| {
-            device.write_block(range.block, range.begin, &buf[offset..offset + range.len()])
+        let meta = self.is_meta();
+        self._io_at(offset, offset + buf.len(), true, |device, range, offset| {
+            let buf = &buf[offset..offset + range.len()];
+            match meta {
+                true => self.fs.write_meta(range.block, range.begin, buf),
+                false => device.write_block(range.block, range.begin, buf),
+            }
         })
     }
-    /// Clean content, no matter what type it is
-    fn _clean_at(&self, begin: usize, end: usize) -> vfs::Result<usize> {
-        static ZEROS: [u8; BLKSIZE] = [0; BLKSIZE];
-        self._io_at(begin, end, |device, range, _| {
-            device.write_block(range.block, range.begin, &ZEROS[..range.len()])
+    /// Vectored version of `_read_at`, all buffers are mapped in one pass
+    fn _read_vectored_at(&self, offset: usize, bufs: &mut [&mut [u8]]) -> vfs::Result<usize> {
+        let lens: Vec<usize> = bufs.iter().map(|buf| buf.len()).collect();
+        let total: usize = lens.iter().sum();
+        self._io_at(offset, offset + total, false, |device, range, offset| {
+            for (i, part, pos) in vectored_parts(&lens, offset, offset + range.len()) {
+                let buf = &mut bufs[i][part];
+                if range.block == 0 {
+                    // read a hole
+                    buf.fill(0);
+                } else {
+                    device.read_block(range.block, range.begin + pos - offset, buf)?;
+                }
+            }
+            Ok(())
         })
     }
-    fn nlinks_inc(&self) {
-        self.disk_inode.write().nlinks += 1;
+    /// Vectored version of `_write_at`, all buffers are mapped in one pass
+    fn _write_vectored_at(&self, offset: usize, bufs: &[&[u8]]) -> vfs::Result<usize> {
+        let lens: Vec<usize> = bufs.iter().map(|buf| buf.len()).collect();
+        let total: usize = lens.iter().sum();
+        self._io_at(offset, offset + total, true, |device, range, offset| {
+            for (i, part, pos) in vectored_parts(&lens, offset, offset + range.len()) {
+                device.write_block(range.block, range.begin + pos - offset, &bufs[i][part])?;
+            }
+            Ok(())
+        })
     }
-    fn nlinks_dec(&self) {
-        let mut disk_inode = self.disk_inode.write();
-        assert!(disk_inode.nlinks > 0);
-        disk_inode.nlinks -= 1;
+    /// Map content in `begin..end` to disk blocks like `_io_at`, without any I/O on them
+    fn _map_at(&self, begin: usize, end: usize, alloc: bool) -> vfs::Result<Vec<BlockRange>> {
+        let mut ranges = Vec::new();
+        self._io_at(begin, end, alloc, |_, range, _| {
+            ranges.push(range.clone());
+            Ok(())
+        })?;
+        Ok(ranges)
     }
-
-    pub fn link_inodeimpl(&self, name: &str, other: &Arc<INodeImpl>) -> vfs::Result<()> {
-        let info = self.metadata()?;
-        if info.type_ != vfs::FileType::Dir {
-            return Err(FsError::NotDir);
+    /// Async version of `_read_at`, the block mapping is still read synchronously
+    async fn _async_read_at(&self, offset: usize, buf: &mut [u8]) -> vfs::Result<usize> {
+        let ranges = self._map_at(offset, offset + buf.len(), false)?;
+        let mut buf_offset = 0usize;
+        for range in ranges {
+            let buf = &mut buf[buf_offset..buf_offset + range.len()];
+            if range.block == 0 {
+                // read a hole
+                buf.fill(0);
+            } else {
+                async_read_block(&*self.fs.device, range.block, range.begin, buf).await?;
+            }
+            buf_offset += range.len();
         }
-        if info.nlinks == 0 {
-            return Err(FsError::DirRemoved);
+        Ok(buf_offset)
+    }
+    /// Async version of `_write_at`, the blocks are allocated synchronously.
+    ///
+    /// The allocation commits before the data is written, so a crash between them
+    /// leaves stale content in the new blocks, but never a corrupted file system.
+    async fn _async_write_at(&self, offset: usize, buf: &[u8]) -> vfs::Result<usize> {
+        let ranges = self
+            .fs
+            .transaction(|| self._map_at(offset, offset + buf.len(), true))?;
+        let mut buf_offset = 0usize;
+        for range in ranges {
+            let buf = &buf[buf_offset..buf_offset + range.len()];
+            async_write_block(&*self.fs.device, range.block, range.begin, buf).await?;
+            buf_offset += range.len();
         }
-        if self.get_file_inode_id(name).is_some() {
-            return Err(FsError::EntryExist);
+        Ok(buf_offset)
+    }
+    /// Clean content, no matter what type it is
+    fn _clean_at(&self, begin: usize, end: usize) -> vfs::Result<()> {
+        let size = self.disk_inode.read().size as usize;
+        let iter = BlockIter {
+            begin: size.min(begin),
+            end: size.min(end),
+            block_size_log2: BLKSIZE_LOG2,
+        };
+        for range in iter {
+            let disk_block_id = self.get_disk_block_id(range.block)?;
+            if disk_block_id == 0 {
+                // already clean
+                continue;
+            }
+            let disk_block_id = self.unshare_block(range.block, disk_block_id)?;
+            let zeros = &ZEROS[..range.len()];
+            match self.is_meta() {
+                true => self.fs.write_meta(disk_block_id, range.begin, zeros)?,
+                false => self
+                    .fs
+                    .device
+                    .write_block(disk_block_id, range.begin, zeros)?,
+            }
+        }
+        Ok(())
+    }
+    /// Make file block `file_block_id` on disk block `disk_block_id` private before writing
+    /// to it, by copying it to a new block if shared. Return the disk block to write.
+    fn unshare_block(
+        &self,
+        file_block_id: BlockId,
+        disk_block_id: BlockId,
+    ) -> vfs::Result<BlockId> {
+        if !self.fs.is_shared_block(disk_block_id) {
+            return Ok(disk_block_id);
         }
-        let child = other;
-        if !Arc::ptr_eq(&self.fs, &child.fs) {
+        let new_block = self.fs.alloc_block_near(self.block_goal(file_block_id)?)?;
+        let mut data: [u8; BLKSIZE] = unsafe { uninit_memory() };
+        self.fs.device.read_block(disk_block_id, 0, &mut data)?;
+        self.fs.device.write_block(new_block, 0, &data)?;
+        if !self.fs.release_shared_block(disk_block_id) {
+            // the others have made their copies, the block is ours now
+            self.fs.free_block(new_block);
+            return Ok(disk_block_id);
+        }
+        self.set_disk_block_id(file_block_id, new_block)?;
+        Ok(new_block)
+    }
+    /// `inode` as a regular file in the same SFS as this file
+    fn sibling_file<'a>(&self, inode: &'a dyn INode) -> vfs::Result<&'a INodeImpl> {
+        let inode = inode
+            .downcast_ref::<INodeImpl>()
+            .ok_or(FsError::NotSameFs)?;
+        if !Arc::ptr_eq(&self.fs, &inode.fs) {
             return Err(FsError::NotSameFs);
         }
-        if child.metadata()?.type_ == vfs::FileType::Dir {
-            return Err(FsError::IsDir);
+        if self.disk_inode.read().type_ != FileType::File
+            || inode.disk_inode.read().type_ != FileType::File
+        {
+            return Err(FsError::NotFile);
+        }
+        Ok(inode)
+    }
+    /// Copy `len` bytes at `src_offset` of `src` to `dst_offset`, zeros are left as holes if sparse.
+    /// The file must be large enough.
+    fn _copy_range(
+        &self,
+        src: &INodeImpl,
+        src_offset: usize,
+        dst_offset: usize,
+        len: usize,
+    ) -> vfs::Result<()> {
+        let mut buf = vec![0u8; len.min(16 * BLKSIZE)];
+        let mut copied = 0;
+        while copied < len {
+            let chunk = buf.len().min(len - copied);
+            let read = src._read_at(src_offset + copied, &mut buf[..chunk])?;
+            if read == 0 {
+                break;
+            }
+            let dst = dst_offset + copied;
+            if self.sparse() && buf[..read].iter().all(|&b| b == 0) {
+                self._punch_hole(dst, dst + read)?;
+            } else {
+                self._write_at(dst, &buf[..read])?;
+            }
+            copied += read;
         }
-        let entry = DiskEntry {
-            id: child.id as u32,
-            name: Str256::from(name),
-        };
-        let disk_inode = self.disk_inode.write();
-        let old_size = disk_inode.size as usize;
-        self._resize(old_size + BLKSIZE)?;
-        self._write_at(old_size, entry.as_buf()).unwrap();
-        child.nlinks_inc();
         Ok(())
     }
+    /// Share the blocks of `src` in `src_offset..src_offset + len` at `dst_offset`.
+    /// The range is checked as `INode::clone_range`.
+    fn _clone_range(
+        &self,
+        src: &INodeImpl,
+        src_offset: usize,
+        dst_offset: usize,
+        len: usize,
+    ) -> vfs::Result<()> {
+        let dst_end = dst_offset + len;
+        if (self.disk_inode.read().size as usize) < dst_end {
+            self._resize(dst_end)?;
+        }
+        let (src_block, dst_block) = (src_offset / BLKSIZE, dst_offset / BLKSIZE);
+        for i in 0..len.div_ceil(BLKSIZE) {
+            let block = src.get_disk_block_id(src_block + i)?;
+            let old_block = self.get_disk_block_id(dst_block + i)?;
+            if block == old_block {
+                continue;
+            }
+            let new_block = if block == 0 || self.fs.share_block(block) {
+                block
+            } else {
+                // too many references, make a copy
+                let new_block = self.fs.alloc_block()?;
+                let mut data: [u8; BLKSIZE] = unsafe { uninit_memory() };
+                self.fs.device.read_block(block, 0, &mut data)?;
+                self.fs.device.write_block(new_block, 0, &data)?;
+                new_block
+            };
+            self.set_disk_block_id(dst_block + i, new_block)?;
+            if old_block != 0 {
+                self.fs.free_block(old_block);
+            }
+        }
+        Ok(())
+    }
+    /// Fill a page at `offset` from the content
+    fn load_page(&self, offset: usize, data: &mut [u8]) -> vfs::Result<()> {
+        let len = self._read_at(offset, data)?;
+        data[len..].fill(0);
+        Ok(())
+    }
+    /// Write back the cached pages written through memory mappings
+    fn writeback_pages(&self) -> vfs::Result<()> {
+        self.fs.pages.writeback(self.id, |offset, data| {
+            let size = self.disk_inode.read().size as usize;
+            let len = data.len().min(size.saturating_sub(offset));
+            self._write_at(offset, &data[..len])?;
+            Ok(())
+        })
+    }
+    /// Reload the cached pages in `begin..end` after the content is changed without writes
+    fn reload_pages(&self, begin: usize, end: usize) -> vfs::Result<()> {
+        self.fs.pages.reload(self.id, begin, end, |offset, data| {
+            self.load_page(offset, data)
+        })
+    }
+    /// Overlay `bufs` read at `offset` with the cached pages
+    fn overlay_vectored(&self, offset: usize, bufs: &mut [&mut [u8]], len: usize) {
+        let lens: Vec<usize> = bufs.iter().map(|buf| buf.len()).collect();
+        for (i, part, pos) in vectored_parts(&lens, 0, len) {
+            self.fs
+                .pages
+                .overlay(self.id, offset + pos, &mut bufs[i][part]);
+        }
+    }
+    /// The device of a device INode
+    fn device_inode(&self) -> Option<Arc<DeviceINode>> {
+        let device_inodes = self.fs.device_inodes.read();
+        device_inodes.get(&self.device_inode_id).cloned()
+    }
+    /// Write the INode if dirty, in the running transaction if journaled
+    fn write_disk_inode(&self) -> vfs::Result<()> {
+        let mut disk_inode = self.disk_inode.write();
+        if disk_inode.dirty() {
+            self.fs.write_meta(self.id, 0, disk_inode.as_buf())?;
+            disk_inode.sync();
+        }
+        Ok(())
+    }
+    fn nlinks_inc(&self) {
+        self.disk_inode.write().nlinks += 1;
+    }
+    /// Fail with `Corrupted` if the INode has no link left
+    fn nlinks_dec(&self) -> vfs::Result<()> {
+        let mut disk_inode = self.disk_inode.write();
+        if disk_inode.nlinks == 0 {
+            error!("INode {} has no link to remove", self.id);
+            return Err(FsError::Corrupted);
+        }
+        disk_inode.nlinks -= 1;
+        Ok(())
+    }
+
+    pub fn link_inodeimpl(&self, name: &str, other: &Arc<INodeImpl>) -> vfs::Result<()> {
+        self.fs.transaction(|| {
+            self.fs.check_writable()?;
+            let info = self.metadata()?;
+            if info.type_ != vfs::FileType::Dir {
+                return Err(FsError::NotDir);
+            }
+            if info.nlinks == 0 {
+                return Err(FsError::DirRemoved);
+            }
+            if self.get_file_inode_id(name)?.is_some() {
+                return Err(FsError::EntryExist);
+            }
+            let child = other;
+            if !Arc::ptr_eq(&self.fs, &child.fs) {
+                return Err(FsError::NotSameFs);
+            }
+            if child.metadata()?.type_ == vfs::FileType::Dir {
+                return Err(FsError::IsDir);
+            }
+            let child_type = child.disk_inode.read().type_;
+            self.insert_direntry(
+                &DiskEntry {
+                    id: child.id as u32,
+                    name: Str256::from(name),
+                },
+                child_type,
+            )?;
+            child.nlinks_inc();
+            self.fs.notifier.create(self.id, name, false);
+            self.fs.notifier.attrib(child.id);
+            Ok(())
+        })
+    }
 }
 
 impl vfs::INode for INodeImpl {
     fn read_at(&self, offset: usize, buf: &mut [u8]) -> vfs::Result<usize> {
         match self.disk_inode.read().type_ {
-            FileType::File => self._read_at(offset, buf),
+            FileType::File => {
+                let len = self._read_at(offset, buf)?;
+                self.fs.pages.overlay(self.id, offset, &mut buf[..len]);
+                Ok(len)
+            }
             FileType::SymLink => self._read_at(offset, buf),
             FileType::CharDevice => {
                 let device_inodes = self.fs.device_inodes.read();
@@ -422,13 +1171,17 @@ impl vfs::INode for INodeImpl {
     fn write_at(&self, offset: usize, buf: &[u8]) -> vfs::Result<usize> {
         let DiskINode { type_, size, .. } = **self.disk_inode.read();
         match type_ {
-            FileType::File | FileType::SymLink => {
+            FileType::File | FileType::SymLink => self.fs.transaction(|| {
+                self.fs.check_writable()?;
                 let end_offset = offset + buf.len();
                 if (size as usize) < end_offset {
                     self._resize(end_offset)?;
                 }
-                self._write_at(offset, buf)
-            }
+                let len = self._write_at(offset, buf)?;
+                self.fs.pages.update(self.id, offset, &buf[..len]);
+                self.fs.notifier.modify(self.id);
+                Ok(len)
+            }),
             FileType::CharDevice => {
                 let device_inodes = self.fs.device_inodes.write();
                 let device_inode = device_inodes.get(&self.device_inode_id);
@@ -440,6 +1193,93 @@ impl vfs::INode for INodeImpl {
             _ => Err(FsError::NotFile),
         }
     }
+    fn read_vectored_at(&self, offset: usize, bufs: &mut [&mut [u8]]) -> vfs::Result<usize> {
+        let type_ = self.disk_inode.read().type_;
+        match type_ {
+            FileType::File | FileType::SymLink => {
+                let len = self._read_vectored_at(offset, bufs)?;
+                self.overlay_vectored(offset, bufs, len);
+                Ok(len)
+            }
+            FileType::CharDevice => {
+                let device = self.device_inode().ok_or(FsError::DeviceError)?;
+                device.read_vectored_at(offset, bufs)
+            }
+            _ => Err(FsError::NotFile),
+        }
+    }
+    fn write_vectored_at(&self, offset: usize, bufs: &[&[u8]]) -> vfs::Result<usize> {
+        let DiskINode { type_, size, .. } = **self.disk_inode.read();
+        match type_ {
+            FileType::File | FileType::SymLink => self.fs.transaction(|| {
+                self.fs.check_writable()?;
+                let end_offset = offset + bufs.iter().map(|buf| buf.len()).sum::<usize>();
+                if (size as usize) < end_offset {
+                    self._resize(end_offset)?;
+                }
+                let len = self._write_vectored_at(offset, bufs)?;
+                let lens: Vec<usize> = bufs.iter().map(|buf| buf.len()).collect();
+                for (i, part, pos) in vectored_parts(&lens, 0, len) {
+                    self.fs.pages.update(self.id, offset + pos, &bufs[i][part]);
+                }
+                self.fs.notifier.modify(self.id);
+                Ok(len)
+            }),
+            FileType::CharDevice => {
+                let device = self.device_inode().ok_or(FsError::DeviceError)?;
+                device.write_vectored_at(offset, bufs)
+            }
+            _ => Err(FsError::NotFile),
+        }
+    }
+    fn async_read_at<'a>(
+        &'a self,
+        offset: usize,
+        buf: &'a mut [u8],
+    ) -> BoxFuture<'a, vfs::Result<usize>> {
+        Box::pin(async move {
+            let type_ = self.disk_inode.read().type_;
+            match type_ {
+                FileType::File | FileType::SymLink => {
+                    let len = self._async_read_at(offset, buf).await?;
+                    self.fs.pages.overlay(self.id, offset, &mut buf[..len]);
+                    Ok(len)
+                }
+                FileType::CharDevice => {
+                    let device = self.device_inode().ok_or(FsError::DeviceError)?;
+                    device.async_read_at(offset, buf).await
+                }
+                _ => Err(FsError::NotFile),
+            }
+        })
+    }
+    fn async_write_at<'a>(
+        &'a self,
+        offset: usize,
+        buf: &'a [u8],
+    ) -> BoxFuture<'a, vfs::Result<usize>> {
+        Box::pin(async move {
+            let DiskINode { type_, size, .. } = **self.disk_inode.read();
+            match type_ {
+                FileType::File | FileType::SymLink => {
+                    self.fs.check_writable()?;
+                    let end_offset = offset + buf.len();
+                    if (size as usize) < end_offset {
+                        self.fs.transaction(|| self._resize(end_offset))?;
+                    }
+                    let len = self._async_write_at(offset, buf).await?;
+                    self.fs.pages.update(self.id, offset, &buf[..len]);
+                    self.fs.notifier.modify(self.id);
+                    Ok(len)
+                }
+                FileType::CharDevice => {
+                    let device = self.device_inode().ok_or(FsError::DeviceError)?;
+                    device.async_write_at(offset, buf).await
+                }
+                _ => Err(FsError::NotFile),
+            }
+        })
+    }
     fn poll(&self) -> vfs::Result<vfs::PollStatus> {
         Ok(vfs::PollStatus {
             read: true,
@@ -449,7 +1289,18 @@ impl vfs::INode for INodeImpl {
     }
     /// the size returned here is logical size(entry num for directory), not the disk space used.
     fn metadata(&self) -> vfs::Result<vfs::Metadata> {
+        let has_owner = self.fs.super_block.read().has_owner();
         let disk_inode = self.disk_inode.read();
+        // images before REVISION_OWNER have no permission and ownership
+        let (mode, uid, gid) = if has_owner {
+            (
+                disk_inode.mode,
+                disk_inode.uid as usize,
+                disk_inode.gid as usize,
+            )
+        } else {
+            (0o777, 0, 0)
+        };
         Ok(vfs::Metadata {
             dev: 0,
             inode: self.id,
@@ -458,231 +1309,564 @@ impl vfs::INode for INodeImpl {
                 FileType::Dir => disk_inode.size as usize,
                 FileType::CharDevice => 0,
                 FileType::BlockDevice => 0,
-                _ => panic!("Unknown file type"),
+                _ => {
+                    error!("INode {} has an unknown type", self.id);
+                    return Err(FsError::Corrupted);
+                }
             },
-            mode: 0o777,
-            type_: vfs::FileType::from(disk_inode.type_),
+            mode,
+            type_: vfs::FileType::try_from(disk_inode.type_)?,
             blocks: disk_inode.blocks as usize,
             atime: disk_inode.atime,
             mtime: disk_inode.mtime,
             ctime: disk_inode.ctime,
             nlinks: disk_inode.nlinks as usize,
-            uid: 0,
-            gid: 0,
+            uid,
+            gid,
             blk_size: BLKSIZE,
             rdev: self.device_inode_id,
         })
     }
     fn set_metadata(&self, metadata: &vfs::Metadata) -> vfs::Result<()> {
-        let mut disk_inode = self.disk_inode.write();
-        disk_inode.atime = metadata.atime;
-        disk_inode.mtime = metadata.mtime;
-        disk_inode.ctime = metadata.ctime;
-        Ok(())
+        self.fs.transaction(|| {
+            self.fs.check_writable()?;
+            let has_owner = self.fs.super_block.read().has_owner();
+            let mut disk_inode = self.disk_inode.write();
+            if has_owner {
+                disk_inode.mode = metadata.mode & 0o7777;
+                disk_inode.uid = u32::try_from(metadata.uid).map_err(|_| FsError::InvalidParam)?;
+                disk_inode.gid = u32::try_from(metadata.gid).map_err(|_| FsError::InvalidParam)?;
+            } else if (metadata.mode, metadata.uid, metadata.gid) != (0o777, 0, 0) {
+                // can not be stored in old images
+                return Err(FsError::NotSupported);
+            }
+            disk_inode.atime = metadata.atime;
+            disk_inode.mtime = metadata.mtime;
+            disk_inode.ctime = metadata.ctime;
+            drop(disk_inode);
+            self.fs.notifier.attrib(self.id);
+            Ok(())
+        })
     }
     fn sync_all(&self) -> vfs::Result<()> {
-        let mut disk_inode = self.disk_inode.write();
-        if disk_inode.dirty() {
-            self.fs
-                .device
-                .write_block(self.id, 0, disk_inode.as_buf())?;
-            disk_inode.sync();
+        if self.fs.read_only {
+            return Ok(());
         }
-        Ok(())
+        self.fs.transaction(|| {
+            self.writeback_pages()?;
+            self.write_disk_inode()
+        })?;
+        // wait for the operations running at the same time
+        self.fs.commit(true)
     }
     fn sync_data(&self) -> vfs::Result<()> {
         self.sync_all()
     }
     fn resize(&self, len: usize) -> vfs::Result<()> {
-        if self.disk_inode.read().type_ != FileType::File
-            && self.disk_inode.read().type_ != FileType::SymLink
-        {
-            return Err(FsError::NotFile);
+        self.fs.transaction(|| {
+            self.fs.check_writable()?;
+            if self.disk_inode.read().type_ != FileType::File
+                && self.disk_inode.read().type_ != FileType::SymLink
+            {
+                return Err(FsError::NotFile);
+            }
+            self._resize(len)?;
+            self.fs.notifier.modify(self.id);
+            Ok(())
+        })
+    }
+    fn fallocate(&self, flags: vfs::FallocateFlags, offset: usize, len: usize) -> vfs::Result<()> {
+        self.fs.transaction(|| {
+            self.fs.check_writable()?;
+            flags.check()?;
+            if self.disk_inode.read().type_ != FileType::File {
+                return Err(FsError::NotFile);
+            }
+            if (flags.punch_hole || flags.zero_range) && !self.sparse() {
+                return Err(FsError::NotSupported);
+            }
+            let end = offset.checked_add(len).ok_or(FsError::InvalidParam)?;
+            if len == 0 {
+                return Err(FsError::InvalidParam);
+            }
+            if end > MAX_FILE_SIZE {
+                return Err(FsError::FileTooBig);
+            }
+            self.writeback_pages()?;
+            if flags.punch_hole {
+                self._punch_hole(offset, end)?;
+            } else {
+                if !flags.keep_size && end > self.disk_inode.read().size as usize {
+                    self._resize(end)?;
+                } else {
+                    self._grow_blocks(end.div_ceil(BLKSIZE))?;
+                }
+                if flags.zero_range {
+                    self._punch_hole(offset, end)?;
+                }
+                self._alloc_range(offset, end)?;
+            }
+            self.reload_pages(offset, end)?;
+            self.fs.notifier.modify(self.id);
+            Ok(())
+        })
+    }
+    fn seek_data(&self, offset: usize) -> vfs::Result<Option<usize>> {
+        let size = self.disk_inode.read().size as usize;
+        if offset >= size {
+            return Ok(None);
+        }
+        for i in offset / BLKSIZE..size.div_ceil(BLKSIZE) {
+            if self.get_disk_block_id(i)? != 0 {
+                return Ok(Some(offset.max(i * BLKSIZE)));
+            }
+        }
+        Ok(None)
+    }
+    fn seek_hole(&self, offset: usize) -> vfs::Result<Option<usize>> {
+        let size = self.disk_inode.read().size as usize;
+        if offset >= size {
+            return Ok(None);
+        }
+        for i in offset / BLKSIZE..size.div_ceil(BLKSIZE) {
+            if self.get_disk_block_id(i)? == 0 {
+                return Ok(Some(offset.max(i * BLKSIZE)));
+            }
         }
-        self._resize(len)
+        Ok(Some(size))
+    }
+    fn copy_range(
+        &self,
+        src: &dyn INode,
+        src_offset: usize,
+        dst_offset: usize,
+        len: usize,
+    ) -> vfs::Result<usize> {
+        self.fs.transaction(|| {
+            self.fs.check_writable()?;
+            let src = match self.sibling_file(src) {
+                Ok(src) => src,
+                Err(FsError::NotSameFs) => {
+                    return vfs::generic_copy_range(self, src, src_offset, dst_offset, len)
+                }
+                Err(e) => return Err(e),
+            };
+            let src_size = src.disk_inode.read().size as usize;
+            let len = len.min(src_size.saturating_sub(src_offset));
+            let dst_end = dst_offset.checked_add(len).ok_or(FsError::InvalidParam)?;
+            if dst_end > MAX_FILE_SIZE {
+                return Err(FsError::FileTooBig);
+            }
+            if src.id == self.id && src_offset < dst_end && dst_offset < src_offset + len {
+                return Err(FsError::InvalidParam);
+            }
+            if len == 0 {
+                return Ok(0);
+            }
+            // share the whole blocks if the offsets are equally aligned, and copy the rest
+            let dst_size = self.disk_inode.read().size as usize;
+            let (mut head, mut body) = (len, 0);
+            if self.fs.super_block.read().has_refmap()
+                && src_offset % BLKSIZE == dst_offset % BLKSIZE
+            {
+                head = len.min((BLKSIZE - src_offset % BLKSIZE) % BLKSIZE);
+                body = (len - head) / BLKSIZE * BLKSIZE;
+                if src_offset + len == src_size && dst_end >= dst_size {
+                    // the last partial block can be shared too
+                    body = len - head;
+                }
+            }
+            if dst_size < dst_end {
+                self._resize(dst_end)?;
+            }
+            src.writeback_pages()?;
+            self.writeback_pages()?;
+            self._copy_range(src, src_offset, dst_offset, head)?;
+            self._clone_range(src, src_offset + head, dst_offset + head, body)?;
+            let done = head + body;
+            self._copy_range(src, src_offset + done, dst_offset + done, len - done)?;
+            self.reload_pages(dst_offset, dst_end)?;
+            self.fs.notifier.modify(self.id);
+            Ok(len)
+        })
+    }
+    fn clone_range(
+        &self,
+        src: &dyn INode,
+        src_offset: usize,
+        dst_offset: usize,
+        len: usize,
+    ) -> vfs::Result<()> {
+        self.fs.transaction(|| {
+            self.fs.check_writable()?;
+            let src = self.sibling_file(src)?;
+            if !self.fs.super_block.read().has_refmap() {
+                return Err(FsError::NotSupported);
+            }
+            let src_size = src.disk_inode.read().size as usize;
+            if !src_offset.is_multiple_of(BLKSIZE)
+                || !dst_offset.is_multiple_of(BLKSIZE)
+                || src_offset > src_size
+            {
+                return Err(FsError::InvalidParam);
+            }
+            let len = match len {
+                0 => src_size - src_offset,
+                len => len.min(src_size - src_offset),
+            };
+            let dst_end = dst_offset.checked_add(len).ok_or(FsError::InvalidParam)?;
+            if dst_end > MAX_FILE_SIZE {
+                return Err(FsError::FileTooBig);
+            }
+            // an unaligned end is only allowed at the end of both files
+            let dst_size = self.disk_inode.read().size as usize;
+            if !len.is_multiple_of(BLKSIZE) && (src_offset + len != src_size || dst_end < dst_size)
+            {
+                return Err(FsError::InvalidParam);
+            }
+            if src.id == self.id && src_offset < dst_end && dst_offset < src_offset + len {
+                return Err(FsError::InvalidParam);
+            }
+            src.writeback_pages()?;
+            self.writeback_pages()?;
+            self._clone_range(src, src_offset, dst_offset, len)?;
+            self.reload_pages(dst_offset, dst_end)?;
+            self.fs.notifier.modify(self.id);
+            Ok(())
+        })
     }
     fn create2(
         &self,
         name: &str,
         type_: vfs::FileType,
-        _mode: u32,
+        mode: u32,
         data: usize,
     ) -> vfs::Result<Arc<dyn vfs::INode>> {
-        let info = self.metadata()?;
-        if info.type_ != vfs::FileType::Dir {
-            return Err(FsError::NotDir);
-        }
-        if info.nlinks == 0 {
-            return Err(FsError::DirRemoved);
-        }
-
-        // Ensure the name is not exist
-        if self.get_file_inode_id(name).is_some() {
-            return Err(FsError::EntryExist);
-        }
-
-        // Create new INode
-        let inode = match type_ {
-            vfs::FileType::File => self.fs.new_inode_file()?,
-            vfs::FileType::SymLink => self.fs.new_inode_symlink()?,
-            vfs::FileType::Dir => self.fs.new_inode_dir(self.id)?,
-            vfs::FileType::CharDevice => self.fs.new_inode_chardevice(data)?,
-            _ => return Err(vfs::FsError::InvalidParam),
-        };
-
-        // Write new entry
-        self.append_direntry(&DiskEntry {
-            id: inode.id as u32,
-            name: Str256::from(name),
-        })?;
-        inode.nlinks_inc();
-        if type_ == vfs::FileType::Dir {
-            inode.nlinks_inc(); //for .
-            self.nlinks_inc(); //for ..
-        }
-
-        Ok(inode)
-    }
+        self.fs.transaction(|| {
+            self.fs.check_writable()?;
+            let info = self.metadata()?;
+            if info.type_ != vfs::FileType::Dir {
+                return Err(FsError::NotDir);
+            }
+            if info.nlinks == 0 {
+                return Err(FsError::DirRemoved);
+            }
+            check_name(name)?;
 
-    fn link(&self, name: &str, other: &Arc<dyn INode>) -> vfs::Result<()> {
-        let info = self.metadata()?;
-        if info.type_ != vfs::FileType::Dir {
-            return Err(FsError::NotDir);
-        }
-        if info.nlinks == 0 {
-            return Err(FsError::DirRemoved);
-        }
-        if self.get_file_inode_id(name).is_some() {
-            return Err(FsError::EntryExist);
-        }
-        let child = other
-            .downcast_ref::<INodeImpl>()
-            .ok_or(FsError::NotSameFs)?;
-        if !Arc::ptr_eq(&self.fs, &child.fs) {
-            return Err(FsError::NotSameFs);
-        }
-        if child.metadata()?.type_ == vfs::FileType::Dir {
-            return Err(FsError::IsDir);
-        }
-        self.append_direntry(&DiskEntry {
-            id: child.id as u32,
-            name: Str256::from(name),
-        })?;
-        child.nlinks_inc();
-        Ok(())
-    }
-    fn unlink(&self, name: &str) -> vfs::Result<()> {
-        let info = self.metadata()?;
-        if info.type_ != vfs::FileType::Dir {
-            return Err(FsError::NotDir);
-        }
-        if info.nlinks == 0 {
-            return Err(FsError::DirRemoved);
-        }
-        if name == "." {
-            return Err(FsError::IsDir);
-        }
-        if name == ".." {
-            return Err(FsError::IsDir);
-        }
+            // Ensure the name is not exist
+            if self.get_file_inode_id(name)?.is_some() {
+                return Err(FsError::EntryExist);
+            }
 
-        let (inode_id, entry_id) = self
-            .get_file_inode_and_entry_id(name)
-            .ok_or(FsError::EntryNotFound)?;
-        let inode = self.fs.get_inode(inode_id);
+            // Create new INode
+            let inode = match type_ {
+                vfs::FileType::File => self.fs.new_inode_file()?,
+                vfs::FileType::SymLink => self.fs.new_inode_symlink()?,
+                vfs::FileType::Dir => self.fs.new_inode_dir(self.id)?,
+                vfs::FileType::CharDevice => self.fs.new_inode_chardevice(data)?,
+                _ => return Err(vfs::FsError::InvalidParam),
+            };
+            inode.disk_inode.write().mode = mode as u16 & 0o7777;
 
-        let type_ = inode.disk_inode.read().type_;
-        if type_ == FileType::Dir {
-            // only . and ..
-            if inode.disk_inode.read().size as usize / DIRENT_SIZE > 2 {
-                return Err(FsError::DirNotEmpty);
+            // Write new entry
+            let inode_type = inode.disk_inode.read().type_;
+            self.insert_direntry(
+                &DiskEntry {
+                    id: inode.id as u32,
+                    name: Str256::from(name),
+                },
+                inode_type,
+            )?;
+            inode.nlinks_inc();
+            if type_ == vfs::FileType::Dir {
+                inode.nlinks_inc(); //for .
+                self.nlinks_inc(); //for ..
             }
-        }
-        inode.nlinks_dec();
-        if type_ == FileType::Dir {
-            inode.nlinks_dec(); //for .
-            self.nlinks_dec(); //for ..
-        }
-        self.remove_direntry(entry_id)?;
+            self.fs
+                .notifier
+                .create(self.id, name, type_ == vfs::FileType::Dir);
 
-        Ok(())
+            Ok(inode as Arc<dyn vfs::INode>)
+        })
     }
-    fn move_(&self, old_name: &str, target: &Arc<dyn INode>, new_name: &str) -> vfs::Result<()> {
-        let info = self.metadata()?;
-        if info.type_ != vfs::FileType::Dir {
-            return Err(FsError::NotDir);
-        }
-        if info.nlinks == 0 {
-            return Err(FsError::DirRemoved);
-        }
-        if old_name == "." {
-            return Err(FsError::IsDir);
-        }
-        if old_name == ".." {
-            return Err(FsError::IsDir);
-        }
 
-        let dest = target
-            .downcast_ref::<INodeImpl>()
-            .ok_or(FsError::NotSameFs)?;
-        let dest_info = dest.metadata()?;
-        if !Arc::ptr_eq(&self.fs, &dest.fs) {
-            return Err(FsError::NotSameFs);
-        }
-        if dest_info.type_ != vfs::FileType::Dir {
-            return Err(FsError::NotDir);
-        }
-        if dest_info.nlinks == 0 {
-            return Err(FsError::DirRemoved);
-        }
-        if let Some((_, id)) = dest.get_file_inode_and_entry_id(new_name) {
-            dest.remove_direntry(id)?;
+    fn symlink(&self, name: &str, target: &str) -> vfs::Result<Arc<dyn vfs::INode>> {
+        self.fs.transaction(|| {
+            let inode = self.create2(name, vfs::FileType::SymLink, 0o777, 0)?;
+            if let Err(e) = inode.write_at(0, target.as_bytes()) {
+                if let Err(unlink_error) = self.unlink(name) {
+                    error!("failed to remove symlink {}: {:?}", name, unlink_error);
+                }
+                return Err(e);
+            }
+            Ok(inode)
+        })
+    }
+
+    fn read_link(&self) -> vfs::Result<String> {
+        let DiskINode { type_, size, .. } = **self.disk_inode.read();
+        if type_ != FileType::SymLink {
+            return Err(FsError::InvalidParam);
         }
+        let mut buf = vec![0u8; size as usize];
+        self._read_at(0, &mut buf)?;
+        String::from_utf8(buf).map_err(|_| FsError::InvalidParam)
+    }
 
-        let (inode_id, entry_id) = self
-            .get_file_inode_and_entry_id(old_name)
-            .ok_or(FsError::EntryNotFound)?;
-        if info.inode == dest_info.inode {
-            // rename: in place modify name
-            self.write_direntry(
-                entry_id,
+    fn link(&self, name: &str, other: &Arc<dyn INode>) -> vfs::Result<()> {
+        self.fs.transaction(|| {
+            self.fs.check_writable()?;
+            let info = self.metadata()?;
+            if info.type_ != vfs::FileType::Dir {
+                return Err(FsError::NotDir);
+            }
+            if info.nlinks == 0 {
+                return Err(FsError::DirRemoved);
+            }
+            check_name(name)?;
+            if self.get_file_inode_id(name)?.is_some() {
+                return Err(FsError::EntryExist);
+            }
+            let child = other
+                .downcast_ref::<INodeImpl>()
+                .ok_or(FsError::NotSameFs)?;
+            if !Arc::ptr_eq(&self.fs, &child.fs) {
+                return Err(FsError::NotSameFs);
+            }
+            if child.metadata()?.type_ == vfs::FileType::Dir {
+                return Err(FsError::IsDir);
+            }
+            let child_type = child.disk_inode.read().type_;
+            self.insert_direntry(
                 &DiskEntry {
-                    id: inode_id as u32,
-                    name: Str256::from(new_name),
+                    id: child.id as u32,
+                    name: Str256::from(name),
                 },
+                child_type,
             )?;
-        } else {
-            // move
-            dest.append_direntry(&DiskEntry {
-                id: inode_id as u32,
-                name: Str256::from(new_name),
-            })?;
+            child.nlinks_inc();
+            self.fs.notifier.create(self.id, name, false);
+            self.fs.notifier.attrib(child.id);
+            Ok(())
+        })
+    }
+    fn unlink(&self, name: &str) -> vfs::Result<()> {
+        self.fs.transaction(|| {
+            self.fs.check_writable()?;
+            let info = self.metadata()?;
+            if info.type_ != vfs::FileType::Dir {
+                return Err(FsError::NotDir);
+            }
+            if info.nlinks == 0 {
+                return Err(FsError::DirRemoved);
+            }
+            if name == "." {
+                return Err(FsError::IsDir);
+            }
+            if name == ".." {
+                return Err(FsError::IsDir);
+            }
+
+            let (inode_id, entry_id) = self
+                .get_file_inode_and_entry_id(name)?
+                .ok_or(FsError::EntryNotFound)?;
+            let inode = self.fs.get_inode(inode_id)?;
+
+            let type_ = inode.disk_inode.read().type_;
+            if type_ == FileType::Dir && !inode.is_empty_dir()? {
+                return Err(FsError::DirNotEmpty);
+            }
+            inode.nlinks_dec()?;
+            if type_ == FileType::Dir {
+                inode.nlinks_dec()?; //for .
+                self.nlinks_dec()?; //for ..
+            }
             self.remove_direntry(entry_id)?;
+            let nlinks = inode.disk_inode.read().nlinks as usize;
+            self.fs
+                .notifier
+                .delete(self.id, name, inode_id, type_ == FileType::Dir, nlinks);
+
+            Ok(())
+        })
+    }
+    fn rename(
+        &self,
+        old_name: &str,
+        target: &Arc<dyn INode>,
+        new_name: &str,
+        flags: vfs::RenameFlags,
+    ) -> vfs::Result<()> {
+        self.fs.transaction(|| {
+            self.fs.check_writable()?;
+            let info = self.metadata()?;
+            if info.type_ != vfs::FileType::Dir {
+                return Err(FsError::NotDir);
+            }
+            if info.nlinks == 0 {
+                return Err(FsError::DirRemoved);
+            }
+            if old_name == "." || new_name == "." {
+                return Err(FsError::IsDir);
+            }
+            if old_name == ".." || new_name == ".." {
+                return Err(FsError::IsDir);
+            }
+            check_name(new_name)?;
+            if flags.noreplace && flags.exchange {
+                return Err(FsError::InvalidParam);
+            }
+
+            let dest = target
+                .downcast_ref::<INodeImpl>()
+                .ok_or(FsError::NotSameFs)?;
+            let dest_info = dest.metadata()?;
+            if !Arc::ptr_eq(&self.fs, &dest.fs) {
+                return Err(FsError::NotSameFs);
+            }
+            if dest_info.type_ != vfs::FileType::Dir {
+                return Err(FsError::NotDir);
+            }
+            if dest_info.nlinks == 0 {
+                return Err(FsError::DirRemoved);
+            }
+
+            let (inode_id, entry_id) = self
+                .get_file_inode_and_entry_id(old_name)?
+                .ok_or(FsError::EntryNotFound)?;
+            let inode = self.fs.get_inode(inode_id)?;
+            let inode_type = inode.disk_inode.read().type_;
+            let is_dir = inode_type == FileType::Dir;
+            let replaced = dest.get_file_inode_and_entry_id(new_name)?;
+            if flags.noreplace && replaced.is_some() {
+                return Err(FsError::EntryExist);
+            }
+            if flags.exchange && replaced.is_none() {
+                return Err(FsError::EntryNotFound);
+            }
+            // cross directory, the parent of moved directories changes
+            let moved = info.inode != dest_info.inode;
+            if moved && is_dir {
+                dest.check_not_beneath(inode_id)?;
+            }
+
+            match replaced {
+                None if !moved => {
+                    // rename: in place modify name
+                    self.rename_direntry(
+                        entry_id,
+                        old_name,
+                        &DiskEntry {
+                            id: inode_id as u32,
+                            name: Str256::from(new_name),
+                        },
+                        inode_type,
+                    )?;
+                }
+                None => {
+                    dest.insert_direntry(
+                        &DiskEntry {
+                            id: inode_id as u32,
+                            name: Str256::from(new_name),
+                        },
+                        inode_type,
+                    )?;
+                    self.remove_direntry(entry_id)?;
+                }
+                Some((other_id, other_entry_id)) if flags.exchange => {
+                    let other = self.fs.get_inode(other_id)?;
+                    let other_type = other.disk_inode.read().type_;
+                    let other_is_dir = other_type == FileType::Dir;
+                    if moved && other_is_dir {
+                        self.check_not_beneath(other_id)?;
+                    }
+                    dest.write_direntry(
+                        other_entry_id,
+                        &DiskEntry {
+                            id: inode_id as u32,
+                            name: Str256::from(new_name),
+                        },
+                        inode_type,
+                    )?;
+                    self.write_direntry(
+                        entry_id,
+                        &DiskEntry {
+                            id: other_id as u32,
+                            name: Str256::from(old_name),
+                        },
+                        other_type,
+                    )?;
+                    if moved && other_is_dir {
+                        other.set_parent(self.id)?;
+                        dest.nlinks_dec()?;
+                        self.nlinks_inc();
+                    }
+                    let notifier = &self.fs.notifier;
+                    notifier.rename(dest.id, new_name, self.id, old_name, other_id, other_is_dir);
+                }
+                Some((other_id, _)) if other_id == inode_id => {
+                    // both names are links to the same inode, do nothing
+                    return Ok(());
+                }
+                Some((other_id, other_entry_id)) => {
+                    let other = self.fs.get_inode(other_id)?;
+                    let other_disk_inode = other.disk_inode.read();
+                    let other_is_dir = other_disk_inode.type_ == FileType::Dir;
+                    if is_dir && !other_is_dir {
+                        return Err(FsError::NotDir);
+                    }
+                    if !is_dir && other_is_dir {
+                        return Err(FsError::IsDir);
+                    }
+                    drop(other_disk_inode);
+                    if other_is_dir && !other.is_empty_dir()? {
+                        return Err(FsError::DirNotEmpty);
+                    }
+                    dest.write_direntry(
+                        other_entry_id,
+                        &DiskEntry {
+                            id: inode_id as u32,
+                            name: Str256::from(new_name),
+                        },
+                        inode_type,
+                    )?;
+                    self.remove_direntry(entry_id)?;
+                    // the replaced inode is freed with its last link
+                    other.nlinks_dec()?;
+                    if other_is_dir {
+                        other.nlinks_dec()?; //for .
+                        dest.nlinks_dec()?; //for ..
+                    }
+                    let nlinks = other.disk_inode.read().nlinks as usize;
+                    self.fs.notifier.unlinked(other_id, nlinks);
+                }
+            }
 
-            let inode = self.fs.get_inode(inode_id);
-            if inode.metadata()?.type_ == vfs::FileType::Dir {
-                self.nlinks_dec();
+            if moved && is_dir {
+                inode.set_parent(dest.id)?;
+                self.nlinks_dec()?;
                 dest.nlinks_inc();
             }
-        }
-        Ok(())
+            let notifier = &self.fs.notifier;
+            notifier.rename(self.id, old_name, dest.id, new_name, inode_id, is_dir);
+            Ok(())
+        })
     }
     fn find(&self, name: &str) -> vfs::Result<Arc<dyn vfs::INode>> {
         let info = self.metadata()?;
         if info.type_ != vfs::FileType::Dir {
             return Err(FsError::NotDir);
         }
-        let inode_id = self.get_file_inode_id(name).ok_or(FsError::EntryNotFound)?;
-        Ok(self.fs.get_inode(inode_id))
+        let inode_id = self
+            .get_file_inode_id(name)?
+            .ok_or(FsError::EntryNotFound)?;
+        Ok(self.fs.get_inode(inode_id)?)
     }
     fn get_entry(&self, id: usize) -> vfs::Result<String> {
         if self.disk_inode.read().type_ != FileType::Dir {
             return Err(FsError::NotDir);
         }
-        if id >= self.disk_inode.read().size as usize / DIRENT_SIZE {
-            return Err(FsError::EntryNotFound);
-        };
-        let entry = self.read_direntry(id)?;
+        let entry = self.nth_direntry(id)?;
         Ok(String::from(entry.name.as_ref()))
     }
 
@@ -690,18 +1874,42 @@ impl vfs::INode for INodeImpl {
         if self.disk_inode.read().type_ != FileType::Dir {
             return Err(FsError::NotDir);
         }
-        if id >= self.disk_inode.read().size as usize / DIRENT_SIZE {
-            return Err(FsError::EntryNotFound);
-        };
-        let entry = self.read_direntry(id)?;
+        let entry = self.nth_direntry(id)?;
         Ok((
-            self.fs.get_inode(entry.id as usize).metadata()?,
+            self.fs.get_inode(entry.id as usize)?.metadata()?,
             String::from(entry.name.as_ref()),
         ))
     }
 
+    /// The cookie is the position of direntry, which is stable since unlink leaves a free slot,
+    /// or merges a compact entry into the previous one.
+    fn read_dir(&self, cookie: usize) -> vfs::Result<Option<vfs::DirEntry>> {
+        if self.disk_inode.read().type_ != FileType::Dir {
+            return Err(FsError::NotDir);
+        }
+        let found = match self.scan_direntries(cookie, |found| Ok(Some(found)))? {
+            Some(found) => found,
+            None => return Ok(None),
+        };
+        // compact entries record the type, without loading the INode
+        let type_ = match found.type_ {
+            Some(type_) => type_,
+            None => {
+                let inode = self.fs.get_inode(found.entry.id as INodeId)?;
+                let type_ = inode.disk_inode.read().type_;
+                type_
+            }
+        };
+        Ok(Some(vfs::DirEntry {
+            name: String::from(found.entry.name.as_ref()),
+            inode: found.entry.id as INodeId,
+            type_: vfs::FileType::try_from(type_)?,
+            next_cookie: found.next,
+        }))
+    }
+
     fn io_control(&self, _cmd: u32, _data: usize) -> vfs::Result<usize> {
-        if self.metadata().unwrap().type_ != vfs::FileType::CharDevice {
+        if self.metadata()?.type_ != vfs::FileType::CharDevice {
             return Err(FsError::IOCTLError);
         }
         let device_inodes = self.fs.device_inodes.read();
@@ -714,8 +1922,26 @@ impl vfs::INode for INodeImpl {
             }
         }
     }
-    fn mmap(&self, _area: MMapArea) -> vfs::Result<()> {
-        Err(FsError::NotSupported)
+    fn mmap(&self, area: MMapArea) -> vfs::Result<()> {
+        if self.disk_inode.read().type_ != FileType::File {
+            return Err(FsError::NotFile);
+        }
+        if !area.offset.is_multiple_of(PAGE_SIZE) {
+            return Err(FsError::InvalidParam);
+        }
+        if area.is_shared() && area.prot & MMapArea::PROT_WRITE != 0 {
+            self.fs.check_writable()?;
+        }
+        Ok(())
+    }
+    fn map_page(&self, index: usize) -> vfs::Result<PinnedPage> {
+        if self.disk_inode.read().type_ != FileType::File {
+            return Err(FsError::NotFile);
+        }
+        let offset = index.checked_mul(PAGE_SIZE).ok_or(FsError::InvalidParam)?;
+        self.fs
+            .pages
+            .get_or_load(self.id, index, |data| self.load_page(offset, data))
     }
     fn fs(&self) -> Arc<dyn vfs::FileSystem> {
         self.fs.clone()
@@ -726,18 +1952,59 @@ impl vfs::INode for INodeImpl {
 }
 
 impl Drop for INodeImpl {
-    /// Auto sync when drop
+    /// Auto sync when drop.
+    /// If journaled, the changes are committed with the running or the next transaction,
+    /// since it may be dropped by a commit.
     fn drop(&mut self) {
-        self.sync_all()
-            .expect("Failed to sync when dropping the SimpleFileSystem Inode");
+        if self.fs.read_only {
+            return;
+        }
+        if let Err(e) = self
+            .writeback_pages()
+            .and_then(|()| self.write_disk_inode())
+        {
+            error!("failed to sync INode {} when dropping: {:?}", self.id, e);
+            // the changes are lost
+            self.disk_inode.get_mut().sync();
+        }
         if self.disk_inode.read().nlinks == 0 {
-            self._resize(0).unwrap();
+            // keep the INode allocated if its blocks can not be freed, it is leaked but consistent
+            if let Err(e) = self._resize(0) {
+                error!("failed to free removed INode {}: {:?}", self.id, e);
+                return;
+            }
             self.disk_inode.write().sync();
+            self.fs.pages.remove_inode(self.id);
             self.fs.free_block(self.id);
+            // the name index is freed when dropped
+            match self.dir_index() {
+                Ok(Some(index)) => {
+                    if let Err(e) = index.nlinks_dec() {
+                        error!("failed to free the name index of {}: {:?}", self.id, e);
+                    }
+                }
+                Ok(None) => {}
+                Err(e) => error!("failed to free the name index of {}: {:?}", self.id, e),
+            }
         }
     }
 }
 
+/// Options of `SimpleFileSystem::create_with_options`
+#[derive(Debug, Default, Clone)]
+pub struct CreateOptions {
+    /// number of blocks of the journal, through which metadata is written, 0 without a journal
+    pub journal_blocks: usize,
+    /// whether large directories are indexed by names, see FEATURE_DIR_INDEX
+    pub dir_index: bool,
+    /// whether directories consist of compact entries, see FEATURE_COMPACT_DIRENT
+    pub compact_dirents: bool,
+    /// whether blocks are mapped by extent trees, see FEATURE_EXTENTS
+    pub extents: bool,
+    /// whether files can share blocks by `clone_range`, counted in a refmap after the free map
+    pub refmap: bool,
+}
+
 /// filesystem for sfs
 ///
 /// ## 内部可变性
@@ -748,77 +2015,204 @@ pub struct SimpleFileSystem {
     /// on-disk superblock
     super_block: RwLock<Dirty<SuperBlock>>,
     /// blocks in use are mared 0
-    free_map: RwLock<Dirty<BitVec<Lsb0, u8>>>,
+    free_map: RwLock<DirtyBlocks<BitVec<Lsb0, u8>>>,
+    /// extra references to each block shared by cloned files, empty without the refmap
+    ref_map: RwLock<DirtyBlocks<Vec<u8>>>,
     /// inode list
-    inodes: RwLock<BTreeMap<INodeId, Weak<INodeImpl>>>,
+    inodes: INodeCache<INodeImpl>,
+    /// pages of files mapped into memory
+    pages: PageCache,
     /// device
     device: Arc<dyn Device>,
     /// Pointer to self, used by INodes
     self_ptr: Weak<SimpleFileSystem>,
     /// device inode
     device_inodes: RwLock<BTreeMap<usize, Arc<DeviceINode>>>,
+    /// change events of INodes
+    notifier: Notifier,
+    /// never write to the device
+    read_only: bool,
+    /// metadata is written through it if the image has one
+    journal: Option<Journal>,
+    /// held shared by operations changing the metadata, and exclusively by commits
+    transactions: RwLock<()>,
+    /// blocks freed since the last sync without a journal, which are not reused before it
+    freed: Mutex<Vec<BlockId>>,
 }
 
 impl SimpleFileSystem {
     /// Load SFS from device
     pub fn open(device: Arc<dyn Device>) -> vfs::Result<Arc<Self>> {
-        let super_block = device.load_struct::<SuperBlock>(BLKN_SUPER)?;
+        Self::load(device, false)
+    }
+    /// Load SFS from device without writing to it, all changes fail with `ReadOnly`
+    pub fn open_read_only(device: Arc<dyn Device>) -> vfs::Result<Arc<Self>> {
+        Self::load(device, true)
+    }
+    fn load(device: Arc<dyn Device>, read_only: bool) -> vfs::Result<Arc<Self>> {
+        let mut super_block = device.load_struct::<SuperBlock>(BLKN_SUPER)?;
         if !super_block.check() {
             return Err(FsError::WrongFs);
         }
+        let mut journal = None;
+        if super_block.has_journal() {
+            let refmap_blocks = match super_block.has_refmap() {
+                true => super_block.refmap_blocks as usize,
+                false => 0,
+            };
+            let j = Journal::new(
+                super_block.journal_start as usize,
+                super_block.journal_blocks as usize,
+                operation_blocks(
+                    super_block.blocks as usize,
+                    super_block.freemap_blocks as usize,
+                    refmap_blocks,
+                    super_block.extents(),
+                ),
+            );
+            j.recover(&*device, read_only)?;
+            // the super block may be changed by the replayed transaction
+            j.read(&*device, BLKN_SUPER, 0, super_block.as_buf_mut())?;
+            journal = Some(j);
+        }
+        let read = |id: BlockId, buf: &mut [u8]| match journal.as_ref() {
+            Some(journal) => journal.read(&*device, id, 0, buf),
+            None => device.read_block(id, 0, buf),
+        };
         let mut freemap_disk = vec![0u8; BLKSIZE * super_block.freemap_blocks as usize];
         for i in 0..super_block.freemap_blocks as usize {
-            device.read_block(
+            read(
                 BLKN_FREEMAP + i,
-                0,
                 &mut freemap_disk[i * BLKSIZE..(i + 1) * BLKSIZE],
             )?;
         }
+        let mut ref_map = Vec::new();
+        if super_block.has_refmap() {
+            ref_map.resize(BLK_NREF * super_block.refmap_blocks as usize, 0);
+            let begin = BLKN_FREEMAP + super_block.freemap_blocks as usize;
+            read(begin, &mut ref_map)?;
+        }
+        let free_map = DirtyBlocks::new(
+            BitVec::from_vec(freemap_disk),
+            super_block.freemap_blocks as usize,
+        );
+        let refmap_blocks = ref_map.len() / BLK_NREF;
+        let ref_map = DirtyBlocks::new(ref_map, refmap_blocks);
 
-        Ok(SimpleFileSystem {
+        let sfs = SimpleFileSystem {
             super_block: RwLock::new(Dirty::new(super_block)),
-            free_map: RwLock::new(Dirty::new(BitVec::from_vec(freemap_disk))),
-            inodes: RwLock::new(BTreeMap::new()),
+            free_map: RwLock::new(free_map),
+            ref_map: RwLock::new(ref_map),
+            inodes: INodeCache::new(),
+            pages: PageCache::default(),
             device,
             self_ptr: Weak::default(),
             device_inodes: RwLock::new(BTreeMap::new()),
+            notifier: Notifier::new(),
+            read_only,
+            journal,
+            transactions: RwLock::new(()),
+            freed: Mutex::default(),
         }
-        .wrap())
+        .wrap();
+        // fail here rather than in `root_inode`
+        sfs.get_inode(BLKN_ROOT)?;
+        Ok(sfs)
     }
     /// Create a new SFS on blank disk
     pub fn create(device: Arc<dyn Device>, space: usize) -> vfs::Result<Arc<Self>> {
-        let blocks = (space + BLKSIZE - 1) / BLKSIZE;
-        let freemap_blocks = (space + BLKBITS * BLKSIZE - 1) / BLKBITS / BLKSIZE;
+        Self::create_with_options(device, space, &CreateOptions::default())
+    }
+    /// Create a new SFS on blank disk with optional features
+    pub fn create_with_options(
+        device: Arc<dyn Device>,
+        space: usize,
+        options: &CreateOptions,
+    ) -> vfs::Result<Arc<Self>> {
+        let journal_blocks = options.journal_blocks;
+        let (blocks, freemap_blocks, refmap_blocks) = layout(space, options.refmap);
         assert!(blocks >= 16, "space too small");
+        if journal_blocks != 0 && journal_blocks < Self::min_journal_blocks(space, options) {
+            return Err(FsError::InvalidParam);
+        }
+        let journal_start = BLKN_FREEMAP + freemap_blocks + refmap_blocks;
+        let data_start = journal_start + journal_blocks;
+        if data_start >= blocks {
+            return Err(FsError::NoDeviceSpace);
+        }
+        let mut features = 0;
+        if options.dir_index {
+            features |= FEATURE_DIR_INDEX;
+        }
+        if options.compact_dirents {
+            features |= FEATURE_COMPACT_DIRENT;
+        }
+        if options.extents {
+            features |= FEATURE_EXTENTS;
+        }
 
         let super_block = SuperBlock {
             magic: MAGIC,
             blocks: blocks as u32,
-            unused_blocks: (blocks - BLKN_FREEMAP - freemap_blocks) as u32,
+            unused_blocks: (blocks - data_start) as u32,
             info: Str32::from(DEFAULT_INFO),
             freemap_blocks: freemap_blocks as u32,
+            revision: REVISION,
+            refmap_blocks: refmap_blocks as u32,
+            journal_start: journal_start as u32,
+            journal_blocks: journal_blocks as u32,
+            features,
         };
         let free_map = {
             let mut bitset = BitVec::with_capacity(freemap_blocks * BLKBITS);
             bitset.extend(core::iter::repeat(false).take(freemap_blocks * BLKBITS));
-            for i in (BLKN_FREEMAP + freemap_blocks)..blocks {
+            for i in data_start..blocks {
                 bitset.set(i, true);
             }
             bitset
         };
+        let journal = match journal_blocks {
+            0 => {
+                // read back by allocations and syncs, all blocks are in use until written
+                for block in BLKN_SUPER..journal_start {
+                    device.write_block(block, 0, &ZEROS)?;
+                }
+                None
+            }
+            _ => {
+                // no transaction to replay
+                device.write_block(journal_start, 0, &ZEROS)?;
+                Some(Journal::new(
+                    journal_start,
+                    journal_blocks,
+                    operation_blocks(blocks, freemap_blocks, refmap_blocks, options.extents),
+                ))
+            }
+        };
 
         let sfs = SimpleFileSystem {
             super_block: RwLock::new(Dirty::new_dirty(super_block)),
-            free_map: RwLock::new(Dirty::new_dirty(free_map)),
-            inodes: RwLock::new(BTreeMap::new()),
+            free_map: RwLock::new(DirtyBlocks::new_dirty(free_map, freemap_blocks)),
+            ref_map: RwLock::new(DirtyBlocks::new_dirty(
+                vec![0; BLK_NREF * refmap_blocks],
+                refmap_blocks,
+            )),
+            inodes: INodeCache::new(),
+            pages: PageCache::default(),
             device,
             self_ptr: Weak::default(),
             device_inodes: RwLock::new(BTreeMap::new()),
+            notifier: Notifier::new(),
+            read_only: false,
+            journal,
+            transactions: RwLock::new(()),
+            freed: Mutex::default(),
         }
         .wrap();
 
         // Init root INode
         let root = sfs._new_inode(BLKN_ROOT, Dirty::new_dirty(DiskINode::new_dir()));
+        root.disk_inode.write().mode = 0o777;
         root.init_direntry(BLKN_ROOT)?;
         root.nlinks_inc(); //for .
         root.nlinks_inc(); //for ..(root's parent is itself)
@@ -826,6 +2220,17 @@ impl SimpleFileSystem {
 
         Ok(sfs)
     }
+    /// Min number of blocks of the journal of SFS created with `options`, so that the largest
+    /// transaction of an operation fits
+    pub fn min_journal_blocks(space: usize, options: &CreateOptions) -> usize {
+        let (blocks, freemap_blocks, refmap_blocks) = layout(space, options.refmap);
+        journal::min_blocks(operation_blocks(
+            blocks,
+            freemap_blocks,
+            refmap_blocks,
+            options.extents,
+        ))
+    }
     /// Wrap pure SimpleFileSystem with Arc
     /// Used in constructors
     fn wrap(self) -> Arc<Self> {
@@ -840,33 +2245,228 @@ impl SimpleFileSystem {
         unsafe { Arc::from_raw(ptr) }
     }
 
-    /// Allocate a block, return block id
-    fn alloc_block(&self) -> Option<usize> {
+    /// Fail with `ReadOnly` if opened read-only
+    /// Fail with `ReadOnly` if opened read-only, or the journal is aborted
+    fn check_writable(&self) -> vfs::Result<()> {
+        if self.read_only || self.journal.as_ref().is_some_and(Journal::is_aborted) {
+            return Err(FsError::ReadOnly);
+        }
+        Ok(())
+    }
+    /// Allocate a block, return block id.
+    /// Fail with `Corrupted` if the free map and the count of unused blocks disagree.
+    fn alloc_block(&self) -> vfs::Result<usize> {
+        self.alloc_block_near(0)
+    }
+    /// Allocate a block at `goal`, or the first free one after it
+    fn alloc_block_near(&self, goal: BlockId) -> vfs::Result<usize> {
         let mut free_map = self.free_map.write();
-        let id = free_map.alloc();
-        if let Some(block_id) = id {
-            let mut super_block = self.super_block.write();
-            if super_block.unused_blocks == 0 {
-                free_map.set(block_id, true);
-                return None;
-            }
-            super_block.unused_blocks -= 1; // will not underflow
-            trace!("alloc block {:#x}", block_id);
-        } else {
-            let super_block = self.super_block.read();
-            panic!("{:?}", super_block)
+        let mut block_id = free_map.alloc(goal);
+        if block_id.is_none() && self.release_freed(&mut free_map) {
+            warn!("reuse the blocks freed since the last sync");
+            block_id = free_map.alloc(goal);
         }
-        id
+        let mut super_block = self.super_block.write();
+        let block_id = match block_id {
+            Some(block_id) => block_id,
+            None if super_block.unused_blocks == 0 => return Err(FsError::NoDeviceSpace),
+            None => {
+                error!("no free block, but {:?}", **super_block);
+                return Err(FsError::Corrupted);
+            }
+        };
+        if super_block.unused_blocks == 0 {
+            free_map.set_bit(block_id, true);
+            return Err(FsError::NoDeviceSpace);
+        }
+        if self.journal.is_none() && !self.read_only {
+            if let Err(e) = self.mark_in_use(block_id) {
+                free_map.set_bit(block_id, true);
+                return Err(e);
+            }
+        }
+        super_block.unused_blocks -= 1; // will not underflow
+        trace!("alloc block {:#x}", block_id);
+        Ok(block_id)
     }
-    /// Free a block
+    /// Without a journal, mark `block_id` in use on the device before anything refers to it,
+    /// e.g. an indirect block written in place. Unless it is freed since the last sync,
+    /// and still in use on the device. The count is written first not to run ahead of it.
+    fn mark_in_use(&self, block_id: BlockId) -> vfs::Result<()> {
+        let (offset, bit) = (block_id / 8, 1u8 << (block_id % 8));
+        let mut byte = [0u8];
+        self.device.read_block(BLKN_FREEMAP, offset, &mut byte)?;
+        if byte[0] & bit == 0 {
+            return Ok(());
+        }
+        let field = core::mem::offset_of!(SuperBlock, unused_blocks);
+        let mut unused = 0u32;
+        self.device
+            .read_block(BLKN_SUPER, field, unused.as_buf_mut())?;
+        self.device
+            .write_block(BLKN_SUPER, field, unused.saturating_sub(1).as_buf())?;
+        self.device
+            .write_block(BLKN_FREEMAP, offset, &[byte[0] & !bit])
+    }
+    /// Allocate a block and fill it with zeros
+    fn alloc_zeroed_block(&self) -> vfs::Result<usize> {
+        self.alloc_zeroed_block_near(0)
+    }
+    /// Allocate a block like `alloc_block_near`, and fill it with zeros
+    fn alloc_zeroed_block_near(&self, goal: BlockId) -> vfs::Result<usize> {
+        let block_id = self.alloc_block_near(goal)?;
+        self.device.write_block(block_id, 0, &ZEROS)?;
+        Ok(block_id)
+    }
+    /// Free a block, or drop a reference to it if it is shared.
+    /// If journaled, it is freed when the running transaction commits. Otherwise it is counted
+    /// unused at once, but marked free by the next sync, after the INodes which may refer to it
+    /// are written.
     fn free_block(&self, block_id: usize) {
-        let mut free_map = self.free_map.write();
-        assert!(!free_map[block_id]);
-        free_map.set(block_id, true);
+        if self.release_shared_block(block_id) {
+            return;
+        }
+        match self.journal.as_ref() {
+            Some(journal) => journal.free(block_id),
+            None => {
+                self.super_block.write().unused_blocks += 1;
+                self.freed.lock().push(block_id);
+            }
+        }
+    }
+    /// Mark the blocks freed without a journal free in `free_map`, return whether there are any
+    fn release_freed(&self, free_map: &mut DirtyBlocks<BitVec<Lsb0, u8>>) -> bool {
+        let freed = core::mem::take(&mut *self.freed.lock());
+        for &block_id in freed.iter() {
+            if free_map[block_id] {
+                error!("block {:#x} is freed twice", block_id);
+                continue;
+            }
+            free_map.set_bit(block_id, true);
+            trace!("free block {:#x}", block_id);
+        }
+        !freed.is_empty()
+    }
+    /// Mark a block free in `free_map`, log and skip it if it is free already
+    fn release_block(&self, free_map: &mut DirtyBlocks<BitVec<Lsb0, u8>>, block_id: usize) {
+        if free_map[block_id] {
+            error!("block {:#x} is freed twice", block_id);
+            return;
+        }
+        free_map.set_bit(block_id, true);
         self.super_block.write().unused_blocks += 1;
         trace!("free block {:#x}", block_id);
     }
 
+    /// Write the references added to the refmap block `id` since the last sync, but keep
+    /// those dropped on the device, since the INodes there may still refer to the blocks
+    fn write_shares_added(&self, refs_new: &[u8], id: BlockId) -> vfs::Result<()> {
+        let mut refs = [0u8; BLKSIZE];
+        self.device.read_block(id, 0, &mut refs)?;
+        for (old, &new) in refs.iter_mut().zip(refs_new) {
+            *old = (*old).max(new);
+        }
+        self.device.write_block(id, 0, &refs)
+    }
+
+    /// Add a reference to a block for a cloned file.
+    /// Return false if not supported, or the block has too many references.
+    fn share_block(&self, block_id: usize) -> bool {
+        let mut ref_map = self.ref_map.write();
+        match ref_map.get(block_id) {
+            Some(&shares) if shares < MAX_BLOCK_SHARES => {
+                ref_map.block_mut(block_id / BLK_NREF)[block_id] = shares + 1;
+                true
+            }
+            _ => false,
+        }
+    }
+    /// Drop a reference to a block if it is shared, return whether it was shared
+    fn release_shared_block(&self, block_id: usize) -> bool {
+        let mut ref_map = self.ref_map.write();
+        match ref_map.get(block_id) {
+            Some(&shares) if shares > 0 => {
+                ref_map.block_mut(block_id / BLK_NREF)[block_id] = shares - 1;
+                true
+            }
+            _ => false,
+        }
+    }
+    /// Whether a block is shared by files
+    fn is_shared_block(&self, block_id: usize) -> bool {
+        self.ref_map.read().get(block_id).is_some_and(|&n| n > 0)
+    }
+
+    /// Read metadata at `offset` of block `id`, as changed by the running transaction
+    fn read_meta(&self, id: BlockId, offset: usize, buf: &mut [u8]) -> vfs::Result<()> {
+        match self.journal.as_ref() {
+            Some(journal) => journal.read(&*self.device, id, offset, buf),
+            None => self.device.read_block(id, offset, buf),
+        }
+    }
+    /// Write metadata at `offset` of block `id`, in the running transaction if journaled
+    fn write_meta(&self, id: BlockId, offset: usize, buf: &[u8]) -> vfs::Result<()> {
+        match self.journal.as_ref() {
+            Some(journal) => journal.write(&*self.device, id, offset, buf),
+            None => self.device.write_block(id, offset, buf),
+        }
+    }
+    /// Run `f` as an operation changing the metadata. Its changes are committed
+    /// with those of the operations running at the same time, when the last one ends.
+    fn transaction<T>(&self, f: impl FnOnce() -> vfs::Result<T>) -> vfs::Result<T> {
+        if self.journal.as_ref().is_some_and(Journal::nearly_full) {
+            // left to the running operations if there are any, as usual
+            self.commit(false)?;
+        }
+        let operation = self.transactions.read();
+        let ret = f();
+        drop(operation);
+        self.commit(false)?;
+        ret
+    }
+    /// Commit the metadata changed so far as a transaction, nothing to do without a journal.
+    /// Unless `wait`, it is left to the running operations if there are any.
+    fn commit(&self, wait: bool) -> vfs::Result<()> {
+        let journal = match self.journal.as_ref() {
+            Some(journal) if !self.read_only => journal,
+            _ => return Ok(()),
+        };
+        let _commit = match wait {
+            true => self.transactions.write(),
+            false => match self.transactions.try_write() {
+                Some(commit) => commit,
+                None => return Ok(()),
+            },
+        };
+        for inode in self.inodes.alive() {
+            inode.write_disk_inode()?;
+        }
+        let mut free_map = self.free_map.write();
+        for block_id in journal.take_freed() {
+            self.release_block(&mut free_map, block_id);
+        }
+        let mut super_block = self.super_block.write();
+        if super_block.dirty() {
+            journal.write(&*self.device, BLKN_SUPER, 0, super_block.as_buf())?;
+            super_block.sync();
+        }
+        // only the blocks of the maps changed, the refmap alone may be larger than the journal
+        for i in free_map.dirty_blocks() {
+            let data = &free_map.as_buf()[i * BLKSIZE..(i + 1) * BLKSIZE];
+            journal.write(&*self.device, BLKN_FREEMAP + i, 0, data)?;
+        }
+        free_map.sync();
+        let mut ref_map = self.ref_map.write();
+        let begin = BLKN_FREEMAP + super_block.freemap_blocks as usize;
+        for i in ref_map.dirty_blocks() {
+            let data = &ref_map[i * BLK_NREF..(i + 1) * BLK_NREF];
+            journal.write(&*self.device, begin + i, 0, data)?;
+        }
+        ref_map.sync();
+        drop((free_map, super_block, ref_map));
+        journal.commit(&*self.device)
+    }
+
     pub fn new_device_inode(&self, device_inode_id: usize, device_inode: Arc<DeviceINode>) {
         self.device_inodes
             .write()
@@ -883,98 +2483,132 @@ impl SimpleFileSystem {
             fs: self.self_ptr.upgrade().unwrap(),
             device_inode_id,
         });
-        self.inodes.write().insert(id, Arc::downgrade(&inode));
+        self.inodes.insert(id, &inode);
         inode
     }
 
     /// Get inode by id. Load if not in memory.
-    /// ** Must ensure it's a valid INode **
-    fn get_inode(&self, id: INodeId) -> Arc<INodeImpl> {
-        assert!(!self.free_map.read()[id]);
+    /// Fail with `Corrupted` if `id` is not an allocated block.
+    fn get_inode(&self, id: INodeId) -> vfs::Result<Arc<INodeImpl>> {
+        if self.free_map.read().get(id).as_deref() != Some(&false) {
+            error!("INode {} is not allocated", id);
+            return Err(FsError::Corrupted);
+        }
 
-        // In the BTreeSet and not weak.
-        if let Some(inode) = self.inodes.read().get(&id) {
-            if let Some(inode) = inode.upgrade() {
-                return inode;
-            }
+        if let Some(inode) = self.inodes.get(id) {
+            return Ok(inode);
+        }
+        // Load if not in memory
+        let mut disk_inode: DiskINode = unsafe { uninit_memory() };
+        self.read_meta(id, 0, disk_inode.as_buf_mut())?;
+        if !self.super_block.read().has_dir_index() {
+            disk_inode.index = 0;
         }
-        // Load if not in set, or is weak ref.
-        let disk_inode = Dirty::new(self.device.load_struct::<DiskINode>(id).unwrap());
-        self._new_inode(id, disk_inode)
+        let disk_inode = Dirty::new(disk_inode);
+        Ok(self._new_inode(id, disk_inode))
     }
     /// Create a new INode file
     fn new_inode_file(&self) -> vfs::Result<Arc<INodeImpl>> {
-        let id = self.alloc_block().ok_or(FsError::NoDeviceSpace)?;
+        let id = self.alloc_block()?;
         let disk_inode = Dirty::new_dirty(DiskINode::new_file());
         Ok(self._new_inode(id, disk_inode))
     }
     /// Create a new INode symlink
     fn new_inode_symlink(&self) -> vfs::Result<Arc<INodeImpl>> {
-        let id = self.alloc_block().ok_or(FsError::NoDeviceSpace)?;
+        let id = self.alloc_block()?;
         let disk_inode = Dirty::new_dirty(DiskINode::new_symlink());
         Ok(self._new_inode(id, disk_inode))
     }
     /// Create a new INode dir
     fn new_inode_dir(&self, parent: INodeId) -> vfs::Result<Arc<INodeImpl>> {
-        let id = self.alloc_block().ok_or(FsError::NoDeviceSpace)?;
+        let id = self.alloc_block()?;
         let disk_inode = Dirty::new_dirty(DiskINode::new_dir());
         let inode = self._new_inode(id, disk_inode);
         inode.init_direntry(parent)?;
         Ok(inode)
     }
+    /// Create a new name index of a directory, unlinked until it is filled
+    fn new_inode_dir_index(&self) -> vfs::Result<Arc<INodeImpl>> {
+        let id = self.alloc_block()?;
+        let disk_inode = Dirty::new_dirty(DiskINode::new_dir_index());
+        Ok(self._new_inode(id, disk_inode))
+    }
     /// Create a new INode chardevice
     pub fn new_inode_chardevice(&self, device_inode_id: usize) -> vfs::Result<Arc<INodeImpl>> {
-        let id = self.alloc_block().ok_or(FsError::NoDeviceSpace)?;
-        let disk_inode = Dirty::new_dirty(DiskINode::new_chardevice(device_inode_id));
-        let new_inode = self._new_inode(id, disk_inode);
-        Ok(new_inode)
-    }
-    fn flush_weak_inodes(&self) {
-        let mut inodes = self.inodes.write();
-        let remove_ids: Vec<_> = inodes
-            .iter()
-            .filter(|(_, inode)| inode.upgrade().is_none())
-            .map(|(&id, _)| id)
-            .collect();
-        for id in remove_ids.iter() {
-            inodes.remove(id);
-        }
+        self.transaction(|| {
+            self.check_writable()?;
+            let id = self.alloc_block()?;
+            let disk_inode = Dirty::new_dirty(DiskINode::new_chardevice(device_inode_id));
+            let new_inode = self._new_inode(id, disk_inode);
+            Ok(new_inode)
+        })
     }
 }
 
 impl vfs::FileSystem for SimpleFileSystem {
     /// Write back super block if dirty
     fn sync(&self) -> vfs::Result<()> {
-        // order is important, see issue #18
+        if self.read_only {
+            return Ok(());
+        }
+        if self.journal.is_some() {
+            // each INode in a transaction of its own, which fits in the journal
+            self.inodes.flush();
+            for inode in self.inodes.alive() {
+                self.transaction(|| inode.writeback_pages())?;
+            }
+            // wait for the operations running at the same time
+            return self.commit(true);
+        }
+        // order is important, see issue #18: a crash must not leave a block in use but free.
+        // The blocks allocated are marked by `mark_in_use`, and the references added before
+        // the INodes are written. Those freed or dropped are written after the INodes.
+        // The pages are written back before the maps are locked, since they allocate blocks.
+        self.inodes.flush();
+        let inodes = self.inodes.alive();
+        for inode in inodes.iter() {
+            inode.writeback_pages()?;
+        }
+        let refmap_start = BLKN_FREEMAP + self.super_block.read().freemap_blocks as usize;
+        {
+            let ref_map = self.ref_map.read();
+            for i in ref_map.dirty_blocks() {
+                let data = &ref_map[i * BLK_NREF..(i + 1) * BLK_NREF];
+                self.write_shares_added(data, refmap_start + i)?;
+            }
+        }
+        for inode in inodes.iter() {
+            inode.sync_all()?;
+        }
+        self.device.sync()?;
         let mut free_map = self.free_map.write();
+        self.release_freed(&mut free_map);
         let mut super_block = self.super_block.write();
+        let mut ref_map = self.ref_map.write();
+        for i in free_map.dirty_blocks() {
+            let data = &free_map.as_buf()[i * BLKSIZE..(i + 1) * BLKSIZE];
+            self.device.write_block(BLKN_FREEMAP + i, 0, data)?;
+        }
+        free_map.sync();
+        for i in ref_map.dirty_blocks() {
+            let data = &ref_map[i * BLK_NREF..(i + 1) * BLK_NREF];
+            self.device.write_block(refmap_start + i, 0, data)?;
+        }
+        ref_map.sync();
+        // the blocks freed are counted after they are marked free
         if super_block.dirty() {
             self.device
                 .write_at(BLKSIZE * BLKN_SUPER, super_block.as_buf())?;
             super_block.sync();
         }
-        if free_map.dirty() {
-            let data = free_map.as_buf();
-            for i in 0..super_block.freemap_blocks as usize {
-                self.device.write_at(
-                    BLKSIZE * (BLKN_FREEMAP + i),
-                    &data[i * BLKSIZE..(i + 1) * BLKSIZE],
-                )?;
-            }
-            free_map.sync();
-        }
-        self.flush_weak_inodes();
-        for inode in self.inodes.read().values() {
-            if let Some(inode) = inode.upgrade() {
-                inode.sync_all()?;
-            }
-        }
         self.device.sync()?;
         Ok(())
     }
 
+    /// Opening checks the root INode, so this panics only if it can not be read again later
     fn root_inode(&self) -> Arc<dyn vfs::INode> {
         self.get_inode(BLKN_ROOT)
+            .expect("failed to load the root INode")
         // let root = self.get_inode(BLKN_ROOT);
         // root.create("dev", vfs::FileType::Dir, 0).expect("fail to create dev"); // what's mode?
         // return root;
@@ -993,31 +2627,158 @@ impl vfs::FileSystem for SimpleFileSystem {
             namemax: MAX_FNAME_LEN,
         }
     }
+
+    fn notifier(&self) -> Option<&Notifier> {
+        Some(&self.notifier)
+    }
 }
 
 impl Drop for SimpleFileSystem {
     /// Auto sync when drop
     fn drop(&mut self) {
-        self.sync()
-            .expect("Failed to sync when dropping the SimpleFileSystem");
+        if let Err(e) = self.sync() {
+            error!("failed to sync when dropping the SimpleFileSystem: {:?}", e);
+            // the changes are lost
+            self.super_block.get_mut().sync();
+            self.free_map.get_mut().sync();
+            self.ref_map.get_mut().sync();
+        }
     }
 }
 
+/// Blocks of the whole image, of the free map and of the refmap if any, for SFS of `space` bytes
+fn layout(space: usize, refmap: bool) -> (usize, usize, usize) {
+    let blocks = space.div_ceil(BLKSIZE);
+    let freemap_blocks = space.div_ceil(BLKBITS * BLKSIZE);
+    let refmap_blocks = match refmap {
+        true => blocks.div_ceil(BLK_NREF),
+        false => 0,
+    };
+    (blocks, freemap_blocks, refmap_blocks)
+}
+
+/// Blocks of INodes and directories an operation changes at most,
+/// e.g. those of both directories, the INode moved and the one replaced by a rename
+const OPERATION_INODE_BLOCKS: usize = 16;
+
+/// Blocks of metadata an operation changes at most: the super block, the maps, the mappings
+/// of two files as large as the image, as a file is cloned to another, and a few INodes.
+/// The slots of a name index rebuilt are not counted, a rebuild larger than the journal aborts it.
+fn operation_blocks(
+    blocks: usize,
+    freemap_blocks: usize,
+    refmap_blocks: usize,
+    extents: bool,
+) -> usize {
+    let data = blocks.min(MAX_FILE_SIZE / BLKSIZE + 1);
+    let mapping = match extents {
+        true => {
+            let mut nodes = data.div_ceil(EXTENT_BLOCK_NENTRY);
+            let mut total = nodes;
+            while nodes > EXTENT_ROOT_NENTRY {
+                nodes = nodes.div_ceil(EXTENT_BLOCK_NENTRY);
+                total += nodes;
+            }
+            total
+        }
+        false if data <= MAX_NBLOCK_DIRECT => 0,
+        false if data <= MAX_NBLOCK_INDIRECT => 1,
+        false => 2 + (data - MAX_NBLOCK_INDIRECT).div_ceil(BLK_NENTRY),
+    };
+    1 + freemap_blocks + refmap_blocks + 2 * mapping + OPERATION_INODE_BLOCKS
+}
+
 trait BitsetAlloc {
-    fn alloc(&mut self) -> Option<usize>;
+    /// Take the first free bit from `goal`, wrapping around at the end
+    fn alloc(&mut self, goal: usize) -> Option<usize>;
 }
 
-impl BitsetAlloc for BitVec<Lsb0, u8> {
-    fn alloc(&mut self) -> Option<usize> {
+impl BitsetAlloc for DirtyBlocks<BitVec<Lsb0, u8>> {
+    fn alloc(&mut self, goal: usize) -> Option<usize> {
         // TODO: more efficient
-        let id = (0..self.len()).find(|&i| self[i]);
+        let goal = goal.min(self.len());
+        let id = (goal..self.len()).chain(0..goal).find(|&i| self[i]);
         if let Some(id) = id {
-            self.set(id, false);
+            self.set_bit(id, false);
         }
         id
     }
 }
 
+/// A map of blocks like `Dirty`, but which knows the blocks of it changed.
+/// Changing it through `DerefMut` marks all of its blocks dirty.
+struct DirtyBlocks<T> {
+    value: T,
+    /// number of blocks of the map
+    count: usize,
+    /// the blocks changed, by their index in the map
+    blocks: BTreeSet<usize>,
+    all: bool,
+}
+
+impl<T> DirtyBlocks<T> {
+    fn new(value: T, count: usize) -> Self {
+        DirtyBlocks {
+            value,
+            count,
+            blocks: BTreeSet::new(),
+            all: false,
+        }
+    }
+    fn new_dirty(value: T, count: usize) -> Self {
+        let mut map = Self::new(value, count);
+        map.all = true;
+        map
+    }
+    fn dirty(&self) -> bool {
+        self.all || !self.blocks.is_empty()
+    }
+    /// Reset dirty
+    fn sync(&mut self) {
+        self.blocks.clear();
+        self.all = false;
+    }
+    /// Change the block `index` of the map
+    fn block_mut(&mut self, index: usize) -> &mut T {
+        self.blocks.insert(index);
+        &mut self.value
+    }
+    /// Indexes of the blocks changed
+    fn dirty_blocks(&self) -> Vec<usize> {
+        match self.all {
+            true => (0..self.count).collect(),
+            false => self.blocks.iter().copied().collect(),
+        }
+    }
+}
+
+impl DirtyBlocks<BitVec<Lsb0, u8>> {
+    fn set_bit(&mut self, index: usize, value: bool) {
+        self.block_mut(index / BLKBITS).set(index, value);
+    }
+}
+
+impl<T> Deref for DirtyBlocks<T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        &self.value
+    }
+}
+
+impl<T> DerefMut for DirtyBlocks<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.all = true;
+        &mut self.value
+    }
+}
+
+impl<T> Drop for DirtyBlocks<T> {
+    /// Guard it is not dirty when dropping
+    fn drop(&mut self) {
+        assert!(!self.dirty(), "data dirty when dropping");
+    }
+}
+
 impl AsBuf for BitVec<Lsb0, u8> {
     fn as_buf(&self) -> &[u8] {
         self.as_raw_slice()
@@ -1029,15 +2790,17 @@ impl AsBuf for BitVec<Lsb0, u8> {
 
 impl AsBuf for [u8; BLKSIZE] {}
 
-impl From<FileType> for vfs::FileType {
-    fn from(t: FileType) -> Self {
+/// Fail with `Corrupted` for the types not in any directory
+impl TryFrom<FileType> for vfs::FileType {
+    type Error = FsError;
+    fn try_from(t: FileType) -> vfs::Result<Self> {
         match t {
-            FileType::File => vfs::FileType::File,
-            FileType::SymLink => vfs::FileType::SymLink,
-            FileType::Dir => vfs::FileType::Dir,
-            FileType::CharDevice => vfs::FileType::CharDevice,
-            FileType::BlockDevice => vfs::FileType::BlockDevice,
-            _ => panic!("unknown file type"),
+            FileType::File => Ok(vfs::FileType::File),
+            FileType::SymLink => Ok(vfs::FileType::SymLink),
+            FileType::Dir => Ok(vfs::FileType::Dir),
+            FileType::CharDevice => Ok(vfs::FileType::CharDevice),
+            FileType::BlockDevice => Ok(vfs::FileType::BlockDevice),
+            _ => Err(FsError::Corrupted),
         }
     }
 }
@@ -4,7 +4,7 @@ use crate::vfs;
 use alloc::str;
 
 use core::fmt::{Debug, Error, Formatter};
-use core::mem::{size_of, size_of_val};
+use core::mem::{offset_of, size_of, size_of_val};
 use core::slice;
 use rcore_fs::vfs::Timespec;
 use static_assertions::const_assert;
@@ -23,6 +23,16 @@ pub struct SuperBlock {
     pub info: Str32,
     /// number of freemap blocks
     pub freemap_blocks: u32,
+    /// on-disk format revision, 0 for images created before revisions existed
+    pub revision: u32,
+    /// number of refmap blocks after the freemap, 0 if none, only valid since REVISION_REFLINK
+    pub refmap_blocks: u32,
+    /// first block of the journal, only valid since REVISION_JOURNAL
+    pub journal_start: u32,
+    /// number of journal blocks, 0 without a journal, only valid since REVISION_JOURNAL
+    pub journal_blocks: u32,
+    /// optional features as FEATURE_* bits, only valid since REVISION_DIR_INDEX
+    pub features: u32,
 }
 
 /// inode (on disk)
@@ -53,6 +63,14 @@ pub struct DiskINode {
     pub mtime: Timespec,
     /// Time of last change
     pub ctime: Timespec,
+    /// permission bits, only valid since REVISION_OWNER
+    pub mode: u16,
+    /// owner user id, only valid since REVISION_OWNER
+    pub uid: u32,
+    /// owner group id, only valid since REVISION_OWNER
+    pub gid: u32,
+    /// the DirIndex INode of a directory, 0 if not indexed, only valid since REVISION_DIR_INDEX
+    pub index: u32,
 }
 
 /*
@@ -69,6 +87,42 @@ pub struct IndirectBlock {
     pub entries: [u32; BLK_NENTRY],
 }
 
+/// Header of the journal, followed by the descriptor blocks and the blocks of the last committed
+/// transaction
+#[repr(C)]
+pub struct JournalHeader {
+    /// JOURNAL_MAGIC if a transaction is committed, which may not be written in place yet
+    pub magic: u32,
+    /// sequence number of the transaction
+    pub sequence: u32,
+    /// number of blocks in the transaction
+    pub count: u32,
+    /// checksum of the sequence number, the block numbers and the blocks
+    pub checksum: u32,
+    /// where the blocks are written in place
+    pub blocks: [u32; JOURNAL_NENTRY],
+}
+
+/// Header of the name index of a directory, followed by the slots of a hash table
+#[repr(C)]
+pub struct DirIndexHeader {
+    /// number of entries in use, including "." and ".."
+    pub count: u32,
+    /// the first free entry + 1, 0 if none. Free entries are linked by `DiskEntry::next_free`.
+    /// With compact entries, the first block which may have space for an entry instead.
+    pub free: u32,
+}
+
+/// A slot of the hash table in the name index, probed linearly from `hash`
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct DirIndexSlot {
+    /// hash of the name
+    pub hash: u32,
+    /// the entry + 1, 0 if the slot is empty
+    pub entry: u32,
+}
+
 /// file entry (on disk)
 #[repr(C)]
 #[derive(Debug)]
@@ -79,6 +133,45 @@ pub struct DiskEntry {
     pub name: Str256,
 }
 
+/// header of a compact file entry (on disk), followed by the name.
+/// The entries in a block are chained by `rec_len` from its beginning to its end.
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct CompactEntry {
+    /// inode number, 0 if unused
+    pub id: u32,
+    /// length of the entry with the unused space after it, up to the next entry
+    pub rec_len: u16,
+    /// length of the name
+    pub name_len: u8,
+    /// type of the inode as `FileType`
+    pub type_: u8,
+}
+
+/// header of a node in the extent tree (on disk), followed by the entries
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct ExtentHeader {
+    /// number of entries in use
+    pub count: u16,
+    /// 0 for a leaf of extents, otherwise the height of an index node above the leaves
+    pub depth: u16,
+}
+
+/// an extent (on disk), mapping file blocks `start..start + len` to disk blocks from `block`.
+/// In an index node, `block` is the child node mapping the file blocks from `start`,
+/// and `len` is 0.
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct Extent {
+    /// first file block
+    pub start: u32,
+    /// first disk block, or the child node
+    pub block: u32,
+    /// number of blocks
+    pub len: u32,
+}
+
 #[repr(C)]
 pub struct Str256(pub [u8; 256]);
 
@@ -111,6 +204,33 @@ impl Debug for Str32 {
     }
 }
 
+impl DiskEntry {
+    /// An unused entry, left in the middle of a directory by unlink
+    pub fn free() -> Self {
+        DiskEntry {
+            id: 0,
+            name: Str256([0; 256]),
+        }
+    }
+    /// An unused entry of an indexed directory, linked to the free entry `next` + 1
+    pub fn free_linked(next: u32) -> Self {
+        let mut entry = Self::free();
+        entry.name.0[..4].copy_from_slice(&next.to_ne_bytes());
+        entry
+    }
+    /// Whether this entry is unused.
+    /// Inode 0 is the superblock, which can not be a file.
+    pub fn is_free(&self) -> bool {
+        self.id == 0
+    }
+    /// The next free entry + 1 of a free entry in an indexed directory, 0 if none
+    pub fn next_free(&self) -> u32 {
+        let mut next = [0; 4];
+        next.copy_from_slice(&self.name.0[..4]);
+        u32::from_ne_bytes(next)
+    }
+}
+
 impl<'a> From<&'a str> for Str256 {
     fn from(s: &'a str) -> Self {
         let mut ret = [0u8; 256];
@@ -130,10 +250,85 @@ impl<'a> From<&'a str> for Str32 {
 impl SuperBlock {
     pub fn check(&self) -> bool {
         self.magic == MAGIC
+            && self.revision <= REVISION
+            && (!self.has_dir_index() || self.features & !SUPPORTED_FEATURES == 0)
+    }
+    /// Whether inodes store mode, uid and gid
+    pub fn has_owner(&self) -> bool {
+        self.revision >= REVISION_OWNER
+    }
+    /// Whether blocks can be shared by files, counted in the refmap
+    pub fn has_refmap(&self) -> bool {
+        self.revision >= REVISION_REFLINK && self.refmap_blocks > 0
+    }
+    /// Whether metadata is written through a journal
+    pub fn has_journal(&self) -> bool {
+        self.revision >= REVISION_JOURNAL && self.journal_blocks > 0
+    }
+    /// Whether directories may have name indexes
+    pub fn has_dir_index(&self) -> bool {
+        self.revision >= REVISION_DIR_INDEX
+    }
+    /// Whether directories may have free entries before the last one
+    pub fn has_free_dirents(&self) -> bool {
+        self.revision >= REVISION_FREE_DIRENT
+    }
+    /// Whether files may have holes, which are not allocated
+    pub fn sparse(&self) -> bool {
+        self.revision >= REVISION_SPARSE
+    }
+    /// Whether large directories are indexed when they grow
+    pub fn indexes_dirs(&self) -> bool {
+        self.has_dir_index() && self.features & FEATURE_DIR_INDEX != 0
+    }
+    /// Whether directories consist of compact entries instead of `DiskEntry`
+    pub fn compact_dirents(&self) -> bool {
+        self.revision >= REVISION_DIR_INDEX && self.features & FEATURE_COMPACT_DIRENT != 0
+    }
+    /// Whether blocks are mapped by extent trees instead of block pointers
+    pub fn extents(&self) -> bool {
+        self.revision >= REVISION_DIR_INDEX && self.features & FEATURE_EXTENTS != 0
+    }
+}
+
+impl CompactEntry {
+    /// Length of an entry with a name of `name_len` bytes, aligned to 4 bytes
+    pub const fn len_of(name_len: usize) -> usize {
+        (size_of::<CompactEntry>() + name_len + 3) & !3
+    }
+    /// Length of the entry without the unused space after it, 0 if unused
+    pub fn used_len(&self) -> usize {
+        match self.id {
+            0 => 0,
+            _ => Self::len_of(self.name_len as usize),
+        }
+    }
+}
+
+impl FileType {
+    /// The file type of a raw value on disk
+    pub fn from_raw(raw: u16) -> Option<Self> {
+        Some(match raw {
+            1 => FileType::File,
+            2 => FileType::Dir,
+            3 => FileType::SymLink,
+            4 => FileType::CharDevice,
+            5 => FileType::BlockDevice,
+            6 => FileType::DirIndex,
+            _ => return None,
+        })
     }
 }
 
 impl DiskINode {
+    /// The block pointers, holding the root of the extent tree if FEATURE_EXTENTS.
+    /// An empty tree is all zeros.
+    pub fn extent_root(&self) -> &[u8] {
+        &self.as_buf()[EXTENT_ROOT_OFFSET..EXTENT_ROOT_OFFSET + EXTENT_ROOT_SIZE]
+    }
+    pub fn extent_root_mut(&mut self) -> &mut [u8] {
+        &mut self.as_buf_mut()[EXTENT_ROOT_OFFSET..EXTENT_ROOT_OFFSET + EXTENT_ROOT_SIZE]
+    }
     pub const fn new_file() -> Self {
         DiskINode {
             size: 0,
@@ -147,6 +342,10 @@ impl DiskINode {
             atime: Timespec { sec: 0, nsec: 0 },
             mtime: Timespec { sec: 0, nsec: 0 },
             ctime: Timespec { sec: 0, nsec: 0 },
+            mode: 0,
+            uid: 0,
+            gid: 0,
+            index: 0,
         }
     }
     pub const fn new_symlink() -> Self {
@@ -162,6 +361,10 @@ impl DiskINode {
             atime: Timespec { sec: 0, nsec: 0 },
             mtime: Timespec { sec: 0, nsec: 0 },
             ctime: Timespec { sec: 0, nsec: 0 },
+            mode: 0,
+            uid: 0,
+            gid: 0,
+            index: 0,
         }
     }
     pub const fn new_dir() -> Self {
@@ -177,6 +380,29 @@ impl DiskINode {
             atime: Timespec { sec: 0, nsec: 0 },
             mtime: Timespec { sec: 0, nsec: 0 },
             ctime: Timespec { sec: 0, nsec: 0 },
+            mode: 0,
+            uid: 0,
+            gid: 0,
+            index: 0,
+        }
+    }
+    pub const fn new_dir_index() -> Self {
+        DiskINode {
+            size: 0,
+            type_: FileType::DirIndex,
+            nlinks: 0,
+            blocks: 0,
+            direct: [0; NDIRECT],
+            indirect: 0,
+            db_indirect: 0,
+            device_inode_id: NODEVICE,
+            atime: Timespec { sec: 0, nsec: 0 },
+            mtime: Timespec { sec: 0, nsec: 0 },
+            ctime: Timespec { sec: 0, nsec: 0 },
+            mode: 0,
+            uid: 0,
+            gid: 0,
+            index: 0,
         }
     }
     pub const fn new_chardevice(device_inode_id: usize) -> Self {
@@ -192,6 +418,10 @@ impl DiskINode {
             atime: Timespec { sec: 0, nsec: 0 },
             mtime: Timespec { sec: 0, nsec: 0 },
             ctime: Timespec { sec: 0, nsec: 0 },
+            mode: 0,
+            uid: 0,
+            gid: 0,
+            index: 0,
         }
     }
 }
@@ -214,6 +444,20 @@ impl AsBuf for DiskEntry {}
 
 impl AsBuf for u32 {}
 
+impl AsBuf for IndirectBlock {}
+
+impl AsBuf for JournalHeader {}
+
+impl AsBuf for DirIndexHeader {}
+
+impl AsBuf for DirIndexSlot {}
+
+impl AsBuf for CompactEntry {}
+
+impl AsBuf for ExtentHeader {}
+
+impl AsBuf for Extent {}
+
 /*
  * Simple FS (SFS) definitions visible to ucore. This covers the on-disk format
  * and is used by tools that work on SFS volumes, such as mksfs.
@@ -225,6 +469,28 @@ pub const NODEVICE: usize = 100;
 
 /// magic number for sfs
 pub const MAGIC: u32 = 0x2f8dbe2b;
+/// current on-disk format revision
+pub const REVISION: u32 = REVISION_SPARSE;
+/// revision which adds mode, uid and gid to inodes
+pub const REVISION_OWNER: u32 = 1;
+/// revision which adds the refmap for blocks shared by cloned files
+pub const REVISION_REFLINK: u32 = 2;
+/// revision which adds the optional journal
+pub const REVISION_JOURNAL: u32 = 3;
+/// revision which adds the optional features and the name indexes of directories
+pub const REVISION_DIR_INDEX: u32 = 4;
+/// revision which frees directory entries in place, leaving free entries in the middle
+pub const REVISION_FREE_DIRENT: u32 = 5;
+/// revision which allows holes in files, mapped to block 0
+pub const REVISION_SPARSE: u32 = 6;
+/// feature of indexing large directories by names
+pub const FEATURE_DIR_INDEX: u32 = 1;
+/// feature of directories with compact entries of variable length
+pub const FEATURE_COMPACT_DIRENT: u32 = 2;
+/// feature of mapping blocks by extent trees
+pub const FEATURE_EXTENTS: u32 = 4;
+/// all the features known to this implementation, images with others are rejected
+pub const SUPPORTED_FEATURES: u32 = FEATURE_DIR_INDEX | FEATURE_COMPACT_DIRENT | FEATURE_EXTENTS;
 /// size of block
 pub const BLKSIZE: usize = 1usize << BLKSIZE_LOG2;
 /// log2( size of block )
@@ -248,18 +514,43 @@ pub const BLKN_ROOT: BlockId = 1;
 pub const BLKN_FREEMAP: BlockId = 2;
 /// number of bits in a block
 pub const BLKBITS: usize = BLKSIZE * 8;
+/// number of block reference counts in a refmap block
+pub const BLK_NREF: usize = BLKSIZE;
+/// max number of extra references to a shared block
+pub const MAX_BLOCK_SHARES: u8 = u8::MAX;
 /// size of one entry
 pub const ENTRY_SIZE: usize = 4;
 /// number of entries in a block
 pub const BLK_NENTRY: usize = BLKSIZE / ENTRY_SIZE;
+/// magic number of a committed journal header
+pub const JOURNAL_MAGIC: u32 = 0x4a524e4c;
+/// number of blocks named by a journal header, the others by the descriptor blocks after it
+pub const JOURNAL_NENTRY: usize = (BLKSIZE - 16) / ENTRY_SIZE;
 /// size of a dirent used in the size field
 pub const DIRENT_SIZE: usize = MAX_FNAME_LEN + 1 + ENTRY_SIZE;
+/// directories larger than a block are indexed, if FEATURE_DIR_INDEX
+pub const DIR_INDEX_THRESHOLD: usize = BLKSIZE;
+/// position of ".." in a directory of compact entries, after "."
+pub const COMPACT_DOTDOT: usize = CompactEntry::len_of(1);
+/// min number of slots in a name index
+pub const DIR_INDEX_MIN_SLOTS: usize = 64;
 /// max number of blocks with direct blocks
 pub const MAX_NBLOCK_DIRECT: usize = NDIRECT;
 /// max number of blocks with indirect blocks
 pub const MAX_NBLOCK_INDIRECT: usize = NDIRECT + BLK_NENTRY;
 /// max number of blocks with double indirect blocks
 pub const MAX_NBLOCK_DOUBLE_INDIRECT: usize = NDIRECT + BLK_NENTRY + BLK_NENTRY * BLK_NENTRY;
+/// offset of the root of the extent tree in `DiskINode`, in place of the block pointers
+pub const EXTENT_ROOT_OFFSET: usize = offset_of!(DiskINode, direct);
+/// size of the root of the extent tree, from `direct` to `db_indirect`
+pub const EXTENT_ROOT_SIZE: usize = offset_of!(DiskINode, db_indirect) + 4 - EXTENT_ROOT_OFFSET;
+/// number of entries in the root of the extent tree
+pub const EXTENT_ROOT_NENTRY: usize =
+    (EXTENT_ROOT_SIZE - size_of::<ExtentHeader>()) / size_of::<Extent>();
+/// number of entries in a block of the extent tree
+pub const EXTENT_BLOCK_NENTRY: usize = (BLKSIZE - size_of::<ExtentHeader>()) / size_of::<Extent>();
+/// max depth of the extent tree, enough for an extent per block of the largest file
+pub const MAX_EXTENT_DEPTH: usize = 3;
 
 /// file types
 #[repr(u16)]
@@ -271,10 +562,19 @@ pub enum FileType {
     SymLink = 3,
     CharDevice = 4,
     BlockDevice = 5,
+    /// the name index of a directory, which is not in any directory
+    DirIndex = 6,
 }
 
 const_assert!(size_of::<SuperBlock>() <= BLKSIZE);
 const_assert!(size_of::<DiskINode>() <= BLKSIZE);
 const_assert!(size_of::<DiskEntry>() <= BLKSIZE);
 const_assert!(size_of::<IndirectBlock>() == BLKSIZE);
+const_assert!(size_of::<JournalHeader>() == BLKSIZE);
+const_assert!(size_of::<DirIndexHeader>() == size_of::<DirIndexSlot>());
+const_assert!(BLKSIZE <= u16::MAX as usize);
+const_assert!(EXTENT_ROOT_SIZE == (NDIRECT + 2) * ENTRY_SIZE);
+const_assert!(
+    EXTENT_ROOT_NENTRY * EXTENT_BLOCK_NENTRY.pow(MAX_EXTENT_DEPTH as u32) > MAX_FILE_SIZE / BLKSIZE
+);
 const_assert!(DEFAULT_INFO.len() <= MAX_INFO_LEN);
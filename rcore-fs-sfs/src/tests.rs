@@ -1,12 +1,17 @@
 extern crate std;
 
-use crate::*;
+use crate::{extent::NodeAt, fsck::Problem, *};
 use rcore_fs::{
+    crash,
+    dev::{fault::FaultyDevice, DevError, DevErrorKind},
+    notify::{Event, EventKind, EventMask},
+    page_cache,
     util::uninit_memory,
-    vfs::{FileSystem, FileType, Metadata, Result, Timespec},
+    vfs::{FallocateFlags, FileSystem, FileType, Metadata, RenameFlags, Result, Timespec},
 };
 use std::{
     fs::{self, OpenOptions},
+    io::{Read, Seek, SeekFrom},
     sync::{Arc, Mutex},
 };
 
@@ -563,3 +568,2122 @@ fn create_then_get_entry() -> Result<()> {
     sfs.sync()?;
     Ok(())
 }
+
+#[test]
+fn mode_and_owner() -> Result<()> {
+    let file = tempfile::tempfile().expect("failed to create file");
+    let device: Arc<dyn Device> = Arc::new(Mutex::new(file));
+    let sfs = SimpleFileSystem::create(device.clone(), 32 * 4096 * 4096)?;
+    let root = sfs.root_inode();
+    let file1 = root.create("file1", FileType::File, 0o640)?;
+    assert_eq!(file1.metadata()?.mode, 0o640);
+
+    let mut info = file1.metadata()?;
+    info.mode = 0o600;
+    info.uid = 1000;
+    info.gid = 100;
+    file1.set_metadata(&info)?;
+    drop(file1);
+    drop(root);
+    sfs.sync()?;
+    drop(sfs);
+
+    let sfs = SimpleFileSystem::open(device)?;
+    let info = sfs.root_inode().lookup("file1")?.metadata()?;
+    assert_eq!((info.mode, info.uid, info.gid), (0o600, 1000, 100));
+    Ok(())
+}
+
+#[test]
+fn open_old_revision() -> Result<()> {
+    let file = tempfile::tempfile().expect("failed to create file");
+    let device: Arc<dyn Device> = Arc::new(Mutex::new(file));
+    let sfs = SimpleFileSystem::create(device.clone(), 32 * 4096 * 4096)?;
+    for name in &["file1", "file2", "file3"] {
+        sfs.root_inode().create(name, FileType::File, 0o640)?;
+    }
+    sfs.sync()?;
+    drop(sfs);
+
+    // downgrade the image to the revision without mode and owner
+    let mut super_block = device.load_struct::<SuperBlock>(BLKN_SUPER)?;
+    super_block.revision = 0;
+    device.write_block(BLKN_SUPER, 0, super_block.as_buf())?;
+
+    let sfs = SimpleFileSystem::open(device)?;
+    let file1 = sfs.root_inode().lookup("file1")?;
+    let mut info = file1.metadata()?;
+    assert_eq!((info.mode, info.uid, info.gid), (0o777, 0, 0));
+    info.atime = Timespec { sec: 1, nsec: 0 };
+    file1.set_metadata(&info)?;
+    info.mode = 0o600;
+    assert_eq!(file1.set_metadata(&info), Err(FsError::NotSupported));
+
+    // removed entries are not left free, the last one is moved instead
+    let root = sfs.root_inode();
+    root.unlink("file2")?;
+    let names = root
+        .entries(0)
+        .map(|entry| Ok(entry?.name))
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(names, [".", "..", "file1", "file3"]);
+    assert_eq!(root.metadata()?.size, 4 * DIRENT_SIZE);
+
+    // files are not sparse
+    file1.resize(3 * BLKSIZE)?;
+    assert_eq!(file1.seek_hole(0)?, Some(3 * BLKSIZE));
+    let punch = FallocateFlags {
+        keep_size: true,
+        punch_hole: true,
+        ..Default::default()
+    };
+    assert_eq!(
+        file1.fallocate(punch, 0, BLKSIZE),
+        Err(FsError::NotSupported)
+    );
+    Ok(())
+}
+
+#[test]
+fn open_unknown_feature() -> Result<()> {
+    let file = tempfile::tempfile().expect("failed to create file");
+    let device: Arc<dyn Device> = Arc::new(Mutex::new(file));
+    let sfs = SimpleFileSystem::create(device.clone(), 32 * 4096 * 4096)?;
+    sfs.sync()?;
+    drop(sfs);
+
+    // a feature of a later implementation
+    let mut super_block = device.load_struct::<SuperBlock>(BLKN_SUPER)?;
+    super_block.features |= 1 << 31;
+    device.write_block(BLKN_SUPER, 0, super_block.as_buf())?;
+    assert_eq!(SimpleFileSystem::open(device).err(), Some(FsError::WrongFs));
+    Ok(())
+}
+
+#[test]
+fn path_resolution() -> Result<()> {
+    use rcore_fs::path::PathResolver;
+
+    let sfs = _create_new_sfs();
+    let root = sfs.root_inode();
+    let dir1 = root.create("dir1", FileType::Dir, 0o777)?;
+    let file1 = dir1.create("file1", FileType::File, 0o777)?;
+    let link_dir = root.symlink("link_dir", "dir1/")?;
+    let long_target = "./".repeat(300) + "dir1/file1";
+    root.symlink("long", &long_target)?;
+    root.symlink("loop1", "loop2")?;
+    root.symlink("loop2", "/loop1")?;
+    dir1.symlink("abs", "/file1")?;
+
+    let resolver = PathResolver::new(root.clone());
+    assert!(Arc::ptr_eq(&resolver.resolve(&root, "long", true)?, &file1));
+    assert!(Arc::ptr_eq(
+        &resolver.resolve(&root, "link_dir/file1", false)?,
+        &file1
+    ));
+    // O_NOFOLLOW
+    assert!(Arc::ptr_eq(
+        &resolver.resolve(&root, "link_dir", false)?,
+        &link_dir
+    ));
+    assert!(Arc::ptr_eq(
+        &resolver.resolve(&root, "link_dir/", false)?,
+        &dir1
+    ));
+    assert_eq!(
+        resolver.resolve(&root, "dir1/file1/", true).err(),
+        Some(FsError::NotDir)
+    );
+    assert_eq!(
+        resolver.resolve(&root, "loop1", true).err(),
+        Some(FsError::SymLoop)
+    );
+    assert!(resolver.resolve(&root, "loop1", false).is_ok());
+    assert_eq!(
+        resolver.resolve(&root, "", true).err(),
+        Some(FsError::EntryNotFound)
+    );
+
+    let (parent, name) = resolver.resolve_parent(&root, "link_dir/new/")?;
+    assert!(Arc::ptr_eq(&parent, &dir1));
+    assert_eq!(name, "new");
+
+    // chroot to dir1
+    let resolver = PathResolver::new(dir1.clone());
+    assert!(Arc::ptr_eq(
+        &resolver.resolve(&dir1, "../../file1", true)?,
+        &file1
+    ));
+    assert!(Arc::ptr_eq(
+        &resolver.resolve(&root, "/file1", true)?,
+        &file1
+    ));
+    assert!(Arc::ptr_eq(&resolver.resolve(&dir1, "abs", true)?, &file1));
+
+    let mut resolver = PathResolver::new(root.clone());
+    resolver.max_symlinks = 0;
+    assert_eq!(
+        resolver.resolve(&root, "link_dir/file1", true).err(),
+        Some(FsError::SymLoop)
+    );
+
+    sfs.sync()?;
+    Ok(())
+}
+
+#[test]
+fn symlink_and_read_link() -> Result<()> {
+    let sfs = _create_new_sfs();
+    let root = sfs.root_inode();
+    let target = "a/".repeat(1000) + "file";
+    let link = root.symlink("link", &target)?;
+    assert_eq!(link.metadata()?.type_, FileType::SymLink);
+    assert_eq!(link.read_link()?, target);
+    assert_eq!(root.lookup("link")?.read_link()?, target);
+    assert_eq!(root.read_link(), Err(FsError::InvalidParam));
+    assert_eq!(root.symlink("link", "x").err(), Some(FsError::EntryExist));
+
+    sfs.sync()?;
+    Ok(())
+}
+
+#[test]
+fn read_dir_stable_cookies() -> Result<()> {
+    let sfs = _create_new_sfs();
+    let root = sfs.root_inode();
+    let names = |root: &Arc<dyn INode>, cookie| -> Result<Vec<String>> {
+        root.entries(cookie).map(|entry| Ok(entry?.name)).collect()
+    };
+    for name in &["a", "b", "c", "d"] {
+        root.create(name, FileType::File, 0o777)?;
+    }
+    assert_eq!(names(&root, 0)?, [".", "..", "a", "b", "c", "d"]);
+
+    let entries = root.entries(0).collect::<Result<Vec<_>>>()?;
+    assert_eq!(entries[2].type_, FileType::File);
+    assert_eq!(entries[2].inode, root.find("a")?.metadata()?.inode);
+    let cookie_c = entries[3].next_cookie;
+
+    // removing an entry before the cookie doesn't move the others
+    root.unlink("a")?;
+    assert_eq!(names(&root, cookie_c)?, ["c", "d"]);
+    assert_eq!(names(&root, 0)?, [".", "..", "b", "c", "d"]);
+
+    // the hole is reused by the next insertion
+    root.create("e", FileType::Dir, 0o777)?;
+    assert_eq!(names(&root, 0)?, [".", "..", "e", "b", "c", "d"]);
+    assert_eq!(root.get_entry(2)?, "e");
+
+    // removing trailing entries shrinks the directory
+    let size = root.metadata()?.size;
+    root.unlink("d")?;
+    root.unlink("c")?;
+    assert!(root.metadata()?.size < size);
+    root.unlink("e")?;
+    root.unlink("b")?;
+    assert_eq!(names(&root, 0)?, [".", ".."]);
+
+    sfs.sync()?;
+    Ok(())
+}
+
+#[test]
+fn rename_flags() -> Result<()> {
+    let sfs = _create_new_sfs();
+    let root = sfs.root_inode();
+    let noreplace = RenameFlags {
+        noreplace: true,
+        ..Default::default()
+    };
+    let exchange = RenameFlags {
+        exchange: true,
+        ..Default::default()
+    };
+    let file1 = root.create("file1", FileType::File, 0o777)?;
+    let file2 = root.create("file2", FileType::File, 0o777)?;
+    let dir1 = root.create("dir1", FileType::Dir, 0o777)?;
+    let dir2 = dir1.create("dir2", FileType::Dir, 0o777)?;
+
+    assert_eq!(
+        root.rename("file1", &root, "file2", noreplace),
+        Err(FsError::EntryExist)
+    );
+    assert_eq!(
+        root.rename("file1", &root, "none", exchange),
+        Err(FsError::EntryNotFound)
+    );
+
+    // exchange a file and a dir in different dirs
+    root.rename("file1", &dir1, "dir2", exchange)?;
+    assert_eq!(
+        root.lookup("file1")?.metadata()?.inode,
+        dir2.metadata()?.inode
+    );
+    assert_eq!(
+        root.lookup("dir1/dir2")?.metadata()?.inode,
+        file1.metadata()?.inode
+    );
+    assert_eq!(
+        root.lookup("file1/..")?.metadata()?.inode,
+        root.metadata()?.inode
+    );
+    assert_eq!(root.metadata()?.nlinks, 4);
+    assert_eq!(dir1.metadata()?.nlinks, 2);
+
+    // replace a file, the replaced one loses its link
+    root.rename("file2", &dir1, "dir2", RenameFlags::default())?;
+    assert_eq!(file1.metadata()?.nlinks, 0);
+    assert_eq!(root.find("file2").err(), Some(FsError::EntryNotFound));
+    assert_eq!(
+        root.lookup("dir1/dir2")?.metadata()?.inode,
+        file2.metadata()?.inode
+    );
+
+    // replace an empty dir, moving into another dir
+    let dir3 = dir1.create("dir3", FileType::Dir, 0o777)?;
+    root.move_("file1", &dir1, "dir3")?;
+    assert_eq!(dir3.metadata()?.nlinks, 0);
+    assert_eq!(
+        root.lookup("dir1/dir3/..")?.metadata()?.inode,
+        dir1.metadata()?.inode
+    );
+    assert_eq!(root.metadata()?.nlinks, 3);
+    assert_eq!(dir1.metadata()?.nlinks, 3);
+    assert_eq!(
+        root.move_("dir1", &dir1, "dir1"),
+        Err(FsError::InvalidParam)
+    );
+    assert_eq!(root.move_("dir1", &dir2, "dir"), Err(FsError::InvalidParam));
+    assert_eq!(
+        dir1.rename("dir3", &root, "dir1", exchange),
+        Err(FsError::InvalidParam)
+    );
+    dir1.create("file3", FileType::File, 0o777)?;
+    assert_eq!(
+        dir2.move_("file3", &root, "dir1"),
+        Err(FsError::EntryNotFound)
+    );
+    assert_eq!(dir1.move_("file3", &root, "dir1"), Err(FsError::IsDir));
+    assert_eq!(dir1.move_("dir3", &dir1, "dir2"), Err(FsError::NotDir));
+    let dir4 = root.create("dir4", FileType::Dir, 0o777)?;
+    assert_eq!(dir4.move_("..", &root, "x"), Err(FsError::IsDir));
+    assert_eq!(root.move_("dir4", &root, "dir1"), Err(FsError::DirNotEmpty));
+
+    sfs.sync()?;
+    Ok(())
+}
+
+#[test]
+fn sparse_file_and_fallocate() -> Result<()> {
+    let sfs = _create_new_sfs();
+    let root = sfs.root_inode();
+    let file = root.create("file", FileType::File, 0o777)?;
+    let bfree = || sfs.info().bfree;
+    let free = bfree();
+
+    // resize leaves holes
+    file.resize(1 << 30)?;
+    assert_eq!(bfree(), free);
+    let mut buf = [1u8; 16];
+    file.read_at(0x1234_5678, &mut buf)?;
+    assert_eq!(buf, [0; 16]);
+    assert_eq!(file.seek_data(0)?, None);
+    assert_eq!(file.seek_hole(100)?, Some(100));
+
+    // write allocates the block and the indirect blocks
+    file.write_at(0x1234_5678, b"hello")?;
+    assert_eq!(bfree(), free - 3);
+    file.read_at(0x1234_5670, &mut buf)?;
+    assert_eq!(&buf, b"\0\0\0\0\0\0\0\0hello\0\0\0");
+    assert_eq!(file.seek_data(0)?, Some(0x1234_5000));
+    assert_eq!(file.seek_data(0x1234_5678)?, Some(0x1234_5678));
+    assert_eq!(file.seek_hole(0x1234_5000)?, Some(0x1234_6000));
+    assert_eq!(file.seek_data(0x1234_6000)?, None);
+    assert_eq!(file.seek_hole(1 << 30)?, None);
+
+    // punch hole frees full blocks and cleans partial ones
+    let punch = FallocateFlags {
+        keep_size: true,
+        punch_hole: true,
+        ..Default::default()
+    };
+    assert_eq!(
+        file.fallocate(
+            FallocateFlags {
+                punch_hole: true,
+                ..Default::default()
+            },
+            0,
+            1
+        ),
+        Err(FsError::InvalidParam)
+    );
+    file.fallocate(punch, 0x1234_567a, 1)?;
+    file.read_at(0x1234_5678, &mut buf[..5])?;
+    assert_eq!(&buf[..5], b"he\0lo");
+    file.fallocate(punch, 0x1234_5000, 0x1000)?;
+    assert_eq!(bfree(), free - 2);
+    assert_eq!(file.seek_data(0)?, None);
+
+    // preallocate without changing the size
+    let keep_size = FallocateFlags {
+        keep_size: true,
+        ..Default::default()
+    };
+    file.fallocate(keep_size, (1 << 30) - 10, 0x2000)?;
+    assert_eq!(file.metadata()?.size, 1 << 30);
+    // 3 blocks and a new indirect block
+    assert_eq!(bfree(), free - 6);
+    // the preallocated blocks are kept when writing after the end
+    file.write_at(1 << 30, b"world")?;
+    assert_eq!(bfree(), free - 6);
+    assert_eq!(file.metadata()?.size, (1 << 30) + 5);
+
+    // preallocate and extend the size
+    file.fallocate(FallocateFlags::default(), 0, 0x1800)?;
+    assert_eq!(file.metadata()?.size, (1 << 30) + 5);
+    assert_eq!(bfree(), free - 8);
+    file.write_at(0, &[1; 0x2000])?;
+    assert_eq!(bfree(), free - 8);
+
+    // zero range keeps the blocks allocated
+    let zero = FallocateFlags {
+        zero_range: true,
+        ..Default::default()
+    };
+    file.fallocate(zero, 0x800, 0x1000)?;
+    assert_eq!(bfree(), free - 8);
+    file.read_at(0x7f8, &mut buf)?;
+    assert_eq!(buf, [1, 1, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0]);
+    file.read_at(0x17f8, &mut buf)?;
+    assert_eq!(buf, [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 1, 1, 1, 1]);
+    assert_eq!(file.seek_hole(0)?, Some(0x2000));
+
+    // truncate frees all blocks after the end
+    file.resize(0x1801)?;
+    assert_eq!(bfree(), free - 2);
+    // and the rest of the last block is clean after growing
+    file.resize(0x2000)?;
+    file.read_at(0x17f8, &mut buf)?;
+    assert_eq!(buf, [0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0]);
+    file.resize(0)?;
+    assert_eq!(bfree(), free);
+
+    sfs.sync()?;
+
+    // the end of file in a data block
+    let file2 = root.create("file2", FileType::File, 0o777)?;
+    file2.write_at(0, b"hello")?;
+    assert_eq!(file2.seek_data(5)?, None);
+    assert_eq!(file2.seek_hole(0)?, Some(5));
+    Ok(())
+}
+
+#[test]
+fn notify_events() -> Result<()> {
+    let sfs = _create_new_sfs();
+    let root = sfs.root_inode();
+    let dir = root.create("dir", FileType::Dir, 0o777)?;
+    let root_watch = root.watch(EventMask::ALL)?;
+    let dir_watch = dir.watch(EventMask {
+        create: true,
+        moved: true,
+        ..EventMask::default()
+    })?;
+    let event = |kind, name: &str, is_dir, cookie| Event {
+        kind,
+        name: Some(String::from(name)),
+        is_dir,
+        cookie,
+    };
+    let self_event = |kind| Event {
+        kind,
+        name: None,
+        is_dir: false,
+        cookie: 0,
+    };
+
+    let file = root.create("file", FileType::File, 0o777)?;
+    let file_watch = file.watch(EventMask::ALL)?;
+    assert!(!file_watch.poll()?.read);
+    file.write_at(0, b"hello")?;
+    file.write_at(5, b"world")?;
+    assert!(file_watch.poll()?.read);
+    file.set_metadata(&file.metadata()?)?;
+    root.move_("file", &dir, "moved")?;
+    dir.link("link", &file)?;
+    dir.unlink("moved")?;
+    dir.unlink("link")?;
+    root.unlink("dir")?;
+
+    let root_events = root_watch.read_events();
+    let cookie = root_events[1].cookie;
+    assert_ne!(cookie, 0);
+    assert_eq!(
+        root_events,
+        vec![
+            event(EventKind::Create, "file", false, 0),
+            event(EventKind::MovedFrom, "file", false, cookie),
+            event(EventKind::Delete, "dir", true, 0),
+        ]
+    );
+    assert!(!root_watch.poll()?.read);
+    assert_eq!(
+        dir_watch.read_events(),
+        vec![
+            event(EventKind::MovedTo, "moved", false, cookie),
+            event(EventKind::Create, "link", false, 0),
+            // removed with `dir`
+            self_event(EventKind::Ignored),
+        ]
+    );
+    // the same events in a row are merged
+    assert_eq!(
+        file_watch.read_events(),
+        vec![
+            self_event(EventKind::Modify),
+            self_event(EventKind::Attrib),
+            self_event(EventKind::MoveSelf),
+            self_event(EventKind::Attrib),
+            self_event(EventKind::DeleteSelf),
+            self_event(EventKind::Ignored),
+        ]
+    );
+
+    // overflow
+    let watch = sfs.notifier().unwrap().watch(BLKN_ROOT, EventMask::ALL, 2);
+    for name in ["a", "b", "c"] {
+        root.create(name, FileType::File, 0o777)?;
+    }
+    let events = watch.read_events();
+    assert_eq!(events.len(), 3);
+    assert_eq!(events[2], self_event(EventKind::Overflow));
+    assert!(!watch.poll()?.read);
+    Ok(())
+}
+
+#[test]
+fn async_read_write() -> Result<()> {
+    use rcore_fs::util::block_on;
+    let sfs = _create_new_sfs();
+    let root = sfs.root_inode();
+    let file = root.create("file", FileType::File, 0o777)?;
+    let data: Vec<u8> = (0..3 * BLKSIZE + 100).map(|i| i as u8).collect();
+    assert_eq!(block_on(file.async_write_at(100, &data))?, data.len());
+    assert_eq!(file.metadata()?.size, data.len() + 100);
+
+    let mut buf = vec![0xffu8; data.len() + 100];
+    assert_eq!(block_on(file.async_read_at(0, &mut buf))?, buf.len());
+    assert!(buf[..100].iter().all(|&b| b == 0));
+    assert_eq!(buf[100..], data[..]);
+    // the same as the sync version
+    let mut sync_buf = vec![0u8; buf.len()];
+    file.read_at(0, &mut sync_buf)?;
+    assert_eq!(sync_buf, buf);
+    // holes
+    file.resize(10 * BLKSIZE)?;
+    let mut buf = vec![0xffu8; BLKSIZE];
+    assert_eq!(
+        block_on(file.async_read_at(8 * BLKSIZE, &mut buf))?,
+        BLKSIZE
+    );
+    assert!(buf.iter().all(|&b| b == 0));
+
+    let dir = root.create("dir", FileType::Dir, 0o777)?;
+    assert_eq!(
+        block_on(dir.async_read_at(0, &mut buf)),
+        Err(FsError::NotFile)
+    );
+    Ok(())
+}
+
+/// Counts the requests to the inner device
+struct CountingDevice {
+    inner: Mutex<std::fs::File>,
+    reads: std::sync::atomic::AtomicUsize,
+}
+
+impl rcore_fs::dev::Device for CountingDevice {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> rcore_fs::dev::Result<usize> {
+        self.reads.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        self.inner.read_at(offset, buf)
+    }
+    fn write_at(&self, offset: usize, buf: &[u8]) -> rcore_fs::dev::Result<usize> {
+        self.inner.write_at(offset, buf)
+    }
+    fn sync(&self) -> rcore_fs::dev::Result<()> {
+        self.inner.sync()
+    }
+}
+
+#[test]
+fn vectored_io() -> Result<()> {
+    use std::sync::atomic::Ordering;
+    let device = Arc::new(CountingDevice {
+        inner: Mutex::new(tempfile::tempfile().expect("failed to create file")),
+        reads: Default::default(),
+    });
+    let sfs =
+        SimpleFileSystem::create(device.clone(), 32 * 4096 * 4096).expect("failed to create SFS");
+    let root = sfs.root_inode();
+    let file = root.create("file", FileType::File, 0o777)?;
+    let a: Vec<u8> = (0..BLKSIZE + 10).map(|i| i as u8).collect();
+    let b: Vec<u8> = (0..2 * BLKSIZE).map(|i| (i * 7) as u8).collect();
+    let len = a.len() + b.len();
+    assert_eq!(file.write_vectored_at(5, &[&a, &[], &b])?, len);
+    assert_eq!(file.metadata()?.size, len + 5);
+
+    let mut x = vec![0xffu8; 5];
+    let mut y = vec![0xffu8; len + 10];
+    let reads = device.reads.load(Ordering::SeqCst);
+    assert_eq!(file.read_vectored_at(0, &mut [&mut x, &mut y])?, len + 5);
+    // the freshly allocated blocks are contiguous, so one request per buffer
+    assert_eq!(device.reads.load(Ordering::SeqCst), reads + 2);
+    assert_eq!(x, [0; 5]);
+    assert_eq!(y[..a.len()], a[..]);
+    assert_eq!(y[a.len()..len], b[..]);
+    assert!(y[len..].iter().all(|&b| b == 0xff));
+    // the same as the sync version
+    let mut buf = vec![0u8; len + 5];
+    let reads = device.reads.load(Ordering::SeqCst);
+    file.read_at(0, &mut buf)?;
+    assert_eq!(device.reads.load(Ordering::SeqCst), reads + 1);
+    assert_eq!(buf[..5], x[..]);
+    assert_eq!(buf[5..], y[..len]);
+
+    // holes
+    file.resize(10 * BLKSIZE)?;
+    let mut x = vec![0xffu8; BLKSIZE];
+    let mut y = vec![0xffu8; BLKSIZE];
+    assert_eq!(
+        file.read_vectored_at(7 * BLKSIZE, &mut [&mut x, &mut y])?,
+        2 * BLKSIZE
+    );
+    assert!(x.iter().chain(y.iter()).all(|&b| b == 0));
+
+    let dir = root.create("dir", FileType::Dir, 0o777)?;
+    assert_eq!(
+        dir.read_vectored_at(0, &mut [&mut x]),
+        Err(FsError::NotFile)
+    );
+    Ok(())
+}
+
+#[test]
+fn copy_and_clone_range() -> Result<()> {
+    let file = tempfile::tempfile().expect("failed to create file");
+    let device: Arc<dyn Device> = Arc::new(Mutex::new(file));
+    let options = CreateOptions {
+        refmap: true,
+        ..CreateOptions::default()
+    };
+    let sfs = SimpleFileSystem::create_with_options(device.clone(), 32 * 4096 * 4096, &options)?;
+    let root = sfs.root_inode();
+    let a = root.create("a", FileType::File, 0o777)?;
+    let b = root.create("b", FileType::File, 0o777)?;
+    let data: Vec<u8> = (0..3 * BLKSIZE + 100).map(|i| (i % 251) as u8).collect();
+    a.write_at(0, &data)?;
+    let read_all = |inode: &Arc<dyn INode>| -> Result<Vec<u8>> {
+        let mut buf = vec![0u8; inode.metadata()?.size];
+        inode.read_at(0, &mut buf)?;
+        Ok(buf)
+    };
+
+    // aligned copies share the blocks
+    let free = sfs.info().bfree;
+    assert_eq!(b.copy_range(a.as_ref(), 0, 0, usize::MAX)?, data.len());
+    assert_eq!(sfs.info().bfree, free);
+    assert_eq!(read_all(&b)?, data);
+    // writes are private
+    b.write_at(10, &[0xff; 10])?;
+    assert_eq!(sfs.info().bfree, free - 1);
+    assert_eq!(read_all(&a)?, data);
+    assert_eq!(read_all(&b)?[10..20], [0xff; 10]);
+    a.resize(BLKSIZE + 10)?;
+    a.resize(2 * BLKSIZE)?;
+    assert_eq!(read_all(&b)?[20..], data[20..]);
+
+    // unaligned copies
+    let c = root.create("c", FileType::File, 0o777)?;
+    assert_eq!(c.copy_range(b.as_ref(), 1, 2, 2 * BLKSIZE)?, 2 * BLKSIZE);
+    assert_eq!(read_all(&c)?[2..], read_all(&b)?[1..2 * BLKSIZE + 1]);
+    assert_eq!(
+        c.copy_range(c.as_ref(), 0, 100, 200),
+        Err(FsError::InvalidParam)
+    );
+
+    // clones
+    let d = root.create("d", FileType::File, 0o777)?;
+    assert_eq!(
+        d.clone_range(b.as_ref(), 1, 0, BLKSIZE),
+        Err(FsError::InvalidParam)
+    );
+    assert_eq!(
+        d.clone_range(b.as_ref(), 0, 0, BLKSIZE + 1),
+        Err(FsError::InvalidParam)
+    );
+    assert_eq!(d.clone_range(root.as_ref(), 0, 0, 0), Err(FsError::NotFile));
+    let free = sfs.info().bfree;
+    d.clone_range(b.as_ref(), BLKSIZE, BLKSIZE, 0)?;
+    assert_eq!(sfs.info().bfree, free);
+    assert_eq!(d.metadata()?.size, data.len());
+    assert_eq!(read_all(&d)?[..BLKSIZE], [0; BLKSIZE]);
+    assert_eq!(read_all(&d)?[BLKSIZE..], data[BLKSIZE..]);
+    // the shared blocks are kept until the last file is removed
+    root.unlink("b")?;
+    drop(b);
+    assert_eq!(read_all(&d)?[BLKSIZE..], data[BLKSIZE..]);
+    sfs.sync()?;
+    drop((a, c, d, root));
+    drop(sfs);
+
+    let sfs = SimpleFileSystem::open(device)?;
+    let root = sfs.root_inode();
+    let d = root.lookup("d")?;
+    let free = sfs.info().bfree;
+    d.write_at(BLKSIZE, &[1])?;
+    assert_eq!(sfs.info().bfree, free);
+    assert_eq!(read_all(&d)?[BLKSIZE + 1..], data[BLKSIZE + 1..]);
+    root.unlink("d")?;
+    Ok(())
+}
+
+#[test]
+fn copy_range_without_refmap() -> Result<()> {
+    let sfs = _create_new_sfs();
+    assert_eq!(sfs.super_block.read().refmap_blocks, 0);
+    let root = sfs.root_inode();
+    let a = root.create("a", FileType::File, 0o777)?;
+    let b = root.create("b", FileType::File, 0o777)?;
+    a.write_at(0, &[1; BLKSIZE])?;
+
+    assert_eq!(
+        b.clone_range(a.as_ref(), 0, 0, 0),
+        Err(FsError::NotSupported)
+    );
+    // copies write blocks of their own
+    let free = sfs.info().bfree;
+    assert_eq!(b.copy_range(a.as_ref(), 0, 0, BLKSIZE)?, BLKSIZE);
+    assert_eq!(sfs.info().bfree, free - 1);
+    let mut buf = [0; BLKSIZE];
+    b.read_at(0, &mut buf)?;
+    assert_eq!(buf, [1; BLKSIZE]);
+    Ok(())
+}
+
+#[test]
+fn mmap_pages() -> Result<()> {
+    let sfs = _create_new_sfs();
+    let root = sfs.root_inode();
+    let file = root.create("file", FileType::File, 0o777)?;
+    let data: Vec<u8> = (0..5000).map(|i| (i % 251) as u8).collect();
+    file.write_at(0, &data)?;
+    let area = |offset, flags| MMapArea {
+        start_vaddr: 0x10000,
+        end_vaddr: 0x12000,
+        prot: 0x3,
+        flags,
+        offset,
+    };
+    assert_eq!(
+        root.mmap(area(0, MMapArea::MAP_SHARED)).err(),
+        Some(FsError::NotFile)
+    );
+    let unaligned = area(100, MMapArea::MAP_SHARED);
+    assert!(page_cache::map_area(&*file, &unaligned).is_err());
+
+    let pages = page_cache::map_area(&*file, &area(0, MMapArea::MAP_SHARED))?;
+    assert_eq!(pages.len(), 2);
+    let mut buf = [0u8; PAGE_SIZE];
+    pages[0].read(0, &mut buf);
+    assert_eq!(&buf[..], &data[..PAGE_SIZE]);
+    pages[1].read(0, &mut buf);
+    assert_eq!(&buf[..904], &data[PAGE_SIZE..]);
+    assert!(buf[904..].iter().all(|&b| b == 0));
+    // a private mapping gets the same pages to be copied on write
+    let private = page_cache::map_area(&*file, &area(PAGE_SIZE, MMapArea::MAP_PRIVATE))?;
+    assert_eq!(private[0].as_ptr(), pages[1].as_ptr());
+
+    // writes are seen by the mapping
+    file.write_at(10, b"abc")?;
+    pages[0].read(10, &mut buf[..3]);
+    assert_eq!(&buf[..3], b"abc");
+
+    // writes through the mapping are seen by reads, and written back on sync
+    unsafe {
+        *pages[0].as_ptr().add(100) = 0xff;
+        *pages[1].as_ptr().add(1000) = 0xff;
+    }
+    pages[0].mark_dirty();
+    pages[1].mark_dirty();
+    let inode = file.downcast_ref::<INodeImpl>().unwrap();
+    file.read_at(100, &mut buf[..1])?;
+    assert_eq!(buf[0], 0xff);
+    inode._read_at(100, &mut buf[..1])?;
+    assert_eq!(buf[0], data[100]);
+    file.sync_data()?;
+    inode._read_at(100, &mut buf[..1])?;
+    assert_eq!(buf[0], 0xff);
+    // but not beyond the end of file
+    assert_eq!(file.metadata()?.size, 5000);
+    file.resize(6000)?;
+    file.read_at(PAGE_SIZE + 1000, &mut buf[..1])?;
+    assert_eq!(buf[0], 0);
+
+    // truncation and holes punched are seen by the mapping
+    file.resize(50)?;
+    pages[0].read(40, &mut buf[..20]);
+    assert_eq!(&buf[..10], &data[40..50]);
+    assert!(buf[10..20].iter().all(|&b| b == 0));
+    let punch = FallocateFlags {
+        keep_size: true,
+        punch_hole: true,
+        ..Default::default()
+    };
+    file.fallocate(punch, 0, 4)?;
+    pages[0].read(0, &mut buf[..5]);
+    assert_eq!(&buf[..5], &[0, 0, 0, 0, data[4]]);
+    Ok(())
+}
+
+#[test]
+fn sync_mapped_hole() -> Result<()> {
+    let sfs = _create_new_sfs();
+    let file = sfs.root_inode().create("file", FileType::File, 0o777)?;
+    file.resize(2 * PAGE_SIZE)?;
+    let free = sfs.info().bfree;
+    // the page written back is over a hole, so the sync allocates a block
+    let page = file.map_page(0)?;
+    page.write(10, b"mapped");
+    sfs.sync()?;
+    assert_eq!(sfs.info().bfree, free - 1);
+    let inode = file.downcast_ref::<INodeImpl>().unwrap();
+    let mut buf = [0u8; 6];
+    inode._read_at(10, &mut buf)?;
+    assert_eq!(&buf, b"mapped");
+    Ok(())
+}
+
+#[test]
+fn sync_mapped_page() -> Result<()> {
+    let sfs = _create_new_sfs();
+    let file = sfs.root_inode().create("file", FileType::File, 0o777)?;
+    file.write_at(0, b"data")?;
+    file.map_page(0)?.mark_dirty();
+    sfs.sync()?;
+    // and when dropped
+    file.map_page(0)?.mark_dirty();
+    drop(file);
+    drop(sfs);
+    Ok(())
+}
+
+#[test]
+fn limit_errors() -> Result<()> {
+    let sfs = _create_new_sfs();
+    let root = sfs.root_inode();
+    let long = "x".repeat(MAX_FNAME_LEN + 1);
+    assert_eq!(
+        root.create(&long, FileType::File, 0o777).err(),
+        Some(FsError::NameTooLong)
+    );
+    let file = root.create(&long[1..], FileType::File, 0o777)?;
+    assert_eq!(root.link(&long, &file), Err(FsError::NameTooLong));
+    assert_eq!(
+        root.move_(&long[1..], &root, &long),
+        Err(FsError::NameTooLong)
+    );
+    assert_eq!(file.resize(MAX_FILE_SIZE + 1), Err(FsError::FileTooBig));
+    assert_eq!(
+        file.write_at(MAX_FILE_SIZE, b"x").err(),
+        Some(FsError::FileTooBig)
+    );
+    Ok(())
+}
+
+#[test]
+fn read_only() -> Result<()> {
+    let mut file = tempfile::tempfile().expect("failed to create file");
+    let device = Arc::new(Mutex::new(file.try_clone().unwrap()));
+    let mut read_image = || {
+        let mut image = Vec::new();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_to_end(&mut image).unwrap();
+        image
+    };
+    {
+        let sfs = SimpleFileSystem::create(device.clone(), 32 * 4096 * 4096)?;
+        let file1 = sfs.root_inode().create("file1", FileType::File, 0o777)?;
+        file1.write_at(0, b"hello")?;
+        sfs.sync()?;
+    }
+    let image = read_image();
+
+    let sfs = SimpleFileSystem::open_read_only(device)?;
+    let root = sfs.root_inode();
+    let file1 = root.lookup("file1")?;
+    let mut buf = [0u8; 5];
+    assert_eq!(file1.read_at(0, &mut buf)?, 5);
+    assert_eq!(&buf, b"hello");
+
+    assert_eq!(file1.write_at(0, b"world"), Err(FsError::ReadOnly));
+    assert_eq!(file1.resize(0), Err(FsError::ReadOnly));
+    assert_eq!(
+        file1.set_metadata(&file1.metadata()?),
+        Err(FsError::ReadOnly)
+    );
+    assert!(matches!(
+        root.create("file2", FileType::File, 0o777),
+        Err(FsError::ReadOnly)
+    ));
+    assert_eq!(root.link("file2", &file1), Err(FsError::ReadOnly));
+    assert_eq!(root.unlink("file1"), Err(FsError::ReadOnly));
+    assert_eq!(root.move_("file1", &root, "file2"), Err(FsError::ReadOnly));
+    sfs.sync()?;
+    drop((file1, root, sfs));
+
+    assert!(read_image() == image, "the image is changed");
+    Ok(())
+}
+
+#[test]
+fn device_errors() -> Result<()> {
+    let io_error = FsError::Device(DevError::new(DevErrorKind::Io));
+    let file = tempfile::tempfile().expect("failed to create file");
+    let device = Arc::new(FaultyDevice::new(Mutex::new(file)));
+    let sfs = SimpleFileSystem::create(device.clone(), 32 * 4096 * 4096)?;
+    let root = sfs.root_inode();
+    root.create("dir", FileType::Dir, 0o777)?
+        .create("file", FileType::File, 0o777)?;
+    sfs.sync()?;
+
+    // the entries are read from the device
+    device.faults().fail_reads(0..usize::MAX);
+    assert_eq!(root.find("dir").err(), Some(io_error));
+    assert_eq!(root.get_entry(0), Err(io_error));
+    assert_eq!(
+        root.create("new", FileType::File, 0o777).err(),
+        Some(io_error)
+    );
+    device.faults().clear();
+    let dir = root.find("dir")?;
+    // so is an INode not in memory
+    let file_id = dir.find("file")?.metadata()?.inode;
+    device.faults().fail_reads(0..usize::MAX);
+    assert!(sfs.inodes.get(file_id).is_none());
+    assert_eq!(sfs.get_inode(file_id).err(), Some(io_error));
+    device.faults().clear();
+
+    device.faults().fail_writes(0..usize::MAX);
+    let file = dir.find("file")?;
+    assert_eq!(file.write_at(0, b"data"), Err(io_error));
+    assert_eq!(sfs.sync(), Err(io_error));
+    // nothing panics when dropped
+    drop((file, dir, root, sfs));
+    Ok(())
+}
+
+#[test]
+fn corrupted_free_map() -> Result<()> {
+    let sfs = _create_new_sfs();
+    let root = sfs.root_inode();
+    let id = root
+        .create("file", FileType::File, 0o777)?
+        .metadata()?
+        .inode;
+
+    // an entry refers to a free block
+    sfs.free_map.write().set(id, true);
+    assert_eq!(root.find("file").err(), Some(FsError::Corrupted));
+    sfs.free_map.write().set(id, false);
+    root.find("file")?;
+
+    // no free block in the free map, while the super block counts some
+    sfs.free_map.write().set_all(false);
+    assert!(sfs.super_block.read().unused_blocks > 0);
+    assert_eq!(
+        root.create("new", FileType::File, 0o777).err(),
+        Some(FsError::Corrupted)
+    );
+    Ok(())
+}
+
+#[test]
+fn corrupted_nlinks() -> Result<()> {
+    let sfs = _create_new_sfs();
+    let root = sfs.root_inode();
+    let file = root.create("file", FileType::File, 0o777)?;
+    let inode = sfs.get_inode(file.metadata()?.inode)?;
+
+    // an entry refers to an INode without links
+    inode.disk_inode.write().nlinks = 0;
+    assert_eq!(root.unlink("file"), Err(FsError::Corrupted));
+    inode.disk_inode.write().nlinks = 1;
+    root.unlink("file")?;
+    Ok(())
+}
+
+#[test]
+fn corrupted_file_type() -> Result<()> {
+    let sfs = _create_new_sfs();
+    let root = sfs.root_inode();
+    let file = root.create("file", FileType::File, 0o777)?;
+    let inode = sfs.get_inode(file.metadata()?.inode)?;
+
+    // a name index in a directory
+    inode.disk_inode.write().type_ = structs::FileType::DirIndex;
+    assert_eq!(file.metadata().err(), Some(FsError::Corrupted));
+    let entries = root.entries(0).collect::<Vec<_>>();
+    assert!(entries.contains(&Err(FsError::Corrupted)));
+    inode.disk_inode.write().type_ = structs::FileType::File;
+    file.metadata()?;
+    Ok(())
+}
+
+#[test]
+fn power_loss() -> Result<()> {
+    let file = tempfile::tempfile().expect("failed to create file");
+    let device = Arc::new(FaultyDevice::new(Mutex::new(file)));
+    let sfs = SimpleFileSystem::create(device.clone(), 32 * 4096 * 4096)?;
+    let root = sfs.root_inode();
+    root.create("file1", FileType::File, 0o777)?
+        .write_at(0, b"file1")?;
+
+    // the writes are durable only after sync
+    device.faults().cache_writes(true);
+    sfs.sync()?;
+    assert_eq!(device.faults().unsynced(), 0);
+    root.find("file1")?.write_at(0, b"FILE1")?;
+    root.create("file2", FileType::File, 0o777)?;
+    sfs.sync()?;
+    root.find("file1")?.write_at(0, b"lost!")?;
+    root.unlink("file2")?;
+    assert!(device.faults().unsynced() > 0);
+    device.power_loss(&[])?;
+    // the file system in memory can not write any more
+    device.faults().fail_writes(0..usize::MAX);
+    drop((root, sfs));
+    device.faults().clear();
+
+    let sfs = SimpleFileSystem::open_read_only(device)?;
+    let root = sfs.root_inode();
+    let mut buf = [0u8; 5];
+    root.find("file1")?.read_at(0, &mut buf)?;
+    assert_eq!(&buf, b"FILE1");
+    root.find("file2")?;
+    Ok(())
+}
+
+/// A device in memory
+#[derive(Default)]
+struct MemDevice(Mutex<Vec<u8>>);
+
+impl rcore_fs::dev::Device for MemDevice {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> rcore_fs::dev::Result<usize> {
+        let data = self.0.lock().unwrap();
+        let offset = offset.min(data.len());
+        let len = buf.len().min(data.len() - offset);
+        buf[..len].copy_from_slice(&data[offset..offset + len]);
+        Ok(len)
+    }
+    fn write_at(&self, offset: usize, buf: &[u8]) -> rcore_fs::dev::Result<usize> {
+        let mut data = self.0.lock().unwrap();
+        if data.len() < offset + buf.len() {
+            data.resize(offset + buf.len(), 0);
+        }
+        data[offset..offset + buf.len()].copy_from_slice(buf);
+        Ok(buf.len())
+    }
+    fn sync(&self) -> rcore_fs::dev::Result<()> {
+        Ok(())
+    }
+}
+
+/// Check that the entries, the links, the free map and the refmap of the image agree.
+/// If `leaks`, the blocks and the links may be counted more than they are used.
+fn check_image(device: Arc<MemDevice>, leaks: bool) -> std::result::Result<(), String> {
+    let sfs = SimpleFileSystem::open_read_only(device).map_err(|e| format!("open: {:?}", e))?;
+    let inodes = rcore_fs::crash::check_tree(&sfs.root_inode(), leaks)?;
+    let super_block = sfs.super_block.read();
+    // references to each block, only data blocks shared by clones have more than one
+    let mut refs = vec![0usize; super_block.blocks as usize];
+    let mut mark = |block: usize| match refs.get_mut(block) {
+        Some(refs) => {
+            *refs += 1;
+            Ok(())
+        }
+        None => Err(format!("block {} is out of range", block)),
+    };
+    mark(BLKN_SUPER)?;
+    let maps = super_block.freemap_blocks + super_block.refmap_blocks;
+    for block in BLKN_FREEMAP..BLKN_FREEMAP + maps as usize {
+        mark(block)?;
+    }
+    if super_block.has_journal() {
+        let start = super_block.journal_start as usize;
+        for block in start..start + super_block.journal_blocks as usize {
+            mark(block)?;
+        }
+    }
+    // the name indexes of directories are not in the tree
+    let mut ids = Vec::new();
+    for &id in inodes.keys() {
+        ids.push(id);
+        let inode = sfs
+            .get_inode(id)
+            .map_err(|e| format!("INode {}: {:?}", id, e))?;
+        let index = inode.disk_inode.read().index;
+        if index != 0 {
+            ids.push(index as INodeId);
+        }
+    }
+    for id in ids {
+        let error = |e: FsError| format!("INode {}: {:?}", id, e);
+        let inode = sfs.get_inode(id).map_err(error)?;
+        mark(id)?;
+        let DiskINode {
+            blocks,
+            indirect,
+            db_indirect,
+            ..
+        } = **inode.disk_inode.read();
+        for i in 0..blocks as usize {
+            match inode.get_disk_block_id(i).map_err(error)? {
+                0 => {}
+                block => mark(block)?,
+            }
+        }
+        if super_block.extents() {
+            let mut nodes = vec![inode.load_node(NodeAt::Root).map_err(error)?];
+            while let Some(node) = nodes.pop() {
+                if node.depth == 0 {
+                    continue;
+                }
+                for entry in node.entries.iter() {
+                    mark(entry.block as usize)?;
+                    nodes.push(inode.load_child(entry, node.depth).map_err(error)?);
+                }
+            }
+            continue;
+        }
+        if indirect != 0 {
+            mark(indirect as usize)?;
+        }
+        if db_indirect != 0 {
+            mark(db_indirect as usize)?;
+            for i in 0..BLK_NENTRY {
+                match inode
+                    .indirect_entry(db_indirect as usize, i, false)
+                    .map_err(error)?
+                {
+                    0 => {}
+                    block => mark(block)?,
+                }
+            }
+        }
+    }
+    let free_map = sfs.free_map.read();
+    let ref_map = sfs.ref_map.read();
+    for (block, &refs) in refs.iter().enumerate() {
+        // the refmap counts the references but the first one
+        let counted = 1 + ref_map.get(block).map_or(0, |&shares| shares as usize);
+        match (free_map[block], refs) {
+            (true, 0) if counted > 1 && !leaks => {
+                return Err(format!("block {} is free but shared", block));
+            }
+            (true, 0) => {}
+            (true, _) => return Err(format!("block {} is in use but free", block)),
+            (false, 0) if !leaks => return Err(format!("block {} is leaked", block)),
+            (false, 0) => {}
+            (false, _) if refs > counted || (refs < counted && !leaks) => {
+                return Err(format!(
+                    "block {} has {} references, but {} are counted",
+                    block, refs, counted
+                ));
+            }
+            _ => {}
+        }
+    }
+    let unused = match leaks {
+        // the leaked blocks are not counted either
+        true => free_map.count_ones(),
+        false => refs.iter().filter(|&&refs| refs == 0).count(),
+    };
+    if super_block.unused_blocks as usize > unused
+        || (super_block.unused_blocks as usize) < unused && !leaks
+    {
+        return Err(format!(
+            "{} unused blocks are counted, but {} are free",
+            super_block.unused_blocks, unused
+        ));
+    }
+    Ok(())
+}
+
+/// Replay every crash point of `steps` on SFS created with `options`.
+/// A crash may only leak INodes and blocks, and leaves none at a `Sync` or with a journal.
+/// Without a journal, the entries created or moved are written in place before the INodes
+/// they refer to, so the image may be inconsistent from then on until the next `Sync` ends.
+fn check_crashes(options: &CreateOptions, steps: &[crash::Step]) -> Result<()> {
+    check_crashes_in(256 * BLKSIZE, options, steps)?;
+    Ok(())
+}
+
+/// Like `check_crashes`, but on SFS of `space` bytes. Return the number of writes after each step.
+fn check_crashes_in(
+    space: usize,
+    options: &CreateOptions,
+    steps: &[crash::Step],
+) -> Result<Vec<usize>> {
+    let device = Arc::new(FaultyDevice::new(MemDevice::default()));
+    let sfs = SimpleFileSystem::create_with_options(device.clone(), space, options)?;
+    sfs.sync()?;
+    let base = device.inner().0.lock().unwrap().clone();
+    check_image(Arc::new(MemDevice(Mutex::new(base.clone()))), false).unwrap();
+
+    device.faults().record();
+    let ends = crash::run_workload(&*sfs, steps, || device.faults().log_len())?;
+    let log = device.faults().take_log();
+    let checkpoints = crash::checkpoints(steps, &ends);
+    let windows = match options.journal_blocks {
+        0 => crash::unsafe_windows(steps, &ends, |step| {
+            matches!(step, crash::Step::Create(..) | crash::Step::Rename(..))
+        }),
+        _ => Vec::new(),
+    };
+    for len in 0..=log.len() {
+        let image = Arc::new(MemDevice(Mutex::new(base.clone())));
+        log.replay(len, &*image).unwrap();
+        let problems = fsck::check(image.clone(), false);
+        if windows.iter().any(|window| window.contains(&len)) {
+            // only required not to panic
+            continue;
+        }
+        let clean = options.journal_blocks != 0 || checkpoints.contains(&len);
+        if let Err(e) = check_image(image.clone(), !clean) {
+            panic!("crash after {} of {} writes: {}", len, log.len(), e);
+        }
+        if clean {
+            assert_eq!(problems, Ok(Vec::new()), "crash after {} writes", len);
+        }
+    }
+    Ok(ends)
+}
+
+#[test]
+fn crash_consistency() -> Result<()> {
+    check_crashes(&CreateOptions::default(), crash::WORKLOAD)
+}
+
+#[test]
+fn crash_consistency_journal() -> Result<()> {
+    let options = CreateOptions {
+        journal_blocks: 32,
+        ..CreateOptions::default()
+    };
+    check_crashes(&options, crash::WORKLOAD)
+}
+
+#[test]
+fn crash_consistency_journal_clone() -> Result<()> {
+    use crash::Step::*;
+    let space = 32 * BLK_NREF * BLKSIZE;
+    let mut options = CreateOptions {
+        refmap: true,
+        ..CreateOptions::default()
+    };
+    options.journal_blocks = SimpleFileSystem::min_journal_blocks(space, &options);
+    let steps = &[
+        Create("a", FileType::File),
+        Write("a", 0, b"shared"),
+        Write("a", BLKSIZE, b"shared too"),
+        Sync,
+        Create("b", FileType::File),
+        Clone("a", "b"),
+        Sync,
+        Write("b", 0, b"private"),
+        Unlink("a"),
+        Sync,
+        Unlink("b"),
+        Sync,
+    ];
+    let ends = check_crashes_in(space, &options, steps)?;
+    // only the blocks changed of the maps are journaled
+    let clone = ends[5] - ends[4];
+    assert!(clone < 32, "{} writes to clone", clone);
+    Ok(())
+}
+
+#[test]
+fn journal_size() -> Result<()> {
+    let space = 32 * BLK_NREF * BLKSIZE;
+    let mut options = CreateOptions {
+        refmap: true,
+        ..CreateOptions::default()
+    };
+    let min = SimpleFileSystem::min_journal_blocks(space, &options);
+    // the maps and the mappings of two files as large as the image, besides the header
+    assert!(min > 1 + 4 + 32 + 2 * 130);
+    for journal_blocks in [8, min - 1] {
+        options.journal_blocks = journal_blocks;
+        let device = Arc::new(MemDevice::default());
+        assert_eq!(
+            SimpleFileSystem::create_with_options(device, space, &options).err(),
+            Some(FsError::InvalidParam)
+        );
+    }
+    // more blocks than a header can name
+    let space = 2 * JOURNAL_NENTRY * BLK_NREF * BLKSIZE;
+    options.journal_blocks = SimpleFileSystem::min_journal_blocks(space, &options);
+    let device = Arc::new(MemDevice::default());
+    let sfs = SimpleFileSystem::create_with_options(device.clone(), space, &options)?;
+    sfs.root_inode().create("file", FileType::File, 0o777)?;
+    drop(sfs);
+    check_image(device, false).unwrap();
+    Ok(())
+}
+
+#[test]
+fn journal_abort() -> Result<()> {
+    let device = Arc::new(MemDevice::default());
+    let journal = journal::Journal::new(0, 3, 1);
+    for block in 1..4 {
+        journal.write(&*device, block, 0, b"lost")?;
+    }
+    // never split, nor written in place
+    assert_eq!(journal.commit(&*device), Err(FsError::NoDeviceSpace));
+    assert!(journal.is_aborted());
+    assert_eq!(journal.commit(&*device), Err(FsError::ReadOnly));
+    assert_eq!(journal.write(&*device, 1, 0, b"x"), Err(FsError::ReadOnly));
+    assert!(device.0.lock().unwrap().iter().all(|&b| b == 0));
+    Ok(())
+}
+
+#[test]
+fn reuse_freed_blocks() -> Result<()> {
+    let device = Arc::new(MemDevice::default());
+    let sfs = SimpleFileSystem::create(device.clone(), 256 * BLKSIZE)?;
+    let root = sfs.root_inode();
+    let fill = root.create("fill", FileType::File, 0o777)?;
+    let mut len = 0;
+    while fill.write_at(len, &[0xff; BLKSIZE]).is_ok() {
+        len += BLKSIZE;
+    }
+    sfs.sync()?;
+
+    // the blocks freed since the sync are reused only if there are no others
+    fill.resize(0)?;
+    let file = root.create("file", FileType::File, 0o777)?;
+    file.write_at(0, &vec![1; len - BLKSIZE])?;
+    sfs.sync()?;
+    drop((file, fill, root, sfs));
+    check_image(device, false).unwrap();
+    Ok(())
+}
+
+#[test]
+fn journal_replay() -> Result<()> {
+    let device = Arc::new(FaultyDevice::new(MemDevice::default()));
+    let options = CreateOptions {
+        journal_blocks: 32,
+        ..CreateOptions::default()
+    };
+    let sfs = SimpleFileSystem::create_with_options(device.clone(), 256 * BLKSIZE, &options)?;
+    sfs.sync()?;
+    let base = device.inner().0.lock().unwrap().clone();
+    device.faults().record();
+    let dir = sfs.root_inode().create("dir", FileType::Dir, 0o777)?;
+    dir.create("file", FileType::File, 0o777)?;
+    sfs.sync()?;
+    let log = device.faults().take_log();
+    drop(dir);
+    drop(sfs);
+
+    // crash right after the header of the last transaction is written,
+    // when a read-only SFS first finds the file through the journal
+    let visible = |len: usize| {
+        let image = MemDevice(Mutex::new(base.clone()));
+        log.replay(len, &image).unwrap();
+        let sfs = SimpleFileSystem::open_read_only(Arc::new(image)).unwrap();
+        sfs.root_inode().lookup("dir/file").is_ok()
+    };
+    let len = (0..=log.len()).find(|&len| visible(len)).unwrap();
+    let image = Arc::new(MemDevice(Mutex::new(base)));
+    log.replay(len, &*image).unwrap();
+    let header_magic = |image: &MemDevice, start: usize| {
+        let data = image.0.lock().unwrap();
+        let mut magic = [0u8; 4];
+        magic.copy_from_slice(&data[start * BLKSIZE..start * BLKSIZE + 4]);
+        u32::from_le_bytes(magic)
+    };
+
+    // replayed when opened for writing, and the header is cleared
+    let sfs = SimpleFileSystem::open(image.clone())?;
+    let start = sfs.super_block.read().journal_start as usize;
+    assert!(sfs.root_inode().lookup("dir/file").is_ok());
+    drop(sfs);
+    assert_ne!(header_magic(&image, start), JOURNAL_MAGIC);
+    check_image(image, false).unwrap();
+    Ok(())
+}
+
+#[test]
+fn journal_too_small() {
+    let device = Arc::new(MemDevice::default());
+    let options = CreateOptions {
+        journal_blocks: 1,
+        ..CreateOptions::default()
+    };
+    assert!(SimpleFileSystem::create_with_options(device, 256 * BLKSIZE, &options).is_err());
+}
+
+/// An image with a hard link, a clone, a symlink and a file with double indirect blocks
+fn fsck_image() -> Result<Arc<MemDevice>> {
+    let device = Arc::new(MemDevice::default());
+    let options = CreateOptions {
+        refmap: true,
+        ..CreateOptions::default()
+    };
+    let sfs = SimpleFileSystem::create_with_options(device.clone(), 256 * BLKSIZE, &options)?;
+    let root = sfs.root_inode();
+    let dir = root.create("d", FileType::Dir, 0o777)?;
+    let file = dir.create("f", FileType::File, 0o777)?;
+    file.write_at(0, &[1; BLKSIZE])?;
+    file.write_at(5 << 20, b"far away")?;
+    dir.link("g", &file)?;
+    dir.create("x", FileType::File, 0o777)?;
+    dir.create("y", FileType::File, 0o777)?.write_at(0, b"y")?;
+    root.create("c", FileType::File, 0o777)?
+        .clone_range(file.as_ref(), 0, 0, BLKSIZE)?;
+    root.symlink("s", "d/f")?;
+    sfs.sync()?;
+    Ok(device)
+}
+
+#[test]
+fn fsck_clean() -> Result<()> {
+    let device = fsck_image()?;
+    check_image(device.clone(), false).unwrap();
+    let image = device.0.lock().unwrap().clone();
+    assert_eq!(fsck::check(device.clone(), false)?, []);
+    assert_eq!(fsck::check(device.clone(), true)?, []);
+    assert!(*device.0.lock().unwrap() == image);
+    Ok(())
+}
+
+#[test]
+fn fsck_repair() -> Result<()> {
+    use core::mem::offset_of;
+    let device = fsck_image()?;
+    let (f, x, y, d) = {
+        let sfs = SimpleFileSystem::open_read_only(device.clone())?;
+        let root = sfs.root_inode();
+        let id = |path| root.lookup(path).unwrap().metadata().unwrap().inode;
+        (id("d/f"), id("d/x"), id("d/y"), id("d"))
+    };
+    let peek = |offset: usize| {
+        let data = device.0.lock().unwrap();
+        u32::from_ne_bytes([
+            data[offset],
+            data[offset + 1],
+            data[offset + 2],
+            data[offset + 3],
+        ])
+    };
+    let poke = |offset: usize, bytes: &[u8]| {
+        device.0.lock().unwrap()[offset..offset + bytes.len()].copy_from_slice(bytes);
+    };
+    let direct = |id: usize| id * BLKSIZE + offset_of!(DiskINode, direct);
+    let set_free = |block: usize, free: bool| {
+        let mut data = device.0.lock().unwrap();
+        let byte = &mut data[BLKN_FREEMAP * BLKSIZE + block / 8];
+        *byte = (*byte & !(1 << (block % 8))) | ((free as u8) << (block % 8));
+    };
+
+    // "f" has one more link, and its first block is free
+    poke(
+        f * BLKSIZE + offset_of!(DiskINode, nlinks),
+        &3u16.to_ne_bytes(),
+    );
+    let f_block = peek(direct(f)) as usize;
+    set_free(f_block, true);
+    // the last block is leaked, and the count of unused blocks is wrong
+    set_free(255, false);
+    let unused = peek(offset_of!(SuperBlock, unused_blocks));
+    poke(
+        offset_of!(SuperBlock, unused_blocks),
+        &(unused + 5).to_ne_bytes(),
+    );
+    // the entry "x" is renamed to "x/"
+    let d_block = peek(direct(d)) as usize;
+    let x_entry = (2..BLKSIZE / DIRENT_SIZE)
+        .map(|i| d_block * BLKSIZE + i * DIRENT_SIZE)
+        .find(|&offset| device.0.lock().unwrap()[offset + ENTRY_SIZE..][..2] == *b"x\0")
+        .unwrap();
+    poke(x_entry + ENTRY_SIZE + 1, b"/");
+    // the first block of "y" is out of range
+    let y_block = peek(direct(y)) as usize;
+    poke(direct(y), &1000u32.to_ne_bytes());
+
+    let expected = [
+        Problem::WrongLinks {
+            id: f,
+            nlinks: 3,
+            entries: 2,
+        },
+        Problem::UsedButFree(f_block),
+        Problem::Leaked(255),
+        Problem::WrongUnusedBlocks {
+            counted: unused + 5,
+            // the INode of "x" and the first block of "y" are freed
+            free: unused as usize + 2,
+        },
+        Problem::BadEntry {
+            dir: d,
+            index: (x_entry % BLKSIZE) / DIRENT_SIZE,
+            reason: "has a name with '/'",
+        },
+        Problem::Leaked(x),
+        Problem::BadPointer {
+            id: y,
+            block: 1000,
+            reason: "out of range",
+        },
+        Problem::Leaked(y_block),
+    ];
+    let check = |repair| {
+        let problems = fsck::check(device.clone(), repair).unwrap();
+        for problem in expected.iter() {
+            assert!(problems.contains(problem), "{} is not found", problem);
+        }
+        assert_eq!(problems.len(), expected.len(), "{:?}", problems);
+    };
+    // not changed without repair
+    check(false);
+    check(true);
+    assert_eq!(fsck::check(device.clone(), false)?, []);
+
+    let sfs = SimpleFileSystem::open(device)?;
+    let dir = sfs.root_inode().lookup("d")?;
+    assert!(dir.find("x").is_err());
+    assert_eq!(dir.find("f")?.metadata()?.nlinks, 2);
+    // the bad block of "y" becomes a hole
+    let mut buf = [0xff];
+    assert_eq!(dir.find("y")?.read_at(0, &mut buf)?, 1);
+    assert_eq!(buf, [0]);
+    Ok(())
+}
+
+/// A workload growing a directory over `DIR_INDEX_THRESHOLD` in the fixed format, so that it is indexed
+const INDEX_WORKLOAD: &[crash::Step] = {
+    use crash::Step::*;
+    use FileType::File;
+    &[
+        Create("i", FileType::Dir),
+        Create("i/00", File),
+        Create("i/01", File),
+        Create("i/02", File),
+        Create("i/03", File),
+        Create("i/04", File),
+        Create("i/05", File),
+        Create("i/06", File),
+        Create("i/07", File),
+        Create("i/08", File),
+        Create("i/09", File),
+        Create("i/10", File),
+        Create("i/11", File),
+        Create("i/12", File),
+        Sync,
+        Create("i/13", File),
+        Create("i/14", File),
+        Sync,
+        Rename("i/03", "i/30"),
+        Rename("i/04", "04"),
+        Unlink("i/05"),
+        Sync,
+        Create("i/15", File),
+        Rename("04", "i/14"),
+        Sync,
+    ]
+};
+
+#[test]
+fn crash_consistency_dir_index() -> Result<()> {
+    let options = CreateOptions {
+        dir_index: true,
+        ..CreateOptions::default()
+    };
+    check_crashes(&options, INDEX_WORKLOAD)?;
+    let options = CreateOptions {
+        journal_blocks: 32,
+        dir_index: true,
+        ..CreateOptions::default()
+    };
+    check_crashes(&options, INDEX_WORKLOAD)
+}
+
+#[test]
+fn dir_index() -> Result<()> {
+    let names = |prefix: &str, range: core::ops::Range<usize>| {
+        let prefix = String::from(prefix);
+        range.map(move |i| format!("{}{}", prefix, i))
+    };
+    // large directories stay linear without the feature
+    let sfs = SimpleFileSystem::create(Arc::new(MemDevice::default()), 256 * BLKSIZE)?;
+    let root = sfs.root_inode();
+    for name in names("f", 0..20) {
+        root.create(&name, FileType::File, 0o777)?;
+    }
+    assert_eq!(sfs.get_inode(BLKN_ROOT)?.disk_inode.read().index, 0);
+
+    let device = Arc::new(MemDevice::default());
+    let options = CreateOptions {
+        dir_index: true,
+        ..CreateOptions::default()
+    };
+    let sfs = SimpleFileSystem::create_with_options(device.clone(), 1024 * BLKSIZE, &options)?;
+    let root = sfs.root_inode();
+    let dir = root.create("d", FileType::Dir, 0o777)?;
+    let dir_id = dir.metadata()?.inode;
+    for name in names("f", 0..100) {
+        dir.create(&name, FileType::File, 0o777)?;
+    }
+    assert_ne!(sfs.get_inode(dir_id)?.disk_inode.read().index, 0);
+    for name in names("f", 0..100) {
+        dir.find(&name)?;
+    }
+    assert_eq!(dir.find("g0").err(), Some(FsError::EntryNotFound));
+    assert_eq!(
+        dir.create("f0", FileType::File, 0o777).err(),
+        Some(FsError::EntryExist)
+    );
+
+    // free entries are reused, and the directory is not truncated
+    let size = dir.metadata()?.size;
+    for name in names("f", 90..100) {
+        dir.unlink(&name)?;
+        assert_eq!(dir.find(&name).err(), Some(FsError::EntryNotFound));
+    }
+    for name in names("g", 0..10) {
+        dir.create(&name, FileType::File, 0o777)?;
+    }
+    assert_eq!(dir.metadata()?.size, size);
+    assert_eq!(dir.entries(0).count(), 102);
+
+    dir.move_("f20", &dir, "h20")?;
+    dir.move_("f21", &root, "f21")?;
+    root.move_("f21", &dir, "h21")?;
+    dir.move_("f22", &dir, "f23")?;
+    for (name, found) in [("f20", false), ("h20", true), ("h21", true), ("f22", false)] {
+        assert_eq!(dir.find(name).is_ok(), found, "{}", name);
+    }
+
+    // an indexed directory is empty with only "." and ".."
+    let sub = dir.create("sub", FileType::Dir, 0o777)?;
+    for name in names("s", 0..20) {
+        sub.create(&name, FileType::File, 0o777)?;
+    }
+    assert_eq!(dir.unlink("sub").err(), Some(FsError::DirNotEmpty));
+    for name in names("s", 0..20) {
+        sub.unlink(&name)?;
+    }
+    dir.unlink("sub")?;
+    drop(sub);
+
+    sfs.sync()?;
+    drop((dir, root, sfs));
+    check_image(device.clone(), false).unwrap();
+    assert_eq!(fsck::check(device.clone(), false)?, []);
+    let sfs = SimpleFileSystem::open(device)?;
+    sfs.root_inode().lookup("d/h21")?;
+    Ok(())
+}
+
+#[test]
+fn fsck_bad_dir_index() -> Result<()> {
+    let device = Arc::new(MemDevice::default());
+    let options = CreateOptions {
+        dir_index: true,
+        ..CreateOptions::default()
+    };
+    let sfs = SimpleFileSystem::create_with_options(device.clone(), 256 * BLKSIZE, &options)?;
+    let dir = sfs.root_inode().create("d", FileType::Dir, 0o777)?;
+    for i in 0..20 {
+        dir.create(&format!("f{}", i), FileType::File, 0o777)?;
+    }
+    let dir_id = dir.metadata()?.inode;
+    let index = sfs.get_inode(dir_id)?.dir_index()?.unwrap();
+    let header = index.get_disk_block_id(0)? * BLKSIZE;
+    drop((index, dir));
+    sfs.sync()?;
+    drop(sfs);
+
+    // the count of entries in the header is wrong
+    device.0.lock().unwrap()[header] += 1;
+    let bad_index = Problem::BadIndex {
+        dir: dir_id,
+        reason: "has a wrong count of entries",
+    };
+    assert!(fsck::check(device.clone(), false)?.contains(&bad_index));
+    assert!(fsck::check(device.clone(), true)?.contains(&bad_index));
+    assert_eq!(fsck::check(device.clone(), false)?, []);
+
+    // read in the linear format, and indexed again when it grows
+    let sfs = SimpleFileSystem::open(device.clone())?;
+    let dir = sfs.root_inode().lookup("d")?;
+    assert_eq!(sfs.get_inode(dir_id)?.disk_inode.read().index, 0);
+    dir.find("f19")?;
+    dir.create("g", FileType::File, 0o777)?;
+    assert_ne!(sfs.get_inode(dir_id)?.disk_inode.read().index, 0);
+    dir.find("f19")?;
+    sfs.sync()?;
+    drop((dir, sfs));
+    assert_eq!(fsck::check(device, false)?, []);
+    Ok(())
+}
+
+#[test]
+fn compact_dirents() -> Result<()> {
+    let long_name = |i: usize| format!("{:0>100}", i);
+    let device = Arc::new(MemDevice::default());
+    let options = CreateOptions {
+        compact_dirents: true,
+        ..CreateOptions::default()
+    };
+    let sfs = SimpleFileSystem::create_with_options(device.clone(), 256 * BLKSIZE, &options)?;
+    let root = sfs.root_inode();
+    let dir = root.create("d", FileType::Dir, 0o777)?;
+    // 37 entries of long names fit in a block after "." and ".."
+    for i in 0..100 {
+        dir.create(&long_name(i), FileType::File, 0o777)?;
+    }
+    assert_eq!(dir.metadata()?.size, 3 * BLKSIZE);
+    dir.create("sub", FileType::Dir, 0o777)?;
+    dir.create("link", FileType::SymLink, 0o777)?;
+
+    // the types are read from the entries
+    let entries = dir.entries(0).collect::<Result<Vec<_>>>()?;
+    assert_eq!(entries.len(), 104);
+    let type_of = |name: &str| entries.iter().find(|e| e.name == name).unwrap().type_;
+    assert_eq!(type_of("."), FileType::Dir);
+    assert_eq!(type_of("sub"), FileType::Dir);
+    assert_eq!(type_of("link"), FileType::SymLink);
+    assert_eq!(type_of(&long_name(0)), FileType::File);
+
+    // cookies are stable, and the space of removed entries is reused in place
+    let cookie = entries[50].next_cookie;
+    let next = entries[51].name.clone();
+    for i in 10..20 {
+        dir.unlink(&long_name(i))?;
+    }
+    assert_eq!(dir.entries(cookie).next().unwrap()?.name, next);
+    for i in 200..210 {
+        dir.create(&long_name(i), FileType::File, 0o777)?;
+    }
+    assert_eq!(dir.metadata()?.size, 3 * BLKSIZE);
+    assert_eq!(dir.entries(cookie).next().unwrap()?.name, next);
+
+    // a longer name is moved to another entry
+    dir.move_("sub", &dir, &"s".repeat(MAX_FNAME_LEN))?;
+    dir.move_(&long_name(1), &dir, "short")?;
+    for (name, found) in [
+        ("sub", false),
+        (&*"s".repeat(MAX_FNAME_LEN), true),
+        ("short", true),
+    ] {
+        assert_eq!(dir.find(name).is_ok(), found, "{}", name);
+    }
+
+    // empty blocks at the end are truncated
+    for i in (0..100).filter(|&i| i != 1 && !(10..20).contains(&i)) {
+        dir.unlink(&long_name(i))?;
+    }
+    for i in 200..210 {
+        dir.unlink(&long_name(i))?;
+    }
+    dir.unlink(&"s".repeat(MAX_FNAME_LEN))?;
+    dir.unlink("short")?;
+    assert_eq!(root.unlink("d").err(), Some(FsError::DirNotEmpty));
+    dir.unlink("link")?;
+    assert_eq!(dir.metadata()?.size, BLKSIZE);
+    root.unlink("d")?;
+    drop(dir);
+    root.create("e", FileType::Dir, 0o777)?
+        .create("f", FileType::File, 0o777)?;
+
+    sfs.sync()?;
+    drop((root, sfs));
+    check_image(device.clone(), false).unwrap();
+    assert_eq!(fsck::check(device.clone(), false)?, []);
+    let sfs = SimpleFileSystem::open(device)?;
+    sfs.root_inode().lookup("e/f")?;
+    Ok(())
+}
+
+#[test]
+fn compact_dirents_indexed() -> Result<()> {
+    let device = Arc::new(MemDevice::default());
+    let options = CreateOptions {
+        dir_index: true,
+        compact_dirents: true,
+        ..CreateOptions::default()
+    };
+    let sfs = SimpleFileSystem::create_with_options(device.clone(), 1024 * BLKSIZE, &options)?;
+    let dir = sfs.root_inode().create("d", FileType::Dir, 0o777)?;
+    let dir_id = dir.metadata()?.inode;
+    // indexed when it grows over a block
+    for i in 0..300 {
+        dir.create(&format!("f{}", i), FileType::File, 0o777)?;
+    }
+    assert_eq!(sfs.get_inode(dir_id)?.disk_inode.read().index, 0);
+    for i in 300..400 {
+        dir.create(&format!("f{}", i), FileType::File, 0o777)?;
+    }
+    assert_ne!(sfs.get_inode(dir_id)?.disk_inode.read().index, 0);
+    for i in 0..400 {
+        dir.find(&format!("f{}", i))?;
+    }
+
+    for i in 0..100 {
+        dir.unlink(&format!("f{}", i))?;
+    }
+    let size = dir.metadata()?.size;
+    for i in 0..100 {
+        dir.create(&format!("g{}", i), FileType::File, 0o777)?;
+    }
+    assert_eq!(dir.metadata()?.size, size);
+    dir.move_("g0", &dir, &"h".repeat(100))?;
+    dir.find(&"h".repeat(100))?;
+    assert_eq!(dir.find("g0").err(), Some(FsError::EntryNotFound));
+    assert_eq!(dir.entries(0).count(), 402);
+
+    sfs.sync()?;
+    drop((dir, sfs));
+    check_image(device.clone(), false).unwrap();
+    assert_eq!(fsck::check(device, false)?, []);
+    Ok(())
+}
+
+#[test]
+fn compact_dirents_indexed_gaps() -> Result<()> {
+    let long_name = |i: usize| format!("{:0>100}", i);
+    let device = Arc::new(MemDevice::default());
+    let options = CreateOptions {
+        dir_index: true,
+        compact_dirents: true,
+        ..CreateOptions::default()
+    };
+    let sfs = SimpleFileSystem::create_with_options(device.clone(), 256 * BLKSIZE, &options)?;
+    let dir = sfs.root_inode().create("d", FileType::Dir, 0o777)?;
+    // the first block is left with space for short names only, and indexed by the next ones
+    for i in 0..40 {
+        dir.create(&long_name(i), FileType::File, 0o777)?;
+    }
+    dir.create("short", FileType::File, 0o777)?;
+    let names = dir
+        .entries(0)
+        .map(|entry| Ok(entry?.name))
+        .collect::<Result<Vec<_>>>()?;
+    let position = |name: &str| names.iter().position(|n| n == name).unwrap();
+    assert!(position("short") < position(&long_name(37)));
+    Ok(())
+}
+
+#[test]
+fn crash_consistency_compact_dirents() -> Result<()> {
+    let options = CreateOptions {
+        compact_dirents: true,
+        ..CreateOptions::default()
+    };
+    check_crashes(&options, INDEX_WORKLOAD)?;
+    let options = CreateOptions {
+        journal_blocks: 32,
+        compact_dirents: true,
+        ..CreateOptions::default()
+    };
+    check_crashes(&options, crash::WORKLOAD)
+}
+
+#[test]
+fn fsck_bad_compact_dirent() -> Result<()> {
+    let device = Arc::new(MemDevice::default());
+    let options = CreateOptions {
+        compact_dirents: true,
+        ..CreateOptions::default()
+    };
+    let sfs = SimpleFileSystem::create_with_options(device.clone(), 256 * BLKSIZE, &options)?;
+    let dir = sfs.root_inode().create("d", FileType::Dir, 0o777)?;
+    let ids = ["a", "b", "c"]
+        .iter()
+        .map(|name| Ok(dir.create(name, FileType::File, 0o777)?.metadata()?.inode))
+        .collect::<Result<Vec<_>>>()?;
+    let dir_id = dir.metadata()?.inode;
+    let block = sfs.get_inode(dir_id)?.get_disk_block_id(0)? * BLKSIZE;
+    drop(dir);
+    sfs.sync()?;
+    drop(sfs);
+
+    // "a" at 24 records a wrong type, and the length of "b" at 36 is broken
+    let a = COMPACT_DOTDOT * 2;
+    let b = a + CompactEntry::len_of(1);
+    device.0.lock().unwrap()[block + a + 7] = structs::FileType::Dir as u8;
+    device.0.lock().unwrap()[block + b + 4..][..2].copy_from_slice(&3u16.to_ne_bytes());
+    let expected = [
+        Problem::WrongEntryType {
+            dir: dir_id,
+            index: a,
+            found: structs::FileType::Dir as u8,
+            expected: structs::FileType::File,
+        },
+        Problem::BadEntry {
+            dir: dir_id,
+            index: b,
+            reason: "has a wrong length",
+        },
+        // "b" and "c" after it are lost
+        Problem::Leaked(ids[1]),
+        Problem::Leaked(ids[2]),
+    ];
+    for repair in [false, true] {
+        let problems = fsck::check(device.clone(), repair)?;
+        for problem in expected.iter() {
+            assert!(problems.contains(problem), "{} is not found", problem);
+        }
+    }
+    assert_eq!(fsck::check(device.clone(), false)?, []);
+
+    let sfs = SimpleFileSystem::open(device.clone())?;
+    let dir = sfs.root_inode().lookup("d")?;
+    assert_eq!(dir.list()?, [".", "..", "a"]);
+    dir.create("b", FileType::File, 0o777)?;
+    sfs.sync()?;
+    drop((dir, sfs));
+    assert_eq!(fsck::check(device, false)?, []);
+    Ok(())
+}
+
+/// SFS with extents on a device of `blocks` blocks
+fn create_extents_sfs(blocks: usize) -> Result<(Arc<MemDevice>, Arc<SimpleFileSystem>)> {
+    let device = Arc::new(MemDevice::default());
+    let options = CreateOptions {
+        extents: true,
+        refmap: true,
+        ..CreateOptions::default()
+    };
+    let sfs = SimpleFileSystem::create_with_options(device.clone(), blocks * BLKSIZE, &options)?;
+    Ok((device, sfs))
+}
+
+#[test]
+fn extents() -> Result<()> {
+    let (device, sfs) = create_extents_sfs(4096)?;
+    let root = sfs.root_inode();
+    let free = sfs.info().bfree;
+
+    // a sequential file is a single extent in the INode
+    let file = root.create("seq", FileType::File, 0o777)?;
+    let data: Vec<u8> = (0..512 * BLKSIZE).map(|i| (i % 251) as u8).collect();
+    for chunk in data.chunks(16 * BLKSIZE + 100) {
+        let offset = chunk.as_ptr() as usize - data.as_ptr() as usize;
+        file.write_at(offset, chunk)?;
+    }
+    let inode = sfs.get_inode(file.metadata()?.inode)?;
+    let node = inode.load_node(NodeAt::Root)?;
+    assert_eq!(node.depth, 0);
+    assert_eq!(node.entries.len(), 1);
+    assert_eq!(node.entries[0].len, 512);
+    assert_eq!(sfs.info().bfree, free - 1 - 512);
+    let mut buf = vec![0u8; data.len()];
+    assert_eq!(file.read_at(0, &mut buf)?, data.len());
+    assert!(buf == data);
+
+    // every other block makes an extent, which splits the nodes
+    let sparse = root.create("sparse", FileType::File, 0o777)?;
+    for i in 0..1500 {
+        sparse.write_at(2 * i * BLKSIZE, &[(i % 255) as u8 + 1])?;
+    }
+    let inode = sfs.get_inode(sparse.metadata()?.inode)?;
+    assert_eq!(inode.load_node(NodeAt::Root)?.depth, 2);
+    for i in (0..1500).step_by(7) {
+        let mut buf = [0u8; 2];
+        sparse.read_at(2 * i * BLKSIZE, &mut buf)?;
+        assert_eq!(buf, [(i % 255) as u8 + 1, 0]);
+    }
+    assert_eq!(sparse.seek_hole(0)?, Some(BLKSIZE));
+    assert_eq!(sparse.seek_data(BLKSIZE)?, Some(2 * BLKSIZE));
+
+    // fill the holes, then punch some of them again
+    sparse.write_at(0, &vec![7u8; 3000 * BLKSIZE])?;
+    let punch = FallocateFlags {
+        keep_size: true,
+        punch_hole: true,
+        ..Default::default()
+    };
+    sparse.fallocate(punch, 100 * BLKSIZE, 1000 * BLKSIZE)?;
+    let mut buf = [1u8; 2];
+    sparse.read_at(100 * BLKSIZE - 1, &mut buf)?;
+    assert_eq!(buf, [7, 0]);
+    sparse.read_at(1100 * BLKSIZE - 1, &mut buf)?;
+    assert_eq!(buf, [0, 7]);
+    sparse.resize(1050 * BLKSIZE)?;
+    sfs.sync()?;
+    check_image(device.clone(), false).unwrap();
+    assert_eq!(fsck::check(device.clone(), false)?, []);
+
+    // all blocks and nodes are freed
+    sparse.resize(0)?;
+    assert!(inode.load_node(NodeAt::Root)?.entries.is_empty());
+    file.resize(0)?;
+    assert_eq!(sfs.info().bfree, free - 2);
+    sfs.sync()?;
+    drop((file, sparse, inode, root, sfs));
+    check_image(device.clone(), false).unwrap();
+    assert_eq!(fsck::check(device, false)?, []);
+    Ok(())
+}
+
+#[test]
+fn extents_clone_range() -> Result<()> {
+    let (device, sfs) = create_extents_sfs(256)?;
+    let root = sfs.root_inode();
+    let a = root.create("a", FileType::File, 0o777)?;
+    let data: Vec<u8> = (0..8 * BLKSIZE).map(|i| (i % 251) as u8).collect();
+    a.write_at(0, &data)?;
+    let b = root.create("b", FileType::File, 0o777)?;
+    let free = sfs.info().bfree;
+    b.clone_range(a.as_ref(), 0, 0, 0)?;
+    assert_eq!(sfs.info().bfree, free);
+    // a write in the middle unshares a block
+    b.write_at(3 * BLKSIZE, &[0xff])?;
+    assert_eq!(sfs.info().bfree, free - 1);
+    let mut buf = vec![0u8; data.len()];
+    a.read_at(0, &mut buf)?;
+    assert!(buf == data);
+    b.read_at(0, &mut buf)?;
+    assert_eq!(buf[3 * BLKSIZE], 0xff);
+    assert_eq!(buf[..3 * BLKSIZE], data[..3 * BLKSIZE]);
+    assert_eq!(buf[3 * BLKSIZE + 1..], data[3 * BLKSIZE + 1..]);
+    root.unlink("a")?;
+    drop(a);
+    sfs.sync()?;
+    drop((b, root, sfs));
+    check_image(device.clone(), false).unwrap();
+    assert_eq!(fsck::check(device, false)?, []);
+    Ok(())
+}
+
+#[test]
+fn extents_punch_no_space() -> Result<()> {
+    let (device, sfs) = create_extents_sfs(256)?;
+    let root = sfs.root_inode();
+    // extents of 3 blocks fill the root
+    let file = root.create("f", FileType::File, 0o777)?;
+    for i in 0..EXTENT_ROOT_NENTRY {
+        file.write_at(4 * i * BLKSIZE, &[i as u8 + 1; 3 * BLKSIZE])?;
+    }
+    let inode = sfs.get_inode(file.metadata()?.inode)?;
+    assert_eq!(
+        inode.load_node(NodeAt::Root)?.entries.len(),
+        EXTENT_ROOT_NENTRY
+    );
+    let fill = root.create("fill", FileType::File, 0o777)?;
+    let mut offset = 0;
+    while fill.write_at(offset, &[0xff; BLKSIZE]).is_ok() {
+        offset += BLKSIZE;
+    }
+
+    // cutting the first extent needs a block to split the root, and keeps it whole
+    let punch = FallocateFlags {
+        keep_size: true,
+        punch_hole: true,
+        ..Default::default()
+    };
+    assert_eq!(
+        file.fallocate(punch, BLKSIZE, BLKSIZE),
+        Err(FsError::NoDeviceSpace)
+    );
+    let mut buf = [0u8; 3 * BLKSIZE];
+    file.read_at(0, &mut buf)?;
+    assert!(buf.iter().all(|&b| b == 1));
+    sfs.sync()?;
+    drop((file, fill, inode, root, sfs));
+    check_image(device.clone(), false).unwrap();
+    assert_eq!(fsck::check(device, false)?, []);
+    Ok(())
+}
+
+#[test]
+fn crash_consistency_extents() -> Result<()> {
+    let options = CreateOptions {
+        extents: true,
+        ..CreateOptions::default()
+    };
+    check_crashes(&options, crash::WORKLOAD)?;
+    let options = CreateOptions {
+        journal_blocks: 32,
+        extents: true,
+        ..CreateOptions::default()
+    };
+    check_crashes(&options, crash::WORKLOAD)
+}
+
+#[test]
+fn fsck_bad_extent() -> Result<()> {
+    use core::mem::{offset_of, size_of};
+    let (device, sfs) = create_extents_sfs(256)?;
+    let root = sfs.root_inode();
+    let file = root.create("f", FileType::File, 0o777)?;
+    // two extents of 2 blocks
+    file.write_at(0, &[1; 2 * BLKSIZE])?;
+    file.write_at(4 * BLKSIZE, &[2; 2 * BLKSIZE])?;
+    let g = root.create("g", FileType::File, 0o777)?;
+    let id = file.metadata()?.inode;
+    let g_id = g.metadata()?.inode;
+    let lost = sfs.get_inode(id)?.get_disk_block_id(4)?;
+    sfs.sync()?;
+    drop((file, g, root, sfs));
+
+    // the second extent of "f" is beyond the device, and the root of "g" is broken
+    let extent = |id: INodeId, i: usize| {
+        id * BLKSIZE + EXTENT_ROOT_OFFSET + size_of::<ExtentHeader>() + i * size_of::<Extent>()
+    };
+    let block = extent(id, 1) + offset_of!(Extent, block);
+    device.0.lock().unwrap()[block..][..4].copy_from_slice(&255u32.to_ne_bytes());
+    let root = g_id * BLKSIZE + EXTENT_ROOT_OFFSET;
+    device.0.lock().unwrap()[root..][..2].copy_from_slice(&100u16.to_ne_bytes());
+    let expected = [
+        Problem::BadPointer {
+            id,
+            block: 256,
+            reason: "out of range",
+        },
+        Problem::BadExtent {
+            id: g_id,
+            reason: "has too many entries",
+        },
+        Problem::Leaked(lost),
+        Problem::Leaked(lost + 1),
+    ];
+    for repair in [false, true] {
+        let problems = fsck::check(device.clone(), repair)?;
+        for problem in expected.iter() {
+            assert!(problems.contains(problem), "{} is not found", problem);
+        }
+    }
+    assert_eq!(fsck::check(device.clone(), false)?, []);
+
+    // the blocks of the bad extent become a hole
+    let sfs = SimpleFileSystem::open(device.clone())?;
+    let file = sfs.root_inode().lookup("f")?;
+    let mut buf = [0u8; 2];
+    file.read_at(2 * BLKSIZE - 1, &mut buf)?;
+    assert_eq!(buf, [1, 0]);
+    file.read_at(4 * BLKSIZE, &mut buf)?;
+    assert_eq!(buf, [0, 0]);
+    file.write_at(4 * BLKSIZE, &[3])?;
+    sfs.sync()?;
+    drop((file, sfs));
+    check_image(device.clone(), false).unwrap();
+    assert_eq!(fsck::check(device, false)?, []);
+    Ok(())
+}
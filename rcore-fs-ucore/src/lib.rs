@@ -319,6 +319,27 @@ pub enum ErrorCode {
     NOTEMPTY = -24,
 }
 
+impl From<vfs::FsError> for ErrorCode {
+    fn from(err: vfs::FsError) -> Self {
+        use rcore_fs::errno::*;
+        match err.to_errno() {
+            ENOENT => ErrorCode::NoEntry,
+            EISDIR => ErrorCode::IsDir,
+            ENOTDIR => ErrorCode::NotDir,
+            EXDEV => ErrorCode::XDEV,
+            EEXIST => ErrorCode::EXISTS,
+            ENOTEMPTY => ErrorCode::NOTEMPTY,
+            EBUSY => ErrorCode::BUSY,
+            ENOSYS => ErrorCode::Unimplemented,
+            ENOMEM => ErrorCode::NO_MEM,
+            ENODEV => ErrorCode::NO_DEV,
+            E2BIG | EFBIG => ErrorCode::TOO_BIG,
+            EINVAL => ErrorCode::Invalid,
+            _ => ErrorCode::UNSPECIFIED,
+        }
+    }
+}
+
 // Wrapper functions
 
 impl AsRef<[u8]> for IoBuf {
@@ -447,13 +468,19 @@ static INODE_OPS: INodeOps = {
     }
     extern fn read(inode: &mut INode, buf: &mut IoBuf) -> ErrorCode {
         println!("inode.read");
-        let len = inode.read_at(buf.offset as usize, buf.as_mut()).unwrap();
+        let len = match inode.read_at(buf.offset as usize, buf.as_mut()) {
+            Ok(len) => len,
+            Err(e) => return e.into(),
+        };
         buf.skip(len);
         ErrorCode::Ok
     }
     extern fn write(inode: &mut INode, buf: &mut IoBuf) -> ErrorCode {
         println!("inode.write");
-        let len = inode.write_at(buf.offset as usize, buf.as_ref()).unwrap();
+        let len = match inode.write_at(buf.offset as usize, buf.as_ref()) {
+            Ok(len) => len,
+            Err(e) => return e.into(),
+        };
         buf.skip(len);
         ErrorCode::Ok
     }
@@ -532,7 +559,7 @@ static INODE_OPS: INodeOps = {
                 *inode_store = inode;
                 ErrorCode::Ok
             }
-            Err(_) => ErrorCode::NoEntry,
+            Err(e) => e.into(),
         }
     }
     extern fn ioctl(inode: &mut INode, op: i32, data: *mut u8) -> ErrorCode {
@@ -0,0 +1,364 @@
+//! Caches of INodes and directory entries shared by file systems
+//!
+//! `INodeCache` maps INode ids to the INodes in memory without keeping them alive,
+//! so that every user of an INode shares the same object.
+//! `DentryCache` remembers the results of `find`, including names that do not exist,
+//! and has to be invalidated by the file system when the names change. A result is
+//! inserted with the generation of its directory taken before `find`, and dropped if
+//! the directory is invalidated in between.
+
+use alloc::{
+    collections::BTreeMap,
+    string::String,
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::{Mutex, RwLock};
+
+/// Default capacity of a `DentryCache`
+pub const DEFAULT_DENTRY_CAPACITY: usize = 1024;
+
+/// Number of generations of directories, shared by the ids with the same remainder
+const DIR_GENERATIONS: usize = 64;
+
+/// Hit and miss counters of a cache
+#[derive(Debug, Default)]
+pub struct CacheStats {
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+}
+
+/// A snapshot of `CacheStats`
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct CacheStatsSnapshot {
+    pub hits: usize,
+    pub misses: usize,
+}
+
+impl CacheStats {
+    pub(crate) fn hit(&self) {
+        self.hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn miss(&self) {
+        self.misses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> CacheStatsSnapshot {
+        CacheStatsSnapshot {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn reset(&self) {
+        self.hits.store(0, Ordering::Relaxed);
+        self.misses.store(0, Ordering::Relaxed);
+    }
+}
+
+/// INodes in memory by id, dropped ones are removed lazily
+pub struct INodeCache<T: ?Sized> {
+    inodes: RwLock<BTreeMap<usize, Weak<T>>>,
+    stats: CacheStats,
+}
+
+impl<T: ?Sized> Default for INodeCache<T> {
+    fn default() -> Self {
+        INodeCache {
+            inodes: RwLock::new(BTreeMap::new()),
+            stats: CacheStats::default(),
+        }
+    }
+}
+
+impl<T: ?Sized> INodeCache<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Get the INode `id` if it is in memory
+    pub fn get(&self, id: usize) -> Option<Arc<T>> {
+        let inode = self.inodes.read().get(&id).and_then(Weak::upgrade);
+        match inode {
+            Some(_) => self.stats.hit(),
+            None => self.stats.miss(),
+        }
+        inode
+    }
+
+    /// Get the INode `id`, or load it by `load` if it is not in memory
+    ///
+    /// `load` runs without the cache locked. If others load the same INode meanwhile,
+    /// theirs is returned.
+    pub fn get_or_load<E>(
+        &self,
+        id: usize,
+        load: impl FnOnce() -> Result<Arc<T>, E>,
+    ) -> Result<Arc<T>, E> {
+        if let Some(inode) = self.get(id) {
+            return Ok(inode);
+        }
+        let inode = load()?;
+        let mut inodes = self.inodes.write();
+        if let Some(other) = inodes.get(&id).and_then(Weak::upgrade) {
+            return Ok(other);
+        }
+        inodes.insert(id, Arc::downgrade(&inode));
+        Ok(inode)
+    }
+
+    /// Put a new INode `id` in, replacing the old one
+    pub fn insert(&self, id: usize, inode: &Arc<T>) {
+        self.inodes.write().insert(id, Arc::downgrade(inode));
+    }
+
+    pub fn remove(&self, id: usize) {
+        self.inodes.write().remove(&id);
+    }
+
+    /// Remove the dropped INodes
+    pub fn flush(&self) {
+        self.inodes
+            .write()
+            .retain(|_, inode| inode.strong_count() > 0);
+    }
+
+    /// All INodes in memory
+    pub fn alive(&self) -> Vec<Arc<T>> {
+        self.inodes
+            .read()
+            .values()
+            .filter_map(Weak::upgrade)
+            .collect()
+    }
+
+    pub fn stats(&self) -> &CacheStats {
+        &self.stats
+    }
+}
+
+/// Result of a `DentryCache` lookup
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Dentry<T> {
+    /// The name refers to this INode
+    Positive(T),
+    /// The name does not exist
+    Negative,
+}
+
+struct Dentries<T> {
+    /// dir id -> name -> (entry, last use)
+    dirs: BTreeMap<usize, BTreeMap<String, (Dentry<T>, u64)>>,
+    /// last use -> (dir id, name), the least recently used first
+    lru: BTreeMap<u64, (usize, String)>,
+    tick: u64,
+    /// generations of directories by id modulo `DIR_GENERATIONS`, advanced by invalidation
+    generations: [u64; DIR_GENERATIONS],
+}
+
+impl<T> Dentries<T> {
+    fn advance(&mut self, dir: usize) {
+        self.generations[dir % DIR_GENERATIONS] += 1;
+    }
+
+    fn remove(&mut self, dir: usize, name: &str) {
+        let names = match self.dirs.get_mut(&dir) {
+            Some(names) => names,
+            None => return,
+        };
+        if let Some((_, tick)) = names.remove(name) {
+            self.lru.remove(&tick);
+        }
+        if names.is_empty() {
+            self.dirs.remove(&dir);
+        }
+    }
+}
+
+/// Entries of directories by the directory id and name, evicted when least recently used
+pub struct DentryCache<T> {
+    dentries: Mutex<Dentries<T>>,
+    capacity: usize,
+    stats: CacheStats,
+}
+
+impl<T: Clone> DentryCache<T> {
+    pub fn new(capacity: usize) -> Self {
+        DentryCache {
+            dentries: Mutex::new(Dentries {
+                dirs: BTreeMap::new(),
+                lru: BTreeMap::new(),
+                tick: 0,
+                generations: [0; DIR_GENERATIONS],
+            }),
+            capacity,
+            stats: CacheStats::default(),
+        }
+    }
+
+    /// Look up `name` in directory `dir`, `None` if not cached
+    pub fn lookup(&self, dir: usize, name: &str) -> Option<Dentry<T>> {
+        let mut dentries = self.dentries.lock();
+        let dentries = &mut *dentries;
+        dentries.tick += 1;
+        let tick = dentries.tick;
+        let entry = dentries
+            .dirs
+            .get_mut(&dir)
+            .and_then(|names| names.get_mut(name));
+        let (dentry, last) = match entry {
+            Some(entry) => entry,
+            None => {
+                self.stats.miss();
+                return None;
+            }
+        };
+        self.stats.hit();
+        let key = dentries.lru.remove(last).unwrap();
+        dentries.lru.insert(tick, key);
+        *last = tick;
+        Some(dentry.clone())
+    }
+
+    /// Generation of directory `dir`, to be taken before looking up the file system.
+    /// It changes whenever entries of `dir` are invalidated.
+    pub fn generation(&self, dir: usize) -> u64 {
+        self.dentries.lock().generations[dir % DIR_GENERATIONS]
+    }
+
+    /// Remember that `name` in directory `dir` refers to `inode`,
+    /// unless `dir` is invalidated after `generation`
+    pub fn insert(&self, dir: usize, name: &str, inode: T, generation: u64) {
+        self.put(dir, name, Dentry::Positive(inode), generation);
+    }
+
+    /// Remember that `name` does not exist in directory `dir`,
+    /// unless `dir` is invalidated after `generation`
+    pub fn insert_negative(&self, dir: usize, name: &str, generation: u64) {
+        self.put(dir, name, Dentry::Negative, generation);
+    }
+
+    fn put(&self, dir: usize, name: &str, dentry: Dentry<T>, generation: u64) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut dentries = self.dentries.lock();
+        if dentries.generations[dir % DIR_GENERATIONS] != generation {
+            // the result may be stale
+            return;
+        }
+        dentries.remove(dir, name);
+        while dentries.lru.len() >= self.capacity {
+            let (_, (dir, name)) = dentries.lru.pop_first().unwrap();
+            dentries.remove(dir, &name);
+        }
+        dentries.tick += 1;
+        let tick = dentries.tick;
+        dentries.lru.insert(tick, (dir, String::from(name)));
+        let names = dentries.dirs.entry(dir).or_default();
+        names.insert(String::from(name), (dentry, tick));
+    }
+
+    /// Forget `name` in directory `dir`, after it is created, removed or moved
+    pub fn invalidate(&self, dir: usize, name: &str) {
+        let mut dentries = self.dentries.lock();
+        dentries.remove(dir, name);
+        dentries.advance(dir);
+    }
+
+    /// Forget all entries of directory `dir`, after it is removed
+    pub fn invalidate_dir(&self, dir: usize) {
+        let mut dentries = self.dentries.lock();
+        if let Some(names) = dentries.dirs.remove(&dir) {
+            for (_, tick) in names.values() {
+                dentries.lru.remove(tick);
+            }
+        }
+        dentries.advance(dir);
+    }
+
+    pub fn clear(&self) {
+        let mut dentries = self.dentries.lock();
+        dentries.dirs.clear();
+        dentries.lru.clear();
+        for generation in dentries.generations.iter_mut() {
+            *generation += 1;
+        }
+    }
+
+    /// Number of cached entries
+    pub fn len(&self) -> usize {
+        self.dentries.lock().lru.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn stats(&self) -> &CacheStats {
+        &self.stats
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn inode_cache() {
+        let cache = INodeCache::<usize>::new();
+        let one = Arc::new(1);
+        cache.insert(1, &one);
+        assert!(Arc::ptr_eq(&cache.get(1).unwrap(), &one));
+        assert!(cache.get(2).is_none());
+        let two = cache.get_or_load(2, || Ok::<_, ()>(Arc::new(2))).unwrap();
+        let again = cache.get_or_load(2, || Err(())).unwrap();
+        assert!(Arc::ptr_eq(&two, &again));
+        assert_eq!(cache.alive().len(), 2);
+        drop((two, again));
+        assert!(cache.get(2).is_none());
+        cache.flush();
+        assert_eq!(cache.alive(), [one]);
+        let stats = cache.stats().snapshot();
+        assert_eq!((stats.hits, stats.misses), (2, 3));
+    }
+
+    #[test]
+    fn dentry_cache() {
+        let cache = DentryCache::new(3);
+        assert_eq!(cache.lookup(1, "a"), None);
+        let (one, two) = (cache.generation(1), cache.generation(2));
+        cache.insert(1, "a", 10, one);
+        cache.insert_negative(1, "b", one);
+        cache.insert(2, "a", 20, two);
+        assert_eq!(cache.lookup(1, "a"), Some(Dentry::Positive(10)));
+        assert_eq!(cache.lookup(1, "b"), Some(Dentry::Negative));
+        // the least recently used is evicted
+        cache.insert(2, "b", 21, two);
+        assert_eq!(cache.len(), 3);
+        assert_eq!(cache.lookup(2, "a"), None);
+        assert_eq!(cache.lookup(2, "b"), Some(Dentry::Positive(21)));
+
+        cache.invalidate(1, "a");
+        assert_eq!(cache.lookup(1, "a"), None);
+        cache.invalidate_dir(2);
+        assert_eq!(cache.lookup(2, "b"), None);
+        assert_eq!(cache.len(), 1);
+
+        // a result found before an invalidation is not inserted after it
+        let one = cache.generation(1);
+        cache.invalidate(1, "c");
+        cache.insert_negative(1, "c", one);
+        assert_eq!(cache.lookup(1, "c"), None);
+        cache.insert_negative(1, "c", cache.generation(1));
+        assert_eq!(cache.lookup(1, "c"), Some(Dentry::Negative));
+        cache.clear();
+        assert!(cache.is_empty());
+        let stats = cache.stats().snapshot();
+        assert_eq!((stats.hits, stats.misses), (4, 5));
+        cache.stats().reset();
+        assert_eq!(cache.stats().snapshot(), CacheStatsSnapshot::default());
+    }
+}
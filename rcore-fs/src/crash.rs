@@ -0,0 +1,211 @@
+//! A harness to check the crash consistency of file systems
+//!
+//! A scripted workload runs on a file system whose writes are recorded, e.g. by a
+//! `FaultyDevice`. Then every prefix of the log is replayed as a crashed image, which
+//! is reopened and checked by `check_tree` and the checks of the file system itself.
+//! Any crash may leak INodes and blocks, but only a crash at a checkpoint leaves none.
+
+use crate::vfs::*;
+use alloc::{collections::BTreeMap, format, string::String, sync::Arc, vec::Vec};
+use core::ops::Range;
+
+/// A step of a workload, with paths from the root
+#[derive(Debug, Clone)]
+pub enum Step {
+    /// Keep the INode in memory until the workload ends, so that its changes are written
+    /// by the file system when it syncs rather than when the INode is dropped
+    Open(&'static str),
+    /// Drop the INodes opened
+    Close,
+    Create(&'static str, FileType),
+    Write(&'static str, usize, &'static [u8]),
+    Resize(&'static str, usize),
+    Rename(&'static str, &'static str),
+    /// Share the content of the first file with the second, see `INode::clone_range`
+    Clone(&'static str, &'static str),
+    Unlink(&'static str),
+    Sync,
+}
+
+/// A workload of the common changes to the namespace and the content
+pub const WORKLOAD: &[Step] = &[
+    Step::Create("a", FileType::Dir),
+    Step::Create("a/x", FileType::File),
+    Step::Write("a/x", 0, b"hello"),
+    Step::Sync,
+    Step::Create("b", FileType::Dir),
+    Step::Write("a/x", 0x10000, b"far away"),
+    Step::Create("a/y", FileType::File),
+    Step::Sync,
+    Step::Rename("a/x", "b/x"),
+    Step::Rename("a/y", "a/z"),
+    Step::Unlink("a/z"),
+    Step::Sync,
+    Step::Unlink("a"),
+    Step::Rename("b", "c"),
+    Step::Write("c/x", 2, b"LLO"),
+    Step::Sync,
+    Step::Open("c/x"),
+    Step::Write("c/x", 0x20000, b"further"),
+    Step::Sync,
+    Step::Resize("c/x", 5),
+    Step::Sync,
+    Step::Close,
+    Step::Unlink("c/x"),
+    Step::Unlink("c"),
+    Step::Sync,
+];
+
+/// Split `path` into the parent and the name
+fn split(path: &str) -> (&str, &str) {
+    path.rsplit_once('/').unwrap_or(("", path))
+}
+
+impl Step {
+    /// Run the step on `fs`, the INodes opened are kept in `open`
+    pub fn run(&self, fs: &dyn FileSystem, open: &mut Vec<Arc<dyn INode>>) -> Result<()> {
+        let root = fs.root_inode();
+        match *self {
+            Step::Open(path) => open.push(root.lookup(path)?),
+            Step::Close => open.clear(),
+            Step::Create(path, type_) => {
+                let (dir, name) = split(path);
+                root.lookup(dir)?.create(name, type_, 0o777)?;
+            }
+            Step::Write(path, offset, data) => {
+                root.lookup(path)?.write_at(offset, data)?;
+            }
+            Step::Resize(path, len) => root.lookup(path)?.resize(len)?,
+            Step::Rename(from, to) => {
+                let (dir, name) = split(from);
+                let (new_dir, new_name) = split(to);
+                root.lookup(dir)?
+                    .move_(name, &root.lookup(new_dir)?, new_name)?;
+            }
+            Step::Clone(src, dst) => {
+                root.lookup(dst)?
+                    .clone_range(&*root.lookup(src)?, 0, 0, 0)?;
+            }
+            Step::Unlink(path) => {
+                let (dir, name) = split(path);
+                root.lookup(dir)?.unlink(name)?;
+            }
+            Step::Sync => fs.sync()?,
+        }
+        Ok(())
+    }
+}
+
+/// Run `steps` on `fs`, return `log_len()` after each of them
+pub fn run_workload(
+    fs: &dyn FileSystem,
+    steps: &[Step],
+    log_len: impl Fn() -> usize,
+) -> Result<Vec<usize>> {
+    let mut ends = Vec::new();
+    let mut open = Vec::new();
+    for step in steps {
+        step.run(fs, &mut open)?;
+        ends.push(log_len());
+    }
+    Ok(ends)
+}
+
+/// The crash points right after each `Sync` of `steps` ending at `ends`,
+/// where the file system must be as clean as it was created
+pub fn checkpoints(steps: &[Step], ends: &[usize]) -> Vec<usize> {
+    steps
+        .iter()
+        .zip(ends)
+        .filter(|(step, _)| matches!(step, Step::Sync))
+        .map(|(_, &end)| end)
+        .collect()
+}
+
+/// The crash points from the first write of each step `unsafe_` until the next `Sync` ends.
+/// A file system without a journal may write the entries of such steps before the INodes
+/// they refer to, which are known to be inconsistent until synced.
+pub fn unsafe_windows(
+    steps: &[Step],
+    ends: &[usize],
+    unsafe_: impl Fn(&Step) -> bool,
+) -> Vec<Range<usize>> {
+    let mut windows = Vec::new();
+    let mut begin = 0;
+    let mut window: Option<usize> = None;
+    for (step, &end) in steps.iter().zip(ends) {
+        if window.is_none() && unsafe_(step) {
+            window = Some(begin + 1);
+        }
+        if let (Step::Sync, Some(start)) = (step, window) {
+            windows.push(start..end);
+            window = None;
+        }
+        begin = end;
+    }
+    if let Some(start) = window {
+        windows.push(start..begin + 1);
+    }
+    windows
+}
+
+/// Walk the tree from `root`, check that every entry can be opened, and the links of every
+/// INode match the entries referring to it, counting "." and ".." like SFS and SEFS.
+/// If `leaks`, an INode may have more links than entries, which only leaks it after a crash.
+/// Return the reachable INodes by their ids.
+pub fn check_tree(
+    root: &Arc<dyn INode>,
+    leaks: bool,
+) -> core::result::Result<BTreeMap<usize, Arc<dyn INode>>, String> {
+    let root_id = root.metadata().map_err(|e| format!("root: {:?}", e))?.inode;
+    let mut inodes = BTreeMap::new();
+    let mut links = BTreeMap::new();
+    let mut dirs = Vec::new();
+    inodes.insert(root_id, root.clone());
+    dirs.push((String::from(""), root.clone()));
+    while let Some((path, dir)) = dirs.pop() {
+        let mut cookie = 0;
+        loop {
+            let entry = match dir.read_dir(cookie) {
+                Ok(Some(entry)) => entry,
+                Ok(None) => break,
+                Err(e) => return Err(format!("{}/: {:?}", path, e)),
+            };
+            cookie = entry.next_cookie;
+            let child_path = format!("{}/{}", path, entry.name);
+            *links.entry(entry.inode).or_insert(0) += 1;
+            if entry.name == "." || entry.name == ".." {
+                continue;
+            }
+            let child = dir
+                .find(&entry.name)
+                .map_err(|e| format!("{}: {:?}", child_path, e))?;
+            let info = child
+                .metadata()
+                .map_err(|e| format!("{}: {:?}", child_path, e))?;
+            if info.inode != entry.inode || info.type_ != entry.type_ {
+                return Err(format!(
+                    "{}: the entry does not match the INode",
+                    child_path
+                ));
+            }
+            if inodes.insert(info.inode, child.clone()).is_none() && info.type_ == FileType::Dir {
+                dirs.push((child_path, child));
+            }
+        }
+    }
+    for (id, inode) in inodes.iter() {
+        let nlinks = inode
+            .metadata()
+            .map_err(|e| format!("{}: {:?}", id, e))?
+            .nlinks;
+        let entries = links.get(id).copied().unwrap_or(0);
+        if nlinks < entries || (nlinks > entries && !leaks) {
+            return Err(format!(
+                "INode {} has {} links, but {} entries",
+                id, nlinks, entries
+            ));
+        }
+    }
+    Ok(inodes)
+}
@@ -1,6 +1,11 @@
 //! A naive LRU cache layer for `BlockDevice`
 use super::*;
-use alloc::{vec, vec::Vec};
+use alloc::{boxed::Box, vec, vec::Vec};
+use core::future::Future;
+use core::hint::spin_loop;
+use core::mem::take;
+use core::pin::Pin;
+use core::task::{Context, Poll};
 use spin::{Mutex, MutexGuard};
 
 pub struct BlockCache<T: BlockDevice> {
@@ -14,6 +19,7 @@ struct Buf {
     data: Vec<u8>,
 }
 
+#[derive(Clone, Copy)]
 enum BufStatus {
     /// buffer is unused
     Unused,
@@ -21,6 +27,77 @@ enum BufStatus {
     Valid(BlockId),
     /// buffer needs to be written to disk
     Dirty(BlockId),
+    /// buffer is being read or written back by an async operation, and its data is taken out
+    Busy(BlockId),
+}
+
+impl Buf {
+    fn is_busy(&self) -> bool {
+        matches!(self.status, BufStatus::Busy(_))
+    }
+
+    /// Whether the buffer holds `block_id`
+    fn holds(&self, block_id: BlockId) -> bool {
+        matches!(self.status, BufStatus::Valid(id) | BufStatus::Dirty(id) if id == block_id)
+    }
+}
+
+/// The data of a `Busy` buffer, taken out while waiting for the device without holding the lock.
+/// Dropping it puts the data back with the status to restore, so a cancelled operation
+/// does not leave the buffer busy.
+struct InFlight<'a> {
+    slot: &'a Mutex<Buf>,
+    data: Option<Vec<u8>>,
+    restore: BufStatus,
+}
+
+impl<'a> InFlight<'a> {
+    /// Mark `buf` busy with `block_id` and take its data out
+    fn take(slot: &'a Mutex<Buf>, buf: &mut Buf, block_id: BlockId, restore: BufStatus) -> Self {
+        buf.status = BufStatus::Busy(block_id);
+        InFlight {
+            slot,
+            data: Some(take(&mut buf.data)),
+            restore,
+        }
+    }
+
+    fn data(&mut self) -> &mut Vec<u8> {
+        self.data.as_mut().unwrap()
+    }
+
+    /// Put the data back with `status`
+    fn finish(mut self, status: BufStatus) -> MutexGuard<'a, Buf> {
+        let mut buf = self.slot.lock();
+        buf.data = self.data.take().unwrap();
+        buf.status = status;
+        buf
+    }
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        if let Some(data) = self.data.take() {
+            let mut buf = self.slot.lock();
+            buf.data = data;
+            buf.status = self.restore;
+        }
+    }
+}
+
+/// Let other tasks run, e.g. the one a busy buffer is waiting for
+struct YieldNow(bool);
+
+impl Future for YieldNow {
+    type Output = ();
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.0 {
+            return Poll::Ready(());
+        }
+        self.0 = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
 }
 
 impl<T: BlockDevice> BlockCache<T> {
@@ -36,40 +113,107 @@ impl<T: BlockDevice> BlockCache<T> {
         BlockCache { device, bufs, lru }
     }
 
-    /// Get a buffer for `block_id` with any status
-    fn get_buf(&self, block_id: BlockId) -> MutexGuard<Buf> {
-        let (i, buf) = self._get_buf(block_id);
-        self.lru.lock().visit(i);
-        buf
+    /// Get a buffer for `block_id`, which holds it or is unused.
+    /// If the victim fails to be written back, it is kept dirty.
+    ///
+    /// Spin while the buffer is busy with an async operation.
+    fn get_buf(&self, block_id: BlockId) -> Result<MutexGuard<'_, Buf>> {
+        loop {
+            match self
+                .find_buf(block_id)
+                .or_else(|| self.get_unused_or_victim())
+            {
+                Some((i, mut buf)) if !buf.is_busy() => {
+                    if !buf.holds(block_id) {
+                        self.write_back(&mut buf)?;
+                        buf.status = BufStatus::Unused;
+                    }
+                    self.lru.lock().visit(i);
+                    return Ok(buf);
+                }
+                _ => spin_loop(),
+            }
+        }
     }
 
-    fn _get_buf(&self, block_id: BlockId) -> (usize, MutexGuard<Buf>) {
+    /// Get the buffer holding `block_id`, which may be busy
+    fn find_buf(&self, block_id: BlockId) -> Option<(usize, MutexGuard<'_, Buf>)> {
         for (i, buf) in self.bufs.iter().enumerate() {
             if let Some(lock) = buf.try_lock() {
                 match lock.status {
-                    BufStatus::Valid(id) if id == block_id => return (i, lock),
-                    BufStatus::Dirty(id) if id == block_id => return (i, lock),
+                    BufStatus::Valid(id) | BufStatus::Dirty(id) | BufStatus::Busy(id)
+                        if id == block_id =>
+                    {
+                        return Some((i, lock))
+                    }
                     _ => {}
                 }
             }
         }
-        self.get_unused()
+        None
     }
 
-    /// Get an unused buffer
-    fn get_unused(&self) -> (usize, MutexGuard<Buf>) {
+    /// Get an unused buffer, or the victim which may need writing back.
+    /// Busy buffers are skipped, return `None` if all of them are busy.
+    fn get_unused_or_victim(&self) -> Option<(usize, MutexGuard<'_, Buf>)> {
         for (i, buf) in self.bufs.iter().enumerate() {
             if let Some(lock) = buf.try_lock() {
                 if let BufStatus::Unused = lock.status {
-                    return (i, lock);
+                    return Some((i, lock));
                 }
             }
         }
-        let victim_id = self.lru.lock().victim();
-        let mut victim = self.bufs[victim_id].lock();
-        self.write_back(&mut victim).expect("failed to write back");
-        victim.status = BufStatus::Unused;
-        (victim_id, victim)
+        for _ in 0..self.bufs.len() {
+            let victim_id = self.lru.lock().victim();
+            let buf = self.bufs[victim_id].lock();
+            if !buf.is_busy() {
+                return Some((victim_id, buf));
+            }
+            drop(buf);
+            // move it away from the tail to try the next one
+            self.lru.lock().visit(victim_id);
+        }
+        None
+    }
+
+    /// Async version of `get_buf`, which reads the block if it is not cached and `read` is set.
+    ///
+    /// No lock is held while waiting for the device, the buffer is `Busy` instead.
+    async fn async_get_buf(&self, block_id: BlockId, read: bool) -> Result<MutexGuard<'_, Buf>> {
+        let (i, mut in_flight) = loop {
+            match self
+                .find_buf(block_id)
+                .or_else(|| self.get_unused_or_victim())
+            {
+                Some((i, buf)) if buf.holds(block_id) => {
+                    self.lru.lock().visit(i);
+                    return Ok(buf);
+                }
+                Some((i, buf)) if matches!(buf.status, BufStatus::Dirty(_)) => {
+                    drop(buf);
+                    self.async_write_back(i).await?;
+                }
+                Some((i, mut buf)) if !buf.is_busy() => {
+                    if !read {
+                        buf.status = BufStatus::Unused;
+                        self.lru.lock().visit(i);
+                        return Ok(buf);
+                    }
+                    let slot = &self.bufs[i];
+                    break (
+                        i,
+                        InFlight::take(slot, &mut buf, block_id, BufStatus::Unused),
+                    );
+                }
+                _ => YieldNow(false).await,
+            }
+        };
+        self.device
+            .async_read_at(block_id, in_flight.data())
+            .await?;
+        let buf = in_flight.finish(BufStatus::Valid(block_id));
+        self.lru.lock().visit(i);
+        Ok(buf)
     }
 
     /// Write back data if buffer is dirty
@@ -80,11 +224,38 @@ impl<T: BlockDevice> BlockCache<T> {
         }
         Ok(())
     }
+
+    /// Async version of `write_back` for buffer `i`, which is kept dirty on errors.
+    ///
+    /// Wait if it is busy, and do not hold the lock while waiting for the device.
+    async fn async_write_back(&self, i: usize) -> Result<()> {
+        let slot = &self.bufs[i];
+        let (block_id, mut in_flight) = loop {
+            let mut buf = slot.lock();
+            match buf.status {
+                BufStatus::Dirty(block_id) => {
+                    let dirty = buf.status;
+                    break (block_id, InFlight::take(slot, &mut buf, block_id, dirty));
+                }
+                BufStatus::Busy(_) => {
+                    drop(buf);
+                    YieldNow(false).await;
+                }
+                _ => return Ok(()),
+            }
+        };
+        self.device
+            .async_write_at(block_id, in_flight.data())
+            .await?;
+        in_flight.finish(BufStatus::Valid(block_id));
+        Ok(())
+    }
 }
 
 impl<T: BlockDevice> Drop for BlockCache<T> {
+    /// Errors can not be reported here, call `sync` before dropping to see them
     fn drop(&mut self) {
-        BlockDevice::sync(self).expect("failed to sync");
+        let _ = BlockDevice::sync(self);
     }
 }
 
@@ -92,7 +263,7 @@ impl<T: BlockDevice> BlockDevice for BlockCache<T> {
     const BLOCK_SIZE_LOG2: u8 = T::BLOCK_SIZE_LOG2;
 
     fn read_at(&self, block_id: BlockId, buffer: &mut [u8]) -> Result<()> {
-        let mut buf = self.get_buf(block_id);
+        let mut buf = self.get_buf(block_id)?;
         if let BufStatus::Unused = buf.status {
             // read from device
             self.device.read_at(block_id, &mut buf.data)?;
@@ -104,7 +275,7 @@ impl<T: BlockDevice> BlockDevice for BlockCache<T> {
     }
 
     fn write_at(&self, block_id: BlockId, buffer: &[u8]) -> Result<()> {
-        let mut buf = self.get_buf(block_id);
+        let mut buf = self.get_buf(block_id)?;
         buf.status = BufStatus::Dirty(block_id);
         let len = 1 << Self::BLOCK_SIZE_LOG2 as usize;
         buf.data.copy_from_slice(&buffer[..len]);
@@ -113,11 +284,56 @@ impl<T: BlockDevice> BlockDevice for BlockCache<T> {
 
     fn sync(&self) -> Result<()> {
         for buf in self.bufs.iter() {
-            self.write_back(&mut buf.lock())?;
+            // wait for async write back in progress
+            let mut buf = loop {
+                let buf = buf.lock();
+                if !buf.is_busy() {
+                    break buf;
+                }
+                drop(buf);
+                spin_loop();
+            };
+            self.write_back(&mut buf)?;
         }
         self.device.sync()?;
         Ok(())
     }
+
+    fn async_read_at<'a>(
+        &'a self,
+        block_id: BlockId,
+        buffer: &'a mut [u8],
+    ) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let buf = self.async_get_buf(block_id, true).await?;
+            let len = 1 << Self::BLOCK_SIZE_LOG2 as usize;
+            buffer[..len].copy_from_slice(&buf.data);
+            Ok(())
+        })
+    }
+
+    fn async_write_at<'a>(
+        &'a self,
+        block_id: BlockId,
+        buffer: &'a [u8],
+    ) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let mut buf = self.async_get_buf(block_id, false).await?;
+            buf.status = BufStatus::Dirty(block_id);
+            let len = 1 << Self::BLOCK_SIZE_LOG2 as usize;
+            buf.data.copy_from_slice(&buffer[..len]);
+            Ok(())
+        })
+    }
+
+    fn async_sync(&self) -> BoxFuture<'_, Result<()>> {
+        Box::pin(async move {
+            for i in 0..self.bufs.len() {
+                self.async_write_back(i).await?;
+            }
+            self.device.async_sync().await
+        })
+    }
 }
 
 /// Doubly circular linked list LRU manager
@@ -160,3 +376,96 @@ impl LRU {
         self.prev[head] = id;
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use core::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Mutex;
+
+    /// 4 blocks of 4 bytes, writes fail if `fail` is set
+    struct Faulty {
+        data: Mutex<[u8; 16]>,
+        fail: AtomicBool,
+    }
+
+    impl BlockDevice for Faulty {
+        const BLOCK_SIZE_LOG2: u8 = 2;
+        fn read_at(&self, block_id: BlockId, buf: &mut [u8]) -> Result<()> {
+            let begin = block_id << 2;
+            buf[..4].copy_from_slice(&self.data.lock().unwrap()[begin..begin + 4]);
+            Ok(())
+        }
+        fn write_at(&self, block_id: BlockId, buf: &[u8]) -> Result<()> {
+            if self.fail.load(Ordering::SeqCst) {
+                return Err(DevErrorKind::Io.into());
+            }
+            let begin = block_id << 2;
+            self.data.lock().unwrap()[begin..begin + 4].copy_from_slice(&buf[..4]);
+            Ok(())
+        }
+        fn sync(&self) -> Result<()> {
+            Ok(())
+        }
+        /// Wait once before writing
+        fn async_write_at<'a>(
+            &'a self,
+            block_id: BlockId,
+            buf: &'a [u8],
+        ) -> BoxFuture<'a, Result<()>> {
+            Box::pin(async move {
+                YieldNow(false).await;
+                BlockDevice::write_at(self, block_id, buf)
+            })
+        }
+    }
+
+    #[test]
+    fn write_back_error() {
+        let device = Faulty {
+            data: Mutex::new([0; 16]),
+            fail: AtomicBool::new(true),
+        };
+        let cache = BlockCache::new(device, 1);
+        BlockDevice::write_at(&cache, 0, &[1; 4]).unwrap();
+        // block 0 can not be evicted, and is kept dirty
+        let mut buf = [0u8; 4];
+        assert_eq!(
+            BlockDevice::read_at(&cache, 1, &mut buf),
+            Err(DevError::new(DevErrorKind::Io))
+        );
+        assert_eq!(
+            BlockDevice::sync(&cache),
+            Err(DevError::new(DevErrorKind::Io))
+        );
+        cache.device.fail.store(false, Ordering::SeqCst);
+        BlockDevice::read_at(&cache, 1, &mut buf).unwrap();
+        assert_eq!(cache.device.data.lock().unwrap()[..4], [1; 4]);
+    }
+
+    #[test]
+    fn async_write_back_unlocked() {
+        let mut cx = Context::from_waker(std::task::Waker::noop());
+
+        let device = Faulty {
+            data: Mutex::new([0; 16]),
+            fail: AtomicBool::new(false),
+        };
+        let cache = BlockCache::new(device, 2);
+        BlockDevice::write_at(&cache, 0, &[1; 4]).unwrap();
+        let mut sync = BlockDevice::async_sync(&cache);
+        // block 0 is being written back
+        assert!(sync.as_mut().poll(&mut cx).is_pending());
+        // the other buffer can be used meanwhile
+        let mut buf = [0u8; 4];
+        BlockDevice::write_at(&cache, 1, &[2; 4]).unwrap();
+        BlockDevice::read_at(&cache, 2, &mut buf).unwrap();
+        // cancelling the write back keeps block 0 dirty
+        drop(sync);
+        BlockDevice::sync(&cache).unwrap();
+        assert_eq!(
+            cache.device.data.lock().unwrap()[..8],
+            [1, 1, 1, 1, 2, 2, 2, 2]
+        );
+    }
+}
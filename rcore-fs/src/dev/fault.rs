@@ -0,0 +1,535 @@
+//! Devices misbehaving on purpose, to test error handling and crash consistency
+//!
+//! `FaultyDevice` and `FaultyBlockDevice` wrap a device, and their `FaultInjector` can:
+//!
+//! * fail the reads or writes in some ranges of the device, or at random,
+//! * keep the writes since the last `sync` in a volatile cache, which are lost,
+//!   or partly applied in any order, on a simulated power loss,
+//! * record the writes into a `WriteLog`, whose prefixes can be replayed to get
+//!   the images after a crash at any point.
+
+use super::*;
+use alloc::vec::Vec;
+use core::ops::Range;
+use spin::Mutex;
+
+/// A write to the device, at `offset` in bytes
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Write {
+    pub offset: usize,
+    pub data: Vec<u8>,
+}
+
+/// An entry of `WriteLog`
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum LogEntry {
+    Write(Write),
+    /// The device is synced, the writes before are durable
+    Sync,
+}
+
+/// Writes recorded by a `FaultInjector`, in the order they are issued
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct WriteLog {
+    pub entries: Vec<LogEntry>,
+}
+
+impl WriteLog {
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Apply the writes in the first `len` entries to `device`, which then holds
+    /// the image after a crash at that point
+    pub fn replay(&self, len: usize, device: &dyn Device) -> Result<()> {
+        for entry in self.entries[..len].iter() {
+            if let LogEntry::Write(write) = entry {
+                write_all(device, write.offset, &write.data)?;
+            }
+        }
+        device.sync()
+    }
+}
+
+fn write_all(device: &dyn Device, offset: usize, data: &[u8]) -> Result<()> {
+    match device.write_at(offset, data)? {
+        len if len == data.len() => Ok(()),
+        _ => Err(DevErrorKind::OutOfRange.into()),
+    }
+}
+
+/// A xorshift pseudo random generator, so that random faults are reproducible
+#[derive(Debug, Clone)]
+pub struct XorShift(u64);
+
+impl Default for XorShift {
+    fn default() -> Self {
+        XorShift::new(0x2545_f491_4f6c_dd1d)
+    }
+}
+
+impl XorShift {
+    pub fn new(seed: u64) -> Self {
+        // the state must not be zero
+        XorShift(seed.max(1))
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// A random number in `0..n`
+    pub fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+
+    /// Return true with `probability`
+    pub fn chance(&mut self, probability: f64) -> bool {
+        ((self.next_u64() >> 11) as f64 / (1u64 << 53) as f64) < probability
+    }
+}
+
+#[derive(Default)]
+struct State {
+    read_faults: Vec<Range<usize>>,
+    write_faults: Vec<Range<usize>>,
+    read_probability: f64,
+    write_probability: f64,
+    rng: XorShift,
+    /// Keep the writes in `unsynced` until `sync`
+    caching: bool,
+    unsynced: Vec<Write>,
+    log: Option<WriteLog>,
+}
+
+fn overlaps(faults: &[Range<usize>], range: Range<usize>) -> bool {
+    faults
+        .iter()
+        .any(|fault| fault.start < range.end && range.start < fault.end)
+}
+
+/// Faults of a `FaultyDevice` or `FaultyBlockDevice`, in offsets of bytes
+#[derive(Default)]
+pub struct FaultInjector {
+    state: Mutex<State>,
+}
+
+impl FaultInjector {
+    /// Fail the reads overlapping `range`
+    pub fn fail_reads(&self, range: Range<usize>) {
+        self.state.lock().read_faults.push(range);
+    }
+
+    /// Fail the writes overlapping `range`
+    pub fn fail_writes(&self, range: Range<usize>) {
+        self.state.lock().write_faults.push(range);
+    }
+
+    /// Fail each read with `probability`
+    pub fn fail_reads_randomly(&self, probability: f64) {
+        self.state.lock().read_probability = probability;
+    }
+
+    /// Fail each write with `probability`
+    pub fn fail_writes_randomly(&self, probability: f64) {
+        self.state.lock().write_probability = probability;
+    }
+
+    /// Reset the random generator of faults to `seed`
+    pub fn seed(&self, seed: u64) {
+        self.state.lock().rng = XorShift::new(seed);
+    }
+
+    /// Stop failing reads and writes
+    pub fn clear(&self) {
+        let mut state = self.state.lock();
+        state.read_faults.clear();
+        state.write_faults.clear();
+        state.read_probability = 0.0;
+        state.write_probability = 0.0;
+    }
+
+    /// Whether to keep the writes in a volatile cache until `sync`
+    ///
+    /// Writes cached before are kept until `sync` or a power loss.
+    pub fn cache_writes(&self, caching: bool) {
+        self.state.lock().caching = caching;
+    }
+
+    /// Number of the cached writes, which are lost on a power loss
+    pub fn unsynced(&self) -> usize {
+        self.state.lock().unsynced.len()
+    }
+
+    /// Start recording the writes, dropping the log recorded before
+    pub fn record(&self) {
+        self.state.lock().log = Some(WriteLog::default());
+    }
+
+    /// The number of entries recorded so far
+    pub fn log_len(&self) -> usize {
+        self.state.lock().log.as_ref().map_or(0, WriteLog::len)
+    }
+
+    /// Stop recording and return the log
+    pub fn take_log(&self) -> WriteLog {
+        self.state.lock().log.take().unwrap_or_default()
+    }
+
+    /// Fail if a read of `len` bytes at `offset` should fail.
+    /// Wrappers of other devices may use it to share the faults.
+    pub fn check_read(&self, offset: usize, len: usize) -> Result<()> {
+        let mut state = self.state.lock();
+        let probability = state.read_probability;
+        if overlaps(&state.read_faults, offset..offset + len) || state.rng.chance(probability) {
+            return Err(DevErrorKind::Io.into());
+        }
+        Ok(())
+    }
+
+    /// Fail if a write of `len` bytes at `offset` should fail
+    pub fn check_write(&self, offset: usize, len: usize) -> Result<()> {
+        let mut state = self.state.lock();
+        let probability = state.write_probability;
+        if overlaps(&state.write_faults, offset..offset + len) || state.rng.chance(probability) {
+            return Err(DevErrorKind::Io.into());
+        }
+        Ok(())
+    }
+
+    /// Record a write, return true if it is cached instead of going to the device
+    fn write(&self, offset: usize, data: &[u8]) -> bool {
+        let mut state = self.state.lock();
+        let write = Write {
+            offset,
+            data: data.to_vec(),
+        };
+        if let Some(log) = state.log.as_mut() {
+            log.entries.push(LogEntry::Write(write.clone()));
+        }
+        if !state.caching {
+            return false;
+        }
+        state.unsynced.push(write);
+        true
+    }
+
+    /// Copy the cached writes into `buf` read at `offset`, of which `len` bytes are
+    /// read from the device. Return the length extended by the cached writes.
+    fn overlay(&self, offset: usize, buf: &mut [u8], len: usize) -> usize {
+        let state = self.state.lock();
+        let mut end = len;
+        for write in state.unsynced.iter() {
+            let begin = write.offset.max(offset);
+            let write_end = (write.offset + write.data.len()).min(offset + buf.len());
+            if begin >= write_end {
+                continue;
+            }
+            if end < write_end - offset {
+                // beyond the end of the device, the gap reads as zeros
+                if end < begin - offset {
+                    buf[end..begin - offset].fill(0);
+                }
+                end = write_end - offset;
+            }
+            buf[begin - offset..write_end - offset]
+                .copy_from_slice(&write.data[begin - write.offset..write_end - write.offset]);
+        }
+        end
+    }
+
+    /// Apply the cached writes by `write` in order, and record a sync
+    fn flush(&self, mut write: impl FnMut(&Write) -> Result<()>) -> Result<()> {
+        let unsynced = core::mem::take(&mut self.state.lock().unsynced);
+        for (i, w) in unsynced.iter().enumerate() {
+            if let Err(e) = write(w) {
+                // keep the rest for the next sync
+                let mut state = self.state.lock();
+                let rest = core::mem::take(&mut state.unsynced);
+                state.unsynced = unsynced[i..].to_vec();
+                state.unsynced.extend(rest);
+                return Err(e);
+            }
+        }
+        if let Some(log) = self.state.lock().log.as_mut() {
+            log.entries.push(LogEntry::Sync);
+        }
+        Ok(())
+    }
+
+    /// Drop the cached writes, return those at `keep` in that order
+    fn lose_power(&self, keep: &[usize]) -> Vec<Write> {
+        let unsynced = core::mem::take(&mut self.state.lock().unsynced);
+        keep.iter().map(|&i| unsynced[i].clone()).collect()
+    }
+
+    /// A random subset of the cached writes in random order
+    fn random_survivors(&self) -> Vec<usize> {
+        let mut state = self.state.lock();
+        let n = state.unsynced.len();
+        let mut order: Vec<usize> = (0..n).collect();
+        for i in (1..n).rev() {
+            let j = state.rng.below(i + 1);
+            order.swap(i, j);
+        }
+        order.truncate(state.rng.below(n + 1));
+        order
+    }
+}
+
+/// A wrapper of `Device` with faults
+pub struct FaultyDevice<T> {
+    inner: T,
+    faults: FaultInjector,
+}
+
+impl<T> FaultyDevice<T> {
+    pub fn new(inner: T) -> Self {
+        FaultyDevice {
+            inner,
+            faults: FaultInjector::default(),
+        }
+    }
+
+    /// The wrapped device, without the cached writes
+    pub fn inner(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn faults(&self) -> &FaultInjector {
+        &self.faults
+    }
+}
+
+impl<T: Device> FaultyDevice<T> {
+    /// Simulate a power loss: the cached writes at `keep` reach the device in that order,
+    /// while the others are lost
+    pub fn power_loss(&self, keep: &[usize]) -> Result<()> {
+        for write in self.faults.lose_power(keep) {
+            write_all(&self.inner, write.offset, &write.data)?;
+        }
+        self.inner.sync()
+    }
+
+    /// Simulate a power loss, where a random subset of the cached writes reach the device
+    /// in random order
+    pub fn random_power_loss(&self) -> Result<()> {
+        let keep = self.faults.random_survivors();
+        self.power_loss(&keep)
+    }
+}
+
+impl<T: Device> Device for FaultyDevice<T> {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        self.faults.check_read(offset, buf.len())?;
+        let len = self.inner.read_at(offset, buf)?;
+        Ok(self.faults.overlay(offset, buf, len))
+    }
+
+    fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize> {
+        self.faults.check_write(offset, buf.len())?;
+        if self.faults.write(offset, buf) {
+            return Ok(buf.len());
+        }
+        self.inner.write_at(offset, buf)
+    }
+
+    fn sync(&self) -> Result<()> {
+        self.faults
+            .flush(|write| write_all(&self.inner, write.offset, &write.data))?;
+        self.inner.sync()
+    }
+}
+
+/// A wrapper of `BlockDevice` with faults
+///
+/// The offsets of faults and writes are in bytes, as those of `FaultyDevice`.
+pub struct FaultyBlockDevice<T> {
+    inner: T,
+    faults: FaultInjector,
+}
+
+impl<T> FaultyBlockDevice<T> {
+    pub fn new(inner: T) -> Self {
+        FaultyBlockDevice {
+            inner,
+            faults: FaultInjector::default(),
+        }
+    }
+
+    /// The wrapped device, without the cached writes
+    pub fn inner(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn faults(&self) -> &FaultInjector {
+        &self.faults
+    }
+}
+
+impl<T: BlockDevice> FaultyBlockDevice<T> {
+    /// Simulate a power loss, see `FaultyDevice::power_loss`
+    pub fn power_loss(&self, keep: &[usize]) -> Result<()> {
+        for write in self.faults.lose_power(keep) {
+            let block_id = write.offset >> T::BLOCK_SIZE_LOG2;
+            self.inner.write_at(block_id, &write.data)?;
+        }
+        self.inner.sync()
+    }
+
+    /// Simulate a power loss, see `FaultyDevice::random_power_loss`
+    pub fn random_power_loss(&self) -> Result<()> {
+        let keep = self.faults.random_survivors();
+        self.power_loss(&keep)
+    }
+}
+
+impl<T: BlockDevice> BlockDevice for FaultyBlockDevice<T> {
+    const BLOCK_SIZE_LOG2: u8 = T::BLOCK_SIZE_LOG2;
+
+    fn read_at(&self, block_id: BlockId, buf: &mut [u8]) -> Result<()> {
+        let size = 1 << Self::BLOCK_SIZE_LOG2;
+        let offset = block_id << Self::BLOCK_SIZE_LOG2;
+        self.faults.check_read(offset, size)?;
+        self.inner.read_at(block_id, buf)?;
+        self.faults.overlay(offset, &mut buf[..size], size);
+        Ok(())
+    }
+
+    fn write_at(&self, block_id: BlockId, buf: &[u8]) -> Result<()> {
+        let size = 1 << Self::BLOCK_SIZE_LOG2;
+        let offset = block_id << Self::BLOCK_SIZE_LOG2;
+        self.faults.check_write(offset, size)?;
+        if self.faults.write(offset, &buf[..size]) {
+            return Ok(());
+        }
+        self.inner.write_at(block_id, buf)
+    }
+
+    fn sync(&self) -> Result<()> {
+        self.faults.flush(|write| {
+            let block_id = write.offset >> Self::BLOCK_SIZE_LOG2;
+            self.inner.write_at(block_id, &write.data)
+        })?;
+        self.inner.sync()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// 4 blocks of 4 bytes
+    #[derive(Default)]
+    struct Mem(Mutex<[u8; 16]>);
+
+    impl BlockDevice for Mem {
+        const BLOCK_SIZE_LOG2: u8 = 2;
+        fn read_at(&self, block_id: BlockId, buf: &mut [u8]) -> Result<()> {
+            if block_id >= 4 {
+                return Err(DevErrorKind::OutOfRange.into());
+            }
+            let begin = block_id << 2;
+            buf[..4].copy_from_slice(&self.0.lock()[begin..begin + 4]);
+            Ok(())
+        }
+        fn write_at(&self, block_id: BlockId, buf: &[u8]) -> Result<()> {
+            if block_id >= 4 {
+                return Err(DevErrorKind::OutOfRange.into());
+            }
+            let begin = block_id << 2;
+            self.0.lock()[begin..begin + 4].copy_from_slice(&buf[..4]);
+            Ok(())
+        }
+        fn sync(&self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn faults() {
+        let device = FaultyDevice::new(Mem::default());
+        let mut buf = [0u8; 4];
+        device.faults().fail_reads(4..8);
+        assert_eq!(device.read_at(0, &mut buf), Ok(4));
+        assert!(device.read_at(6, &mut buf).is_err());
+        device.faults().fail_writes_randomly(1.0);
+        assert!(device.write_at(0, &buf).is_err());
+        device.faults().clear();
+        assert_eq!(device.read_at(6, &mut buf), Ok(4));
+        assert_eq!(device.write_at(0, &buf), Ok(4));
+
+        let device = FaultyBlockDevice::new(Mem::default());
+        device.faults().fail_writes(8..9);
+        assert!(BlockDevice::write_at(&device, 1, &buf).is_ok());
+        assert!(BlockDevice::write_at(&device, 2, &buf).is_err());
+        // a write through the failing block is short
+        assert_eq!(Device::write_at(&device, 6, &buf), Ok(2));
+    }
+
+    #[test]
+    fn power_loss() {
+        let device = FaultyDevice::new(Mem::default());
+        device.faults().cache_writes(true);
+        device.write_at(0, &[1; 4]).unwrap();
+        device.write_at(2, &[2; 4]).unwrap();
+        let mut buf = [0u8; 8];
+        assert_eq!(device.read_at(0, &mut buf), Ok(8));
+        assert_eq!(buf, [1, 1, 2, 2, 2, 2, 0, 0]);
+        assert_eq!(*device.inner().0.lock(), [0; 16]);
+        assert_eq!(device.faults().unsynced(), 2);
+
+        // the second write reaches the device before the first one
+        device.power_loss(&[1, 0]).unwrap();
+        assert_eq!(device.faults().unsynced(), 0);
+        assert_eq!(device.inner().0.lock()[..8], [1, 1, 1, 1, 2, 2, 0, 0]);
+
+        device.write_at(8, &[3; 4]).unwrap();
+        Device::sync(&device).unwrap();
+        device.write_at(12, &[4; 4]).unwrap();
+        device.power_loss(&[]).unwrap();
+        assert_eq!(device.inner().0.lock()[8..], [3, 3, 3, 3, 0, 0, 0, 0]);
+
+        let device = FaultyBlockDevice::new(Mem::default());
+        device.faults().cache_writes(true);
+        device.faults().seed(1);
+        for i in 0..4 {
+            BlockDevice::write_at(&device, i, &[i as u8 + 1; 4]).unwrap();
+        }
+        device.random_power_loss().unwrap();
+        let data = *device.inner().0.lock();
+        for i in 0..4 {
+            let block = &data[i * 4..i * 4 + 4];
+            assert!(block == [0; 4] || block == [i as u8 + 1; 4]);
+        }
+    }
+
+    #[test]
+    fn write_log() {
+        let device = FaultyDevice::new(Mem::default());
+        device.faults().record();
+        device.write_at(0, &[1; 4]).unwrap();
+        Device::sync(&device).unwrap();
+        device.write_at(4, &[2; 2]).unwrap();
+        let log = device.faults().take_log();
+        assert_eq!(log.len(), 3);
+        assert_eq!(log.entries[1], LogEntry::Sync);
+
+        let image = Mem::default();
+        log.replay(1, &image).unwrap();
+        assert_eq!(image.0.lock()[..8], [1, 1, 1, 1, 0, 0, 0, 0]);
+        let image = Mem::default();
+        log.replay(log.len(), &image).unwrap();
+        assert_eq!(image.0.lock()[..8], [1, 1, 1, 1, 2, 2, 0, 0]);
+        // not recording any more
+        device.write_at(8, &[3; 4]).unwrap();
+        assert!(device.faults().take_log().is_empty());
+    }
+}
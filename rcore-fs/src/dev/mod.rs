@@ -1,6 +1,8 @@
 use crate::{util::*, vfs::Timespec};
+use alloc::boxed::Box;
 
 pub mod block_cache;
+pub mod fault;
 pub mod std_impl;
 
 /// A current time provider
@@ -9,10 +11,27 @@ pub trait TimeProvider: Send + Sync {
 }
 
 /// Interface for FS to read & write
+///
+/// The async versions let slow devices park the task instead of blocking the executor.
+/// They default to the sync versions.
 pub trait Device: Send + Sync {
     fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize>;
     fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize>;
     fn sync(&self) -> Result<()>;
+
+    fn async_read_at<'a>(
+        &'a self,
+        offset: usize,
+        buf: &'a mut [u8],
+    ) -> BoxFuture<'a, Result<usize>> {
+        Box::pin(async move { self.read_at(offset, buf) })
+    }
+    fn async_write_at<'a>(&'a self, offset: usize, buf: &'a [u8]) -> BoxFuture<'a, Result<usize>> {
+        Box::pin(async move { self.write_at(offset, buf) })
+    }
+    fn async_sync(&self) -> BoxFuture<'_, Result<()>> {
+        Box::pin(async move { self.sync() })
+    }
 }
 
 /// Device which can only R/W in blocks
@@ -21,11 +40,101 @@ pub trait BlockDevice: Send + Sync {
     fn read_at(&self, block_id: BlockId, buf: &mut [u8]) -> Result<()>;
     fn write_at(&self, block_id: BlockId, buf: &[u8]) -> Result<()>;
     fn sync(&self) -> Result<()>;
+
+    /// Read consecutive blocks from `block_id`, the length of `buf` is a multiple of block size.
+    ///
+    /// Devices able to serve multi-block requests should override it to submit them at once.
+    fn read_blocks(&self, block_id: BlockId, buf: &mut [u8]) -> Result<()> {
+        let size = 1 << Self::BLOCK_SIZE_LOG2;
+        for (i, buf) in buf.chunks_mut(size).enumerate() {
+            self.read_at(block_id + i, buf)?;
+        }
+        Ok(())
+    }
+    /// Write consecutive blocks from `block_id`, the length of `buf` is a multiple of block size.
+    fn write_blocks(&self, block_id: BlockId, buf: &[u8]) -> Result<()> {
+        let size = 1 << Self::BLOCK_SIZE_LOG2;
+        for (i, buf) in buf.chunks(size).enumerate() {
+            self.write_at(block_id + i, buf)?;
+        }
+        Ok(())
+    }
+
+    fn async_read_at<'a>(
+        &'a self,
+        block_id: BlockId,
+        buf: &'a mut [u8],
+    ) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move { self.read_at(block_id, buf) })
+    }
+    fn async_write_at<'a>(&'a self, block_id: BlockId, buf: &'a [u8]) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move { self.write_at(block_id, buf) })
+    }
+    fn async_sync(&self) -> BoxFuture<'_, Result<()>> {
+        Box::pin(async move { self.sync() })
+    }
 }
 
 /// The error type for device.
-#[derive(Debug, PartialEq, Eq)]
-pub struct DevError;
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DevError {
+    pub kind: DevErrorKind,
+    /// The error code of the device or the host, 0 if unknown
+    pub code: i32,
+}
+
+/// The kind of a `DevError`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DevErrorKind {
+    /// The device failed to read or write
+    Io,
+    /// The offset is beyond the end of the device
+    OutOfRange,
+    /// No space left on the device
+    NoSpace,
+    /// The device is read-only
+    ReadOnly,
+    /// The device is busy, try again later
+    Busy,
+    /// The device did not respond in time
+    TimedOut,
+    /// The operation is not supported by the device
+    Unsupported,
+    /// Out of memory
+    NoMemory,
+    Other,
+}
+
+impl DevError {
+    pub const fn new(kind: DevErrorKind) -> Self {
+        DevError { kind, code: 0 }
+    }
+
+    pub const fn with_code(kind: DevErrorKind, code: i32) -> Self {
+        DevError { kind, code }
+    }
+
+    /// The error number of the kind, see `FsError::to_errno`
+    pub fn errno(&self) -> i32 {
+        use crate::errno::*;
+        match self.kind {
+            DevErrorKind::Io | DevErrorKind::Other => EIO,
+            DevErrorKind::OutOfRange => ENXIO,
+            DevErrorKind::NoSpace => ENOSPC,
+            DevErrorKind::ReadOnly => EROFS,
+            DevErrorKind::Busy => EBUSY,
+            DevErrorKind::TimedOut => ETIMEDOUT,
+            DevErrorKind::Unsupported => EOPNOTSUPP,
+            DevErrorKind::NoMemory => ENOMEM,
+        }
+    }
+}
+
+impl From<DevErrorKind> for DevError {
+    fn from(kind: DevErrorKind) -> Self {
+        DevError::new(kind)
+    }
+}
 
 /// A specialized `Result` type for device.
 pub type Result<T> = core::result::Result<T, DevError>;
@@ -43,20 +152,24 @@ macro_rules! try0 {
 /// Helper functions to R/W BlockDevice in bytes
 impl<T: BlockDevice> Device for T {
     fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
-        let iter = BlockIter {
+        let end = offset + buf.len();
+        let mut iter = BlockIter {
             begin: offset,
-            end: offset + buf.len(),
+            end,
             block_size_log2: Self::BLOCK_SIZE_LOG2,
         };
 
         // For each block
-        for range in iter {
+        while let Some(range) = iter.next() {
             let len = range.origin_begin() - offset;
-            let buf = &mut buf[range.origin_begin() - offset..range.origin_end() - offset];
             if range.is_full() {
-                // Read to target buf directly
-                try0!(len, BlockDevice::read_at(self, range.block, buf));
+                // Read all following full blocks to target buf directly
+                let full_end = end >> Self::BLOCK_SIZE_LOG2 << Self::BLOCK_SIZE_LOG2;
+                let buf = &mut buf[len..full_end - offset];
+                try0!(len, BlockDevice::read_blocks(self, range.block, buf));
+                iter.begin = full_end;
             } else {
+                let buf = &mut buf[len..range.origin_end() - offset];
                 let mut block_buf: [u8; 1 << 10] = unsafe { uninit_memory() };
                 assert!(Self::BLOCK_SIZE_LOG2 <= 10);
                 // Read to local buf first
@@ -69,20 +182,24 @@ impl<T: BlockDevice> Device for T {
     }
 
     fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize> {
-        let iter = BlockIter {
+        let end = offset + buf.len();
+        let mut iter = BlockIter {
             begin: offset,
-            end: offset + buf.len(),
+            end,
             block_size_log2: Self::BLOCK_SIZE_LOG2,
         };
 
         // For each block
-        for range in iter {
+        while let Some(range) = iter.next() {
             let len = range.origin_begin() - offset;
-            let buf = &buf[range.origin_begin() - offset..range.origin_end() - offset];
             if range.is_full() {
-                // Write to target buf directly
-                try0!(len, BlockDevice::write_at(self, range.block, buf));
+                // Write all following full blocks from target buf directly
+                let full_end = end >> Self::BLOCK_SIZE_LOG2 << Self::BLOCK_SIZE_LOG2;
+                let buf = &buf[len..full_end - offset];
+                try0!(len, BlockDevice::write_blocks(self, range.block, buf));
+                iter.begin = full_end;
             } else {
+                let buf = &buf[len..range.origin_end() - offset];
                 let mut block_buf: [u8; 1 << 10] = unsafe { uninit_memory() };
                 assert!(Self::BLOCK_SIZE_LOG2 <= 10);
                 // Read to local buf first
@@ -99,6 +216,87 @@ impl<T: BlockDevice> Device for T {
     fn sync(&self) -> Result<()> {
         BlockDevice::sync(self)
     }
+
+    fn async_read_at<'a>(
+        &'a self,
+        offset: usize,
+        buf: &'a mut [u8],
+    ) -> BoxFuture<'a, Result<usize>> {
+        Box::pin(async move {
+            let iter = BlockIter {
+                begin: offset,
+                end: offset + buf.len(),
+                block_size_log2: Self::BLOCK_SIZE_LOG2,
+            };
+
+            // For each block
+            for range in iter {
+                let len = range.origin_begin() - offset;
+                let buf = &mut buf[range.origin_begin() - offset..range.origin_end() - offset];
+                if range.is_full() {
+                    // Read to target buf directly
+                    try0!(
+                        len,
+                        BlockDevice::async_read_at(self, range.block, buf).await
+                    );
+                } else {
+                    let mut block_buf = [0u8; 1 << 10];
+                    assert!(Self::BLOCK_SIZE_LOG2 <= 10);
+                    // Read to local buf first
+                    try0!(
+                        len,
+                        BlockDevice::async_read_at(self, range.block, &mut block_buf).await
+                    );
+                    // Copy to target buf then
+                    buf.copy_from_slice(&block_buf[range.begin..range.end]);
+                }
+            }
+            Ok(buf.len())
+        })
+    }
+
+    fn async_write_at<'a>(&'a self, offset: usize, buf: &'a [u8]) -> BoxFuture<'a, Result<usize>> {
+        Box::pin(async move {
+            let iter = BlockIter {
+                begin: offset,
+                end: offset + buf.len(),
+                block_size_log2: Self::BLOCK_SIZE_LOG2,
+            };
+
+            // For each block
+            for range in iter {
+                let len = range.origin_begin() - offset;
+                let buf = &buf[range.origin_begin() - offset..range.origin_end() - offset];
+                if range.is_full() {
+                    // Write to target buf directly
+                    try0!(
+                        len,
+                        BlockDevice::async_write_at(self, range.block, buf).await
+                    );
+                } else {
+                    let mut block_buf = [0u8; 1 << 10];
+                    assert!(Self::BLOCK_SIZE_LOG2 <= 10);
+                    // Read to local buf first
+                    try0!(
+                        len,
+                        BlockDevice::async_read_at(self, range.block, &mut block_buf).await
+                    );
+                    // Write to local buf
+                    block_buf[range.begin..range.end].copy_from_slice(buf);
+                    // Write back to target buf
+                    try0!(
+                        len,
+                        BlockDevice::async_write_at(self, range.block, &block_buf).await
+                    );
+                }
+            }
+            Ok(buf.len())
+        })
+    }
+
+    fn async_sync(&self) -> BoxFuture<'_, Result<()>> {
+        BlockDevice::async_sync(self)
+    }
 }
 
 #[cfg(test)]
@@ -110,7 +308,7 @@ mod test {
         const BLOCK_SIZE_LOG2: u8 = 2;
         fn read_at(&self, block_id: BlockId, buf: &mut [u8]) -> Result<()> {
             if block_id >= 4 {
-                return Err(DevError);
+                return Err(DevErrorKind::OutOfRange.into());
             }
             let begin = block_id << 2;
             buf[..4].copy_from_slice(&self.lock().unwrap()[begin..begin + 4]);
@@ -118,7 +316,7 @@ mod test {
         }
         fn write_at(&self, block_id: BlockId, buf: &[u8]) -> Result<()> {
             if block_id >= 4 {
-                return Err(DevError);
+                return Err(DevErrorKind::OutOfRange.into());
             }
             let begin = block_id << 2;
             self.lock().unwrap()[begin..begin + 4].copy_from_slice(&buf[..4]);
@@ -180,4 +378,62 @@ mod test {
             [0, 0, 0, 3, 4, 5, 6, 7, 8, 0, 0, 3, 4, 5, 6, 7]
         );
     }
+
+    #[test]
+    fn read_write_blocks() {
+        let buf: Mutex<[u8; 16]> = Mutex::new([0; 16]);
+        let data: [u8; 8] = [1, 2, 3, 4, 5, 6, 7, 8];
+        BlockDevice::write_blocks(&buf, 1, &data).unwrap();
+        let mut res = [0u8; 8];
+        BlockDevice::read_blocks(&buf, 1, &mut res).unwrap();
+        assert_eq!(res, data);
+        assert_eq!(
+            BlockDevice::read_blocks(&buf, 3, &mut res),
+            Err(DevError::new(DevErrorKind::OutOfRange))
+        );
+
+        // the full blocks in the middle
+        let mut res = [0u8; 11];
+        let ret = Device::read_at(&buf, 2, &mut res);
+        assert_eq!(ret, Ok(11));
+        assert_eq!(res, [0, 0, 1, 2, 3, 4, 5, 6, 7, 8, 0]);
+        let ret = Device::write_at(&buf, 3, &[9; 10]);
+        assert_eq!(ret, Ok(10));
+        assert_eq!(
+            *buf.lock().unwrap(),
+            [0, 0, 0, 9, 9, 9, 9, 9, 9, 9, 9, 9, 9, 0, 0, 0]
+        );
+    }
+
+    #[test]
+    fn async_read_write() {
+        use crate::util::block_on;
+        let buf: Mutex<[u8; 16]> = Mutex::new([0; 16]);
+        let res: [u8; 6] = [3, 4, 5, 6, 7, 8];
+        let ret = block_on(Device::async_write_at(&buf, 11, &res));
+        assert_eq!(ret, Ok(5));
+        assert_eq!(
+            *buf.lock().unwrap(),
+            [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 3, 4, 5, 6, 7]
+        );
+        let mut res: [u8; 6] = [0; 6];
+        let ret = block_on(Device::async_read_at(&buf, 10, &mut res));
+        assert_eq!(ret, Ok(6));
+        assert_eq!(res, [0, 3, 4, 5, 6, 7]);
+    }
+
+    #[test]
+    fn async_block_cache() {
+        use crate::util::block_on;
+        let cache = block_cache::BlockCache::new(Mutex::new([0u8; 16]), 2);
+        for block in 0..4 {
+            let data = [block as u8; 4];
+            block_on(BlockDevice::async_write_at(&cache, block, &data)).unwrap();
+        }
+        let mut res = [0u8; 16];
+        let ret = block_on(Device::async_read_at(&cache, 0, &mut res));
+        assert_eq!(ret, Ok(16));
+        assert_eq!(res, [0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3]);
+        block_on(Device::async_sync(&cache)).unwrap();
+    }
 }
@@ -44,7 +44,19 @@ impl TimeProvider for StdTimeProvider {
 }
 
 impl From<Error> for DevError {
-    fn from(_: Error) -> Self {
-        DevError
+    fn from(e: Error) -> Self {
+        use std::io::ErrorKind;
+        let kind = match e.kind() {
+            ErrorKind::UnexpectedEof => DevErrorKind::OutOfRange,
+            ErrorKind::WouldBlock => DevErrorKind::Busy,
+            ErrorKind::TimedOut => DevErrorKind::TimedOut,
+            ErrorKind::Unsupported => DevErrorKind::Unsupported,
+            ErrorKind::OutOfMemory => DevErrorKind::NoMemory,
+            ErrorKind::StorageFull => DevErrorKind::NoSpace,
+            ErrorKind::ReadOnlyFilesystem => DevErrorKind::ReadOnly,
+            ErrorKind::Other => DevErrorKind::Other,
+            _ => DevErrorKind::Io,
+        };
+        DevError::with_code(kind, e.raw_os_error().unwrap_or(0))
     }
 }
@@ -0,0 +1,55 @@
+//! Error numbers of `FsError::to_errno`
+//!
+//! They are those of Linux, or those of the host with `std` on Unix,
+//! so that the bindings to the host can pass them as they are.
+
+#[cfg(not(all(feature = "std", unix)))]
+mod consts {
+    pub const EPERM: i32 = 1;
+    pub const ENOENT: i32 = 2;
+    pub const EINTR: i32 = 4;
+    pub const EIO: i32 = 5;
+    pub const ENXIO: i32 = 6;
+    pub const E2BIG: i32 = 7;
+    pub const EAGAIN: i32 = 11;
+    pub const ENOMEM: i32 = 12;
+    pub const EACCES: i32 = 13;
+    pub const EBUSY: i32 = 16;
+    pub const EEXIST: i32 = 17;
+    pub const EXDEV: i32 = 18;
+    pub const ENODEV: i32 = 19;
+    pub const ENOTDIR: i32 = 20;
+    pub const EISDIR: i32 = 21;
+    pub const EINVAL: i32 = 22;
+    pub const ENOTTY: i32 = 25;
+    pub const EFBIG: i32 = 27;
+    pub const ENOSPC: i32 = 28;
+    pub const EROFS: i32 = 30;
+    pub const EMLINK: i32 = 31;
+    pub const EDEADLK: i32 = 35;
+    pub const ENAMETOOLONG: i32 = 36;
+    pub const ENOSYS: i32 = 38;
+    pub const ENOTEMPTY: i32 = 39;
+    pub const ELOOP: i32 = 40;
+    /// `ENODATA` of Linux
+    pub const ENOATTR: i32 = 61;
+    pub const EOPNOTSUPP: i32 = 95;
+    pub const ETIMEDOUT: i32 = 110;
+    pub const EUCLEAN: i32 = 117;
+}
+
+#[cfg(all(feature = "std", unix))]
+mod consts {
+    pub use libc::{
+        E2BIG, EACCES, EAGAIN, EBUSY, EDEADLK, EEXIST, EFBIG, EINTR, EINVAL, EIO, EISDIR, ELOOP,
+        EMLINK, ENAMETOOLONG, ENODEV, ENOENT, ENOMEM, ENOSPC, ENOSYS, ENOTDIR, ENOTEMPTY, ENOTTY,
+        ENXIO, EOPNOTSUPP, EPERM, EROFS, ETIMEDOUT, EXDEV,
+    };
+    // there is no `EUCLEAN`, which is read back as `EIO`
+    #[cfg(not(target_os = "linux"))]
+    pub use libc::{EIO as EUCLEAN, ENOATTR};
+    #[cfg(target_os = "linux")]
+    pub use libc::{ENODATA as ENOATTR, EUCLEAN};
+}
+
+pub use consts::*;
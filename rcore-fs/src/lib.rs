@@ -2,9 +2,17 @@
 
 extern crate alloc;
 
+pub mod cache;
+pub mod crash;
 pub mod dev;
 pub mod dirty;
+pub mod errno;
 pub mod file;
+pub mod lock;
+pub mod notify;
+pub mod page_cache;
+pub mod path;
+pub mod read_only;
 pub mod util;
 pub mod vfs;
 
@@ -0,0 +1,589 @@
+//! Advisory file locks
+//!
+//! `LockManager` implements whole-file locks like `flock(2)` and byte-range
+//! record locks like POSIX `fcntl(2)`. Locks live outside of file systems and
+//! are keyed by `LockKey`, so one manager serves every `INode`, including the
+//! ones under different mount points.
+
+use crate::vfs::{FsError, INode, Result};
+use alloc::{collections::BTreeMap, vec, vec::Vec};
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+use spin::Mutex;
+
+/// Identity of a file: its file system and its inode number.
+/// A file reached through different wrappers like mount points has the same key.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct LockKey {
+    fs: usize,
+    inode: usize,
+}
+
+impl LockKey {
+    pub fn new(inode: &dyn INode) -> Result<Self> {
+        Ok(LockKey {
+            fs: inode.fs().identity(),
+            inode: inode.metadata()?.inode,
+        })
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum LockType {
+    /// Shared lock (F_RDLCK, LOCK_SH)
+    Read,
+    /// Exclusive lock (F_WRLCK, LOCK_EX)
+    Write,
+    /// Release the lock (F_UNLCK, LOCK_UN)
+    Unlock,
+}
+
+/// A POSIX record lock on bytes `start..end` of a file
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct PosixLock {
+    pub type_: LockType,
+    pub start: usize,
+    /// Exclusive end, `usize::MAX` to lock up to the end of file however it grows
+    pub end: usize,
+    /// Owner of the lock, usually the process. Locks never conflict with the same owner.
+    pub owner: u64,
+    /// Process holding the lock, only reported by `LockManager::test_posix`
+    pub pid: u32,
+}
+
+impl PosixLock {
+    fn overlaps(&self, other: &PosixLock) -> bool {
+        self.start < other.end && other.start < self.end
+    }
+
+    fn conflicts(&self, other: &PosixLock) -> bool {
+        self.owner != other.owner
+            && self.overlaps(other)
+            && (self.type_ == LockType::Write || other.type_ == LockType::Write)
+    }
+}
+
+/// Locks of a file
+#[derive(Default)]
+struct FileLocks {
+    /// flock owner -> `Read` or `Write`
+    flocks: BTreeMap<u64, LockType>,
+    /// POSIX locks. Locks of the same owner never overlap.
+    posix: Vec<PosixLock>,
+    /// Wakers of the pending acquisitions, woken when any lock is released
+    waiters: Vec<Waker>,
+}
+
+impl FileLocks {
+    fn is_empty(&self) -> bool {
+        self.flocks.is_empty() && self.posix.is_empty() && self.waiters.is_empty()
+    }
+}
+
+#[derive(Default)]
+struct Inner {
+    files: BTreeMap<LockKey, FileLocks>,
+    /// POSIX owner -> the lock it is waiting for, used to detect deadlocks
+    waiting: BTreeMap<u64, (LockKey, PosixLock)>,
+}
+
+impl Inner {
+    fn file(&mut self, key: LockKey) -> &mut FileLocks {
+        self.files.entry(key).or_default()
+    }
+
+    /// Drop the entry of the file if nothing is left in it
+    fn tidy(&mut self, key: LockKey) {
+        if self.files.get(&key).is_some_and(|file| file.is_empty()) {
+            self.files.remove(&key);
+        }
+    }
+
+    /// Remove a waiter registered with `waker`, if it has not been woken yet
+    fn remove_waiter(&mut self, key: LockKey, waker: &Waker) {
+        if let Some(file) = self.files.get_mut(&key) {
+            if let Some(i) = file.waiters.iter().position(|w| w.will_wake(waker)) {
+                file.waiters.swap_remove(i);
+            }
+        }
+        self.tidy(key);
+    }
+
+    fn try_set_posix(
+        &mut self,
+        key: LockKey,
+        lock: &PosixLock,
+        wake: &mut Vec<Waker>,
+    ) -> Result<()> {
+        if lock.start >= lock.end {
+            return Err(FsError::InvalidParam);
+        }
+        let file = self.file(key);
+        if lock.type_ != LockType::Unlock && file.posix.iter().any(|l| l.conflicts(lock)) {
+            return Err(FsError::Again);
+        }
+        // replace the range in the locks of the owner, splitting them when needed
+        let mut locks = Vec::with_capacity(file.posix.len() + 2);
+        for l in file.posix.drain(..) {
+            if l.owner != lock.owner || !l.overlaps(lock) {
+                locks.push(l);
+                continue;
+            }
+            if l.start < lock.start {
+                locks.push(PosixLock {
+                    end: lock.start,
+                    ..l
+                });
+            }
+            if l.end > lock.end {
+                locks.push(PosixLock {
+                    start: lock.end,
+                    ..l
+                });
+            }
+        }
+        if lock.type_ != LockType::Unlock {
+            // merge with the adjacent locks of the same owner and type
+            let mut merged = *lock;
+            locks.retain(|l| {
+                let adjacent = l.owner == lock.owner
+                    && l.type_ == lock.type_
+                    && (l.end == lock.start || l.start == lock.end);
+                if adjacent {
+                    merged.start = merged.start.min(l.start);
+                    merged.end = merged.end.max(l.end);
+                }
+                !adjacent
+            });
+            locks.push(merged);
+        }
+        file.posix = locks;
+        // an unlock or a downgrade may let others in
+        wake.append(&mut file.waiters);
+        self.tidy(key);
+        Ok(())
+    }
+
+    fn try_flock(
+        &mut self,
+        key: LockKey,
+        owner: u64,
+        type_: LockType,
+        wake: &mut Vec<Waker>,
+    ) -> Result<()> {
+        let file = self.file(key);
+        let conflict = type_ != LockType::Unlock
+            && file
+                .flocks
+                .iter()
+                .any(|(&o, &t)| o != owner && (t == LockType::Write || type_ == LockType::Write));
+        // like Linux, a conversion drops the old lock first even if the new one is refused,
+        // so that two owners upgrading their shared locks do not wait for each other
+        if file.flocks.remove(&owner).is_some() {
+            wake.append(&mut file.waiters);
+        }
+        if conflict {
+            self.tidy(key);
+            return Err(FsError::Again);
+        }
+        if type_ != LockType::Unlock {
+            file.flocks.insert(owner, type_);
+        }
+        self.tidy(key);
+        Ok(())
+    }
+
+    /// Whether waiting for `lock` makes a cycle of owners waiting for each other
+    fn would_deadlock(&self, key: LockKey, lock: &PosixLock) -> bool {
+        let mut visited = Vec::new();
+        let mut stack = vec![(key, *lock)];
+        while let Some((key, request)) = stack.pop() {
+            let holders = match self.files.get(&key) {
+                Some(file) => file.posix.iter().filter(|l| l.conflicts(&request)),
+                None => continue,
+            };
+            for holder in holders {
+                if holder.owner == lock.owner {
+                    return true;
+                }
+                if visited.contains(&holder.owner) {
+                    continue;
+                }
+                visited.push(holder.owner);
+                if let Some(&waiting) = self.waiting.get(&holder.owner) {
+                    stack.push(waiting);
+                }
+            }
+        }
+        false
+    }
+}
+
+/// Advisory locks of files
+///
+/// flock locks and POSIX locks are independent of each other, as on Linux.
+#[derive(Default)]
+pub struct LockManager {
+    inner: Mutex<Inner>,
+}
+
+impl LockManager {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Get a POSIX lock of others which conflicts with `lock` (F_GETLK)
+    pub fn test_posix(&self, key: LockKey, lock: &PosixLock) -> Option<PosixLock> {
+        let inner = self.inner.lock();
+        let file = inner.files.get(&key)?;
+        file.posix.iter().find(|l| l.conflicts(lock)).copied()
+    }
+
+    /// Set or release a POSIX lock without waiting (F_SETLK)
+    ///
+    /// Return `Again` if others hold conflicting locks.
+    pub fn try_set_posix(&self, key: LockKey, lock: PosixLock) -> Result<()> {
+        let mut wake = Vec::new();
+        let result = self.inner.lock().try_set_posix(key, &lock, &mut wake);
+        wake_all(wake);
+        result
+    }
+
+    /// Set or release a POSIX lock, waiting for conflicting locks to be released (F_SETLKW)
+    ///
+    /// Resolve to `Deadlock` if the owners of the conflicting locks are waiting
+    /// for locks of this owner, directly or not.
+    pub fn set_posix(&self, key: LockKey, lock: PosixLock) -> LockFuture<'_> {
+        LockFuture {
+            manager: self,
+            key,
+            request: Request::Posix(lock),
+            waiting: false,
+            waker: None,
+        }
+    }
+
+    /// Set, convert or release (`Unlock`) the flock lock of `owner` without waiting
+    ///
+    /// Return `Again` if others hold conflicting locks.
+    pub fn try_flock(&self, key: LockKey, owner: u64, type_: LockType) -> Result<()> {
+        let mut wake = Vec::new();
+        let result = self.inner.lock().try_flock(key, owner, type_, &mut wake);
+        wake_all(wake);
+        result
+    }
+
+    /// Set, convert or release the flock lock of `owner`, waiting for conflicting locks
+    pub fn flock(&self, key: LockKey, owner: u64, type_: LockType) -> LockFuture<'_> {
+        LockFuture {
+            manager: self,
+            key,
+            request: Request::Flock(owner, type_),
+            waiting: false,
+            waker: None,
+        }
+    }
+
+    /// Release all POSIX locks of `owner` on the file, when it closes any descriptor of it
+    pub fn release_posix(&self, key: LockKey, owner: u64) {
+        let unlock = PosixLock {
+            type_: LockType::Unlock,
+            start: 0,
+            end: usize::MAX,
+            owner,
+            pid: 0,
+        };
+        self.try_set_posix(key, unlock).unwrap();
+    }
+
+    /// Release the flock lock of `owner`, when its open file is closed
+    pub fn release_flock(&self, key: LockKey, owner: u64) {
+        self.try_flock(key, owner, LockType::Unlock).unwrap();
+    }
+}
+
+fn wake_all(wakers: Vec<Waker>) {
+    for waker in wakers {
+        waker.wake();
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+enum Request {
+    Posix(PosixLock),
+    Flock(u64, LockType),
+}
+
+/// A pending lock acquisition, see `LockManager::set_posix` and `LockManager::flock`
+///
+/// Dropping it gives up waiting.
+pub struct LockFuture<'a> {
+    manager: &'a LockManager,
+    key: LockKey,
+    request: Request,
+    /// Whether the request is recorded in `Inner::waiting`
+    waiting: bool,
+    /// The waker registered in the waiters of the file
+    waker: Option<Waker>,
+}
+
+impl Future for LockFuture<'_> {
+    type Output = Result<()>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let key = this.key;
+        let mut wake = Vec::new();
+        let mut inner = this.manager.inner.lock();
+        let result = match this.request {
+            Request::Posix(lock) => inner.try_set_posix(key, &lock, &mut wake),
+            Request::Flock(owner, type_) => inner.try_flock(key, owner, type_, &mut wake),
+        };
+        let result = match (result, this.request) {
+            (Err(FsError::Again), Request::Posix(lock)) if inner.would_deadlock(key, &lock) => {
+                Err(FsError::Deadlock)
+            }
+            (Err(FsError::Again), request) => {
+                if let Request::Posix(lock) = request {
+                    inner.waiting.insert(lock.owner, (key, lock));
+                    this.waiting = true;
+                }
+                if let Some(waker) = this.waker.take() {
+                    inner.remove_waiter(key, &waker);
+                }
+                inner.file(key).waiters.push(cx.waker().clone());
+                this.waker = Some(cx.waker().clone());
+                drop(inner);
+                wake_all(wake);
+                return Poll::Pending;
+            }
+            (result, _) => result,
+        };
+        if let (true, Request::Posix(lock)) = (this.waiting, this.request) {
+            inner.waiting.remove(&lock.owner);
+            this.waiting = false;
+        }
+        if let Some(waker) = this.waker.take() {
+            inner.remove_waiter(key, &waker);
+        }
+        drop(inner);
+        wake_all(wake);
+        Poll::Ready(result)
+    }
+}
+
+impl Drop for LockFuture<'_> {
+    fn drop(&mut self) {
+        let mut inner = self.manager.inner.lock();
+        if let (true, Request::Posix(lock)) = (self.waiting, self.request) {
+            inner.waiting.remove(&lock.owner);
+        }
+        if let Some(waker) = self.waker.take() {
+            inner.remove_waiter(self.key, &waker);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::util::block_on;
+    use std::sync::Arc;
+    use std::task::Wake;
+
+    struct Flag(std::sync::atomic::AtomicBool);
+
+    impl Wake for Flag {
+        fn wake(self: Arc<Self>) {
+            self.0.store(true, std::sync::atomic::Ordering::SeqCst);
+        }
+    }
+
+    fn poll(future: &mut LockFuture, flag: &Arc<Flag>) -> Poll<Result<()>> {
+        let waker = Waker::from(flag.clone());
+        Pin::new(future).poll(&mut Context::from_waker(&waker))
+    }
+
+    fn flag() -> Arc<Flag> {
+        Arc::new(Flag(Default::default()))
+    }
+
+    const KEY: LockKey = LockKey { fs: 1, inode: 1 };
+
+    fn lock(type_: LockType, start: usize, end: usize, owner: u64) -> PosixLock {
+        PosixLock {
+            type_,
+            start,
+            end,
+            owner,
+            pid: owner as u32,
+        }
+    }
+
+    fn locks_of(manager: &LockManager, owner: u64) -> Vec<(LockType, usize, usize)> {
+        let inner = manager.inner.lock();
+        let mut locks: Vec<_> = inner
+            .files
+            .get(&KEY)
+            .map(|file| file.posix.clone())
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|l| l.owner == owner)
+            .map(|l| (l.type_, l.start, l.end))
+            .collect();
+        locks.sort_by_key(|l| l.1);
+        locks
+    }
+
+    #[test]
+    fn posix_split_merge() {
+        use LockType::*;
+        let manager = LockManager::new();
+        manager.try_set_posix(KEY, lock(Write, 0, 100, 1)).unwrap();
+        manager.try_set_posix(KEY, lock(Read, 40, 60, 1)).unwrap();
+        assert_eq!(
+            locks_of(&manager, 1),
+            vec![(Write, 0, 40), (Read, 40, 60), (Write, 60, 100)]
+        );
+        manager.try_set_posix(KEY, lock(Write, 40, 60, 1)).unwrap();
+        assert_eq!(locks_of(&manager, 1), vec![(Write, 0, 100)]);
+        manager.try_set_posix(KEY, lock(Unlock, 10, 20, 1)).unwrap();
+        assert_eq!(
+            locks_of(&manager, 1),
+            vec![(Write, 0, 10), (Write, 20, 100)]
+        );
+        manager
+            .try_set_posix(KEY, lock(Read, 200, usize::MAX, 1))
+            .unwrap();
+        assert_eq!(
+            locks_of(&manager, 1),
+            vec![(Write, 0, 10), (Write, 20, 100), (Read, 200, usize::MAX)]
+        );
+
+        // others
+        assert_eq!(
+            manager.try_set_posix(KEY, lock(Read, 5, 15, 2)),
+            Err(FsError::Again)
+        );
+        manager.try_set_posix(KEY, lock(Read, 10, 20, 2)).unwrap();
+        manager.try_set_posix(KEY, lock(Read, 300, 400, 2)).unwrap();
+        assert_eq!(
+            manager.test_posix(KEY, &lock(Write, 0, usize::MAX, 3)),
+            Some(lock(Write, 0, 10, 1))
+        );
+        assert_eq!(manager.test_posix(KEY, &lock(Read, 10, 20, 3)), None);
+        assert_eq!(
+            manager.try_set_posix(KEY, lock(Read, 5, 5, 1)),
+            Err(FsError::InvalidParam)
+        );
+
+        manager.release_posix(KEY, 1);
+        assert_eq!(locks_of(&manager, 1), vec![]);
+        manager.release_posix(KEY, 2);
+        assert!(manager.inner.lock().files.is_empty());
+    }
+
+    #[test]
+    fn flock() {
+        use LockType::*;
+        let manager = LockManager::new();
+        manager.try_flock(KEY, 1, Read).unwrap();
+        manager.try_flock(KEY, 2, Read).unwrap();
+        assert_eq!(manager.try_flock(KEY, 3, Write), Err(FsError::Again));
+        // independent of POSIX locks
+        manager.try_set_posix(KEY, lock(Write, 0, 10, 3)).unwrap();
+
+        let flag = flag();
+        let mut future = manager.flock(KEY, 3, Write);
+        assert!(poll(&mut future, &flag).is_pending());
+        manager.release_flock(KEY, 1);
+        assert!(flag.0.swap(false, std::sync::atomic::Ordering::SeqCst));
+        assert!(poll(&mut future, &flag).is_pending());
+        manager.try_flock(KEY, 2, Unlock).unwrap();
+        assert_eq!(poll(&mut future, &flag), Poll::Ready(Ok(())));
+
+        // a refused conversion drops the old lock
+        manager.release_flock(KEY, 3);
+        manager.try_flock(KEY, 1, Read).unwrap();
+        manager.try_flock(KEY, 2, Read).unwrap();
+        assert_eq!(manager.try_flock(KEY, 1, Write), Err(FsError::Again));
+        manager.try_flock(KEY, 2, Write).unwrap();
+    }
+
+    #[test]
+    fn posix_wait_and_deadlock() {
+        use LockType::*;
+        let manager = LockManager::new();
+        let other = LockKey { fs: 1, inode: 2 };
+        manager.try_set_posix(KEY, lock(Write, 0, 10, 1)).unwrap();
+        manager.try_set_posix(other, lock(Write, 0, 10, 2)).unwrap();
+        manager.try_set_posix(KEY, lock(Read, 20, 30, 3)).unwrap();
+
+        // 1 waits for 2
+        let flag1 = flag();
+        let mut future1 = manager.set_posix(other, lock(Write, 5, 6, 1));
+        assert!(poll(&mut future1, &flag1).is_pending());
+        // 2 waiting for 1 would be a deadlock
+        let mut future2 = manager.set_posix(KEY, lock(Read, 0, 1, 2));
+        assert_eq!(
+            poll(&mut future2, &flag()),
+            Poll::Ready(Err(FsError::Deadlock))
+        );
+        // but not waiting for 3
+        let flag2 = flag();
+        let mut future2 = manager.set_posix(KEY, lock(Write, 25, 26, 2));
+        assert!(poll(&mut future2, &flag2).is_pending());
+        // 3 waits for 2 which waits for 3
+        let mut future3 = manager.set_posix(other, lock(Read, 0, 1, 3));
+        assert_eq!(
+            poll(&mut future3, &flag()),
+            Poll::Ready(Err(FsError::Deadlock))
+        );
+        drop(future2);
+        let mut future3 = manager.set_posix(other, lock(Read, 0, 1, 3));
+        assert!(poll(&mut future3, &flag()).is_pending());
+        drop(future3);
+
+        manager.release_posix(other, 2);
+        assert!(flag1.0.load(std::sync::atomic::Ordering::SeqCst));
+        assert_eq!(poll(&mut future1, &flag1), Poll::Ready(Ok(())));
+        drop(future1);
+        assert!(manager.inner.lock().waiting.is_empty());
+    }
+
+    #[test]
+    fn drop_waiting() {
+        let manager = LockManager::new();
+        manager.try_flock(KEY, 1, LockType::Write).unwrap();
+        let flag = flag();
+        for _ in 0..3 {
+            let mut future = manager.flock(KEY, 2, LockType::Read);
+            assert!(poll(&mut future, &flag).is_pending());
+            assert!(poll(&mut future, &flag).is_pending());
+            assert_eq!(manager.inner.lock().files[&KEY].waiters.len(), 1);
+        }
+        // the waiters given up are gone with the file entry
+        assert!(manager.inner.lock().files[&KEY].waiters.is_empty());
+        manager.release_flock(KEY, 1);
+        assert!(!flag.0.load(std::sync::atomic::Ordering::SeqCst));
+        assert!(manager.inner.lock().files.is_empty());
+    }
+
+    #[test]
+    fn block_on_waits() {
+        let manager = Arc::new(LockManager::new());
+        manager.try_flock(KEY, 1, LockType::Write).unwrap();
+        let waiter = {
+            let manager = manager.clone();
+            std::thread::spawn(move || block_on(manager.flock(KEY, 2, LockType::Read)))
+        };
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        manager.release_flock(KEY, 1);
+        assert_eq!(waiter.join().unwrap(), Ok(()));
+        assert_eq!(
+            manager.try_flock(KEY, 1, LockType::Write),
+            Err(FsError::Again)
+        );
+    }
+}
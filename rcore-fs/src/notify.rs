@@ -0,0 +1,307 @@
+//! Change notifications like inotify
+//!
+//! A file system owns a `Notifier` and emits events into it on namespace and
+//! data changes. A consumer registers a `Watch` on an INode with
+//! `INode::watch`, then drains its events, or polls it like any other INode.
+
+use crate::vfs::{FsError, INode, PollStatus, Result};
+use alloc::{
+    boxed::Box,
+    collections::{BTreeMap, VecDeque},
+    string::String,
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use core::any::Any;
+use core::future::{poll_fn, Future};
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU32, Ordering};
+use core::task::{Poll, Waker};
+use spin::Mutex;
+
+/// Default capacity of the event queue of a watch, the same as Linux `max_queued_events`
+pub const MAX_QUEUED_EVENTS: usize = 16384;
+
+/// Kind of change, the values are Linux `IN_*` masks
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum EventKind {
+    Modify = 0x2,       // IN_MODIFY, the content is changed
+    Attrib = 0x4,       // IN_ATTRIB, the metadata, extended attributes or links are changed
+    MovedFrom = 0x40,   // IN_MOVED_FROM, an entry is moved out of the directory
+    MovedTo = 0x80,     // IN_MOVED_TO, an entry is moved into the directory
+    Create = 0x100,     // IN_CREATE, an entry is created in the directory
+    Delete = 0x200,     // IN_DELETE, an entry is removed from the directory
+    DeleteSelf = 0x400, // IN_DELETE_SELF, the last link is removed
+    MoveSelf = 0x800,   // IN_MOVE_SELF, the INode itself is moved
+    Overflow = 0x4000,  // IN_Q_OVERFLOW, some events are dropped as the queue is full
+    Ignored = 0x8000,   // IN_IGNORED, the watch is removed as the INode is deleted
+}
+
+/// Kinds of change to watch. `Overflow` and `Ignored` are always reported.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct EventMask {
+    pub modify: bool,
+    pub attrib: bool,
+    /// Both `MovedFrom` and `MovedTo`
+    pub moved: bool,
+    pub create: bool,
+    pub delete: bool,
+    pub delete_self: bool,
+    pub move_self: bool,
+}
+
+impl EventMask {
+    pub const ALL: Self = EventMask {
+        modify: true,
+        attrib: true,
+        moved: true,
+        create: true,
+        delete: true,
+        delete_self: true,
+        move_self: true,
+    };
+
+    pub fn contains(&self, kind: EventKind) -> bool {
+        match kind {
+            EventKind::Modify => self.modify,
+            EventKind::Attrib => self.attrib,
+            EventKind::MovedFrom | EventKind::MovedTo => self.moved,
+            EventKind::Create => self.create,
+            EventKind::Delete => self.delete,
+            EventKind::DeleteSelf => self.delete_self,
+            EventKind::MoveSelf => self.move_self,
+            EventKind::Overflow | EventKind::Ignored => true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Event {
+    pub kind: EventKind,
+    /// Name of the entry, for the events of a directory about its entries
+    pub name: Option<String>,
+    /// Whether the subject of the event is a directory
+    pub is_dir: bool,
+    /// Pairs the `MovedFrom` and `MovedTo` of a rename, 0 for other events
+    pub cookie: u32,
+}
+
+impl Event {
+    fn new(kind: EventKind) -> Self {
+        Event {
+            kind,
+            name: None,
+            is_dir: false,
+            cookie: 0,
+        }
+    }
+}
+
+struct Queue {
+    events: VecDeque<Event>,
+    /// Events have been dropped since the last `read_events`
+    overflow: bool,
+    waiters: Vec<Waker>,
+}
+
+/// A watch on an INode, which queues its events
+pub struct Watch {
+    mask: EventMask,
+    capacity: usize,
+    queue: Mutex<Queue>,
+}
+
+impl Watch {
+    fn push(&self, event: &Event) {
+        if !self.mask.contains(event.kind) {
+            return;
+        }
+        let mut queue = self.queue.lock();
+        // merge with the same event at the end, like Linux
+        if queue.events.back() == Some(event) {
+            return;
+        }
+        if queue.events.len() >= self.capacity {
+            queue.overflow = true;
+        } else {
+            queue.events.push_back(event.clone());
+        }
+        let waiters = core::mem::take(&mut queue.waiters);
+        drop(queue);
+        for waker in waiters {
+            waker.wake();
+        }
+    }
+
+    /// Take all queued events. An `Overflow` event ends them if some events are dropped.
+    pub fn read_events(&self) -> Vec<Event> {
+        let mut queue = self.queue.lock();
+        let mut events: Vec<Event> = queue.events.drain(..).collect();
+        if queue.overflow {
+            queue.overflow = false;
+            events.push(Event::new(EventKind::Overflow));
+        }
+        events
+    }
+
+    fn readable(&self) -> bool {
+        let queue = self.queue.lock();
+        !queue.events.is_empty() || queue.overflow
+    }
+}
+
+/// Readable when events are queued. Read them with `read_events`.
+impl INode for Watch {
+    fn read_at(&self, _offset: usize, _buf: &mut [u8]) -> Result<usize> {
+        Err(FsError::NotSupported)
+    }
+
+    fn write_at(&self, _offset: usize, _buf: &[u8]) -> Result<usize> {
+        Err(FsError::NotSupported)
+    }
+
+    fn poll(&self) -> Result<PollStatus> {
+        Ok(PollStatus {
+            read: self.readable(),
+            write: false,
+            error: false,
+        })
+    }
+
+    /// Wait until events are queued
+    fn async_poll<'a>(
+        &'a self,
+    ) -> Pin<Box<dyn Future<Output = Result<PollStatus>> + Send + Sync + 'a>> {
+        Box::pin(poll_fn(move |cx| {
+            let mut queue = self.queue.lock();
+            if !queue.events.is_empty() || queue.overflow {
+                return Poll::Ready(Ok(PollStatus {
+                    read: true,
+                    write: false,
+                    error: false,
+                }));
+            }
+            if !queue.waiters.iter().any(|w| w.will_wake(cx.waker())) {
+                queue.waiters.push(cx.waker().clone());
+            }
+            Poll::Pending
+        }))
+    }
+
+    fn as_any_ref(&self) -> &dyn Any {
+        self
+    }
+}
+
+/// Watches of the INodes of a file system, which it emits events into
+#[derive(Default)]
+pub struct Notifier {
+    /// inode id -> watches
+    watches: Mutex<BTreeMap<usize, Vec<Weak<Watch>>>>,
+    cookie: AtomicU32,
+}
+
+impl Notifier {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register a watch on INode `inode`, which queues at most `capacity` events
+    pub fn watch(&self, inode: usize, mask: EventMask, capacity: usize) -> Arc<Watch> {
+        let watch = Arc::new(Watch {
+            mask,
+            capacity,
+            queue: Mutex::new(Queue {
+                events: VecDeque::new(),
+                overflow: false,
+                waiters: Vec::new(),
+            }),
+        });
+        let mut watches = self.watches.lock();
+        let list = watches.entry(inode).or_default();
+        list.retain(|w| w.strong_count() > 0);
+        list.push(Arc::downgrade(&watch));
+        watch
+    }
+
+    /// Send `event` to the watches on INode `inode`
+    pub fn notify(&self, inode: usize, event: Event) {
+        let mut watches = self.watches.lock();
+        let list = match watches.get_mut(&inode) {
+            Some(list) => list,
+            None => return,
+        };
+        list.retain(|w| match w.upgrade() {
+            Some(watch) => {
+                watch.push(&event);
+                true
+            }
+            None => false,
+        });
+        if list.is_empty() {
+            watches.remove(&inode);
+        }
+    }
+
+    /// The content of `inode` is changed
+    pub fn modify(&self, inode: usize) {
+        self.notify(inode, Event::new(EventKind::Modify));
+    }
+
+    /// The metadata of `inode` is changed
+    pub fn attrib(&self, inode: usize) {
+        self.notify(inode, Event::new(EventKind::Attrib));
+    }
+
+    /// Entry `name` is created in directory `dir`, or linked to an existing INode
+    pub fn create(&self, dir: usize, name: &str, is_dir: bool) {
+        self.notify(dir, Self::entry_event(EventKind::Create, name, is_dir, 0));
+    }
+
+    /// Entry `name` of `inode` is removed from directory `dir`, leaving `nlinks` links
+    pub fn delete(&self, dir: usize, name: &str, inode: usize, is_dir: bool, nlinks: usize) {
+        self.notify(dir, Self::entry_event(EventKind::Delete, name, is_dir, 0));
+        self.unlinked(inode, nlinks);
+    }
+
+    /// `inode` loses a link without a directory event, e.g. replaced by a rename
+    ///
+    /// The watches on it are removed with its last link.
+    pub fn unlinked(&self, inode: usize, nlinks: usize) {
+        if nlinks > 0 {
+            self.attrib(inode);
+            return;
+        }
+        self.notify(inode, Event::new(EventKind::DeleteSelf));
+        self.notify(inode, Event::new(EventKind::Ignored));
+        // the inode id may be reused
+        self.watches.lock().remove(&inode);
+    }
+
+    /// Entry `old_name` of `inode` in directory `old_dir` is moved to `new_name` in `new_dir`
+    pub fn rename(
+        &self,
+        old_dir: usize,
+        old_name: &str,
+        new_dir: usize,
+        new_name: &str,
+        inode: usize,
+        is_dir: bool,
+    ) {
+        let cookie = self.cookie.fetch_add(1, Ordering::Relaxed).wrapping_add(1);
+        let from = Self::entry_event(EventKind::MovedFrom, old_name, is_dir, cookie);
+        self.notify(old_dir, from);
+        let to = Self::entry_event(EventKind::MovedTo, new_name, is_dir, cookie);
+        self.notify(new_dir, to);
+        self.notify(inode, Event::new(EventKind::MoveSelf));
+    }
+
+    fn entry_event(kind: EventKind, name: &str, is_dir: bool, cookie: u32) -> Event {
+        Event {
+            kind,
+            name: Some(String::from(name)),
+            is_dir,
+            cookie,
+        }
+    }
+}
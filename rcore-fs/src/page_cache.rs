@@ -0,0 +1,394 @@
+//! Pages of files in memory for memory mapping
+//!
+//! A `PageCache` holds pages of files by the INode id and page index.
+//! The file systems keep them coherent with `read_at` and `write_at`:
+//! reads are overlaid with the cached pages, and writes go to the file and the pages.
+//! Pages written through memory mappings are marked dirty by the kernel,
+//! and written back to the file by `sync_data` or `sync_all` of the INode.
+//!
+//! The kernel gets the pages of an `MMapArea` with `map_area`.
+//! A page of a shared mapping can be mapped directly,
+//! while a private mapping should map it read-only and copy it on the first write.
+
+use crate::cache::CacheStats;
+use crate::vfs::{FsError, INode, MMapArea, Result};
+use alloc::{boxed::Box, collections::BTreeMap, sync::Arc, vec::Vec};
+use core::cell::UnsafeCell;
+use core::ops::Deref;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use spin::Mutex;
+
+/// Size of a page in bytes
+pub const PAGE_SIZE: usize = 4096;
+
+/// Default number of pages of a `PageCache`
+pub const DEFAULT_PAGE_CAPACITY: usize = 1024;
+
+#[repr(C, align(4096))]
+struct PageData([u8; PAGE_SIZE]);
+
+/// A page of a file
+///
+/// The data is page aligned and never moves, so that it can be mapped to user space.
+pub struct Page {
+    data: Box<UnsafeCell<PageData>>,
+    /// Serializes the copies by the file system
+    lock: Mutex<()>,
+    dirty: AtomicBool,
+    pins: AtomicUsize,
+}
+
+// Accesses by the file system are serialized by `lock`,
+// and those through memory mappings are up to the kernel.
+unsafe impl Send for Page {}
+unsafe impl Sync for Page {}
+
+impl Page {
+    fn new() -> Self {
+        Page {
+            data: Box::new(UnsafeCell::new(PageData([0; PAGE_SIZE]))),
+            lock: Mutex::new(()),
+            dirty: AtomicBool::new(false),
+            pins: AtomicUsize::new(0),
+        }
+    }
+
+    /// Address of the data, to be mapped by the kernel
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.data.get() as *mut u8
+    }
+
+    /// Read `buf` at `offset` of the page, return the length read
+    pub fn read(&self, offset: usize, buf: &mut [u8]) -> usize {
+        let len = buf.len().min(PAGE_SIZE.saturating_sub(offset));
+        let _lock = self.lock.lock();
+        unsafe {
+            let data = &(*self.data.get()).0;
+            buf[..len].copy_from_slice(&data[offset..offset + len]);
+        }
+        len
+    }
+
+    /// Write `buf` at `offset` of the page and mark it dirty, return the length written
+    pub fn write(&self, offset: usize, buf: &[u8]) -> usize {
+        let len = buf.len().min(PAGE_SIZE.saturating_sub(offset));
+        let _lock = self.lock.lock();
+        unsafe {
+            let data = &mut (*self.data.get()).0;
+            data[offset..offset + len].copy_from_slice(&buf[..len]);
+        }
+        self.mark_dirty();
+        len
+    }
+
+    fn fill(&self, load: impl FnOnce(&mut [u8]) -> Result<()>) -> Result<()> {
+        let _lock = self.lock.lock();
+        unsafe { load(&mut (*self.data.get()).0) }
+    }
+
+    /// Mark the page as written through a memory mapping
+    pub fn mark_dirty(&self) {
+        self.dirty.store(true, Ordering::Release);
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        self.dirty.load(Ordering::Acquire)
+    }
+
+    /// Is the page mapped by someone?
+    pub fn is_pinned(&self) -> bool {
+        self.pins.load(Ordering::Acquire) != 0
+    }
+}
+
+/// A page which is not evicted from the `PageCache` until dropped
+pub struct PinnedPage(Arc<Page>);
+
+impl PinnedPage {
+    fn new(page: Arc<Page>) -> Self {
+        page.pins.fetch_add(1, Ordering::AcqRel);
+        PinnedPage(page)
+    }
+}
+
+impl Clone for PinnedPage {
+    fn clone(&self) -> Self {
+        PinnedPage::new(self.0.clone())
+    }
+}
+
+impl Drop for PinnedPage {
+    fn drop(&mut self) {
+        self.0.pins.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+impl Deref for PinnedPage {
+    type Target = Page;
+
+    fn deref(&self) -> &Page {
+        &self.0
+    }
+}
+
+/// Pages of files by (INode id, page index)
+///
+/// Clean pages which are not pinned are evicted when the cache is full.
+pub struct PageCache {
+    pages: Mutex<BTreeMap<(usize, usize), Arc<Page>>>,
+    capacity: usize,
+    stats: CacheStats,
+}
+
+impl PageCache {
+    pub fn new(capacity: usize) -> Self {
+        PageCache {
+            pages: Mutex::new(BTreeMap::new()),
+            capacity,
+            stats: CacheStats::default(),
+        }
+    }
+
+    /// Get page `index` of INode `id`, or load it by `load` if it is not in memory
+    ///
+    /// `load` fills the page from the file, and runs with the cache locked,
+    /// so that it is not raced by the writes to the file.
+    pub fn get_or_load(
+        &self,
+        id: usize,
+        index: usize,
+        load: impl FnOnce(&mut [u8]) -> Result<()>,
+    ) -> Result<PinnedPage> {
+        let mut pages = self.pages.lock();
+        if let Some(page) = pages.get(&(id, index)) {
+            self.stats.hit();
+            return Ok(PinnedPage::new(page.clone()));
+        }
+        self.stats.miss();
+        if pages.len() >= self.capacity {
+            let unused: Vec<_> = pages
+                .iter()
+                .filter(|(_, page)| !page.is_pinned() && !page.is_dirty())
+                .map(|(&key, _)| key)
+                .take(pages.len() + 1 - self.capacity)
+                .collect();
+            for key in unused {
+                pages.remove(&key);
+            }
+        }
+        let page = Arc::new(Page::new());
+        page.fill(load)?;
+        pages.insert((id, index), page.clone());
+        Ok(PinnedPage::new(page))
+    }
+
+    /// Call `f` with the cached pages of INode `id` overlapping `begin..end`,
+    /// and the range of the page and the offset of the range in the file
+    fn for_each(
+        &self,
+        id: usize,
+        begin: usize,
+        end: usize,
+        mut f: impl FnMut(&Page, usize, usize, usize) -> Result<()>,
+    ) -> Result<()> {
+        if begin >= end {
+            return Ok(());
+        }
+        let pages = self.pages.lock();
+        let range = (id, begin / PAGE_SIZE)..(id, (end - 1) / PAGE_SIZE + 1);
+        for (&(_, index), page) in pages.range(range) {
+            let page_begin = index * PAGE_SIZE;
+            let offset = begin.max(page_begin);
+            let page_end = end.min(page_begin + PAGE_SIZE);
+            f(page, offset - page_begin, page_end - page_begin, offset)?;
+        }
+        Ok(())
+    }
+
+    /// Overlay `buf` read at `offset` of INode `id` with the cached pages
+    pub fn overlay(&self, id: usize, offset: usize, buf: &mut [u8]) {
+        self.for_each(id, offset, offset + buf.len(), |page, begin, end, pos| {
+            let pos = pos - offset;
+            page.read(begin, &mut buf[pos..pos + end - begin]);
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    /// Update the cached pages with `buf` written at `offset` of INode `id`
+    ///
+    /// The pages are marked dirty, in case a writeback of old data races with the write.
+    pub fn update(&self, id: usize, offset: usize, buf: &[u8]) {
+        self.for_each(id, offset, offset + buf.len(), |page, begin, _, pos| {
+            let pos = pos - offset;
+            page.write(begin, &buf[pos..]);
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    /// Reload the cached pages of INode `id` overlapping `begin..end` by `load`,
+    /// after the file is changed other than by writes
+    ///
+    /// `load` is called with the offset of each page in the file.
+    pub fn reload(
+        &self,
+        id: usize,
+        begin: usize,
+        end: usize,
+        mut load: impl FnMut(usize, &mut [u8]) -> Result<()>,
+    ) -> Result<()> {
+        self.for_each(id, begin, end, |page, _, _, pos| {
+            let offset = pos / PAGE_SIZE * PAGE_SIZE;
+            page.fill(|data| load(offset, data))
+        })
+    }
+
+    /// Drop the cached data of INode `id` beyond `size`, after it is truncated
+    pub fn truncate(&self, id: usize, size: usize) {
+        let mut pages = self.pages.lock();
+        let range = (id, size / PAGE_SIZE)..(id, usize::MAX);
+        let mut unused = Vec::new();
+        for (&key, page) in pages.range(range) {
+            let page_begin = key.1 * PAGE_SIZE;
+            if page_begin >= size && !page.is_pinned() {
+                unused.push(key);
+                continue;
+            }
+            let begin = size.saturating_sub(page_begin);
+            page.fill(|data| {
+                data[begin..].fill(0);
+                Ok(())
+            })
+            .unwrap();
+        }
+        for key in unused {
+            pages.remove(&key);
+        }
+    }
+
+    /// Write back the dirty pages of INode `id` by `write`
+    ///
+    /// `write` is called with the offset of each page in the file and its data.
+    pub fn writeback(
+        &self,
+        id: usize,
+        mut write: impl FnMut(usize, &[u8]) -> Result<()>,
+    ) -> Result<()> {
+        let pages = self.pages.lock();
+        let mut data = Box::new([0u8; PAGE_SIZE]);
+        for (&(_, index), page) in pages.range((id, 0)..(id, usize::MAX)) {
+            if !page.dirty.swap(false, Ordering::AcqRel) {
+                continue;
+            }
+            page.read(0, &mut data[..]);
+            if let Err(e) = write(index * PAGE_SIZE, &data[..]) {
+                page.mark_dirty();
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Forget the pages of INode `id`, after it is removed
+    ///
+    /// The pinned pages are still valid, but no longer backed by the file.
+    pub fn remove_inode(&self, id: usize) {
+        let mut pages = self.pages.lock();
+        let keys: Vec<_> = pages
+            .range((id, 0)..(id, usize::MAX))
+            .map(|(&key, _)| key)
+            .collect();
+        for key in keys {
+            pages.remove(&key);
+        }
+    }
+
+    /// Number of cached pages
+    pub fn len(&self) -> usize {
+        self.pages.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn stats(&self) -> &CacheStats {
+        &self.stats
+    }
+}
+
+impl Default for PageCache {
+    fn default() -> Self {
+        PageCache::new(DEFAULT_PAGE_CAPACITY)
+    }
+}
+
+/// Map `area` of `inode`, and get the pages of it, pinned until dropped
+pub fn map_area(inode: &dyn INode, area: &MMapArea) -> Result<Vec<PinnedPage>> {
+    if !area.offset.is_multiple_of(PAGE_SIZE) || area.end_vaddr <= area.start_vaddr {
+        return Err(FsError::InvalidParam);
+    }
+    inode.mmap(area.clone())?;
+    area.pages().map(|index| inode.map_page(index)).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn load(value: u8) -> impl FnOnce(&mut [u8]) -> Result<()> {
+        move |data| {
+            data.fill(value);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn page_cache() {
+        let cache = PageCache::new(2);
+        let page = cache.get_or_load(1, 0, load(1)).unwrap();
+        assert!((page.as_ptr() as usize).is_multiple_of(PAGE_SIZE));
+        let again = cache.get_or_load(1, 0, load(2)).unwrap();
+        assert!(Arc::ptr_eq(&page.0, &again.0));
+
+        // reads are overlaid and writes are passed to the pages
+        let mut buf = [0u8; 8];
+        cache.overlay(1, PAGE_SIZE - 4, &mut buf);
+        assert_eq!(buf, [1, 1, 1, 1, 0, 0, 0, 0]);
+        cache.update(1, 2, &[3, 3]);
+        let mut buf = [0u8; 4];
+        page.read(0, &mut buf);
+        assert_eq!(buf, [1, 1, 3, 3]);
+
+        // dirty pages are written back once
+        let mut written = Vec::new();
+        let mut write = |offset, data: &[u8]| {
+            written.push((offset, data[2]));
+            Ok(())
+        };
+        cache.writeback(1, &mut write).unwrap();
+        cache.writeback(1, &mut write).unwrap();
+        assert_eq!(written, [(0, 3)]);
+
+        // pinned and dirty pages are not evicted
+        drop((page, again));
+        let dirty = cache.get_or_load(2, 0, load(0)).unwrap();
+        dirty.write(0, &[1]);
+        drop(dirty);
+        let _pinned = cache.get_or_load(2, 1, load(0)).unwrap();
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get_or_load(1, 0, load(4)).is_ok());
+        assert_eq!(cache.len(), 3);
+
+        cache.truncate(2, 1);
+        assert_eq!(cache.len(), 3);
+        let mut buf = [1u8; 2];
+        cache.overlay(2, 0, &mut buf);
+        assert_eq!(buf, [1, 0]);
+        cache.remove_inode(2);
+        assert_eq!(cache.len(), 1);
+        let stats = cache.stats().snapshot();
+        assert_eq!((stats.hits, stats.misses), (1, 4));
+    }
+}
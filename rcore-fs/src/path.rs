@@ -0,0 +1,145 @@
+//! Path resolution with symlinks, like `path_resolution(7)` in Linux
+
+use crate::vfs::{AccessMode, Credentials, FileType, FsError, INode, Result};
+use alloc::{string::String, sync::Arc, vec::Vec};
+
+/// Default max number of symlinks followed in one resolution, same as Linux
+pub const MAX_SYMLINKS: usize = 40;
+
+/// Resolve paths relative to a root directory
+///
+/// Absolute paths start at `root`, and `..` never goes above it,
+/// so the resolver can be used for chroot.
+pub struct PathResolver {
+    /// The root directory
+    pub root: Arc<dyn INode>,
+    /// Credentials used to check search permission on directories
+    pub cred: Credentials,
+    /// Max number of symlinks followed in total, exceeding it returns `SymLoop`
+    pub max_symlinks: usize,
+}
+
+impl PathResolver {
+    /// Create a resolver with root `root`, as the superuser
+    pub fn new(root: Arc<dyn INode>) -> Self {
+        PathResolver {
+            root,
+            cred: Credentials::root(),
+            max_symlinks: MAX_SYMLINKS,
+        }
+    }
+
+    /// Resolve `path` relative to `cwd`
+    ///
+    /// Symlinks in the middle of the path are always followed,
+    /// the final component is followed only if `follow` is set (no `O_NOFOLLOW`).
+    /// A path with trailing slash must refer to a directory.
+    pub fn resolve(
+        &self,
+        cwd: &Arc<dyn INode>,
+        path: &str,
+        follow: bool,
+    ) -> Result<Arc<dyn INode>> {
+        if path.is_empty() {
+            return Err(FsError::EntryNotFound);
+        }
+        let mut current = if path.starts_with('/') {
+            self.root.clone()
+        } else {
+            cwd.clone()
+        };
+        let mut must_dir = path.ends_with('/');
+        // components to be resolved, in reverse order
+        let mut rest = components(path);
+        let mut symlinks = 0;
+
+        while let Some(name) = rest.pop() {
+            let info = current.metadata()?;
+            if info.type_ != FileType::Dir {
+                return Err(FsError::NotDir);
+            }
+            self.cred.check_access(&info, AccessMode::SEARCH)?;
+            match name.as_str() {
+                "." => continue,
+                ".." => {
+                    if !self.is_root(&current)? {
+                        current = current.find("..")?;
+                    }
+                    continue;
+                }
+                _ => {}
+            }
+            let next = current.find(&name)?;
+            let is_last = rest.is_empty();
+            if next.metadata()?.type_ == FileType::SymLink && (!is_last || follow || must_dir) {
+                if symlinks == self.max_symlinks {
+                    return Err(FsError::SymLoop);
+                }
+                symlinks += 1;
+                let target = next.read_link()?;
+                if target.is_empty() {
+                    return Err(FsError::EntryNotFound);
+                }
+                if is_last && target.ends_with('/') {
+                    must_dir = true;
+                }
+                if target.starts_with('/') {
+                    current = self.root.clone();
+                }
+                // the target is resolved relative to the directory containing the link
+                rest.extend(components(&target));
+                continue;
+            }
+            current = next;
+        }
+
+        if must_dir && current.metadata()?.type_ != FileType::Dir {
+            return Err(FsError::NotDir);
+        }
+        Ok(current)
+    }
+
+    /// Resolve the parent directory of `path` relative to `cwd`,
+    /// return the directory and the final component
+    ///
+    /// The final component is not resolved, useful for create, unlink and rename.
+    pub fn resolve_parent(
+        &self,
+        cwd: &Arc<dyn INode>,
+        path: &str,
+    ) -> Result<(Arc<dyn INode>, String)> {
+        let trimmed = path.trim_end_matches('/');
+        if path.is_empty() {
+            return Err(FsError::EntryNotFound);
+        }
+        if trimmed.is_empty() {
+            // the path is "/"
+            return Ok((self.root.clone(), String::from(".")));
+        }
+        match trimmed.rfind('/') {
+            Some(pos) => {
+                let dir = self.resolve(cwd, &trimmed[..=pos], true)?;
+                Ok((dir, String::from(&trimmed[pos + 1..])))
+            }
+            None => {
+                let dir = self.resolve(cwd, ".", true)?;
+                Ok((dir, String::from(trimmed)))
+            }
+        }
+    }
+
+    /// Is `inode` the root directory of this resolver?
+    fn is_root(&self, inode: &Arc<dyn INode>) -> Result<bool> {
+        let same_fs =
+            Arc::as_ptr(&inode.fs()) as *const u8 == Arc::as_ptr(&self.root.fs()) as *const u8;
+        Ok(same_fs && inode.metadata()?.inode == self.root.metadata()?.inode)
+    }
+}
+
+/// Split `path` into non-empty components, in reverse order
+fn components(path: &str) -> Vec<String> {
+    path.rsplit('/')
+        .filter(|name| !name.is_empty())
+        .map(String::from)
+        .collect()
+}
@@ -0,0 +1,439 @@
+//! A read-only view of any file system
+//!
+//! `ReadOnlyFS` wraps a `FileSystem` and every INode found through it. Reads are
+//! forwarded to the inner INodes, while the calls which would change the file system
+//! fail with `FsError::ReadOnly`. Devices, pipes and sockets can still be written,
+//! as writing them does not change the file system.
+
+use crate::notify::Notifier;
+use crate::page_cache::PinnedPage;
+use crate::util::BoxFuture;
+use crate::vfs::*;
+use alloc::{
+    boxed::Box,
+    string::String,
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use core::any::Any;
+use core::future::Future;
+use core::pin::Pin;
+
+/// A read-only wrapper of file system `inner`, which should be opened read-only too
+pub struct ReadOnlyFS {
+    inner: Arc<dyn FileSystem>,
+    /// Weak reference to self
+    self_ref: Weak<ReadOnlyFS>,
+}
+
+/// INode of `ReadOnlyFS`
+pub struct ReadOnlyINode {
+    /// The inner INode
+    inode: Arc<dyn INode>,
+    /// Associated `ReadOnlyFS`
+    fs: Arc<ReadOnlyFS>,
+}
+
+impl ReadOnlyFS {
+    pub fn new(inner: Arc<dyn FileSystem>) -> Arc<Self> {
+        ReadOnlyFS {
+            inner,
+            self_ref: Weak::default(),
+        }
+        .wrap()
+    }
+
+    /// Wrap pure `ReadOnlyFS` with `Arc<..>`.
+    /// Used in constructors.
+    fn wrap(self) -> Arc<Self> {
+        // Create an Arc, make a Weak from it, then put it into the struct.
+        // It's a little tricky.
+        let fs = Arc::new(self);
+        let weak = Arc::downgrade(&fs);
+        let ptr = Arc::into_raw(fs) as *mut Self;
+        unsafe {
+            (*ptr).self_ref = weak;
+            Arc::from_raw(ptr)
+        }
+    }
+
+    /// The wrapped file system
+    pub fn inner(&self) -> &Arc<dyn FileSystem> {
+        &self.inner
+    }
+
+    fn wrap_inode(&self, inode: Arc<dyn INode>) -> Arc<dyn INode> {
+        Arc::new(ReadOnlyINode {
+            inode,
+            fs: self.self_ref.upgrade().unwrap(),
+        })
+    }
+}
+
+impl FileSystem for ReadOnlyFS {
+    /// Nothing can be changed through this file system, so nothing is forwarded.
+    /// The inner file system must be opened read-only itself, if it may have changes
+    /// of its own, e.g. a journal replayed, which it writes when it syncs or is dropped.
+    fn sync(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn root_inode(&self) -> Arc<dyn INode> {
+        self.wrap_inode(self.inner.root_inode())
+    }
+
+    fn info(&self) -> FsInfo {
+        self.inner.info()
+    }
+
+    fn notifier(&self) -> Option<&Notifier> {
+        self.inner.notifier()
+    }
+
+    fn identity(&self) -> usize {
+        self.inner.identity()
+    }
+}
+
+impl ReadOnlyINode {
+    /// The wrapped INode
+    pub fn inner(&self) -> &Arc<dyn INode> {
+        &self.inode
+    }
+
+    /// Writes to the content of devices, pipes and sockets are allowed
+    fn check_write(&self) -> Result<()> {
+        match self.inode.metadata()?.type_ {
+            FileType::File | FileType::Dir | FileType::SymLink => Err(FsError::ReadOnly),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl INode for ReadOnlyINode {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        self.inode.read_at(offset, buf)
+    }
+
+    fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize> {
+        self.check_write()?;
+        self.inode.write_at(offset, buf)
+    }
+
+    fn read_vectored_at(&self, offset: usize, bufs: &mut [&mut [u8]]) -> Result<usize> {
+        self.inode.read_vectored_at(offset, bufs)
+    }
+
+    fn write_vectored_at(&self, offset: usize, bufs: &[&[u8]]) -> Result<usize> {
+        self.check_write()?;
+        self.inode.write_vectored_at(offset, bufs)
+    }
+
+    fn async_read_at<'a>(
+        &'a self,
+        offset: usize,
+        buf: &'a mut [u8],
+    ) -> BoxFuture<'a, Result<usize>> {
+        self.inode.async_read_at(offset, buf)
+    }
+
+    fn async_write_at<'a>(&'a self, offset: usize, buf: &'a [u8]) -> BoxFuture<'a, Result<usize>> {
+        Box::pin(async move {
+            self.check_write()?;
+            self.inode.async_write_at(offset, buf).await
+        })
+    }
+
+    fn poll(&self) -> Result<PollStatus> {
+        self.inode.poll()
+    }
+
+    fn async_poll<'a>(
+        &'a self,
+    ) -> Pin<Box<dyn Future<Output = Result<PollStatus>> + Send + Sync + 'a>> {
+        self.inode.async_poll()
+    }
+
+    fn metadata(&self) -> Result<Metadata> {
+        self.inode.metadata()
+    }
+
+    fn set_metadata(&self, _metadata: &Metadata) -> Result<()> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn get_xattr(&self, name: &str) -> Result<Vec<u8>> {
+        self.inode.get_xattr(name)
+    }
+
+    fn set_xattr(&self, _name: &str, _value: &[u8], _flags: XattrFlags) -> Result<()> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn list_xattr(&self) -> Result<Vec<String>> {
+        self.inode.list_xattr()
+    }
+
+    fn remove_xattr(&self, _name: &str) -> Result<()> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn sync_all(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn sync_data(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn resize(&self, _len: usize) -> Result<()> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn fallocate(&self, _flags: FallocateFlags, _offset: usize, _len: usize) -> Result<()> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn seek_data(&self, offset: usize) -> Result<Option<usize>> {
+        self.inode.seek_data(offset)
+    }
+
+    fn seek_hole(&self, offset: usize) -> Result<Option<usize>> {
+        self.inode.seek_hole(offset)
+    }
+
+    fn copy_range(
+        &self,
+        _src: &dyn INode,
+        _src_offset: usize,
+        _dst_offset: usize,
+        _len: usize,
+    ) -> Result<usize> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn clone_range(
+        &self,
+        _src: &dyn INode,
+        _src_offset: usize,
+        _dst_offset: usize,
+        _len: usize,
+    ) -> Result<()> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn create(&self, _name: &str, _type_: FileType, _mode: u32) -> Result<Arc<dyn INode>> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn create2(
+        &self,
+        _name: &str,
+        _type_: FileType,
+        _mode: u32,
+        _data: usize,
+    ) -> Result<Arc<dyn INode>> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn symlink(&self, _name: &str, _target: &str) -> Result<Arc<dyn INode>> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn read_link(&self) -> Result<String> {
+        self.inode.read_link()
+    }
+
+    fn link(&self, _name: &str, _other: &Arc<dyn INode>) -> Result<()> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn unlink(&self, _name: &str) -> Result<()> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn move_(&self, _old_name: &str, _target: &Arc<dyn INode>, _new_name: &str) -> Result<()> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn rename(
+        &self,
+        _old_name: &str,
+        _target: &Arc<dyn INode>,
+        _new_name: &str,
+        _flags: RenameFlags,
+    ) -> Result<()> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn find(&self, name: &str) -> Result<Arc<dyn INode>> {
+        Ok(self.fs.wrap_inode(self.inode.find(name)?))
+    }
+
+    fn get_entry(&self, id: usize) -> Result<String> {
+        self.inode.get_entry(id)
+    }
+
+    fn get_entry_with_metadata(&self, id: usize) -> Result<(Metadata, String)> {
+        self.inode.get_entry_with_metadata(id)
+    }
+
+    fn read_dir(&self, cookie: usize) -> Result<Option<DirEntry>> {
+        self.inode.read_dir(cookie)
+    }
+
+    fn io_control(&self, cmd: u32, data: usize) -> Result<usize> {
+        self.inode.io_control(cmd, data)
+    }
+
+    fn mmap(&self, area: MMapArea) -> Result<()> {
+        if area.is_shared() && area.prot & MMapArea::PROT_WRITE != 0 {
+            self.check_write()?;
+        }
+        self.inode.mmap(area)
+    }
+
+    /// The pages of files could be marked dirty and written back by the inner file system
+    fn map_page(&self, index: usize) -> Result<PinnedPage> {
+        self.check_write()?;
+        self.inode.map_page(index)
+    }
+
+    fn fs(&self) -> Arc<dyn FileSystem> {
+        self.fs.clone()
+    }
+
+    fn as_any_ref(&self) -> &dyn Any {
+        self
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct OneFile;
+
+    struct Root;
+
+    struct File;
+
+    impl FileSystem for OneFile {
+        fn sync(&self) -> Result<()> {
+            unreachable!()
+        }
+
+        fn root_inode(&self) -> Arc<dyn INode> {
+            Arc::new(Root)
+        }
+
+        fn info(&self) -> FsInfo {
+            unimplemented!()
+        }
+    }
+
+    fn metadata(inode: usize, type_: FileType) -> Metadata {
+        Metadata {
+            dev: 0,
+            inode,
+            size: 0,
+            blk_size: 0,
+            blocks: 0,
+            atime: Timespec { sec: 0, nsec: 0 },
+            mtime: Timespec { sec: 0, nsec: 0 },
+            ctime: Timespec { sec: 0, nsec: 0 },
+            type_,
+            mode: 0o777,
+            nlinks: 1,
+            uid: 0,
+            gid: 0,
+            rdev: 0,
+        }
+    }
+
+    impl INode for Root {
+        fn read_at(&self, _offset: usize, _buf: &mut [u8]) -> Result<usize> {
+            Err(FsError::IsDir)
+        }
+
+        fn write_at(&self, _offset: usize, _buf: &[u8]) -> Result<usize> {
+            unreachable!()
+        }
+
+        fn poll(&self) -> Result<PollStatus> {
+            Ok(PollStatus::default())
+        }
+
+        fn metadata(&self) -> Result<Metadata> {
+            Ok(metadata(1, FileType::Dir))
+        }
+
+        fn find(&self, name: &str) -> Result<Arc<dyn INode>> {
+            match name {
+                "." | ".." => Ok(Arc::new(Root)),
+                "file" => Ok(Arc::new(File)),
+                _ => Err(FsError::EntryNotFound),
+            }
+        }
+
+        fn as_any_ref(&self) -> &dyn Any {
+            self
+        }
+    }
+
+    impl INode for File {
+        fn read_at(&self, _offset: usize, buf: &mut [u8]) -> Result<usize> {
+            buf.fill(1);
+            Ok(buf.len())
+        }
+
+        fn write_at(&self, _offset: usize, _buf: &[u8]) -> Result<usize> {
+            unreachable!()
+        }
+
+        fn poll(&self) -> Result<PollStatus> {
+            Ok(PollStatus::default())
+        }
+
+        fn metadata(&self) -> Result<Metadata> {
+            Ok(metadata(2, FileType::File))
+        }
+
+        fn as_any_ref(&self) -> &dyn Any {
+            self
+        }
+    }
+
+    #[test]
+    fn read_only() -> Result<()> {
+        let fs = ReadOnlyFS::new(Arc::new(OneFile));
+        fs.sync()?;
+        let root = fs.root_inode();
+        let file = root.lookup("file")?;
+        assert!(file.downcast_ref::<ReadOnlyINode>().is_some());
+        let mut buf = [0u8; 4];
+        assert_eq!(file.read_at(0, &mut buf)?, 4);
+        assert_eq!(buf, [1; 4]);
+
+        assert_eq!(file.write_at(0, &buf), Err(FsError::ReadOnly));
+        assert_eq!(file.resize(0), Err(FsError::ReadOnly));
+        assert_eq!(file.set_metadata(&file.metadata()?), Err(FsError::ReadOnly));
+        assert!(matches!(
+            root.create("new", FileType::File, 0o666),
+            Err(FsError::ReadOnly)
+        ));
+        assert_eq!(root.link("link", &file), Err(FsError::ReadOnly));
+        assert_eq!(root.unlink("file"), Err(FsError::ReadOnly));
+        assert_eq!(root.move_("file", &root, "new"), Err(FsError::ReadOnly));
+        let area = MMapArea {
+            start_vaddr: 0,
+            end_vaddr: 0x1000,
+            prot: MMapArea::PROT_WRITE,
+            flags: MMapArea::MAP_SHARED,
+            offset: 0,
+        };
+        assert_eq!(file.mmap(area), Err(FsError::ReadOnly));
+        assert_eq!(file.map_page(0).err(), Some(FsError::ReadOnly));
+        Ok(())
+    }
+}
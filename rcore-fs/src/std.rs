@@ -16,16 +16,20 @@ impl std::error::Error for FsError {}
 impl From<std::io::Error> for FsError {
     fn from(e: Error) -> Self {
         use std::io::ErrorKind;
+        // the error numbers of the host are those of `FsError::to_errno`
+        #[cfg(all(feature = "std", unix))]
+        if let Some(errno) = e.raw_os_error() {
+            return FsError::from_errno(errno);
+        }
         match e.kind() {
             ErrorKind::NotFound => FsError::EntryNotFound,
-            // We do not have permission in our fs, just ignore the file
-            ErrorKind::PermissionDenied => FsError::EntryNotFound,
+            ErrorKind::PermissionDenied => FsError::PermissionDenied,
             ErrorKind::AlreadyExists => FsError::EntryExist,
             ErrorKind::WouldBlock => FsError::Again,
             ErrorKind::InvalidInput => FsError::InvalidParam,
             ErrorKind::InvalidData => FsError::InvalidParam,
             // The host fs is the device here
-            _ => FsError::DeviceError,
+            _ => FsError::Device(e.into()),
         }
     }
 }
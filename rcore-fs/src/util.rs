@@ -1,3 +1,39 @@
+use alloc::boxed::Box;
+use core::future::Future;
+use core::pin::Pin;
+
+/// A boxed future returned by the async methods of traits
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + Sync + 'a>>;
+
+/// Run a future to completion, parking the current thread while it is pending
+#[cfg(any(test, feature = "std"))]
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    use core::task::{Context, Poll, Waker};
+    use std::sync::Arc;
+    use std::task::Wake;
+    use std::thread::{self, Thread};
+
+    struct Unparker(Thread);
+
+    impl Wake for Unparker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    let waker = Waker::from(Arc::new(Unparker(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    let mut future = future;
+    // Safety: the future is never moved after being pinned here
+    let mut future = unsafe { Pin::new_unchecked(&mut future) };
+    loop {
+        match future.as_mut().poll(&mut cx) {
+            Poll::Ready(output) => return output,
+            Poll::Pending => thread::park(),
+        }
+    }
+}
+
 /// Given a range and iterate sub-range for each block
 pub struct BlockIter {
     pub begin: usize,
@@ -5,7 +41,7 @@ pub struct BlockIter {
     pub block_size_log2: u8,
 }
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq)]
 pub struct BlockRange {
     pub block: usize,
     pub begin: usize,
@@ -1,11 +1,14 @@
-use crate::dev::DevError;
-use alloc::{boxed::Box, string::String, sync::Arc, vec::Vec};
+use crate::dev::{DevError, DevErrorKind};
+use crate::notify::{EventMask, Notifier, Watch, MAX_QUEUED_EVENTS};
+use crate::page_cache::{PinnedPage, PAGE_SIZE};
+use crate::util::BoxFuture;
+use alloc::{boxed::Box, string::String, sync::Arc, vec, vec::Vec};
 use core::any::Any;
 use core::fmt;
 use core::future::Future;
+use core::ops::Range;
 use core::pin::Pin;
 use core::result;
-use core::str;
 
 /// Abstract file system object such as file or directory.
 pub trait INode: Any + Sync + Send {
@@ -15,6 +18,61 @@ pub trait INode: Any + Sync + Send {
     /// Write bytes at `offset` from `buf`, return the number of bytes written.
     fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize>;
 
+    /// Read bytes at `offset` into `bufs` in order, return the number of bytes read.
+    ///
+    /// It stops at the first short read. File systems may override it to map and
+    /// submit the whole range at once.
+    fn read_vectored_at(&self, offset: usize, bufs: &mut [&mut [u8]]) -> Result<usize> {
+        let mut len = 0;
+        for buf in bufs.iter_mut() {
+            match self.read_at(offset + len, buf) {
+                Ok(n) => {
+                    len += n;
+                    if n < buf.len() {
+                        break;
+                    }
+                }
+                Err(e) if len == 0 => return Err(e),
+                Err(_) => break,
+            }
+        }
+        Ok(len)
+    }
+
+    /// Write bytes at `offset` from `bufs` in order, return the number of bytes written.
+    fn write_vectored_at(&self, offset: usize, bufs: &[&[u8]]) -> Result<usize> {
+        let mut len = 0;
+        for buf in bufs.iter() {
+            match self.write_at(offset + len, buf) {
+                Ok(n) => {
+                    len += n;
+                    if n < buf.len() {
+                        break;
+                    }
+                }
+                Err(e) if len == 0 => return Err(e),
+                Err(_) => break,
+            }
+        }
+        Ok(len)
+    }
+
+    /// Read bytes at `offset` into `buf`, async version.
+    ///
+    /// INodes on slow devices, pipes or sockets may park the task here instead of blocking.
+    fn async_read_at<'a>(
+        &'a self,
+        offset: usize,
+        buf: &'a mut [u8],
+    ) -> BoxFuture<'a, Result<usize>> {
+        Box::pin(async move { self.read_at(offset, buf) })
+    }
+
+    /// Write bytes at `offset` from `buf`, async version.
+    fn async_write_at<'a>(&'a self, offset: usize, buf: &'a [u8]) -> BoxFuture<'a, Result<usize>> {
+        Box::pin(async move { self.write_at(offset, buf) })
+    }
+
     /// Poll the events, return a bitmap of events.
     fn poll(&self) -> Result<PollStatus>;
 
@@ -35,6 +93,26 @@ pub trait INode: Any + Sync + Send {
         Err(FsError::NotSupported)
     }
 
+    /// Get the value of the extended attribute `name`
+    fn get_xattr(&self, _name: &str) -> Result<Vec<u8>> {
+        Err(FsError::NotSupported)
+    }
+
+    /// Set the extended attribute `name` to `value`
+    fn set_xattr(&self, _name: &str, _value: &[u8], _flags: XattrFlags) -> Result<()> {
+        Err(FsError::NotSupported)
+    }
+
+    /// Get the names of all extended attributes
+    fn list_xattr(&self) -> Result<Vec<String>> {
+        Err(FsError::NotSupported)
+    }
+
+    /// Remove the extended attribute `name`
+    fn remove_xattr(&self, _name: &str) -> Result<()> {
+        Err(FsError::NotSupported)
+    }
+
     /// Sync all data and metadata
     fn sync_all(&self) -> Result<()> {
         Err(FsError::NotSupported)
@@ -50,6 +128,56 @@ pub trait INode: Any + Sync + Send {
         Err(FsError::NotSupported)
     }
 
+    /// Manipulate the allocated space of the file in `offset..offset + len`
+    ///
+    /// Without flags, allocate the space and extend the file size if needed.
+    fn fallocate(&self, _flags: FallocateFlags, _offset: usize, _len: usize) -> Result<()> {
+        Err(FsError::NotSupported)
+    }
+
+    /// Find the first data at or after `offset`, like `lseek` with `SEEK_DATA`.
+    /// Return `None` if there is no data after `offset`.
+    fn seek_data(&self, offset: usize) -> Result<Option<usize>> {
+        let size = self.metadata()?.size;
+        Ok(if offset < size { Some(offset) } else { None })
+    }
+
+    /// Find the first hole at or after `offset`, like `lseek` with `SEEK_HOLE`.
+    /// The end of file is an implicit hole. Return `None` if `offset` is beyond the end.
+    fn seek_hole(&self, offset: usize) -> Result<Option<usize>> {
+        let size = self.metadata()?.size;
+        Ok(if offset < size { Some(size) } else { None })
+    }
+
+    /// Copy `len` bytes at `src_offset` of `src` to `dst_offset` of this file, like
+    /// `copy_file_range`. Return the number of bytes copied, which is short at the end of `src`.
+    ///
+    /// File systems may copy without a round trip through a buffer, or share the blocks.
+    fn copy_range(
+        &self,
+        src: &dyn INode,
+        src_offset: usize,
+        dst_offset: usize,
+        len: usize,
+    ) -> Result<usize> {
+        generic_copy_range(self, src, src_offset, dst_offset, len)
+    }
+
+    /// Share the content of `src` in `src_offset..src_offset + len` with this file at
+    /// `dst_offset`, like `ioctl` with `FICLONERANGE`. Writes to either copy are private.
+    ///
+    /// Offsets must be aligned to the block size, and so must `len` unless the range ends
+    /// at the end of `src`. A `len` of 0 means to the end of `src`.
+    fn clone_range(
+        &self,
+        _src: &dyn INode,
+        _src_offset: usize,
+        _dst_offset: usize,
+        _len: usize,
+    ) -> Result<()> {
+        Err(FsError::NotSupported)
+    }
+
     /// Create a new INode in the directory
     fn create(&self, name: &str, type_: FileType, mode: u32) -> Result<Arc<dyn INode>> {
         self.create2(name, type_, mode, 0)
@@ -66,6 +194,35 @@ pub trait INode: Any + Sync + Send {
         self.create(name, type_, mode)
     }
 
+    /// Create a symlink `name` pointing to `target` in the directory
+    fn symlink(&self, name: &str, target: &str) -> Result<Arc<dyn INode>> {
+        let inode = self.create(name, FileType::SymLink, 0o777)?;
+        if let Err(e) = inode.write_at(0, target.as_bytes()) {
+            // the error of writing is returned even if the link can not be removed
+            let _ = self.unlink(name);
+            return Err(e);
+        }
+        Ok(inode)
+    }
+
+    /// Read the target of the symlink
+    fn read_link(&self) -> Result<String> {
+        let info = self.metadata()?;
+        if info.type_ != FileType::SymLink {
+            return Err(FsError::InvalidParam);
+        }
+        let mut buf = vec![0u8; info.size];
+        let mut len = 0;
+        while len < buf.len() {
+            match self.read_at(len, &mut buf[len..])? {
+                0 => break,
+                n => len += n,
+            }
+        }
+        buf.truncate(len);
+        String::from_utf8(buf).map_err(|_| FsError::InvalidParam)
+    }
+
     /// Create a hard link `name` to `other`
     fn link(&self, _name: &str, _other: &Arc<dyn INode>) -> Result<()> {
         Err(FsError::NotSupported)
@@ -78,7 +235,22 @@ pub trait INode: Any + Sync + Send {
 
     /// Move INode `self/old_name` to `target/new_name`.
     /// If `target` equals `self`, do rename.
-    fn move_(&self, _old_name: &str, _target: &Arc<dyn INode>, _new_name: &str) -> Result<()> {
+    fn move_(&self, old_name: &str, target: &Arc<dyn INode>, new_name: &str) -> Result<()> {
+        self.rename(old_name, target, new_name, RenameFlags::default())
+    }
+
+    /// Move INode `self/old_name` to `target/new_name` with `flags`.
+    ///
+    /// An existing `target/new_name` is replaced unless `flags.noreplace` is set,
+    /// or swapped with `self/old_name` if `flags.exchange` is set.
+    /// Return `InvalidParam` if a directory would be moved beneath itself.
+    fn rename(
+        &self,
+        _old_name: &str,
+        _target: &Arc<dyn INode>,
+        _new_name: &str,
+        _flags: RenameFlags,
+    ) -> Result<()> {
         Err(FsError::NotSupported)
     }
 
@@ -100,6 +272,26 @@ pub trait INode: Any + Sync + Send {
         Ok((entry.metadata()?, name))
     }
 
+    /// Read the first directory entry at or after `cookie`, return `None` at the end.
+    ///
+    /// Iteration starts at cookie 0, and continues at `DirEntry::next_cookie`.
+    /// A cookie remains valid after other entries are unlinked.
+    fn read_dir(&self, cookie: usize) -> Result<Option<DirEntry>> {
+        // fallback to entry index, which is not stable across unlinks
+        let name = match self.get_entry(cookie) {
+            Ok(name) => name,
+            Err(FsError::EntryNotFound) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let info = self.find(&name)?.metadata()?;
+        Ok(Some(DirEntry {
+            name,
+            inode: info.inode,
+            type_: info.type_,
+            next_cookie: cookie + 1,
+        }))
+    }
+
     /// Control device
     fn io_control(&self, _cmd: u32, _data: usize) -> Result<usize> {
         Err(FsError::NotSupported)
@@ -110,6 +302,14 @@ pub trait INode: Any + Sync + Send {
         Err(FsError::NotSupported)
     }
 
+    /// Get page `index` of the file for memory mapping, see `page_cache` for the usage
+    ///
+    /// The page is pinned in the page cache until dropped,
+    /// and the INode should be kept as long as it is mapped.
+    fn map_page(&self, _index: usize) -> Result<PinnedPage> {
+        Err(FsError::NotSupported)
+    }
+
     /// Get the file system of the INode
     fn fs(&self) -> Arc<dyn FileSystem> {
         unimplemented!();
@@ -126,17 +326,29 @@ impl dyn INode {
         self.as_any_ref().downcast_ref::<T>()
     }
 
+    /// Register a watch on this INode for the events in `mask`
+    pub fn watch(&self, mask: EventMask) -> Result<Arc<Watch>> {
+        let inode = self.metadata()?.inode;
+        let fs = self.fs();
+        let notifier = fs.notifier().ok_or(FsError::NotSupported)?;
+        Ok(notifier.watch(inode, mask, MAX_QUEUED_EVENTS))
+    }
+
     /// Get all directory entries as a Vec
     pub fn list(&self) -> Result<Vec<String>> {
         let info = self.metadata()?;
         if info.type_ != FileType::Dir {
             return Err(FsError::NotDir);
         }
-        Ok((0..)
-            .map(|i| self.get_entry(i))
-            .take_while(|result| result.is_ok())
-            .filter_map(|result| result.ok())
-            .collect())
+        self.entries(0).map(|entry| Ok(entry?.name)).collect()
+    }
+
+    /// Iterate directory entries, starting at `cookie`
+    pub fn entries(&self, cookie: usize) -> DirIter<'_> {
+        DirIter {
+            inode: self,
+            cookie: Some(cookie),
+        }
     }
 
     /// Lookup path from current INode, and do not follow symlinks
@@ -145,7 +357,29 @@ impl dyn INode {
     }
 
     /// Lookup path from current INode, and follow symlinks at most `follow_times` times
+    ///
+    /// See `crate::path::PathResolver` for POSIX path resolution.
     pub fn lookup_follow(&self, path: &str, follow_times: usize) -> Result<Arc<dyn INode>> {
+        // the superuser can search any directory
+        self.lookup_follow_checked(&Credentials::root(), path, follow_times)
+    }
+
+    /// Lookup path from current INode as `cred`, and do not follow symlinks
+    ///
+    /// Search permission is required on every directory along the path.
+    pub fn lookup_checked(&self, cred: &Credentials, path: &str) -> Result<Arc<dyn INode>> {
+        self.lookup_follow_checked(cred, path, 0)
+    }
+
+    /// Lookup path from current INode as `cred`, and follow symlinks at most `follow_times` times
+    ///
+    /// Search permission is required on every directory along the path.
+    pub fn lookup_follow_checked(
+        &self,
+        cred: &Credentials,
+        path: &str,
+        follow_times: usize,
+    ) -> Result<Arc<dyn INode>> {
         if self.metadata()?.type_ != FileType::Dir {
             return Err(FsError::NotDir);
         }
@@ -158,9 +392,11 @@ impl dyn INode {
         };
 
         while !rest_path.is_empty() {
-            if result.metadata()?.type_ != FileType::Dir {
+            let info = result.metadata()?;
+            if info.type_ != FileType::Dir {
                 return Err(FsError::NotDir);
             }
+            cred.check_access(&info, AccessMode::SEARCH)?;
             let name;
             match rest_path.find('/') {
                 None => {
@@ -178,19 +414,196 @@ impl dyn INode {
             let inode = result.find(&name)?;
             // Handle symlink
             if inode.metadata()?.type_ == FileType::SymLink && follow_times > 0 {
-                let mut content = [0u8; 256];
-                let len = inode.read_at(0, &mut content)?;
-                let link_path =
-                    String::from(str::from_utf8(&content[..len]).map_err(|_| FsError::NotDir)?);
+                let link_path = inode.read_link()?;
                 // result remains unchanged
                 let new_path = link_path + "/" + &rest_path;
-                return result.lookup_follow(&new_path, follow_times - 1);
+                return result.lookup_follow_checked(cred, &new_path, follow_times - 1);
             } else {
                 result = inode
             }
         }
         Ok(result)
     }
+
+    /// Lookup path as `cred` like `lookup_follow_checked`,
+    /// then check whether `cred` is allowed to access the target with `access`
+    pub fn open_checked(
+        &self,
+        cred: &Credentials,
+        path: &str,
+        follow_times: usize,
+        access: AccessMode,
+    ) -> Result<Arc<dyn INode>> {
+        let inode = self.lookup_follow_checked(cred, path, follow_times)?;
+        cred.check_access(&inode.metadata()?, access)?;
+        Ok(inode)
+    }
+
+    /// Create a new INode in the directory as `cred`, which will be owned by `cred`
+    ///
+    /// Write and search permission are required on the directory.
+    pub fn create_checked(
+        &self,
+        cred: &Credentials,
+        name: &str,
+        type_: FileType,
+        mode: u32,
+    ) -> Result<Arc<dyn INode>> {
+        let info = self.metadata()?;
+        if info.type_ != FileType::Dir {
+            return Err(FsError::NotDir);
+        }
+        cred.check_access(&info, AccessMode::WRITE_SEARCH)?;
+        let inode = self.create(name, type_, mode)?;
+        let mut info = inode.metadata()?;
+        if (info.uid, info.gid) != (cred.uid, cred.gid) {
+            info.uid = cred.uid;
+            info.gid = cred.gid;
+            match inode.set_metadata(&info) {
+                // the file system has no notion of ownership
+                Ok(()) | Err(FsError::NotSupported) => {}
+                Err(e) => {
+                    // not to leave it with a wrong owner
+                    let _ = self.unlink(name);
+                    return Err(e);
+                }
+            }
+        }
+        Ok(inode)
+    }
+
+    /// Remove a link from the directory as `cred`
+    ///
+    /// Write and search permission are required on the directory.
+    /// If the sticky bit of the directory is set,
+    /// `cred` must also own either the directory or the file.
+    pub fn unlink_checked(&self, cred: &Credentials, name: &str) -> Result<()> {
+        let info = self.metadata()?;
+        if info.type_ != FileType::Dir {
+            return Err(FsError::NotDir);
+        }
+        cred.check_access(&info, AccessMode::WRITE_SEARCH)?;
+        if info.mode & S_ISVTX != 0 && !cred.is_root() && cred.uid != info.uid {
+            let target = self.find(name)?;
+            if cred.uid != target.metadata()?.uid {
+                return Err(FsError::PermissionDenied);
+            }
+        }
+        self.unlink(name)
+    }
+}
+
+/// Iterator over directory entries, created by `INode::entries`
+pub struct DirIter<'a> {
+    inode: &'a dyn INode,
+    /// `None` after the end or an error
+    cookie: Option<usize>,
+}
+
+impl Iterator for DirIter<'_> {
+    type Item = Result<DirEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let result = self.inode.read_dir(self.cookie?);
+        self.cookie = match &result {
+            Ok(Some(entry)) => Some(entry.next_cookie),
+            _ => None,
+        };
+        result.transpose()
+    }
+}
+
+/// Sticky bit of `Metadata::mode`, restricts unlinking in a directory
+pub const S_ISVTX: u16 = 0o1000;
+
+/// Identity of a caller, used in permission checks
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct Credentials {
+    /// User ID
+    pub uid: usize,
+    /// Group ID
+    pub gid: usize,
+    /// Supplementary group IDs
+    pub groups: Vec<usize>,
+}
+
+impl Credentials {
+    /// Credentials of the superuser
+    pub fn root() -> Self {
+        Credentials::default()
+    }
+
+    /// Whether the caller is the superuser
+    pub fn is_root(&self) -> bool {
+        self.uid == 0
+    }
+
+    /// Whether the caller is a member of group `gid`
+    pub fn in_group(&self, gid: usize) -> bool {
+        self.gid == gid || self.groups.contains(&gid)
+    }
+
+    /// Check whether the caller is allowed to access a file with `info` by `access`
+    ///
+    /// The superuser may always read and write, and may execute
+    /// if the file is a directory or any execute bit is set.
+    pub fn check_access(&self, info: &Metadata, access: AccessMode) -> Result<()> {
+        let allowed = if self.is_root() {
+            !access.execute || info.type_ == FileType::Dir || info.mode & 0o111 != 0
+        } else {
+            let bits = if self.uid == info.uid {
+                info.mode >> 6
+            } else if self.in_group(info.gid) {
+                info.mode >> 3
+            } else {
+                info.mode
+            };
+            (!access.read || bits & 0o4 != 0)
+                && (!access.write || bits & 0o2 != 0)
+                && (!access.execute || bits & 0o1 != 0)
+        };
+        if allowed {
+            Ok(())
+        } else {
+            Err(FsError::PermissionDenied)
+        }
+    }
+}
+
+/// Kind of access to a file, execute means search for directories
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct AccessMode {
+    pub read: bool,
+    pub write: bool,
+    pub execute: bool,
+}
+
+impl AccessMode {
+    pub const READ: Self = AccessMode {
+        read: true,
+        write: false,
+        execute: false,
+    };
+    pub const WRITE: Self = AccessMode {
+        read: false,
+        write: true,
+        execute: false,
+    };
+    pub const READ_WRITE: Self = AccessMode {
+        read: true,
+        write: true,
+        execute: false,
+    };
+    pub const SEARCH: Self = AccessMode {
+        read: false,
+        write: false,
+        execute: true,
+    };
+    pub const WRITE_SEARCH: Self = AccessMode {
+        read: false,
+        write: true,
+        execute: true,
+    };
 }
 
 pub enum IOCTLError {
@@ -207,7 +620,7 @@ pub struct PollStatus {
     pub error: bool,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct MMapArea {
     /// Start virtual address
     pub start_vaddr: usize,
@@ -221,6 +634,25 @@ pub struct MMapArea {
     pub offset: usize,
 }
 
+impl MMapArea {
+    /// The area may be written
+    pub const PROT_WRITE: usize = 0x2;
+    /// Writes are shared with the file and the other mappings
+    pub const MAP_SHARED: usize = 0x1;
+    /// Writes are private to the mapping
+    pub const MAP_PRIVATE: usize = 0x2;
+
+    pub fn is_shared(&self) -> bool {
+        self.flags & Self::MAP_SHARED != 0
+    }
+
+    /// Indexes of the pages of the file in the area
+    pub fn pages(&self) -> Range<usize> {
+        let begin = self.offset / PAGE_SIZE;
+        begin..begin + (self.end_vaddr - self.start_vaddr).div_ceil(PAGE_SIZE)
+    }
+}
+
 /// Metadata of INode
 ///
 /// Ref: [http://pubs.opengroup.org/onlinepubs/009604499/basedefs/sys/stat.h.html]
@@ -264,6 +696,69 @@ pub struct Metadata {
     pub rdev: usize, // (major << 8) | minor
 }
 
+/// An entry in directory, returned by `INode::read_dir`
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct DirEntry {
+    /// Name of the entry
+    pub name: String,
+    /// Inode number
+    pub inode: usize,
+    /// Type of file
+    pub type_: FileType,
+    /// Cookie to read the entry after this one
+    pub next_cookie: usize,
+}
+
+/// Flags for setting an extended attribute
+///
+/// Ref: [https://man7.org/linux/man-pages/man2/setxattr.2.html]
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct XattrFlags {
+    /// Fail if the attribute already exists (XATTR_CREATE)
+    pub create: bool,
+    /// Fail if the attribute does not exist (XATTR_REPLACE)
+    pub replace: bool,
+}
+
+/// Flags for `INode::rename`
+///
+/// Ref: [https://man7.org/linux/man-pages/man2/rename.2.html]
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct RenameFlags {
+    /// Fail if the target already exists (RENAME_NOREPLACE)
+    pub noreplace: bool,
+    /// Atomically exchange the source and the target (RENAME_EXCHANGE)
+    pub exchange: bool,
+}
+
+/// Flags for `INode::fallocate`
+///
+/// Ref: [https://man7.org/linux/man-pages/man2/fallocate.2.html]
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct FallocateFlags {
+    /// Do not change the file size (FALLOC_FL_KEEP_SIZE)
+    pub keep_size: bool,
+    /// Deallocate the range and leave a hole, requires `keep_size` (FALLOC_FL_PUNCH_HOLE)
+    pub punch_hole: bool,
+    /// Zero the range and keep it allocated (FALLOC_FL_ZERO_RANGE)
+    pub zero_range: bool,
+}
+
+impl FallocateFlags {
+    /// Check the combination of flags
+    pub fn check(&self) -> Result<()> {
+        if self.punch_hole && (!self.keep_size || self.zero_range) {
+            return Err(FsError::InvalidParam);
+        }
+        Ok(())
+    }
+}
+
+/// Max length of an extended attribute name
+pub const XATTR_NAME_MAX: usize = 255;
+/// Max size of an extended attribute value
+pub const XATTR_SIZE_MAX: usize = 65536;
+
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug, Hash)]
 pub struct Timespec {
     pub sec: i64,
@@ -304,9 +799,9 @@ pub struct FsInfo {
     pub namemax: usize,
 }
 
-// Note: IOError/NoMemory always lead to a panic since it's hard to recover from it.
-//       We also panic when we can not parse the fs on disk normally
-#[derive(Debug, Eq, PartialEq)]
+// Note: errors from the device are kept in `Device`, while `DeviceError` is left for
+//       a missing or failed device without more details.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
 pub enum FsError {
     NotSupported,  // E_UNIMP, or E_INVAL
     NotFile,       // E_ISDIR
@@ -323,10 +818,102 @@ pub enum FsError {
     DeviceError,
     IOCTLError,
     NoDevice,
-    Again,       // E_AGAIN, when no data is available, never happens in fs
-    SymLoop,     // E_LOOP
-    Busy,        // E_BUSY
-    Interrupted, // E_INTR
+    Again,            // E_AGAIN, when no data is available or a lock is held by others
+    SymLoop,          // E_LOOP
+    Busy,             // E_BUSY
+    Interrupted,      // E_INTR
+    NoAttr,           // E_NODATA, when the extended attribute does not exist
+    AttrTooBig,       // E_2BIG, when the extended attribute value is too large
+    PermissionDenied, // E_ACCES
+    Deadlock,         // E_DEADLK, when waiting for a lock would never end
+    NotPermitted,     // E_PERM, when only the owner or root may do it
+    NameTooLong,      // E_NAMETOOLONG
+    ReadOnly,         // E_ROFS
+    FileTooBig,       // E_FBIG
+    TooManyLinks,     // E_MLINK
+    NoMemory,         // E_NOMEM
+    Corrupted,        // E_UCLEAN, when the content on disk is inconsistent
+    Device(DevError), // the device failed, with the error from it
+}
+
+impl FsError {
+    /// The error number of the error, see `errno` for the numbers
+    pub fn to_errno(&self) -> i32 {
+        use crate::errno::*;
+        match self {
+            FsError::NotSupported => ENOSYS,
+            FsError::NotFile => EISDIR,
+            FsError::IsDir => EISDIR,
+            FsError::NotDir => ENOTDIR,
+            FsError::EntryNotFound => ENOENT,
+            FsError::EntryExist => EEXIST,
+            FsError::NotSameFs => EXDEV,
+            FsError::InvalidParam => EINVAL,
+            FsError::NoDeviceSpace => ENOSPC,
+            FsError::DirRemoved => ENOENT,
+            FsError::DirNotEmpty => ENOTEMPTY,
+            FsError::WrongFs => EINVAL,
+            FsError::DeviceError => EIO,
+            FsError::IOCTLError => ENOTTY,
+            FsError::NoDevice => ENODEV,
+            FsError::Again => EAGAIN,
+            FsError::SymLoop => ELOOP,
+            FsError::Busy => EBUSY,
+            FsError::Interrupted => EINTR,
+            FsError::NoAttr => ENOATTR,
+            FsError::AttrTooBig => E2BIG,
+            FsError::PermissionDenied => EACCES,
+            FsError::Deadlock => EDEADLK,
+            FsError::NotPermitted => EPERM,
+            FsError::NameTooLong => ENAMETOOLONG,
+            FsError::ReadOnly => EROFS,
+            FsError::FileTooBig => EFBIG,
+            FsError::TooManyLinks => EMLINK,
+            FsError::NoMemory => ENOMEM,
+            FsError::Corrupted => EUCLEAN,
+            FsError::Device(e) => e.errno(),
+        }
+    }
+
+    /// The error of an error number, the numbers without a variant are kept in `Device`
+    pub fn from_errno(errno: i32) -> Self {
+        use crate::errno::*;
+        match errno {
+            ENOSYS => FsError::NotSupported,
+            EISDIR => FsError::IsDir,
+            ENOTDIR => FsError::NotDir,
+            ENOENT => FsError::EntryNotFound,
+            EEXIST => FsError::EntryExist,
+            EXDEV => FsError::NotSameFs,
+            EINVAL => FsError::InvalidParam,
+            ENOSPC => FsError::NoDeviceSpace,
+            ENOTEMPTY => FsError::DirNotEmpty,
+            EIO => FsError::DeviceError,
+            ENOTTY => FsError::IOCTLError,
+            ENODEV => FsError::NoDevice,
+            EAGAIN => FsError::Again,
+            ELOOP => FsError::SymLoop,
+            EBUSY => FsError::Busy,
+            EINTR => FsError::Interrupted,
+            ENOATTR => FsError::NoAttr,
+            E2BIG => FsError::AttrTooBig,
+            EACCES => FsError::PermissionDenied,
+            EDEADLK => FsError::Deadlock,
+            EPERM => FsError::NotPermitted,
+            ENAMETOOLONG => FsError::NameTooLong,
+            EROFS => FsError::ReadOnly,
+            EFBIG => FsError::FileTooBig,
+            EMLINK => FsError::TooManyLinks,
+            ENOMEM => FsError::NoMemory,
+            // an alias of `EIO` on the hosts without it
+            #[cfg(not(all(feature = "std", unix, not(target_os = "linux"))))]
+            EUCLEAN => FsError::Corrupted,
+            ENXIO => FsError::Device(DevErrorKind::OutOfRange.into()),
+            ETIMEDOUT => FsError::Device(DevErrorKind::TimedOut.into()),
+            EOPNOTSUPP => FsError::Device(DevErrorKind::Unsupported.into()),
+            _ => FsError::Device(DevError::with_code(DevErrorKind::Other, errno)),
+        }
+    }
 }
 
 impl fmt::Display for FsError {
@@ -336,8 +923,8 @@ impl fmt::Display for FsError {
 }
 
 impl From<DevError> for FsError {
-    fn from(_: DevError) -> Self {
-        FsError::DeviceError
+    fn from(e: DevError) -> Self {
+        FsError::Device(e)
     }
 }
 
@@ -353,8 +940,120 @@ pub trait FileSystem: Sync + Send {
 
     /// Get the file system information
     fn info(&self) -> FsInfo;
+
+    /// Get the notifier the file system emits change events into, if any
+    fn notifier(&self) -> Option<&Notifier> {
+        None
+    }
+
+    /// Identity of the file system holding the files, see `lock::LockKey`.
+    /// Wrappers of another file system like `MountFS` have the identity of the wrapped one.
+    fn identity(&self) -> usize {
+        self as *const Self as *const u8 as usize
+    }
+}
+
+/// Copy a range between files through a buffer, the default of `INode::copy_range`.
+/// Stop early at the end of `src`, or when `dst` takes no more bytes.
+pub fn generic_copy_range<T: INode + ?Sized>(
+    dst: &T,
+    src: &dyn INode,
+    src_offset: usize,
+    dst_offset: usize,
+    len: usize,
+) -> Result<usize> {
+    let mut buf = vec![0u8; 0x1000];
+    let mut copied = 0;
+    while copied < len {
+        let chunk = buf.len().min(len - copied);
+        let read = src.read_at(src_offset + copied, &mut buf[..chunk])?;
+        if read == 0 {
+            break;
+        }
+        let written = dst.write_at(dst_offset + copied, &buf[..read])?;
+        if written == 0 {
+            break;
+        }
+        copied += written;
+    }
+    Ok(copied)
 }
 
 pub fn make_rdev(major: usize, minor: usize) -> usize {
     ((major & 0xfff) << 8) | (minor & 0xff)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::errno::*;
+
+    #[test]
+    fn errno() {
+        let errors = [
+            FsError::NotSupported,
+            FsError::IsDir,
+            FsError::EntryNotFound,
+            FsError::NoAttr,
+            FsError::PermissionDenied,
+            FsError::NotPermitted,
+            FsError::NameTooLong,
+            FsError::ReadOnly,
+            FsError::FileTooBig,
+            #[cfg(not(all(feature = "std", unix, not(target_os = "linux"))))]
+            FsError::Corrupted,
+            FsError::Device(DevErrorKind::TimedOut.into()),
+        ];
+        for error in errors {
+            assert_eq!(FsError::from_errno(error.to_errno()), error);
+        }
+        assert_eq!(FsError::NotFile.to_errno(), EISDIR);
+        assert_eq!(
+            FsError::from(DevError::new(DevErrorKind::Io)).to_errno(),
+            EIO
+        );
+        assert_eq!(
+            FsError::from_errno(10000),
+            FsError::Device(DevError::with_code(DevErrorKind::Other, 10000))
+        );
+    }
+
+    /// A file of endless ones, taking at most `room` bytes
+    struct Ones {
+        room: core::sync::atomic::AtomicUsize,
+    }
+
+    impl INode for Ones {
+        fn read_at(&self, _offset: usize, buf: &mut [u8]) -> Result<usize> {
+            buf.fill(1);
+            Ok(buf.len())
+        }
+
+        fn write_at(&self, _offset: usize, buf: &[u8]) -> Result<usize> {
+            use core::sync::atomic::Ordering;
+            let room = self.room.load(Ordering::SeqCst);
+            let len = buf.len().min(room);
+            self.room.store(room - len, Ordering::SeqCst);
+            Ok(len)
+        }
+
+        fn poll(&self) -> Result<PollStatus> {
+            Ok(PollStatus::default())
+        }
+
+        fn as_any_ref(&self) -> &dyn Any {
+            self
+        }
+    }
+
+    #[test]
+    fn copy_range_short_write() -> Result<()> {
+        let src = Ones { room: 0.into() };
+        let dst = Ones {
+            room: 0x1800.into(),
+        };
+        assert_eq!(generic_copy_range(&dst, &src, 0, 0, 0x4000)?, 0x1800);
+        assert_eq!(generic_copy_range(&dst, &src, 0, 0, 0x4000)?, 0);
+        Ok(())
+    }
+}